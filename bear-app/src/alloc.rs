@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use bear_ass::debug::Resolver;
+use bear_ass::parser::ast;
+use bear_vm::vm::{call_site, BearVM, CallbackDebugger, Cell, DynDebugger, ExecutionState, OpCode};
+
+/// Where an allocator command was issued from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    /// The address of the `io` instruction.
+    pub ip: usize,
+    /// The address of the `call` of the routine which executed the `io`, if any.
+    pub caller: Option<usize>,
+}
+
+/// The bookkeeping of the allocator device, shared with the host for `--alloc-report`.
+#[derive(Debug, Default)]
+pub struct Allocations {
+    /// The live blocks: their size and where they were allocated, by address.
+    pub live: BTreeMap<usize, (usize, Option<Site>)>,
+    /// The number of bytes allocated.
+    pub in_use: usize,
+    /// The high-water mark of `in_use`.
+    pub peak: usize,
+    /// The number of allocations made.
+    pub count: usize,
+    /// Frees of addresses which were not allocated.
+    pub invalid_frees: Vec<(usize, Option<Site>)>,
+    /// The site of the `io` being executed, recorded by a `SiteRecorder`.
+    pub site: Option<Site>,
+}
+
+impl Allocations {
+    pub fn allocated(&mut self, address: usize, size: usize) {
+        self.live.insert(address, (size, self.site));
+        self.in_use += size;
+        self.peak = self.peak.max(self.in_use);
+        self.count += 1;
+    }
+
+    /// Returns the size of the freed block, or `None` if `address` was not allocated.
+    pub fn freed(&mut self, address: usize) -> Option<usize> {
+        match self.live.remove(&address) {
+            Some((size, _)) => {
+                self.in_use -= size;
+                Some(size)
+            }
+            None => {
+                self.invalid_frees.push((address, self.site));
+                None
+            }
+        }
+    }
+
+    /// Summarizes usage, and lists the blocks which are still live and the invalid frees.
+    pub fn report(&self, debug: Option<&ast::Debug>) -> String {
+        let resolver = debug.map(Resolver::new);
+        let mut out = format!(
+            "allocations: {}, peak: {} bytes, leaked: {} bytes in {} blocks\n",
+            self.count,
+            self.peak,
+            self.in_use,
+            self.live.len()
+        );
+        for (address, (size, site)) in self.live.iter() {
+            out.push_str(&format!(
+                "leaked {} bytes at {:#x}{}\n",
+                size,
+                address,
+                describe(*site, resolver.as_ref())
+            ));
+        }
+        for (address, site) in self.invalid_frees.iter() {
+            out.push_str(&format!(
+                "invalid free of {:#x}{}\n",
+                address,
+                describe(*site, resolver.as_ref())
+            ));
+        }
+        out
+    }
+}
+
+fn describe(site: Option<Site>, resolver: Option<&Resolver>) -> String {
+    let symbol = |address: usize| match resolver {
+        Some(resolver) => resolver.lookup(address).to_string(),
+        None => format!("{:#x}", address),
+    };
+    match site {
+        None => String::new(),
+        Some(Site { ip, caller: None }) => format!(", allocated at {}", symbol(ip)),
+        Some(Site {
+            ip,
+            caller: Some(caller),
+        }) => format!(
+            ", allocated at {}, called from {}",
+            symbol(ip),
+            symbol(caller)
+        ),
+    }
+}
+
+/**
+ * Records where each `io` is executed from, and the return address on top of the address stack,
+ * so that the allocator device can attribute blocks to their allocation sites.
+ *
+ * Every callback is forwarded to `inner`, so it can be combined with another debugger.
+ */
+pub struct SiteRecorder {
+    allocations: Rc<RefCell<Allocations>>,
+    inner: Option<Box<dyn CallbackDebugger>>,
+}
+
+impl SiteRecorder {
+    pub fn new(
+        allocations: Rc<RefCell<Allocations>>,
+        inner: Option<Box<dyn CallbackDebugger>>,
+    ) -> SiteRecorder {
+        SiteRecorder { allocations, inner }
+    }
+}
+
+impl CallbackDebugger for SiteRecorder {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
+        if let OpCode::Io = op {
+            let caller = state.vm.address.last().map(|ip| call_site(ip.0));
+            self.allocations.borrow_mut().site = Some(Site {
+                ip: state.ip(),
+                caller,
+            });
+        }
+        if let Some(inner) = &self.inner {
+            inner.ip(state, op);
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.data_pop(vm);
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.data_push(vm, cell);
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.address_pop(vm);
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.address_push(vm, cell);
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store(address, value);
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store_8(address, value);
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.brk(state);
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::Deserialize;
+
+use bear_vm::checksum;
+use bear_vm::device::Device;
+use bear_vm::shared::MailboxDevice;
+use bear_vm::vm::BearVM;
+
+use crate::alloc::Allocations;
+use crate::devices::{
+    AllocDevice, Capture, EnvDevice, ExitCode, ExitDevice, InterruptingStdinDevice, LogDevice, MappedFileDevice, NullDevice,
+    FramebufferDevice, KeyboardDevice, Keys, KvDevice, SerialPort, StdinDevice, StdoutDevice, TimerDevice, UartDevice,
+};
+use crate::screen::{PpmScreen, Screen};
+use crate::terminal::Terminal;
+
+/// The name of the project manifest, which may declare the devices to attach.
+pub const MANIFEST: &str = "bear.toml";
+
+/// Replaces the host's stdin, stdout and stderr for the standard devices which have no path.
+#[derive(Debug, Clone, Default)]
+pub struct Redirect {
+    pub stdin: Vec<u8>,
+    pub stdout: Capture,
+    pub stderr: Capture,
+}
+
+/// A device and its parameters.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// Reads bytes from the host's stdin, or from `path`.  With `interrupt`, reads ahead and
+    /// raises that interrupt vector when a byte is waiting.
+    Stdin {
+        path: Option<PathBuf>,
+        #[serde(default)]
+        interrupt: Option<u8>,
+    },
+    /// Writes bytes to the host's stdout, or appends them to `path`.
+    Stdout { path: Option<PathBuf> },
+    /// Writes bytes to the host's stderr, or appends them to `path`.
+    Stderr { path: Option<PathBuf> },
+    /// Writes interned strings to the host's stderr.
+    Log,
+    /// Maps windows of `path`, which is created if it does not exist, into guest memory.
+    File { path: PathBuf },
+    /// Allocates blocks of the `size` bytes of guest memory at `base`.
+    Alloc { base: u32, size: u32 },
+    /// Stores values under string keys in `path`, which is created if it does not exist.
+    Kv { path: PathBuf },
+    /// Exposes the arguments after `--` and the environment variables.
+    Env,
+    /// Sets the status the host exits with, in `Devices::exit`.
+    Exit,
+    /// Answers commands with the `ioctl` function of the Rhai script at `path`.
+    Script { path: PathBuf },
+    /// Counts down instructions or milliseconds.
+    Timer,
+    /// A UART bound to the tty at `path`, or to the TCP connections accepted on `listen`.
+    Uart {
+        path: Option<PathBuf>,
+        listen: Option<String>,
+    },
+    /// A framebuffer of `width` × `height` pixels, shown in a window whose pixels are `scale`
+    /// pixels wide, or written to `path` as a PPM image.
+    Framebuffer {
+        width: u16,
+        height: u16,
+        #[serde(default)]
+        scale: Option<usize>,
+        path: Option<PathBuf>,
+    },
+    /// A keyboard, whose key events come from the window of the framebuffer or from the terminal.
+    Keyboard {
+        #[serde(default)]
+        source: KeySource,
+    },
+    /// One end of a mailbox, whose other end is the only device of the image at `peer`, which
+    /// runs on its own thread.
+    Mailbox { peer: PathBuf },
+    /// Fails every command.  Fills the gaps between explicitly numbered devices.
+    Null,
+}
+
+/// Where a keyboard device gets its key events.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    /// The host's terminal.
+    #[default]
+    Terminal,
+    /// The window of a framebuffer device.
+    Window,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    /// The device id.  Defaults to the next free id.
+    pub id: Option<usize>,
+    #[serde(flatten)]
+    pub kind: DeviceKind,
+}
+
+/**
+ * The devices attached to the VM, in the format of `--devices devices.toml` and `bear.toml`:
+ *
+ * ```toml
+ * [[devices]]
+ * kind = "stdin"
+ * path = "input.txt"
+ *
+ * [[devices]]
+ * kind = "stdout"
+ * ```
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct Devices {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    /// The arguments of the guest, for environment devices.
+    #[serde(skip)]
+    pub arguments: Vec<String>,
+    /// The exit status set by the guest, through the exit devices last built.
+    #[serde(skip)]
+    pub exit: ExitCode,
+}
+
+impl Default for Devices {
+    /// stdin, stdout, the log device, stderr and the exit device, as devices 0 to 4.
+    fn default() -> Self {
+        let devices = vec![
+            DeviceKind::Stdin {
+                path: None,
+                interrupt: None,
+            },
+            DeviceKind::Stdout { path: None },
+            DeviceKind::Log,
+            DeviceKind::Stderr { path: None },
+            DeviceKind::Exit,
+        ];
+        Devices {
+            devices: devices
+                .into_iter()
+                .map(|kind| DeviceConfig { id: None, kind })
+                .collect(),
+            arguments: Vec::new(),
+            exit: ExitCode::default(),
+        }
+    }
+}
+
+impl Devices {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    /// Reads the devices from `bear.toml` in the working directory, if it declares any.
+    pub fn from_manifest() -> Result<Option<Self>, String> {
+        let path = Path::new(MANIFEST);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let devices = Devices::load(path)?;
+        Ok(if devices.devices.is_empty() {
+            None
+        } else {
+            Some(devices)
+        })
+    }
+
+    /// Replaces the path of every device of the same kind.
+    pub fn override_path(&mut self, kind: &str, new: &Path) {
+        for device in self.devices.iter_mut() {
+            match (&mut device.kind, kind) {
+                (DeviceKind::Stdin { path, .. }, "stdin")
+                | (DeviceKind::Stdout { path }, "stdout")
+                | (DeviceKind::Stderr { path }, "stderr") => {
+                    *path = Some(new.to_path_buf())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The id of each device, in the order they are listed.
+    fn ids(&self) -> Result<Vec<usize>, String> {
+        let mut ids: Vec<usize> = Vec::new();
+        for config in self.devices.iter() {
+            let id = match config.id {
+                Some(id) => id,
+                None => (0..).find(|id| !ids.contains(id)).unwrap(),
+            };
+            if ids.contains(&id) {
+                return Err(format!("Device id {} is used more than once.", id));
+            }
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+
+    /// The number of devices created by `build`.
+    pub fn count(&self) -> Result<usize, String> {
+        Ok(self.ids()?.iter().map(|id| id + 1).max().unwrap_or(0))
+    }
+
+    /// Creates the devices, ordered by id.  Allocator devices keep their books in `allocations`.
+    pub fn build(
+        &self,
+        strings: &[String],
+        allocations: &Rc<RefCell<Allocations>>,
+    ) -> Result<Vec<Box<dyn Device>>, String> {
+        self.build_with(strings, allocations, None)
+    }
+
+    /// Like `build`, but the stdin, stdout and stderr devices without a path use `redirect`.
+    pub fn build_redirected(
+        &self,
+        strings: &[String],
+        allocations: &Rc<RefCell<Allocations>>,
+        redirect: &Redirect,
+    ) -> Result<Vec<Box<dyn Device>>, String> {
+        self.build_with(strings, allocations, Some(redirect))
+    }
+
+    fn build_with(
+        &self,
+        strings: &[String],
+        allocations: &Rc<RefCell<Allocations>>,
+        redirect: Option<&Redirect>,
+    ) -> Result<Vec<Box<dyn Device>>, String> {
+        let mut slots: Vec<Option<Box<dyn Device>>> = Vec::new();
+        slots.resize_with(self.count()?, || None);
+        let shared = Shared::default();
+        self.exit.clear();
+        for (id, config) in self.ids()?.into_iter().zip(self.devices.iter()) {
+            slots[id] = Some(build_device(
+                &config.kind,
+                strings,
+                allocations,
+                redirect,
+                &shared,
+                self,
+            )?);
+        }
+        Ok(slots
+            .into_iter()
+            .map(|slot| slot.unwrap_or_else(|| Box::new(NullDevice)))
+            .collect())
+    }
+}
+
+/// What the devices built together share of the host.
+#[derive(Default)]
+struct Shared {
+    /// The keys pressed in a framebuffer window, for keyboards whose source is the window.
+    keys: Keys,
+    /// The host's terminal, for the standard devices which use it.
+    terminal: Terminal,
+}
+
+fn build_device(
+    kind: &DeviceKind,
+    strings: &[String],
+    allocations: &Rc<RefCell<Allocations>>,
+    redirect: Option<&Redirect>,
+    shared: &Shared,
+    devices: &Devices,
+) -> Result<Box<dyn Device>, String> {
+    let keys = &shared.keys;
+    Ok(match kind {
+        DeviceKind::Stdin { path, interrupt } => {
+            let terminal = (path.is_none() && redirect.is_none()).then(|| shared.terminal.clone());
+            let source: Box<dyn std::io::Read + Send> = match (path, redirect) {
+                (Some(path), _) => Box::new(
+                    std::fs::File::open(path).map_err(|e| format!("{:?}: {}", path, e))?,
+                ),
+                (None, Some(redirect)) => Box::new(std::io::Cursor::new(redirect.stdin.clone())),
+                (None, None) => Box::new(std::io::stdin()),
+            };
+            match interrupt {
+                Some(vector) if *vector >= bear_vm::device::Interrupts::COUNT => {
+                    return Err(format!("There is no interrupt vector {}.", vector));
+                }
+                Some(vector) => {
+                    let device = InterruptingStdinDevice::new(source, *vector);
+                    match terminal {
+                        Some(terminal) => Box::new(device.with_terminal(terminal)),
+                        None => Box::new(device),
+                    }
+                }
+                None => {
+                    let device = StdinDevice::new(source);
+                    match terminal {
+                        Some(terminal) => Box::new(device.with_terminal(terminal)),
+                        None => Box::new(device),
+                    }
+                }
+            }
+        }
+        DeviceKind::Stdout { path: None } => match redirect {
+            Some(redirect) => Box::new(StdoutDevice::new(redirect.stdout.clone())),
+            None => {
+                let terminal = shared.terminal.clone();
+                Box::new(StdoutDevice::new(std::io::stdout()).with_terminal(terminal))
+            }
+        },
+        DeviceKind::Stderr { path: None } => match redirect {
+            Some(redirect) => Box::new(StdoutDevice::new(redirect.stderr.clone())),
+            None => Box::new(StdoutDevice::new(std::io::stderr())),
+        },
+        DeviceKind::Stdout { path: Some(path) } | DeviceKind::Stderr { path: Some(path) } => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("{:?}: {}", path, e))?;
+            Box::new(StdoutDevice::new(file))
+        }
+        DeviceKind::Log => Box::new(LogDevice::new(strings.to_vec(), std::io::stderr())),
+        DeviceKind::File { path } => {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|e| format!("{:?}: {}", path, e))?;
+            Box::new(MappedFileDevice::new(file))
+        }
+        DeviceKind::Alloc { base, size } => Box::new(AllocDevice::new(
+            *base as usize,
+            *size as usize,
+            allocations.clone(),
+        )),
+        DeviceKind::Kv { path } => {
+            Box::new(KvDevice::open(path).map_err(|e| format!("{:?}: {}", path, e))?)
+        }
+        DeviceKind::Env => Box::new(EnvDevice::new(devices.arguments.to_vec())),
+        DeviceKind::Exit => Box::new(ExitDevice::new(devices.exit.clone())),
+        DeviceKind::Script { path } => script_device(path)?,
+        DeviceKind::Timer => Box::new(TimerDevice::new()),
+        DeviceKind::Framebuffer {
+            width,
+            height,
+            scale,
+            path,
+        } => {
+            let screen: Box<dyn Screen> = match path {
+                Some(path) => Box::new(PpmScreen::new(path.clone())),
+                None => window(*width as usize, *height as usize, scale.unwrap_or(1), keys)?,
+            };
+            Box::new(FramebufferDevice::new(*width, *height, screen))
+        }
+        DeviceKind::Uart { path, listen } => {
+            let port = match (path, listen) {
+                (Some(path), None) => SerialPort::File(
+                    std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(path)
+                        .map_err(|e| format!("{:?}: {}", path, e))?,
+                ),
+                (None, Some(address)) => SerialPort::Tcp(
+                    std::net::TcpListener::bind(address)
+                        .map_err(|e| format!("{}: {}", address, e))?,
+                ),
+                _ => return Err(String::from("A uart needs either a path or a listen address.")),
+            };
+            Box::new(UartDevice::new(port))
+        }
+        DeviceKind::Keyboard {
+            source: KeySource::Terminal,
+        } => Box::new(KeyboardDevice::terminal()),
+        DeviceKind::Keyboard {
+            source: KeySource::Window,
+        } => Box::new(KeyboardDevice::new(keys.clone())),
+        DeviceKind::Mailbox { peer } => Box::new(mailbox(peer)?),
+        DeviceKind::Null => Box::new(NullDevice),
+    })
+}
+
+/// Starts the image at `peer` on its own thread, and returns the end of the mailbox it talks to.
+fn mailbox(peer: &Path) -> Result<MailboxDevice, String> {
+    let image = std::fs::read(peer).map_err(|e| format!("{:?}: {}", peer, e))?;
+    let image = checksum::strip_header(&image).map_err(|e| format!("{:?}: {}", peer, e))?;
+    let (ours, theirs) = MailboxDevice::pair();
+    let vm = BearVM::new_sendable(bear_vm::util::convert_slice8_to_vec32(image))
+        .with_device(Box::new(theirs));
+    let peer = peer.to_path_buf();
+    std::thread::spawn(move || {
+        let result = vm.start().and_then(|mut state| state.run());
+        if let Err(e) = result {
+            eprintln!("{:?}: {}", peer, e);
+        }
+    });
+    Ok(ours)
+}
+
+#[cfg(feature = "window")]
+fn window(
+    width: usize,
+    height: usize,
+    scale: usize,
+    keys: &Keys,
+) -> Result<Box<dyn Screen>, String> {
+    let keys = keys.clone();
+    Ok(Box::new(crate::screen::WindowScreen::new(width, height, scale, keys)?))
+}
+
+#[cfg(not(feature = "window"))]
+fn window(
+    _width: usize,
+    _height: usize,
+    _scale: usize,
+    _keys: &Keys,
+) -> Result<Box<dyn Screen>, String> {
+    Err(String::from(
+        "A framebuffer needs a path, since bear-app was built without the window feature.",
+    ))
+}
+
+#[cfg(feature = "script")]
+fn script_device(path: &Path) -> Result<Box<dyn Device>, String> {
+    let script = crate::script::Script::load(path)?;
+    Ok(Box::new(crate::script::ScriptDevice::new(script)?))
+}
+
+#[cfg(not(feature = "script"))]
+fn script_device(_path: &Path) -> Result<Box<dyn Device>, String> {
+    Err(String::from(
+        "A script device needs bear-app to be built with the script feature.",
+    ))
+}
@@ -1,7 +1,15 @@
-use std::io::{Read, Write};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use bear_vm::device;
 
+use crate::screen::Screen;
+use crate::terminal::Terminal;
+
 #[derive(Debug, Clone)]
 pub struct Register {
     value: Option<u32>,
@@ -14,6 +22,8 @@ pub struct StdinDevice<T: Read> {
     state: device::GenericDeviceState,
     registers: [Register; 0],
     handle: T,
+    /// The terminal the handle reads, which the guest may control.
+    terminal: Option<Terminal>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +31,8 @@ pub struct StdoutDevice<T: Write> {
     state: device::GenericDeviceState,
     registers: [Register; 0],
     handle: T,
+    /// The terminal the handle writes to, which the guest may control.
+    terminal: Option<Terminal>,
 }
 
 impl<T: Read> StdinDevice<T> {
@@ -29,9 +41,16 @@ impl<T: Read> StdinDevice<T> {
             handle,
             state: device::GenericDeviceState::ReadyForCommand,
             registers: [],
+            terminal: None,
         }
     }
 
+    /// Answers the `Mode`, `Echo` and `Size` commands for `terminal`.
+    pub fn with_terminal(mut self, terminal: Terminal) -> StdinDevice<T> {
+        self.terminal = Some(terminal);
+        self
+    }
+
     pub fn reset(&mut self) {
         self.state = device::GenericDeviceState::ReadyForCommand;
         for reg in &mut self.registers {
@@ -68,13 +87,16 @@ impl<T: Read> device::Device for StdinDevice<T> {
                     }
                     u32::MAX
                 }
-                Some(device::GenericDeviceCommand::Execute {
-                    command,
-                    argument: _,
-                }) => {
-                    if command == device::StreamCommand::Seek as u8 {
-                        u32::MAX
-                    } else if command == device::StreamCommand::Write as u8 {
+                Some(device::GenericDeviceCommand::Execute { command, argument }) => {
+                    if let Some(result) = self
+                        .terminal
+                        .as_ref()
+                        .and_then(|terminal| terminal.command(command, argument))
+                    {
+                        result
+                    } else if command == device::StreamCommand::Seek as u8
+                        || command == device::StreamCommand::Write as u8
+                    {
                         u32::MAX
                     } else if command == device::StreamCommand::Read as u8 {
                         let mut buffer = vec![0u8];
@@ -109,15 +131,147 @@ impl<T: Read> device::Device for StdinDevice<T> {
     fn dma_write_response(&mut self, _address: usize) {}
 }
 
+/// The bytes read ahead of the guest by the thread of an `InterruptingStdinDevice`.
+pub struct ReadAhead {
+    bytes: mpsc::Receiver<u8>,
+    /// A byte taken off the channel to see whether one was waiting.
+    peeked: Option<u8>,
+    /// The guest has been told that the source ended.
+    ended: bool,
+}
+
+impl ReadAhead {
+    /// Whether a byte is waiting, or the source ended and the guest has not been told yet.
+    fn waiting(&mut self) -> bool {
+        if self.peeked.is_some() {
+            return true;
+        }
+        match self.bytes.try_recv() {
+            Ok(byte) => {
+                self.peeked = Some(byte);
+                true
+            }
+            Err(mpsc::TryRecvError::Empty) => false,
+            Err(mpsc::TryRecvError::Disconnected) => !std::mem::replace(&mut self.ended, true),
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    /// Reads one byte, waiting for it if need be.
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        match self.peeked.take().or_else(|| self.bytes.recv().ok()) {
+            Some(byte) => {
+                buffer[0] = byte;
+                Ok(1)
+            }
+            None => {
+                self.ended = true;
+                Ok(0)
+            }
+        }
+    }
+}
+
+/**
+ * A stdin device which reads its source ahead of the guest, on a thread of its own, and raises
+ * interrupt `vector` whenever a byte is waiting, and once when the source ends.  A `Read` in the
+ * handler then returns at once, so guests need not block on input.
+ */
+pub struct InterruptingStdinDevice {
+    inner: StdinDevice<ReadAhead>,
+    vector: u8,
+    /// Hands the interrupt lines to the thread, which only starts reading once it has them.
+    connect: Option<mpsc::Sender<device::Interrupts>>,
+    interrupts: Option<device::Interrupts>,
+}
+
+impl InterruptingStdinDevice {
+    pub fn new(mut source: impl Read + Send + 'static, vector: u8) -> InterruptingStdinDevice {
+        let (sender, bytes) = mpsc::channel();
+        let (connect, lines) = mpsc::channel::<device::Interrupts>();
+        std::thread::spawn(move || {
+            let Ok(interrupts) = lines.recv() else {
+                return;
+            };
+            let mut byte = [0u8];
+            while let Ok(1) = source.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    return;
+                }
+                interrupts.raise(vector);
+            }
+            drop(sender);
+            interrupts.raise(vector);
+        });
+        let handle = ReadAhead {
+            bytes,
+            peeked: None,
+            ended: false,
+        };
+        InterruptingStdinDevice {
+            inner: StdinDevice::new(handle),
+            vector,
+            connect: Some(connect),
+            interrupts: None,
+        }
+    }
+
+    /// Answers the `Mode`, `Echo` and `Size` commands for `terminal`.
+    pub fn with_terminal(mut self, terminal: Terminal) -> InterruptingStdinDevice {
+        self.inner.terminal = Some(terminal);
+        self
+    }
+}
+
+impl device::Device for InterruptingStdinDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let result = self.inner.ioctl(command);
+        // Several bytes may have arrived for one interrupt.
+        if let Some(interrupts) = &self.interrupts {
+            if self.inner.handle.waiting() {
+                interrupts.raise(self.vector);
+            }
+        }
+        result
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn connect_interrupts(&mut self, interrupts: device::Interrupts) {
+        if let Some(connect) = self.connect.take() {
+            // The thread is gone only if it could not run.
+            let _ = connect.send(interrupts.clone());
+        }
+        self.interrupts = Some(interrupts);
+    }
+}
+
 impl<T: Write> StdoutDevice<T> {
     pub fn new(handle: T) -> StdoutDevice<T> {
         StdoutDevice {
             handle,
             state: device::GenericDeviceState::ReadyForCommand,
             registers: [],
+            terminal: None,
         }
     }
 
+    /// Answers the `Mode`, `Echo` and `Size` commands for `terminal`.
+    pub fn with_terminal(mut self, terminal: Terminal) -> StdoutDevice<T> {
+        self.terminal = Some(terminal);
+        self
+    }
+
     pub fn reset(&mut self) {
         self.state = device::GenericDeviceState::ReadyForCommand;
         for reg in &mut self.registers {
@@ -155,13 +309,19 @@ impl<T: Write> device::Device for StdoutDevice<T> {
                     u32::MAX
                 }
                 Some(device::GenericDeviceCommand::Execute { command, argument }) => {
-                    if command == device::StreamCommand::Seek as u8 {
-                        u32::MAX
-                    } else if command == device::StreamCommand::Read as u8 {
+                    if let Some(result) = self
+                        .terminal
+                        .as_ref()
+                        .and_then(|terminal| terminal.command(command, argument))
+                    {
+                        result
+                    } else if command == device::StreamCommand::Seek as u8
+                        || command == device::StreamCommand::Read as u8
+                    {
                         u32::MAX
                     } else if command == device::StreamCommand::Write as u8 {
                         let buffer = vec![argument];
-                        match self.handle.write(&buffer) {
+                        match self.handle.write_all(&buffer) {
                             Ok(_) => 0_u32,
                             Err(_) => u32::MAX,
                         }
@@ -183,4 +343,2294 @@ impl<T: Write> device::Device for StdoutDevice<T> {
     fn dma_write_response(&mut self, _address: usize) {}
 
     fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn halt(&mut self) {
+        let _ = self.handle.flush();
+    }
+}
+
+/// Writes strings interned with `#intern` to the host, by id.
+#[derive(Debug, Clone)]
+pub struct LogDevice<T: Write> {
+    state: device::GenericDeviceState,
+    /// Register 0 is the bank, which supplies the high bits of string ids.
+    registers: [Register; 1],
+    strings: Vec<String>,
+    handle: T,
+}
+
+impl<T: Write> LogDevice<T> {
+    pub fn new(strings: Vec<String>, handle: T) -> LogDevice<T> {
+        LogDevice {
+            handle,
+            strings,
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [Register {
+                value: None,
+                can_read: true,
+                can_write: true,
+            }],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+    }
+
+    fn log(&mut self, id: u32) -> u32 {
+        let result = match self.strings.get(id as usize) {
+            Some(text) => writeln!(self.handle, "{}", text),
+            None => writeln!(self.handle, "<string #{}>", id),
+        };
+        match result {
+            Ok(_) => 0,
+            Err(_) => u32::MAX,
+        }
+    }
+}
+
+impl<T: Write> device::Device for LogDevice<T> {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, argument })
+                    if command == device::LogCommand::Message as u8 =>
+                {
+                    let bank = self.registers[0].value.unwrap_or(0);
+                    self.log((bank << 8) | argument as u32)
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// A placeholder for an unused device id.  Fails every command.
+#[derive(Debug, Clone)]
+pub struct NullDevice;
+
+impl device::Device for NullDevice {
+    fn ioctl(&mut self, _command: u32) -> u32 {
+        u32::MAX
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// Records the assertions failed by guest unit tests, as interned string ids.
+#[derive(Debug, Clone)]
+pub struct AssertDevice {
+    state: device::GenericDeviceState,
+    /// Register 0 is the bank, which supplies the high bits of string ids.
+    registers: [Register; 1],
+    failures: Rc<RefCell<Vec<u32>>>,
+}
+
+impl AssertDevice {
+    pub fn new(failures: Rc<RefCell<Vec<u32>>>) -> AssertDevice {
+        AssertDevice {
+            failures,
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [Register {
+                value: None,
+                can_read: true,
+                can_write: true,
+            }],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+    }
+}
+
+impl device::Device for AssertDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, argument })
+                    if command == device::AssertCommand::Fail as u8 =>
+                {
+                    let bank = self.registers[0].value.unwrap_or(0);
+                    self.failures.borrow_mut().push((bank << 8) | argument as u32);
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// The exit status set by the guest through an exit device, if it set one.
+#[derive(Debug, Clone, Default)]
+pub struct ExitCode(Rc<RefCell<Option<u8>>>);
+
+impl ExitCode {
+    pub fn get(&self) -> Option<u8> {
+        *self.0.borrow()
+    }
+
+    pub fn clear(&self) {
+        *self.0.borrow_mut() = None;
+    }
+}
+
+/**
+ * Sets the status the host exits with once the guest halts: `Execute { command: 0, argument }`
+ * sets it to `argument`.  A reset clears it, back to 0.
+ */
+#[derive(Debug, Clone)]
+pub struct ExitDevice {
+    code: ExitCode,
+}
+
+impl ExitDevice {
+    pub fn new(code: ExitCode) -> ExitDevice {
+        ExitDevice { code }
+    }
+}
+
+impl device::Device for ExitDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        match device::GenericDeviceCommand::decode(command) {
+            Some(device::GenericDeviceCommand::Reset) => {
+                self.code.clear();
+                0
+            }
+            Some(device::GenericDeviceCommand::Execute { command, argument })
+                if command == device::ExitCommand::Set as u8 =>
+            {
+                *self.code.0.borrow_mut() = Some(argument);
+                0
+            }
+            _ => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// A window of a mapped file, in guest memory.
+#[derive(Debug, Clone)]
+struct Window {
+    /// The guest address of the window, in bytes.
+    address: usize,
+    /// The file offset of the window, in bytes.
+    offset: u64,
+    /// The words of the window as they were last read from or written to the file.
+    words: Vec<u32>,
+}
+
+#[derive(Debug, Clone)]
+enum Transfer {
+    /// Copying a window into guest memory, in one burst.
+    Load { window: Window },
+    /// Reading the mapped window back from guest memory, in one burst.
+    Store,
+}
+
+/**
+ * Maps a window of a host file into guest memory by DMA, so that guests can work on files larger
+ * than memory a window at a time.
+ *
+ * Registers 0 and 1 are the low and high halves of the guest address of the window, 2 and 3 those
+ * of its file offset, and 4 is its length in words.  Addresses and offsets are word aligned.
+ * `Map` writes the words of the mapped window which the guest has changed back to the file, and
+ * then maps the window described by the registers; `Sync` only writes back.  The mapped window is
+ * also written back when the guest halts.  Words past the end of the file read as `0`.
+ */
+#[derive(Debug, Clone)]
+pub struct MappedFileDevice<T: Read + Write + Seek> {
+    state: device::GenericDeviceState,
+    registers: [Register; 5],
+    handle: T,
+    mapped: Option<Window>,
+    /// A window to map once the write-back in progress completes.
+    next: Option<Window>,
+    transfer: Option<Transfer>,
+    /// Raised when a transfer starts.
+    attention: device::Attention,
+}
+
+impl<T: Read + Write + Seek> MappedFileDevice<T> {
+    pub fn new(handle: T) -> MappedFileDevice<T> {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        MappedFileDevice {
+            handle,
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register,
+            ],
+            mapped: None,
+            next: None,
+            transfer: None,
+            attention: Default::default(),
+        }
+    }
+
+    /// Forgets the mapped window, without writing it back.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+        self.mapped = None;
+        self.next = None;
+        self.transfer = None;
+    }
+
+    fn register_pair(&self, low: usize) -> u32 {
+        let value = |index: usize| self.registers[index].value.unwrap_or(0);
+        value(low) | (value(low + 1) << 16)
+    }
+
+    /// Reads the window described by the registers from the file.
+    fn read_window(&mut self) -> std::io::Result<Option<Window>> {
+        let address = self.register_pair(0) as usize;
+        let offset = self.register_pair(2) as u64;
+        let length = self.registers[4].value.unwrap_or(0) as usize;
+        if !address.is_multiple_of(4) || !offset.is_multiple_of(4) {
+            return Ok(None);
+        }
+        let mut bytes = Vec::new();
+        self.handle.seek(SeekFrom::Start(offset))?;
+        (&mut self.handle)
+            .take(length as u64 * 4)
+            .read_to_end(&mut bytes)?;
+        bytes.resize(length * 4, 0);
+        Ok(Some(Window {
+            address,
+            offset,
+            words: bear_vm::util::convert_slice8_to_vec32(&bytes),
+        }))
+    }
+
+    /// Starts writing back the mapped window, or the next transfer if nothing is mapped.
+    fn start(&mut self) {
+        self.transfer = if self.mapped.is_some() {
+            Some(Transfer::Store)
+        } else {
+            self.next.take().map(|window| Transfer::Load { window })
+        };
+        if self.transfer.is_some() {
+            self.attention.raise();
+            self.state = device::GenericDeviceState::Busy;
+        } else {
+            self.state = device::GenericDeviceState::ReadyForCommand;
+        }
+    }
+
+    /// Completes the transfer in progress, with the words read back for a `Store`.
+    fn finish(&mut self, words: Vec<u32>) {
+        match self.transfer.take() {
+            Some(Transfer::Load { window }) => self.mapped = Some(window),
+            Some(Transfer::Store) => {
+                if self.write_back(words).is_err() {
+                    self.state = device::GenericDeviceState::Error(1);
+                    self.next = None;
+                    return;
+                }
+                if self.next.is_some() {
+                    self.mapped = None;
+                    return self.start();
+                }
+            }
+            None => {}
+        }
+        self.state = device::GenericDeviceState::ReadyForCommand;
+    }
+
+    /// Writes the words which differ from the mapped window to the file.
+    fn write_back(&mut self, words: Vec<u32>) -> std::io::Result<()> {
+        let mapped = self.mapped.as_mut().unwrap();
+        for (index, (old, new)) in mapped.words.iter().zip(words.iter()).enumerate() {
+            if old != new {
+                self.handle
+                    .seek(SeekFrom::Start(mapped.offset + index as u64 * 4))?;
+                self.handle.write_all(&new.to_le_bytes())?;
+            }
+        }
+        mapped.words = words;
+        self.handle.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> device::Device for MappedFileDevice<T> {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::MapCommand::Map as u8 =>
+                {
+                    match self.read_window() {
+                        Ok(Some(window)) => {
+                            self.next = Some(window);
+                            self.start();
+                            0
+                        }
+                        Ok(None) | Err(_) => u32::MAX,
+                    }
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::MapCommand::Sync as u8 =>
+                {
+                    self.start();
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        match (&self.transfer, &self.mapped) {
+            (Some(Transfer::Load { window }), _) => Some(device::DMARequest::WriteBurst(
+                window.address,
+                window.words.clone(),
+            )),
+            (Some(Transfer::Store), Some(mapped)) => Some(device::DMARequest::ReadBurst(
+                mapped.address,
+                mapped.words.len(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Only burst requests are made.
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_write_burst_response(&mut self, _address: usize, _count: usize) {
+        self.finish(Vec::new());
+    }
+
+    fn dma_read_burst_response(&mut self, _address: usize, words: &[u32]) {
+        self.finish(words.to_vec());
+    }
+
+    /// The window is not in guest memory: nothing is mapped, and the device fails with error `2`.
+    fn dma_error(&mut self, _request: device::DMARequest, _error: device::DmaError) {
+        self.transfer = None;
+        self.mapped = None;
+        self.next = None;
+        self.state = device::GenericDeviceState::Error(2);
+    }
+
+    fn dma_pending(&self) -> Vec<std::ops::Range<usize>> {
+        let window = match (&self.transfer, &self.mapped) {
+            (Some(Transfer::Load { window }), _) => window,
+            (Some(Transfer::Store), Some(mapped)) => mapped,
+            _ => return Vec::new(),
+        };
+        let range = window.address..window.address + window.words.len() * 4;
+        vec![range]
+    }
+
+    fn halt(&mut self) {
+        if self.state == device::GenericDeviceState::ReadyForCommand {
+            self.start();
+        }
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.attention = attention;
+    }
+}
+
+/// When a `TimerDevice` runs out.
+#[derive(Debug, Clone)]
+enum Deadline {
+    /// When the VM's clock reaches this many instructions.
+    Instructions(u64),
+    At(std::time::Instant),
+}
+
+/**
+ * Counts down a number of instructions or milliseconds, for schedulers and timeouts in guests.
+ *
+ * Registers 0 and 1 are the low and high halves of the count, and register 2, if set, the
+ * interrupt vector to raise when the countdown runs out.  `Expired` polls instead.  The device
+ * only notices that the countdown has run out when it is called, on `io` or when it is synced, so
+ * it keeps its attention flag raised while it counts down.
+ */
+#[derive(Debug, Clone)]
+pub struct TimerDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 3],
+    deadline: Option<Deadline>,
+    /// The countdown ran out, and has not been restarted.
+    expired: bool,
+    clock: device::Clock,
+    interrupts: device::Interrupts,
+    attention: device::Attention,
+}
+
+impl Default for TimerDevice {
+    fn default() -> Self {
+        TimerDevice::new()
+    }
+}
+
+impl TimerDevice {
+    pub fn new() -> TimerDevice {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        TimerDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [register.clone(), register.clone(), register],
+            deadline: None,
+            expired: false,
+            clock: Default::default(),
+            interrupts: Default::default(),
+            attention: Default::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+        self.deadline = None;
+        self.expired = false;
+    }
+
+    fn count(&self) -> u64 {
+        let value = |index: usize| self.registers[index].value.unwrap_or(0);
+        (value(0) | (value(1) << 16)) as u64
+    }
+
+    fn start(&mut self, deadline: Deadline) -> u32 {
+        self.deadline = Some(deadline);
+        self.expired = false;
+        self.attention.raise();
+        self.check();
+        0
+    }
+
+    /// Expires the countdown if it has run out, raising the interrupt if there is one.
+    fn check(&mut self) {
+        let due = match &self.deadline {
+            Some(Deadline::Instructions(at)) => self.clock.now() >= *at,
+            Some(Deadline::At(at)) => std::time::Instant::now() >= *at,
+            None => false,
+        };
+        if due {
+            self.deadline = None;
+            self.expired = true;
+            if let Some(vector) = self.registers[2].value {
+                self.interrupts.raise(vector as u8);
+            }
+        }
+    }
+}
+
+impl device::Device for TimerDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        self.check();
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(2, value))
+                    if value >= device::Interrupts::COUNT as u16 =>
+                {
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::TimerCommand::StartInstructions as u8 =>
+                {
+                    let at = self.clock.now() + self.count();
+                    self.start(Deadline::Instructions(at))
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::TimerCommand::StartMilliseconds as u8 =>
+                {
+                    let after = std::time::Duration::from_millis(self.count());
+                    self.start(Deadline::At(std::time::Instant::now() + after))
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::TimerCommand::Cancel as u8 =>
+                {
+                    self.deadline = None;
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::TimerCommand::Expired as u8 =>
+                {
+                    self.expired as u32
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        self.check();
+        if self.deadline.is_some() {
+            self.attention.raise();
+        }
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.attention = attention;
+    }
+
+    fn connect_interrupts(&mut self, interrupts: device::Interrupts) {
+        self.interrupts = interrupts;
+    }
+
+    fn connect_clock(&mut self, clock: device::Clock) {
+        self.clock = clock;
+    }
+}
+
+/// The host end of a `UartDevice`.
+#[derive(Debug)]
+pub enum SerialPort {
+    /// A tty, e.g. one end of a pty pair.
+    File(std::fs::File),
+    /// Accepts one connection at a time.
+    Tcp(std::net::TcpListener),
+}
+
+type Transmitter = std::sync::Arc<std::sync::Mutex<Option<Box<dyn Write + Send>>>>;
+
+/**
+ * A UART, for prototyping firmware-style drivers: bytes are received from and transmitted to a
+ * host `SerialPort`, which is read on a thread of its own once the device is attached.
+ *
+ * Registers 0 and 1 are the low and high halves of the baud rate (9600 by default), 2 the parity
+ * (`0` none, `1` odd, `2` even) and 3 the number of stop bits (`1` or `2`).  They are checked and
+ * kept for drivers to read back, but do not reconfigure the host end.  Register 4, if set, is an
+ * interrupt vector, raised at each sync while a received byte is waiting.
+ */
+pub struct UartDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 5],
+    port: Option<SerialPort>,
+    received: Option<mpsc::Receiver<u8>>,
+    /// A byte taken off the channel to see whether one was waiting.
+    peeked: Option<u8>,
+    /// Where transmitted bytes go, while the port is connected.
+    transmitter: Transmitter,
+    interrupts: device::Interrupts,
+}
+
+impl UartDevice {
+    pub fn new(port: SerialPort) -> UartDevice {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        let mut device = UartDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register,
+            ],
+            port: Some(port),
+            received: None,
+            peeked: None,
+            transmitter: Default::default(),
+            interrupts: Default::default(),
+        };
+        device.reset();
+        device
+    }
+
+    /// Restores the default configuration: 9600 baud, no parity, 1 stop bit and no interrupt.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for (reg, value) in self.registers.iter_mut().zip([Some(9600), Some(0), Some(0), Some(1), None]) {
+            reg.value = value;
+        }
+    }
+
+    fn valid(index: u8, value: u16) -> bool {
+        match index {
+            2 => value <= 2,
+            3 => value == 1 || value == 2,
+            4 => value < device::Interrupts::COUNT as u16,
+            _ => true,
+        }
+    }
+
+    fn waiting(&mut self) -> bool {
+        if self.peeked.is_none() {
+            self.peeked = self.received.as_ref().and_then(|received| received.try_recv().ok());
+        }
+        self.peeked.is_some()
+    }
+
+    fn transmit(&mut self, byte: u8) -> u32 {
+        let mut transmitter = self.transmitter.lock().unwrap();
+        let written = match transmitter.as_mut() {
+            Some(out) => out.write_all(&[byte]).and_then(|_| out.flush()),
+            None => return u32::MAX,
+        };
+        match written {
+            Ok(()) => 0,
+            Err(_) => {
+                *transmitter = None;
+                u32::MAX
+            }
+        }
+    }
+
+    /// Starts the thread which reads the port, raising `attention` when a byte arrives.
+    fn open(&mut self, port: SerialPort, attention: device::Attention) {
+        let (sender, received) = mpsc::channel();
+        self.received = Some(received);
+        let transmitter = self.transmitter.clone();
+        // Forwards the bytes of `input` until it ends, or the device is gone.
+        let forward = move |input: &mut dyn Read| {
+            let mut byte = [0u8];
+            while let Ok(1) = input.read(&mut byte) {
+                if sender.send(byte[0]).is_err() {
+                    return false;
+                }
+                attention.raise();
+            }
+            true
+        };
+        match port {
+            SerialPort::File(file) => {
+                if let Ok(out) = file.try_clone() {
+                    *transmitter.lock().unwrap() = Some(Box::new(out));
+                }
+                std::thread::spawn(move || {
+                    let mut file = file;
+                    forward(&mut file);
+                });
+            }
+            SerialPort::Tcp(listener) => {
+                std::thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        let Ok(mut stream) = stream else {
+                            continue;
+                        };
+                        if let Ok(out) = stream.try_clone() {
+                            *transmitter.lock().unwrap() = Some(Box::new(out));
+                        }
+                        let open = forward(&mut stream);
+                        *transmitter.lock().unwrap() = None;
+                        if !open {
+                            return;
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl device::Device for UartDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(u32::MAX);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() && UartDevice::valid(index, value) {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::UartCommand::Status as u8 =>
+                {
+                    let connected = self.transmitter.lock().unwrap().is_some();
+                    self.waiting() as u32 | (connected as u32) << 1
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::UartCommand::Receive as u8 =>
+                {
+                    self.waiting();
+                    self.peeked.take().map_or(u32::MAX, |byte| byte as u32)
+                }
+                Some(device::GenericDeviceCommand::Execute { command, argument })
+                    if command == device::UartCommand::Transmit as u8 =>
+                {
+                    self.transmit(argument)
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        if let Some(vector) = self.registers[4].value {
+            if self.waiting() {
+                self.interrupts.raise(vector as u8);
+            }
+        }
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn attach(&mut self, attention: device::Attention) {
+        if let Some(port) = self.port.take() {
+            self.open(port, attention);
+        }
+    }
+
+    fn connect_interrupts(&mut self, interrupts: device::Interrupts) {
+        self.interrupts = interrupts;
+    }
+}
+
+/**
+ * A framebuffer, whose pixels are in guest memory: a word per pixel, `0x00RRGGBB`, row by row.
+ *
+ * Registers 0 and 1 are the low and high halves of the guest address of the pixels, and 2 and 3
+ * the width and height, which are read only.  Once the address is set, the pixels are read by DMA
+ * and shown on the `Screen` at a sync, at most every `FramebufferDevice::FRAME`; `Present` shows
+ * them at the next sync, and they are shown a last time when the guest halts.
+ */
+pub struct FramebufferDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 4],
+    screen: Box<dyn Screen>,
+    open: bool,
+    /// When the pixels were last read.
+    shown: Option<Instant>,
+    presenting: bool,
+    reading: bool,
+    halted: bool,
+    /// Raised by `Present`.
+    attention: device::Attention,
+}
+
+impl FramebufferDevice {
+    /// The shortest time between two frames, for 60 frames a second.
+    pub const FRAME: Duration = Duration::from_micros(16_667);
+
+    pub fn new(width: u16, height: u16, screen: Box<dyn Screen>) -> FramebufferDevice {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        let size = |value: u16| Register {
+            value: Some(value as u32),
+            can_read: true,
+            can_write: false,
+        };
+        FramebufferDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [register.clone(), register, size(width), size(height)],
+            screen,
+            open: true,
+            shown: None,
+            presenting: false,
+            reading: false,
+            halted: false,
+            attention: Default::default(),
+        }
+    }
+
+    /// Forgets the address of the pixels, so that no more frames are shown until it is set again.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        self.registers[0].value = None;
+        self.registers[1].value = None;
+        self.presenting = false;
+    }
+
+    fn address(&self) -> Option<usize> {
+        let low = self.registers[0].value?;
+        let high = self.registers[1].value.unwrap_or(0);
+        Some(((high << 16) | low) as usize)
+    }
+
+    fn pixels(&self) -> usize {
+        self.registers[2].value.unwrap_or(0) as usize * self.registers[3].value.unwrap_or(0) as usize
+    }
+}
+
+impl device::Device for FramebufferDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(u32::MAX);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::FramebufferCommand::Present as u8 =>
+                {
+                    self.presenting = true;
+                    self.attention.raise();
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::FramebufferCommand::Status as u8 =>
+                {
+                    self.open as u32
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        if self.reading || !self.open {
+            return None;
+        }
+        let address = self.address()?;
+        if !self.presenting && self.shown.is_some_and(|shown| shown.elapsed() < FramebufferDevice::FRAME) {
+            return None;
+        }
+        self.presenting = false;
+        self.reading = true;
+        self.shown = Some(Instant::now());
+        Some(device::DMARequest::ReadBurst(address, self.pixels()))
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_read_burst_response(&mut self, _address: usize, words: &[u32]) {
+        self.reading = false;
+        let width = self.registers[2].value.unwrap_or(0) as usize;
+        let height = self.registers[3].value.unwrap_or(0) as usize;
+        self.open = self.screen.show(words, width, height);
+        if self.halted && self.open {
+            self.screen.linger();
+        }
+    }
+
+    fn dma_error(&mut self, _request: device::DMARequest, _error: device::DmaError) {
+        self.reading = false;
+        self.state = device::GenericDeviceState::Error(2);
+    }
+
+    fn halt(&mut self) {
+        self.halted = true;
+        self.presenting = true;
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.attention = attention;
+    }
+}
+
+/// The key events waiting to be read by a keyboard device, in the format of
+/// `device::KeyboardCommand::Next`.
+#[derive(Debug, Clone, Default)]
+pub struct Keys(std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<u32>>>);
+
+impl Keys {
+    /// The most events kept; later ones are dropped until the guest catches up.
+    pub const CAPACITY: usize = 256;
+
+    pub fn press(&self, code: u8) {
+        self.push(code as u32);
+    }
+
+    pub fn release(&self, code: u8) {
+        self.push(code as u32 | device::KEY_RELEASED);
+    }
+
+    fn push(&self, event: u32) {
+        let mut queue = self.0.lock().unwrap();
+        if queue.len() < Keys::CAPACITY {
+            queue.push_back(event);
+        }
+    }
+
+    fn pop(&self) -> Option<u32> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/**
+ * Reads the key codes of a chunk of terminal input.  A terminal sends characters rather than keys,
+ * so upper case letters are reported as such, and arrow keys are only recognized when their escape
+ * sequence is read in one chunk, which it is when typed.
+ */
+fn terminal_keys(chunk: &[u8]) -> Vec<u8> {
+    match chunk {
+        [27, b'[', b'A'] => vec![device::key::UP],
+        [27, b'[', b'B'] => vec![device::key::DOWN],
+        [27, b'[', b'C'] => vec![device::key::RIGHT],
+        [27, b'[', b'D'] => vec![device::key::LEFT],
+        _ => chunk
+            .iter()
+            .map(|byte| if *byte == 127 { 8 } else { *byte })
+            .collect(),
+    }
+}
+
+/**
+ * A keyboard, whose key events are queued until the guest reads them.  The events come from the
+ * window of a framebuffer device, which shares the device's `Keys`, or from the host's terminal,
+ * which is read on a thread of its own once the device is attached.  The terminal is put into
+ * cbreak mode, without echo, until the guest halts; each character is reported as a key-down
+ * followed by a key-up.
+ *
+ * Register 0, if set, is an interrupt vector, raised at each sync while an event is waiting.
+ */
+pub struct KeyboardDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 1],
+    keys: Keys,
+    terminal: bool,
+    /// The settings to restore the terminal to, once it is in cbreak mode.
+    saved: Option<String>,
+    interrupts: device::Interrupts,
+}
+
+impl KeyboardDevice {
+    /// A keyboard whose events are pushed to `keys`, e.g. by a window.
+    pub fn new(keys: Keys) -> KeyboardDevice {
+        KeyboardDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [Register {
+                value: None,
+                can_read: true,
+                can_write: true,
+            }],
+            keys,
+            terminal: false,
+            saved: None,
+            interrupts: Default::default(),
+        }
+    }
+
+    /// A keyboard reading the host's terminal.
+    pub fn terminal() -> KeyboardDevice {
+        let mut device = KeyboardDevice::new(Keys::default());
+        device.terminal = true;
+        device
+    }
+
+    /// Forgets the interrupt vector and the waiting events.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        self.registers[0].value = None;
+        while self.keys.pop().is_some() {}
+    }
+
+    /// Runs `stty` on the terminal, returning what it printed.
+    fn stty(args: &[&str]) -> Option<String> {
+        let output = std::process::Command::new("stty")
+            .args(args)
+            .stdin(std::process::Stdio::inherit())
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    }
+
+    fn restore(&mut self) {
+        if let Some(saved) = self.saved.take() {
+            KeyboardDevice::stty(&[saved.trim()]);
+        }
+    }
+}
+
+impl Drop for KeyboardDevice {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+impl device::Device for KeyboardDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(0)) => {
+                    self.registers[0].value.unwrap_or(u32::MAX)
+                }
+                Some(device::GenericDeviceCommand::SetRegister(0, value))
+                    if value < device::Interrupts::COUNT as u16 =>
+                {
+                    self.registers[0].value = Some(value as u32);
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::KeyboardCommand::Next as u8 =>
+                {
+                    self.keys.pop().unwrap_or(u32::MAX)
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::KeyboardCommand::Pending as u8 =>
+                {
+                    self.keys.len() as u32
+                }
+                _ => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        if let Some(vector) = self.registers[0].value {
+            if self.keys.len() > 0 {
+                self.interrupts.raise(vector as u8);
+            }
+        }
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn halt(&mut self) {
+        self.restore();
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        if !self.terminal {
+            return;
+        }
+        self.terminal = false;
+        self.saved = KeyboardDevice::stty(&["-g"]);
+        if self.saved.is_some() {
+            KeyboardDevice::stty(&["-icanon", "-echo"]);
+        }
+        let keys = self.keys.clone();
+        std::thread::spawn(move || {
+            let mut chunk = [0u8; 16];
+            let mut stdin = std::io::stdin();
+            while let Ok(count @ 1..) = stdin.read(&mut chunk) {
+                for code in terminal_keys(&chunk[..count]) {
+                    keys.press(code);
+                    keys.release(code);
+                }
+                attention.raise();
+            }
+        });
+    }
+
+    fn connect_interrupts(&mut self, interrupts: device::Interrupts) {
+        self.interrupts = interrupts;
+    }
+}
+
+/**
+ * Exposes the arguments and environment variables of the host to the guest, so that programs can
+ * be parameterized at run time.
+ *
+ * Registers 0 and 1 are the low and high halves of the guest address of the name of a variable,
+ * and 2 its length in bytes; 3, 4 and 5 are those of a buffer.  Both addresses are word aligned.
+ * `Argument` and `Variable` copy their value into the buffer, up to its length, padding the last
+ * word with zeros.  Register 6, which is read only, is then the length of the value, or
+ * `0xFFFFFFFF` if there is no such argument or variable.  The device is busy until the transfers
+ * have been made.
+ */
+#[derive(Debug)]
+pub struct EnvDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 7],
+    arguments: Vec<String>,
+    /// The value being written to the buffer, once the name has been read for a `Variable`.
+    got: Option<Vec<u32>>,
+    reading_name: bool,
+    /// Raised when a transfer starts.
+    attention: device::Attention,
+}
+
+impl EnvDevice {
+    pub fn new(arguments: Vec<String>) -> EnvDevice {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        EnvDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register,
+                Register {
+                    value: None,
+                    can_read: true,
+                    can_write: false,
+                },
+            ],
+            arguments,
+            got: None,
+            reading_name: false,
+            attention: Default::default(),
+        }
+    }
+
+    /// Forgets the registers and any transfer in progress.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+        self.got = None;
+        self.reading_name = false;
+    }
+
+    fn register_pair(&self, low: usize) -> usize {
+        let value = |index: usize| self.registers[index].value.unwrap_or(0);
+        (value(low) | (value(low + 1) << 16)) as usize
+    }
+
+    /// The address, length in bytes, and length in words of the name (`0`) or buffer (`3`).
+    fn buffer(&self, first: usize) -> (usize, usize, usize) {
+        let length = self.registers[first + 2].value.unwrap_or(0) as usize;
+        (self.register_pair(first), length, length.div_ceil(4))
+    }
+
+    /// Starts copying `value` into the buffer, or records that there is none.
+    fn answer(&mut self, value: Option<&[u8]>) {
+        self.registers[6].value = Some(value.map_or(u32::MAX, |value| value.len() as u32));
+        match value {
+            Some(value) => {
+                let length = value.len().min(self.buffer(3).1);
+                self.got = Some(bear_vm::util::convert_slice8_to_vec32(&value[..length]));
+                self.start();
+            }
+            None => self.state = device::GenericDeviceState::ReadyForCommand,
+        }
+    }
+
+    fn start(&mut self) {
+        self.state = device::GenericDeviceState::Busy;
+        self.attention.raise();
+    }
+}
+
+impl device::Device for EnvDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::EnvCommand::Count as u8 =>
+                {
+                    self.arguments.len() as u32
+                }
+                Some(device::GenericDeviceCommand::Execute { command, argument })
+                    if command == device::EnvCommand::Argument as u8 =>
+                {
+                    let value = self.arguments.get(argument as usize).cloned();
+                    self.answer(value.as_ref().map(|value| value.as_bytes()));
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::EnvCommand::Variable as u8 =>
+                {
+                    self.reading_name = true;
+                    self.start();
+                    0
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        if self.reading_name {
+            let (name, _, words) = self.buffer(0);
+            return Some(device::DMARequest::ReadBurst(name, words));
+        }
+        let (buffer, _, _) = self.buffer(3);
+        self.got
+            .as_ref()
+            .map(|got| device::DMARequest::WriteBurst(buffer, got.clone()))
+    }
+
+    /// Only burst requests are made.
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_write_burst_response(&mut self, _address: usize, _count: usize) {
+        self.got = None;
+        self.state = device::GenericDeviceState::ReadyForCommand;
+    }
+
+    fn dma_read_burst_response(&mut self, _address: usize, words: &[u32]) {
+        self.reading_name = false;
+        let bytes = bear_vm::util::convert_slice32_to_vec8(words);
+        let name = String::from_utf8_lossy(&bytes[..self.buffer(0).1]).into_owned();
+        let value = std::env::var_os(name).map(|value| value.into_encoded_bytes());
+        self.answer(value.as_deref());
+    }
+
+    /// The name or buffer is not in guest memory: the device fails with error `2`.
+    fn dma_error(&mut self, _request: device::DMARequest, _error: device::DmaError) {
+        self.got = None;
+        self.reading_name = false;
+        self.state = device::GenericDeviceState::Error(2);
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.attention = attention;
+    }
+}
+
+/// The transfer a `KvDevice` is waiting for.
+#[derive(Debug, Clone)]
+enum KvTransfer {
+    /// Reading the key, for a command.
+    Key(device::KvCommand),
+    /// Reading the value to put under the key.
+    Value(Vec<u8>),
+    /// Writing the value got.
+    Got(Vec<u32>),
+}
+
+/**
+ * A persistent store of values under string keys, for guests which keep a little state between
+ * runs.  The store is a log of puts and deletes in a host file, which is compacted when it is
+ * opened.
+ *
+ * Registers 0 and 1 are the low and high halves of the guest address of the key, and 2 its length
+ * in bytes; 3, 4 and 5 are those of the value buffer.  Both addresses are word aligned.  `Put`
+ * stores the value buffer, and `Get` copies the value into it, up to its length, padding the last
+ * word with zeros.  Register 6, which is read only, is then the length of the value, or
+ * `0xFFFFFFFF` if there is no such key; after `Delete`, it is `0` if there was.  The device is
+ * busy until the transfers have been made.
+ */
+#[derive(Debug)]
+pub struct KvDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 7],
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    log: std::fs::File,
+    transfer: Option<KvTransfer>,
+    /// Raised when a transfer starts.
+    attention: device::Attention,
+}
+
+impl KvDevice {
+    const PUT: u8 = b'P';
+    const DELETE: u8 = b'D';
+
+    /// Opens the store at `path`, which is created if it does not exist.
+    pub fn open(path: &std::path::Path) -> std::io::Result<KvDevice> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let entries = KvDevice::replay(&bytes);
+        let mut compacted = Vec::new();
+        for (key, value) in entries.iter() {
+            KvDevice::record(&mut compacted, KvDevice::PUT, key, Some(value));
+        }
+        if compacted != bytes {
+            let temporary = path.with_extension("compacting");
+            std::fs::write(&temporary, &compacted)?;
+            std::fs::rename(&temporary, path)?;
+        }
+        let log = std::fs::OpenOptions::new().append(true).create(true).open(path)?;
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        Ok(KvDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register.clone(),
+                register,
+                Register {
+                    value: None,
+                    can_read: true,
+                    can_write: false,
+                },
+            ],
+            entries,
+            log,
+            transfer: None,
+            attention: Default::default(),
+        })
+    }
+
+    /// The entries left by a log.  A record cut short, by a crash while it was written, is ignored.
+    fn replay(mut bytes: &[u8]) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        fn field<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+            let mut length = [0; 4];
+            length.copy_from_slice(bytes.get(..4)?);
+            let length = u32::from_le_bytes(length) as usize;
+            let field = bytes.get(4..4 + length)?;
+            *bytes = &bytes[4 + length..];
+            Some(field)
+        }
+        let mut entries = BTreeMap::new();
+        while let Some((&op, rest)) = bytes.split_first() {
+            bytes = rest;
+            let Some(key) = field(&mut bytes) else {
+                break;
+            };
+            if op == KvDevice::DELETE {
+                entries.remove(key);
+                continue;
+            }
+            let Some(value) = field(&mut bytes) else {
+                break;
+            };
+            entries.insert(key.to_vec(), value.to_vec());
+        }
+        entries
+    }
+
+    /// Appends a record: its operation, then each of its fields as a length and bytes.
+    fn record(out: &mut Vec<u8>, op: u8, key: &[u8], value: Option<&[u8]>) {
+        out.push(op);
+        for field in std::iter::once(key).chain(value) {
+            out.extend(&(field.len() as u32).to_le_bytes());
+            out.extend(field);
+        }
+    }
+
+    fn append(&mut self, op: u8, key: &[u8], value: Option<&[u8]>) -> std::io::Result<()> {
+        let mut bytes = Vec::new();
+        KvDevice::record(&mut bytes, op, key, value);
+        self.log.write_all(&bytes)?;
+        self.log.flush()
+    }
+
+    /// Forgets the registers and any transfer in progress.  The store is kept.
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+        self.transfer = None;
+    }
+
+    fn register_pair(&self, low: usize) -> usize {
+        let value = |index: usize| self.registers[index].value.unwrap_or(0);
+        (value(low) | (value(low + 1) << 16)) as usize
+    }
+
+    /// The address, length in bytes, and length in words of the key (`0`) or value buffer (`3`).
+    fn buffer(&self, first: usize) -> (usize, usize, usize) {
+        let length = self.registers[first + 2].value.unwrap_or(0) as usize;
+        (self.register_pair(first), length, length.div_ceil(4))
+    }
+
+    fn start(&mut self, transfer: KvTransfer) {
+        self.transfer = Some(transfer);
+        self.state = device::GenericDeviceState::Busy;
+        self.attention.raise();
+    }
+
+    /// Goes on with the command once the words read have arrived.
+    fn read(&mut self, words: &[u32]) {
+        let bytes = bear_vm::util::convert_slice32_to_vec8(words);
+        let written = match self.transfer.take() {
+            Some(KvTransfer::Key(device::KvCommand::Get)) => {
+                let key = &bytes[..self.buffer(0).1];
+                let (got, result) = match self.entries.get(key) {
+                    Some(value) => {
+                        let length = value.len().min(self.buffer(3).1);
+                        let cells = bear_vm::util::convert_slice8_to_vec32(&value[..length]);
+                        (Some(cells), value.len() as u32)
+                    }
+                    None => (None, u32::MAX),
+                };
+                self.registers[6].value = Some(result);
+                if let Some(got) = got {
+                    return self.start(KvTransfer::Got(got));
+                }
+                Ok(())
+            }
+            Some(KvTransfer::Key(device::KvCommand::Put)) => {
+                let key = bytes[..self.buffer(0).1].to_vec();
+                return self.start(KvTransfer::Value(key));
+            }
+            Some(KvTransfer::Key(device::KvCommand::Delete)) => {
+                let key = &bytes[..self.buffer(0).1];
+                let found = self.entries.remove(key).is_some();
+                self.registers[6].value = Some(if found { 0 } else { u32::MAX });
+                if found {
+                    self.append(KvDevice::DELETE, key, None)
+                } else {
+                    Ok(())
+                }
+            }
+            Some(KvTransfer::Value(key)) => {
+                let value = bytes[..self.buffer(3).1].to_vec();
+                self.registers[6].value = Some(value.len() as u32);
+                let appended = self.append(KvDevice::PUT, &key, Some(&value));
+                self.entries.insert(key, value);
+                appended
+            }
+            Some(KvTransfer::Got(_)) | None => Ok(()),
+        };
+        self.state = match written {
+            Ok(()) => device::GenericDeviceState::ReadyForCommand,
+            Err(_) => device::GenericDeviceState::Error(1),
+        };
+    }
+}
+
+impl device::Device for KvDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. }) => {
+                    let command = [
+                        device::KvCommand::Get,
+                        device::KvCommand::Put,
+                        device::KvCommand::Delete,
+                    ]
+                    .iter()
+                    .copied()
+                    .find(|known| *known as u8 == command);
+                    match command {
+                        Some(command) => {
+                            self.start(KvTransfer::Key(command));
+                            0
+                        }
+                        None => u32::MAX,
+                    }
+                }
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        let (key, _, key_words) = self.buffer(0);
+        let (value, _, value_words) = self.buffer(3);
+        match self.transfer.as_ref()? {
+            KvTransfer::Key(_) => Some(device::DMARequest::ReadBurst(key, key_words)),
+            KvTransfer::Value(_) => Some(device::DMARequest::ReadBurst(value, value_words)),
+            KvTransfer::Got(words) => Some(device::DMARequest::WriteBurst(value, words.clone())),
+        }
+    }
+
+    /// Only burst requests are made.
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_write_burst_response(&mut self, _address: usize, _count: usize) {
+        self.transfer = None;
+        self.state = device::GenericDeviceState::ReadyForCommand;
+    }
+
+    fn dma_read_burst_response(&mut self, _address: usize, words: &[u32]) {
+        self.read(words);
+    }
+
+    /// The key or value buffer is not in guest memory: the device fails with error `2`.
+    fn dma_error(&mut self, _request: device::DMARequest, _error: device::DmaError) {
+        self.transfer = None;
+        self.state = device::GenericDeviceState::Error(2);
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.attention = attention;
+    }
+}
+
+/**
+ * Allocates blocks of a region of guest memory, first fit, and keeps the books of the blocks in
+ * `Allocations`.
+ *
+ * Registers 0 and 1 are the low and high halves of the argument: the size of the block for
+ * `Alloc`, which returns its address, and the address of the block for `Free`.  Sizes are rounded
+ * up to whole words.
+ */
+#[derive(Debug, Clone)]
+pub struct AllocDevice {
+    state: device::GenericDeviceState,
+    registers: [Register; 2],
+    /// The free blocks: their size, by address.
+    free: BTreeMap<usize, usize>,
+    allocations: Rc<RefCell<crate::alloc::Allocations>>,
+}
+
+impl AllocDevice {
+    pub fn new(
+        base: usize,
+        size: usize,
+        allocations: Rc<RefCell<crate::alloc::Allocations>>,
+    ) -> AllocDevice {
+        let register = Register {
+            value: None,
+            can_read: true,
+            can_write: true,
+        };
+        let mut free = BTreeMap::new();
+        free.insert(base, size);
+        AllocDevice {
+            state: device::GenericDeviceState::ReadyForCommand,
+            registers: [register.clone(), register],
+            free,
+            allocations,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = device::GenericDeviceState::ReadyForCommand;
+        for reg in &mut self.registers {
+            reg.value = None;
+        }
+    }
+
+    fn argument(&self) -> usize {
+        let value = |index: usize| self.registers[index].value.unwrap_or(0);
+        (value(0) | (value(1) << 16)) as usize
+    }
+
+    fn alloc(&mut self, size: usize) -> u32 {
+        let size = size.max(1).div_ceil(4) * 4;
+        let found = self
+            .free
+            .iter()
+            .find(|(_, free)| **free >= size)
+            .map(|(address, free)| (*address, *free));
+        match found {
+            Some((address, free)) => {
+                self.free.remove(&address);
+                if free > size {
+                    self.free.insert(address + size, free - size);
+                }
+                self.allocations.borrow_mut().allocated(address, size);
+                address as u32
+            }
+            None => u32::MAX,
+        }
+    }
+
+    fn release(&mut self, address: usize) -> u32 {
+        let mut size = match self.allocations.borrow_mut().freed(address) {
+            Some(size) => size,
+            None => return u32::MAX,
+        };
+        let mut address = address;
+        // Merge with the neighbouring free blocks.
+        if let Some(next) = self.free.remove(&(address + size)) {
+            size += next;
+        }
+        let previous = self.free.range(..address).next_back().map(|(a, s)| (*a, *s));
+        if let Some((previous, previous_size)) = previous {
+            if previous + previous_size == address {
+                self.free.remove(&previous);
+                address = previous;
+                size += previous_size;
+            }
+        }
+        self.free.insert(address, size);
+        0
+    }
+}
+
+impl device::Device for AllocDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = device::GenericDeviceCommand::decode(command);
+        match self.state {
+            device::GenericDeviceState::ReadyForCommand => match command {
+                Some(device::GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(device::GenericDeviceCommand::GetRegister(index)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &self.registers[index as usize];
+                        if reg.can_read {
+                            return reg.value.unwrap_or(0);
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::SetRegister(index, value)) => {
+                    if (index as usize) < self.registers.len() {
+                        let reg = &mut self.registers[index as usize];
+                        if reg.can_write {
+                            reg.value = Some(value as u32);
+                            return 0;
+                        }
+                    }
+                    u32::MAX
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::AllocCommand::Alloc as u8 =>
+                {
+                    self.alloc(self.argument())
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::AllocCommand::Free as u8 =>
+                {
+                    self.release(self.argument())
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::AllocCommand::Available as u8 =>
+                {
+                    self.free.values().sum::<usize>() as u32
+                }
+                Some(device::GenericDeviceCommand::Execute { command, .. })
+                    if command == device::AllocCommand::Largest as u8 =>
+                {
+                    self.free.values().copied().max().unwrap_or(0) as u32
+                }
+                Some(device::GenericDeviceCommand::Execute { .. }) => u32::MAX,
+                None => u32::MAX,
+            },
+            device::GenericDeviceState::Error(_code) => u32::MAX,
+            device::GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// A writer which appends to a shared buffer, so output can be inspected after the VM is done.
+#[derive(Debug, Clone, Default)]
+pub struct Capture(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The commands received by recorded devices, in order: the device id, and the decoded command
+/// with its result, e.g. `exec 1 72 -> 0`.
+pub type Transcript = Rc<RefCell<Vec<(usize, String)>>>;
+
+/**
+ * Passes everything through to the device it wraps, and records each command in a transcript.
+ * Results are shown signed, so errors read as `-1`; commands which do not decode are shown in hex.
+ */
+pub struct RecordingDevice {
+    id: usize,
+    inner: Box<dyn device::Device>,
+    transcript: Transcript,
+}
+
+impl RecordingDevice {
+    pub fn new(id: usize, inner: Box<dyn device::Device>, transcript: Transcript) -> RecordingDevice {
+        RecordingDevice {
+            id,
+            inner,
+            transcript,
+        }
+    }
+}
+
+impl device::Device for RecordingDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let result = self.inner.ioctl(command);
+        let decoded = match device::GenericDeviceCommand::decode(command) {
+            Some(decoded) => decoded.to_string(),
+            None => format!("{:#010x}", command),
+        };
+        self.transcript
+            .borrow_mut()
+            .push((self.id, format!("{} -> {}", decoded, result as i32)));
+        result
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        self.inner.dma_poll()
+    }
+
+    fn dma_write_response(&mut self, address: usize) {
+        self.inner.dma_write_response(address)
+    }
+
+    fn dma_read_response(&mut self, address: usize, value: u32) {
+        self.inner.dma_read_response(address, value)
+    }
+
+    fn dma_write_burst_response(&mut self, address: usize, count: usize) {
+        self.inner.dma_write_burst_response(address, count)
+    }
+
+    fn dma_read_burst_response(&mut self, address: usize, words: &[u32]) {
+        self.inner.dma_read_burst_response(address, words)
+    }
+
+    fn dma_error(&mut self, request: device::DMARequest, error: device::DmaError) {
+        self.inner.dma_error(request, error)
+    }
+
+    fn dma_pending(&self) -> Vec<std::ops::Range<usize>> {
+        self.inner.dma_pending()
+    }
+
+    fn halt(&mut self) {
+        self.inner.halt()
+    }
+
+    fn attach(&mut self, attention: device::Attention) {
+        self.inner.attach(attention)
+    }
+
+    fn connect_interrupts(&mut self, interrupts: device::Interrupts) {
+        self.inner.connect_interrupts(interrupts)
+    }
+
+    fn connect_clock(&mut self, clock: device::Clock) {
+        self.inner.connect_clock(clock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bear_vm::device::{Device, GenericDeviceCommand};
+
+    fn set(device: &mut dyn Device, index: u8, value: u16) -> u32 {
+        device.ioctl(GenericDeviceCommand::set(index, value).encode())
+    }
+
+    fn get(device: &mut dyn Device, index: u8) -> u32 {
+        device.ioctl(GenericDeviceCommand::get(index).encode())
+    }
+
+    fn exec(device: &mut dyn Device, command: u8, argument: u8) -> u32 {
+        device.ioctl(GenericDeviceCommand::Execute { command, argument }.encode())
+    }
+
+    /// Answers the DMA requests of `device` from `memory`, a word per address, as the VM does.
+    fn serve(device: &mut dyn Device, memory: &mut [u32]) {
+        while let Some(request) = device.dma_poll() {
+            match request {
+                device::DMARequest::Read(address) => device.dma_read_response(address, memory[address / 4]),
+                device::DMARequest::Write(address, value) => {
+                    memory[address / 4] = value;
+                    device.dma_write_response(address);
+                }
+                device::DMARequest::ReadBurst(address, count) => {
+                    let words = memory[address / 4..address / 4 + count].to_vec();
+                    device.dma_read_burst_response(address, &words);
+                }
+                device::DMARequest::WriteBurst(address, words) => {
+                    memory[address / 4..address / 4 + words.len()].copy_from_slice(&words);
+                    device.dma_write_burst_response(address, words.len());
+                }
+            }
+        }
+    }
+
+    /// Copies `bytes` into `memory` at the word-aligned `address`.
+    fn poke(memory: &mut [u32], address: usize, bytes: &[u8]) {
+        let words = bear_vm::util::convert_slice8_to_vec32(bytes);
+        memory[address / 4..address / 4 + words.len()].copy_from_slice(&words);
+    }
+
+    fn peek(memory: &[u32], address: usize, length: usize) -> Vec<u8> {
+        let mut bytes = bear_vm::util::convert_slice32_to_vec8(&memory[address / 4..]);
+        bytes.truncate(length);
+        bytes
+    }
+
+    #[test]
+    fn test_kv_device() {
+        let path = std::env::temp_dir().join(format!("bear-app-kv-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut memory = vec![0u32; 64];
+        // The key is at 0 and the value buffer at 64.
+        let command = |kv: &mut KvDevice, memory: &mut Vec<u32>, op: device::KvCommand, key: &str| {
+            poke(memory, 0, key.as_bytes());
+            set(kv, 2, key.len() as u16);
+            assert_eq!(exec(kv, op as u8, 0), 0);
+            serve(kv, memory);
+            get(kv, 6)
+        };
+        let mut kv = KvDevice::open(&path).unwrap();
+        set(&mut kv, 3, 64);
+        for (key, value) in [("answer", "42"), ("name", "bear"), ("answer", "43")] {
+            poke(&mut memory, 64, value.as_bytes());
+            set(&mut kv, 5, value.len() as u16);
+            assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Put, key), value.len() as u32);
+        }
+        assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Delete, "name"), 0);
+        assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Delete, "name"), u32::MAX);
+        drop(kv);
+
+        // Replaying the log of 4 records leaves one entry, which compacting rewrites as 1 record.
+        let mut kv = KvDevice::open(&path).unwrap();
+        let compacted = std::fs::read(&path).unwrap();
+        assert_eq!(compacted.len(), 1 + 4 + "answer".len() + 4 + "43".len());
+        set(&mut kv, 3, 64);
+        set(&mut kv, 5, 16);
+        memory[16..].fill(0);
+        assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Get, "answer"), 2);
+        assert_eq!(peek(&memory, 64, 4), b"43\0\0");
+        assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Get, "name"), u32::MAX);
+
+        // A record cut short by a crash is ignored.
+        drop(kv);
+        let mut log = compacted.clone();
+        KvDevice::record(&mut log, KvDevice::PUT, b"torn", Some(b"value"));
+        std::fs::write(&path, &log[..log.len() - 2]).unwrap();
+        let mut kv = KvDevice::open(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), compacted);
+        set(&mut kv, 3, 64);
+        set(&mut kv, 5, 16);
+        assert_eq!(command(&mut kv, &mut memory, device::KvCommand::Get, "torn"), u32::MAX);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mapped_file_device() {
+        let file = std::io::Cursor::new((1..=4u32).flat_map(u32::to_le_bytes).collect::<Vec<u8>>());
+        let mut mapped = MappedFileDevice::new(file);
+        let mut memory = vec![0u32; 16];
+        // Maps the second word onwards, 4 words of them, at 16.
+        for (index, value) in [(0, 16), (2, 4), (4, 4)] {
+            assert_eq!(set(&mut mapped, index, value), 0);
+        }
+        assert_eq!(exec(&mut mapped, device::MapCommand::Map as u8, 0), 0);
+        serve(&mut mapped, &mut memory);
+        assert_eq!(memory[4..8], [2, 3, 4, 0]);
+
+        memory[5] = 30;
+        memory[7] = 50;
+        assert_eq!(exec(&mut mapped, device::MapCommand::Sync as u8, 0), 0);
+        serve(&mut mapped, &mut memory);
+        let bytes = mapped.handle.get_ref();
+        assert_eq!(bear_vm::util::convert_slice8_to_vec32(bytes), vec![1, 2, 30, 4, 50]);
+
+        // An unaligned window is refused.
+        set(&mut mapped, 2, 2);
+        assert_eq!(exec(&mut mapped, device::MapCommand::Map as u8, 0), u32::MAX);
+    }
+
+    #[test]
+    fn test_uart_device() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut uart = UartDevice::new(SerialPort::Tcp(listener));
+        uart.attach(Default::default());
+        assert_eq!(get(&mut uart, 0), 9600);
+        assert_eq!(set(&mut uart, 2, 3), u32::MAX);
+        assert_eq!(set(&mut uart, 3, 2), 0);
+
+        let mut host = std::net::TcpStream::connect(address).unwrap();
+        host.write_all(b"h").unwrap();
+        let status = device::UartCommand::Status as u8;
+        while exec(&mut uart, status, 0) != 0b11 {
+            std::thread::yield_now();
+        }
+        assert_eq!(exec(&mut uart, device::UartCommand::Receive as u8, 0), b'h' as u32);
+        assert_eq!(exec(&mut uart, device::UartCommand::Receive as u8, 0), u32::MAX);
+        assert_eq!(exec(&mut uart, device::UartCommand::Transmit as u8, b'i'), 0);
+        let mut byte = [0u8];
+        host.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"i");
+
+        assert_eq!(uart.ioctl(GenericDeviceCommand::reset().encode()), 0);
+        assert_eq!(get(&mut uart, 3), 1);
+    }
+
+    /// The frames shown.
+    #[derive(Clone, Default)]
+    struct Frames(Rc<RefCell<Vec<Vec<u32>>>>);
+
+    impl Screen for Frames {
+        fn show(&mut self, pixels: &[u32], _width: usize, _height: usize) -> bool {
+            self.0.borrow_mut().push(pixels.to_vec());
+            true
+        }
+    }
+
+    #[test]
+    fn test_framebuffer_device() {
+        let frames = Frames::default();
+        let mut framebuffer = FramebufferDevice::new(2, 2, Box::new(frames.clone()));
+        let mut memory = vec![0u32; 16];
+        assert_eq!((get(&mut framebuffer, 2), get(&mut framebuffer, 3)), (2, 2));
+        assert_eq!(set(&mut framebuffer, 2, 4), u32::MAX);
+        // Nothing is shown until the address is set.
+        serve(&mut framebuffer, &mut memory);
+        assert!(frames.0.borrow().is_empty());
+
+        memory[8..12].copy_from_slice(&[0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF]);
+        set(&mut framebuffer, 0, 32);
+        serve(&mut framebuffer, &mut memory);
+        memory[8] = 0;
+        assert_eq!(exec(&mut framebuffer, device::FramebufferCommand::Present as u8, 0), 0);
+        serve(&mut framebuffer, &mut memory);
+        assert_eq!(
+            *frames.0.borrow(),
+            vec![vec![0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF], vec![0, 0x00FF00, 0x0000FF, 0xFFFFFF]]
+        );
+        assert_eq!(exec(&mut framebuffer, device::FramebufferCommand::Status as u8, 0), 1);
+    }
+
+    #[test]
+    fn test_keyboard_device() {
+        let keys = Keys::default();
+        let mut keyboard = KeyboardDevice::new(keys.clone());
+        keys.press(device::key::UP);
+        keys.release(device::key::UP);
+        assert_eq!(exec(&mut keyboard, device::KeyboardCommand::Pending as u8, 0), 2);
+        let next = device::KeyboardCommand::Next as u8;
+        assert_eq!(exec(&mut keyboard, next, 0), device::key::UP as u32);
+        assert_eq!(exec(&mut keyboard, next, 0), device::key::UP as u32 | device::KEY_RELEASED);
+        assert_eq!(exec(&mut keyboard, next, 0), u32::MAX);
+
+        assert_eq!(set(&mut keyboard, 0, device::Interrupts::COUNT as u16), u32::MAX);
+        keys.press(b'a');
+        assert_eq!(keyboard.ioctl(GenericDeviceCommand::reset().encode()), 0);
+        assert_eq!(exec(&mut keyboard, device::KeyboardCommand::Pending as u8, 0), 0);
+        assert_eq!(terminal_keys(b"\x1b[D"), vec![device::key::LEFT]);
+        assert_eq!(terminal_keys(b"a\x7f"), vec![b'a', 8]);
+    }
+
+    #[test]
+    fn test_env_device() {
+        let mut env = EnvDevice::new(vec![String::from("input.txt"), String::from("10")]);
+        let mut memory = vec![0u32; 16];
+        // The name is at 0 and the buffer at 32.
+        set(&mut env, 3, 32);
+        set(&mut env, 5, 8);
+        assert_eq!(exec(&mut env, device::EnvCommand::Count as u8, 0), 2);
+        assert_eq!(exec(&mut env, device::EnvCommand::Argument as u8, 0), 0);
+        serve(&mut env, &mut memory);
+        assert_eq!(get(&mut env, 6), 9);
+        assert_eq!(peek(&memory, 32, 8), b"input.tx");
+        assert_eq!(exec(&mut env, device::EnvCommand::Argument as u8, 2), 0);
+        assert_eq!(get(&mut env, 6), u32::MAX);
+
+        let (name, value) = std::env::vars().next().unwrap();
+        poke(&mut memory, 0, name.as_bytes());
+        set(&mut env, 2, name.len() as u16);
+        memory[8..].fill(0);
+        assert_eq!(exec(&mut env, device::EnvCommand::Variable as u8, 0), 0);
+        serve(&mut env, &mut memory);
+        assert_eq!(get(&mut env, 6), value.len() as u32);
+        assert_eq!(peek(&memory, 32, value.len().min(8)), &value.as_bytes()[..value.len().min(8)]);
+    }
+
+    #[test]
+    fn test_terminal_commands() {
+        let capture = Capture::default();
+        let size = device::StreamCommand::Size as u8;
+        let mut plain = StdoutDevice::new(capture.clone());
+        assert_eq!(exec(&mut plain, size, 0), u32::MAX);
+        // The terminal answers its commands, whether or not there is a tty, and nothing is written.
+        let mut stdout = StdoutDevice::new(capture.clone()).with_terminal(Terminal::default());
+        let columns_and_rows = Terminal::default().size().map_or(u32::MAX, |(c, r)| (c as u32) << 16 | r as u32);
+        assert_eq!(exec(&mut stdout, size, 0), columns_and_rows);
+        assert_eq!(exec(&mut stdout, device::StreamCommand::Write as u8, b'x'), 0);
+        assert_eq!(*capture.0.borrow(), b"x");
+    }
+
+    #[cfg(feature = "script")]
+    #[test]
+    fn test_script_device() {
+        let path = std::env::temp_dir().join(format!("bear-app-script-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            "fn init() { this.sum = 0; }\n\
+             fn ioctl(command) {\n\
+                 let c = decode(command);\n\
+                 if type_of(c) == \"()\" { return -1; }\n\
+                 if c.kind == \"set\" { this.sum += c.value; return 0; }\n\
+                 if c.kind == \"get\" { return this.sum; }\n\
+                 -1\n\
+             }\n",
+        )
+        .unwrap();
+        let script = crate::script::Script::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut scripted = crate::script::ScriptDevice::new(script).unwrap();
+        assert_eq!(set(&mut scripted, 0, 40), 0);
+        assert_eq!(set(&mut scripted, 1, 2), 0);
+        assert_eq!(get(&mut scripted, 0), 42);
+        assert_eq!(exec(&mut scripted, 0, 0), u32::MAX);
+        assert_eq!(scripted.ioctl(0xFFFFFFFF), u32::MAX);
+    }
 }
@@ -0,0 +1,9 @@
+pub mod alloc;
+pub mod config;
+pub mod devices;
+pub mod profile;
+pub mod screen;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod terminal;
+pub mod trace;
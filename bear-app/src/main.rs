@@ -1,147 +1,1033 @@
-use std::collections::HashMap;
-use std::path::Path;
+use std::cell::RefCell;
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
-mod devices;
-use bear_vm::vm::CallbackDebugger;
-use devices::{StdinDevice, StdoutDevice};
+use bear_app::{alloc, config, profile, trace};
+use bear_ass::assembler::Assembler;
+use bear_ass::debug::Resolver;
+use bear_ass::parser::{self, ast, ast::Debug};
+use bear_ass::processor::{self, Processor};
+use bear_vm::checksum;
+use bear_vm::diff::{Differ, StackDiff, StateDiff};
+use bear_vm::radix::NumberFormat;
+use bear_vm::vm::{
+    BearVM, CallbackDebugger, Debugger, DynDebugger, ExecutionState, HaltReason, SyncPolicy,
+};
 
 use colored::*;
 
 
-struct DebugInfo {
-    line: usize,
-    labels: Vec<String>,
+/// How the `--debug` debugger prints each step.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DebugFormat {
+    Text,
+    /// A line of JSON per step, for tools.
+    Json,
 }
 
 struct BasicDebugger {
-    info: HashMap<usize, DebugInfo>,
+    resolver: Resolver,
+    format: NumberFormat,
+    differ: Differ,
+    output: DebugFormat,
 }
 
-fn make_debug_info(raw: bear_ass::parser::ast::Debug) -> HashMap<usize, DebugInfo> {
-    let mut hm = HashMap::new();
-    for e in raw.entries.iter() {
-        hm.insert(
-            e.address,
-            DebugInfo {
-                line: e.line,
-                labels: e.names.clone(),
-            },
-        );
+impl BasicDebugger {
+    /// Prints the line, after its file if it was included, and its labels.
+    fn print_line(e: &ast::DebugEntry) {
+        match &e.file {
+            Some(file) => eprint!("line #: {}:{}", file.display(), e.line),
+            None => eprint!("line #: {}", e.line),
+        }
+        eprint!(" -- {:?}", e.names);
+    }
+
+    /// Adds where the line which starts at `ip` is, and its labels and source, to a JSON record.
+    fn record_line(&self, ip: usize, record: &mut serde_json::Value) {
+        let e = match self.resolver.entry(ip) {
+            Some(e) => e,
+            None => return,
+        };
+        if let Some(file) = &e.file {
+            record["file"] = serde_json::json!(file);
+        }
+        record["line"] = serde_json::json!(e.line);
+        record["column"] = serde_json::json!(e.column);
+        record["span"] = serde_json::json!(e.span);
+        record["labels"] = serde_json::json!(e.names);
+        if let Some(source) = self.resolver.source_line(e.file.as_deref(), e.line) {
+            record["source"] = serde_json::json!(source);
+        }
+    }
+
+    fn print_stack(&self, name: &str, stack: &[bear_vm::vm::Cell]) {
+        eprint!("{}", name.bold());
+        for e in stack.iter().rev() {
+            eprint!("{}", "| ".bold());
+            eprint!("{} ", self.format.value(e.0).truecolor(0x35, 0xBA, 0xF6));
+        }
+        eprintln!();
+    }
+
+    /// Prints the cells which were pushed and popped, and the depth of the stack.
+    fn print_stack_diff(&self, name: &str, diff: &StackDiff, depth: usize) {
+        if diff.is_empty() {
+            return;
+        }
+        eprint!("{}", name.bold());
+        for value in diff.popped.iter().rev() {
+            eprint!("{} ", format!("-{}", self.format.value(*value)).red());
+        }
+        for value in diff.pushed.iter().rev() {
+            eprint!("{} ", format!("+{}", self.format.value(*value)).green());
+        }
+        eprintln!("{}", format!("(depth {})", depth).dimmed());
+    }
+
+    /**
+     * Prints a step as a line of JSON: where it is, the first step's stacks (bottom first), or what
+     * the previous instruction changed.
+     */
+    fn print_record(
+        &self,
+        state: &bear_vm::vm::ExecutionState<DynDebugger>,
+        op: bear_vm::vm::OpCode,
+        diff: Option<StateDiff>,
+    ) {
+        let ip = state.ip();
+        let mut record = serde_json::json!({
+            "ip": ip,
+            "ii": state.instruction_index,
+            "cw": state.current_word_index,
+            "lw": state.loaded_word_index,
+            "op": op.to_string(),
+            "depth": { "data": state.vm.data.len(), "address": state.vm.address.len() },
+        });
+        self.record_line(ip, &mut record);
+        let stack =
+            |diff: &StackDiff| serde_json::json!({ "popped": diff.popped, "pushed": diff.pushed });
+        match diff {
+            None => {
+                let cells = |stack: &bear_vm::stack::Stack| -> Vec<u32> {
+                    stack.iter().map(|cell| cell.0).collect()
+                };
+                record["data"] = serde_json::json!(cells(&state.vm.data));
+                record["address"] = serde_json::json!(cells(&state.vm.address));
+            }
+            Some(diff) => {
+                let writes: Vec<serde_json::Value> = diff
+                    .writes
+                    .iter()
+                    .map(|write| {
+                        serde_json::json!({
+                            "address": write.address,
+                            "value": write.value,
+                            "byte": write.byte,
+                        })
+                    })
+                    .collect();
+                record["previous"] = serde_json::json!({
+                    "op": diff.op.to_string(),
+                    "ip": diff.from,
+                    "jumped": diff.jumped,
+                    "data": stack(&diff.data),
+                    "address": stack(&diff.address),
+                    "writes": writes,
+                });
+            }
+        }
+        eprintln!("{}", record);
     }
-    hm
 }
 
 impl CallbackDebugger for BasicDebugger {
-    fn ip(&self, state: &bear_vm::vm::ExecutionState, op: bear_vm::vm::OpCode) {
+    fn ip(&self, state: &bear_vm::vm::ExecutionState<DynDebugger>, op: bear_vm::vm::OpCode) {
+        if self.output == DebugFormat::Json {
+            let diff = self.differ.step(state, op);
+            self.print_record(state, op, diff);
+            return;
+        }
         let ip = state.ip();
         let ii = state.instruction_index;
         let lw = state.loaded_word_index;
         let cw = state.current_word_index;
 
-        match self.info.get(&ip) {
-            None => {}
-            Some(e) => {
-                eprintln!("line #: {} -- {:?}", e.line, e.labels);
+        if let Some(e) = self.resolver.entry(ip) {
+            BasicDebugger::print_line(e);
+            eprintln!();
+            if let Some(source) = self.resolver.source_line(e.file.as_deref(), e.line) {
+                eprintln!("{} {}", "|".dimmed(), source.trim_end());
             }
         }
+        eprint!("{}", "ip: ".bold());
+        eprint!("{}", self.format.address(ip).truecolor(0x35, 0xBA, 0xF6));
+        eprint!(", ");
         eprint!("{}", "ii: ".bold());
         eprint!("{}", ii.to_string().truecolor(0x35, 0xBA, 0xF6));
         eprint!(", ");
         eprint!("{}", "cw: ".bold());
-        eprint!("{}", cw.to_string().truecolor(0x35, 0xBA, 0xF6));
+        eprint!("{}", self.format.address(cw).truecolor(0x35, 0xBA, 0xF6));
         eprint!(", ");
         eprint!("{}", "lw: ".bold());
-        eprint!("{}", lw.to_string().truecolor(0x35, 0xBA, 0xF6));
+        eprint!("{}", self.format.address(lw).truecolor(0x35, 0xBA, 0xF6));
         eprint!(", ");
         eprint!("{}", "op: ".bold());
         eprintln!("{}", op.to_string().yellow());
 
-        eprint!("{}", "data: ".bold());
-        for e in state.vm.data.iter().rev() {
-            eprint!("{}", "| ".bold());
-            eprint!("{} ", e.0.to_string().truecolor(0x35, 0xBA, 0xF6));
+        // The first step shows the stacks; later steps show what the previous instruction changed.
+        match self.differ.step(state, op) {
+            None => {
+                self.print_stack("data: ", &state.vm.data);
+                self.print_stack("addr: ", &state.vm.address);
+            }
+            Some(diff) => {
+                if diff.jumped {
+                    eprintln!(
+                        "{} {}",
+                        "jumped from".bold(),
+                        self.format.address(diff.from).magenta()
+                    );
+                }
+                self.print_stack_diff("data: ", &diff.data, state.vm.data.len());
+                self.print_stack_diff("addr: ", &diff.address, state.vm.address.len());
+                for write in diff.writes.iter() {
+                    eprintln!(
+                        "{}{} <- {}",
+                        if write.byte { "mem.8: " } else { "mem: " }.bold(),
+                        self.format.address(write.address),
+                        self.format.value(write.value).green()
+                    );
+                }
+            }
         }
         eprintln!();
+    }
 
-        eprint!("{}", "addr: ".bold());
-        for e in state.vm.address.iter().rev() {
-            eprint!("{}", "| ".bold());
-            eprint!("{} ", e.0.to_string().truecolor(0x35, 0xBA, 0xF6));
+    fn data_pop(&self, _vm: &bear_vm::vm::BearVM<DynDebugger>) {}
+    fn data_push(&self, _vm: &bear_vm::vm::BearVM<DynDebugger>, _cell: bear_vm::vm::Cell) {}
+    fn address_pop(&self, _vm: &bear_vm::vm::BearVM<DynDebugger>) {}
+    fn address_push(&self, _vm: &bear_vm::vm::BearVM<DynDebugger>, _cell: bear_vm::vm::Cell) {}
+
+    fn store(&self, address: bear_vm::vm::Cell, value: bear_vm::vm::Cell) {
+        self.differ.store(address.0 as usize, value.0, false);
+    }
+
+    fn store_8(&self, address: bear_vm::vm::Cell, value: bear_vm::vm::Cell) {
+        self.differ.store(address.0 as usize, value.0, true);
+    }
+
+    /// Pauses until Enter is pressed on the terminal, since stdin may belong to the guest.
+    fn brk(&self, state: &bear_vm::vm::ExecutionState<DynDebugger>) {
+        let ip = state.ip();
+        if self.output == DebugFormat::Json {
+            let mut record = serde_json::json!({ "brk": ip });
+            self.record_line(ip, &mut record);
+            eprintln!("{}", record);
+        } else {
+            eprint!("{} {}", "brk".red().bold(), self.format.address(ip));
+            if let Some(e) = self.resolver.entry(ip) {
+                eprint!(" ");
+                BasicDebugger::print_line(e);
+            }
+        }
+        match std::fs::File::open("/dev/tty") {
+            Ok(tty) if self.output == DebugFormat::Json => {
+                std::io::BufReader::new(tty)
+                    .read_line(&mut String::new())
+                    .ok();
+            }
+            Ok(tty) => {
+                eprint!(" (press Enter to continue)");
+                let mut line = String::new();
+                std::io::BufReader::new(tty).read_line(&mut line).ok();
+            }
+            Err(_) => eprintln!(),
         }
-        eprintln!("\n");
     }
+}
 
-    fn data_pop(&self, _vm: &bear_vm::vm::BearVM) {}
-    fn data_push(&self, _vm: &bear_vm::vm::BearVM, _cell: bear_vm::vm::Cell) {}
-    fn address_pop(&self, _vm: &bear_vm::vm::BearVM) {}
-    fn address_push(&self, _vm: &bear_vm::vm::BearVM, _cell: bear_vm::vm::Cell) {}
+/// The binary which stands for an image read from stdin.
+const STDIN: &str = "-";
+
+/// The extension of assembler sources, which are assembled in-process and run.
+const SOURCE: &str = "bear";
+
+/// A ROM image, with the RAM-initialization blob of its other sections if it has any.
+struct Image {
+    rom: Vec<u8>,
+    init: Option<Vec<u8>>,
 }
 
-fn make_vm_from_path(
-    path: &Path,
-    devices: Vec<Box<dyn bear_vm::device::Device>>,
-    debug: bool,
-) -> bear_vm::vm::BearVM {
+/**
+ * Reads the image at `path` with its `.bin` extension, or from stdin, and its `.init` blob.  An
+ * image which starts with a checksum header is checked before it is run.
+ */
+fn read_image(path: &Path) -> Image {
+    if path == Path::new(STDIN) {
+        let mut rom = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut rom)
+            .unwrap_or_else(|e| panic!("No image on stdin: {}", e));
+        let rom = strip_checksum(path, rom);
+        return Image { rom, init: None };
+    }
     let image_path = path.with_extension("bin");
-    let image = std::fs::read(image_path.clone()).unwrap_or_else(|_| panic!("No image: {:?}", image_path));
-    let mut vm = bear_vm::vm::BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image));
+    let rom =
+        std::fs::read(image_path.clone()).unwrap_or_else(|_| panic!("No image: {:?}", image_path));
+    let rom = strip_checksum(&image_path, rom);
+    let init_path = path.with_extension("init");
+    let init = if init_path.exists() {
+        Some(std::fs::read(&init_path).unwrap_or_else(|_| panic!("No init: {:?}", init_path)))
+    } else {
+        None
+    };
+    Image { rom, init }
+}
+
+/// The image without its checksum header, exiting if the image does not match it.
+fn strip_checksum(path: &Path, rom: Vec<u8>) -> Vec<u8> {
+    match checksum::strip_header(&rom) {
+        Ok(body) => body.to_vec(),
+        Err(mismatch) => {
+            eprintln!("{}: {}", path.display(), mismatch);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// An assembled source.
+struct Assembly {
+    image: Image,
+    debug: Debug,
+    /// The files the source includes, which `--watch` watches.
+    #[cfg_attr(not(feature = "watch"), allow(dead_code))]
+    includes: Vec<PathBuf>,
+}
+
+/**
+ * Assembles the source at `path` as `bear-ass` would, searching its directory and then
+ * `include_paths` for includes.  Errors and warnings are rendered against the source.
+ */
+fn assemble(path: &Path, include_paths: Vec<PathBuf>) -> Result<Assembly, String> {
+    let name = path.display().to_string();
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}\n", name, e))?;
+    let program = parser::Parser {}
+        .parse(&text)
+        .map_err(|e| format!("{}: {}\n", name, e))?;
+    let mut options = processor::Options {
+        include_paths,
+        ..Default::default()
+    };
+    if let Some(dir) = path.parent() {
+        options.include_paths.insert(0, dir.to_path_buf());
+    }
+    let processor =
+        Processor::process_with(program, options).map_err(|e| e.render(&name, &text))?;
+    eprint!("{}", processor.render_warnings(&name, &text));
+    let debug = processor
+        .make_debug()
+        .map_err(|e| format!("{}: {}\n", name, e))?;
+    let includes = processor.included_files();
+    let images =
+        Assembler::assemble_sections(processor).map_err(|e| format!("{}: {}\n", name, e))?;
+    let init = if images.len() > 1 {
+        Some(Assembler::make_init_blob(&images))
+    } else {
+        None
+    };
+    let rom = images
+        .into_iter()
+        .next()
+        .map(|image| image.bits)
+        .unwrap_or_default();
+    Ok(Assembly {
+        image: Image { rom, init },
+        debug,
+        includes,
+    })
+}
+
+fn include_paths(args: &ArgMatches) -> Vec<PathBuf> {
+    args.values_of("include")
+        .map_or_else(Vec::new, |dirs| dirs.map(PathBuf::from).collect())
+}
+
+/// The VM for `image`, whose `.init` blob, like `sbrk`, may grow it to at most `memory_limit` bytes.
+fn make_vm(
+    image: Image,
+    devices: Vec<Box<dyn bear_vm::device::Device>>,
+    memory_limit: usize,
+) -> BearVM {
+    let mut vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image.rom))
+        .with_memory_limit(memory_limit);
+    if let Some(blob) = image.init {
+        vm.load_init_blob(&blob)
+            .expect("Could not load RAM-initialization blob.");
+    }
     for device in devices.into_iter() {
         vm = vm.with_device(device);
     }
-    if debug {
-        let dbg_path = path.with_extension("debug");
-        let dbg_raw =
-            std::fs::read_to_string(dbg_path.clone()).unwrap_or_else(|_| panic!("No image: {:?}", dbg_path));
-        let dbg_info: bear_ass::parser::ast::Debug =
-            serde_json::from_str(&dbg_raw).expect("Could not load debug info.");
-        return vm.with_callback_debugger(Box::new(BasicDebugger {
-            info: make_debug_info(dbg_info),
+    vm
+}
+
+fn read_debug_info(dbg_path: &Path) -> Option<Debug> {
+    let dbg_raw = std::fs::read_to_string(dbg_path).ok()?;
+    Some(serde_json::from_str(&dbg_raw).expect("Could not load debug info."))
+}
+
+/**
+ * Runs `image`, or the image at `path`, with the devices and debuggers the command line asks for.
+ * Returns the exit status: the one set by the guest through an exit device, or 0, if it halted;
+ * otherwise `TRAPPED` or `STOPPED`.
+ */
+fn execute(
+    path: &Path,
+    image: Option<Image>,
+    debug_info: Option<&Debug>,
+    devices: &config::Devices,
+    args: &ArgMatches,
+    format: NumberFormat,
+) -> i32 {
+    let strings = debug_info.map(|d| d.strings.clone()).unwrap_or_default();
+    let allocations = Rc::new(RefCell::new(alloc::Allocations::default()));
+    let exit = devices.exit.clone();
+    let devices = devices
+        .build(&strings, &allocations)
+        .unwrap_or_else(|e| panic!("Could not create devices: {}", e));
+    let mut debugger: Option<Box<dyn CallbackDebugger>> = None;
+    if args.is_present("debug") {
+        let info = debug_info.expect("No debug info.");
+        debugger = Some(Box::new(BasicDebugger {
+            resolver: Resolver::new(info),
+            format,
+            differ: Differ::default(),
+            output: match args.value_of("debug-format") {
+                Some("json") => DebugFormat::Json,
+                _ => DebugFormat::Text,
+            },
         }));
     }
-    vm
+    let trace = if args.is_present("trace-chrome") || args.is_present("trace-folded") {
+        let (tracer, trace) = trace::ChromeTracer::new(debug_info, debugger.take());
+        debugger = Some(Box::new(tracer));
+        Some(trace)
+    } else {
+        None
+    };
+    if args.is_present("trace") {
+        let out: Box<dyn Write> = match args.value_of("trace") {
+            Some(trace_path) => Box::new(BufWriter::new(
+                std::fs::File::create(trace_path)
+                    .unwrap_or_else(|e| panic!("Could not create {:?}: {}", trace_path, e)),
+            )),
+            None => Box::new(BufWriter::new(std::io::stderr())),
+        };
+        let trace_format = args
+            .value_of("trace-format")
+            .map_or(trace::TraceFormat::Line, |trace_format| {
+                trace_format.parse().unwrap()
+            });
+        let tops = args.value_of("trace-stack").map_or(0, |tops| {
+            tops.parse()
+                .unwrap_or_else(|_| panic!("Invalid number of cells: {}", tops))
+        });
+        let tracer =
+            trace::InstructionTracer::new(out, trace_format, tops, format, debugger.take());
+        debugger = Some(Box::new(tracer));
+    }
+    let profile = args.value_of("profile").map(|profile_path| {
+        let (profiler, profile) = profile::Profiler::new(debugger.take());
+        debugger = Some(Box::new(profiler));
+        (profile_path, profile)
+    });
+    if args.is_present("alloc-report") {
+        let recorder = alloc::SiteRecorder::new(allocations.clone(), debugger.take());
+        debugger = Some(Box::new(recorder));
+    }
+    if let Some(script_path) = args.value_of("script") {
+        debugger = Some(script_debugger(Path::new(script_path), debugger.take()));
+    }
+    let image = image.unwrap_or_else(|| read_image(path));
+    if let Some(expected) = debug_info.and_then(|d| d.checksum) {
+        if checksum::crc32(&image.rom) != expected {
+            eprintln!(
+                "{}: the debug info was written for a different image; reassemble it.",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+    }
+    let memory_limit = match args.value_of("memory-limit") {
+        Some(limit) => limit
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid memory limit: {}", limit)),
+        None => bear_vm::vm::DEFAULT_MEMORY_LIMIT,
+    };
+    let vm = make_vm(image, devices, memory_limit);
+    // Without a debugger, the VM is compiled without the debugger callbacks.
+    let reason = match debugger {
+        Some(debugger) => run(vm.with_callback_debugger(debugger), args, format),
+        None => run(vm, args, format),
+    };
+    if args.is_present("alloc-report") {
+        eprint!("{}", allocations.borrow().report(debug_info));
+    }
+    if let Some(trace) = trace {
+        if let Some(trace_path) = args.value_of("trace-chrome") {
+            let json = trace.borrow().to_json();
+            std::fs::write(trace_path, json.to_string())
+                .unwrap_or_else(|e| panic!("Could not write {:?}: {}", trace_path, e));
+        }
+        if let Some(folded_path) = args.value_of("trace-folded") {
+            std::fs::write(folded_path, trace.borrow().to_folded())
+                .unwrap_or_else(|e| panic!("Could not write {:?}: {}", folded_path, e));
+        }
+    }
+    if let Some((profile_path, profile)) = profile {
+        let json = profile.borrow().to_json(debug_info);
+        std::fs::write(profile_path, format!("{:#}\n", json))
+            .unwrap_or_else(|e| panic!("Could not write {:?}: {}", profile_path, e));
+    }
+    match reason {
+        HaltReason::Halt => exit.get().map_or(0, i32::from),
+        HaltReason::Trap => TRAPPED,
+        HaltReason::OutOfFuel | HaltReason::TimedOut => STOPPED,
+    }
+}
+
+/**
+ * Assembles and runs the source at `path`, and again whenever it or a file it includes changes,
+ * until interrupted.  Errors are reported, and the files are still watched.
+ */
+#[cfg(feature = "watch")]
+fn watch(path: &Path, args: &ArgMatches, devices: &config::Devices, format: NumberFormat) -> ! {
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use std::time::Duration;
+
+    if path.extension() != Some(SOURCE.as_ref()) {
+        panic!("--watch needs a .{} source.", SOURCE);
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(sender).unwrap_or_else(|e| panic!("Could not watch: {}", e));
+    let source = path
+        .canonicalize()
+        .unwrap_or_else(|e| panic!("{:?}: {}", path, e));
+    let mut includes = Vec::new();
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    loop {
+        match assemble(path, include_paths(args)) {
+            Ok(assembly) => {
+                includes = assembly.includes;
+                let status = execute(
+                    path,
+                    Some(assembly.image),
+                    Some(&assembly.debug),
+                    devices,
+                    args,
+                    format,
+                );
+                eprintln!("Exited with {}.", status);
+            }
+            // Keep watching the includes of the last version which assembled.
+            Err(e) => eprint!("{}", e),
+        }
+        let files: Vec<PathBuf> = std::iter::once(source.clone())
+            .chain(includes.iter().cloned())
+            .collect();
+        // Editors often save by replacing a file, so its directory is watched rather than the file.
+        for dir in dirs.drain(..) {
+            let _ = watcher.unwatch(&dir);
+        }
+        for file in files.iter() {
+            if let Some(dir) = file.parent() {
+                if !dirs.iter().any(|watched| watched == dir) {
+                    watcher
+                        .watch(dir, RecursiveMode::NonRecursive)
+                        .unwrap_or_else(|e| panic!("Could not watch {:?}: {}", dir, e));
+                    dirs.push(dir.to_path_buf());
+                }
+            }
+        }
+        eprintln!("Watching {} file(s) for changes.", files.len());
+        let changed = |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) && event.paths.iter().any(|path| files.contains(path))
+            }
+            Err(_) => false,
+        };
+        while !changed(receiver.recv().expect("The watcher stopped.")) {}
+        // A save can come as several events; let them settle.
+        while receiver.recv_timeout(Duration::from_millis(100)).is_ok() {}
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+fn watch(_path: &Path, _args: &ArgMatches, _devices: &config::Devices, _format: NumberFormat) -> ! {
+    panic!("--watch needs bear-app to be built with the watch feature.")
+}
+
+#[cfg(feature = "script")]
+fn script_debugger(
+    path: &Path,
+    inner: Option<Box<dyn CallbackDebugger>>,
+) -> Box<dyn CallbackDebugger> {
+    let debugger = bear_app::script::Script::load(path)
+        .and_then(|script| bear_app::script::ScriptDebugger::new(script, inner))
+        .unwrap_or_else(|e| panic!("Could not load the script: {}", e));
+    Box::new(debugger)
+}
+
+#[cfg(not(feature = "script"))]
+fn script_debugger(
+    _path: &Path,
+    _inner: Option<Box<dyn CallbackDebugger>>,
+) -> Box<dyn CallbackDebugger> {
+    panic!("--script needs bear-app to be built with the script feature.")
+}
+
+/// The exit status when the guest traps.
+const TRAPPED: i32 = 1;
+/// The exit status when `--max-steps` or `--timeout` stops the guest, as with timeout(1).
+const STOPPED: i32 = 124;
+
+/// Runs `vm` as the command line asks, and reports what went wrong.  Returns why it stopped.
+fn run<D: Debugger>(mut vm: BearVM<D>, args: &ArgMatches, format: NumberFormat) -> HaltReason {
+    if args.is_present("verify") {
+        let defects = vm.verify();
+        for defect in defects.iter() {
+            eprintln!("{}", defect);
+        }
+        if !defects.is_empty() {
+            std::process::exit(1);
+        }
+    }
+    vm.dma_race_detection = args.is_present("check-dma");
+    vm.overflow_traps = args.is_present("trap-overflow");
+    vm.alignment_checks = args.is_present("strict-alignment");
+    vm = match args.value_of("stack-capacity") {
+        None => vm,
+        Some("grow") => vm.with_growable_stacks(),
+        Some(capacity) => vm.with_stack_capacity(
+            capacity
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid stack capacity: {}", capacity)),
+        ),
+    };
+    if args.is_present("predecode") {
+        vm = vm.with_predecode();
+    }
+    if let Some(core_path) = args.value_of("core") {
+        vm = vm.with_core_path(core_path);
+    }
+    vm = match args.value_of("sync") {
+        None | Some("always") => vm,
+        Some("io") => vm.with_sync_policy(SyncPolicy::AfterIo),
+        Some("attention") => vm.with_sync_policy(SyncPolicy::OnAttention),
+        Some(n) => vm.with_sync_policy(SyncPolicy::Every(
+            n.parse()
+                .unwrap_or_else(|_| panic!("Invalid sync policy: {}", n)),
+        )),
+    };
+    if let Some(steps) = args.value_of("max-steps") {
+        vm = vm.with_fuel(
+            steps
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid number of steps: {}", steps)),
+        );
+    }
+    if let Some(seconds) = args.value_of("timeout") {
+        let seconds: f64 = seconds
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid timeout: {}", seconds));
+        vm = vm.with_timeout(Duration::from_secs_f64(seconds));
+    }
+    let mut state = vm.start().expect("Could not start vm.");
+    let result = if args.is_present("unchecked") {
+        // SAFETY: the image passed --verify, and the user vouches for its computed branches.
+        unsafe { state.run_unchecked() }
+    } else {
+        state.run()
+    };
+    let reason = match &result {
+        Err(e) => {
+            eprintln!("IP: {}", format.address(state.ip()));
+            eprintln!("Error: {}", e.message());
+            HaltReason::Trap
+        }
+        Ok(()) if state.running => {
+            let (reason, limit) = match state.vm.fuel {
+                Some(fuel) if state.executed() >= fuel => (HaltReason::OutOfFuel, "--max-steps"),
+                _ => (HaltReason::TimedOut, "--timeout"),
+            };
+            eprintln!("Stopped by {} after {} steps.", limit, state.executed());
+            eprintln!("IP: {}", format.address(state.ip()));
+            let cells = |stack: &bear_vm::stack::Stack| -> Vec<String> {
+                stack.iter().map(|cell| format.value(cell.0)).collect()
+            };
+            eprintln!("Data: [{}]", cells(&state.vm.data).join(", "));
+            eprintln!("Address: [{}]", cells(&state.vm.address).join(", "));
+            reason
+        }
+        Ok(()) => HaltReason::Halt,
+    };
+    if let Some(dump_path) = args.value_of("dump-state") {
+        let error = result.as_ref().err().map(|e| e.message());
+        let json = dump_state(&state, &reason, error, args.is_present("dump-digest"));
+        std::fs::write(dump_path, format!("{:#}\n", json))
+            .unwrap_or_else(|e| panic!("Could not write {:?}: {}", dump_path, e));
+    }
+    for race in state.vm.dma_races.iter() {
+        eprintln!(
+            "DMA race: {} {} at ip {} while device {} is transferring it",
+            if race.store { "store to" } else { "load from" },
+            format.address(race.address),
+            format.address(race.ip),
+            race.device
+        );
+    }
+    reason
+}
+
+/**
+ * The state of the VM after a run, for `--dump-state`: why it stopped, the runtime error if it
+ * trapped, the instruction pointer, the number of instructions executed and both stacks, bottom
+ * first.  With `digest`, also the FNV-1a hash of memory, as 16 hex digits.
+ */
+fn dump_state<D: Debugger>(
+    state: &ExecutionState<D>,
+    reason: &HaltReason,
+    error: Option<&str>,
+    digest: bool,
+) -> serde_json::Value {
+    let reason = match reason {
+        HaltReason::Halt => "halt",
+        HaltReason::Trap => "trap",
+        HaltReason::OutOfFuel => "out_of_fuel",
+        HaltReason::TimedOut => "timed_out",
+    };
+    let cells =
+        |stack: &bear_vm::stack::Stack| -> Vec<u32> { stack.iter().map(|cell| cell.0).collect() };
+    let mut json = serde_json::json!({
+        "reason": reason,
+        "error": error,
+        "ip": state.ip(),
+        "steps": state.executed(),
+        "data": cells(&state.vm.data),
+        "address": cells(&state.vm.address),
+    });
+    if digest {
+        let hash = state
+            .vm
+            .image
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .fold(0xcbf29ce484222325_u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        json["memory"] = serde_json::json!(format!("{:016x}", hash));
+    }
+    json
 }
 
 fn main() {
     let args = App::new("BearVM")
         .version("0.1.0")
         .author("John Connor <john.theman.connor@gmail.com>")
-        .arg(Arg::with_name("binary").takes_value(true))
+        .arg(
+            Arg::with_name("binary")
+                .takes_value(true)
+                .help(
+                    "The image, without its .bin extension, - to read it from stdin, or a .bear \
+                     source to assemble and run",
+                ),
+        )
+        .arg(
+            Arg::with_name("include")
+                .short("I")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("dir")
+                .help("Search dir for the includes of a .bear source"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .takes_value(false)
+                .help("Assemble and run a .bear source again whenever it or its includes change"),
+        )
+        .arg(
+            Arg::with_name("debug-info")
+                .long("debug-info")
+                .takes_value(true)
+                .value_name("file")
+                .help("Read debug info from file [default: the image's .debug file]"),
+        )
         .arg(
             Arg::with_name("debug")
                 .long("debug")
                 .short("d")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("debug-format")
+                .long("debug-format")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("How --debug prints each step: text, or a line of JSON [default: text]"),
+        )
+        .arg(
+            Arg::with_name("no-color")
+                .long("no-color")
+                .takes_value(false)
+                .help("Print without colors, as when NO_COLOR is set"),
+        )
         .arg(Arg::with_name("stdin").long("stdin").takes_value(true))
+        .arg(
+            Arg::with_name("arguments")
+                .multiple(true)
+                .last(true)
+                .help("Arguments for the guest, which env devices expose"),
+        )
         .arg(Arg::with_name("stdout").long("stdout").takes_value(true))
+        .arg(
+            Arg::with_name("stderr")
+                .long("stderr")
+                .takes_value(true)
+                .value_name("file")
+                .help("Write the guest's stderr device to file instead of the terminal"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .value_name("file")
+                .help("Log every instruction executed to file [default: stderr]"),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .long("trace-format")
+                .takes_value(true)
+                .possible_values(&["line", "binary"])
+                .help("How --trace logs each instruction [default: line]"),
+        )
+        .arg(
+            Arg::with_name("trace-stack")
+                .long("trace-stack")
+                .takes_value(true)
+                .value_name("N")
+                .help("Log the top N cells of the data stack with each instruction [default: 0]"),
+        )
+        .arg(
+            Arg::with_name("trace-chrome")
+                .long("trace-chrome")
+                .takes_value(true)
+                .value_name("file")
+                .help("Write guest calls and device ioctls to file as Chrome trace-event JSON"),
+        )
+        .arg(
+            Arg::with_name("trace-folded")
+                .long("trace-folded")
+                .takes_value(true)
+                .value_name("file")
+                .help("Write the instructions executed in each stack of guest calls to file as folded stacks, for flame graphs"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .value_name("file")
+                .help("Write how many times each address, source line and label was executed to file as JSON"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .takes_value(false)
+                .help("Check the image for invalid opcodes, missing operands and bad jump targets before running it"),
+        )
+        .arg(
+            Arg::with_name("check-dma")
+                .long("check-dma")
+                .takes_value(false)
+                .help("Report guest loads and stores to memory which a device is transferring"),
+        )
+        .arg(
+            Arg::with_name("trap-overflow")
+                .long("trap-overflow")
+                .takes_value(false)
+                .help("Trap when add, sub or mul overflows as signed, instead of wrapping around"),
+        )
+        .arg(
+            Arg::with_name("strict-alignment")
+                .long("strict-alignment")
+                .takes_value(false)
+                .help("Trap on a load or store from an address which is not a multiple of the cell size"),
+        )
+        .arg(
+            Arg::with_name("memory-limit")
+                .long("memory-limit")
+                .value_name("bytes")
+                .takes_value(true)
+                .help("The most bytes sbrk may grow the image to [default: 64 MiB]"),
+        )
+        .arg(
+            Arg::with_name("stack-capacity")
+                .long("stack-capacity")
+                .takes_value(true)
+                .value_name("cells")
+                .help("The capacity of each stack, or grow for stacks which grow as needed [default: 4096]"),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .takes_value(true)
+                .value_name("policy")
+                .help("When devices are polled for DMA: always, io, attention, or every N instructions [default: always]"),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true)
+                .value_name("N")
+                .help("Stop the guest after N instructions, with a dump of its state"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("secs")
+                .help("Stop the guest after secs seconds, with a dump of its state"),
+        )
+        .arg(
+            Arg::with_name("dump-state")
+                .long("dump-state")
+                .takes_value(true)
+                .value_name("file")
+                .help("Write the final state of the VM to file as JSON"),
+        )
+        .arg(
+            Arg::with_name("dump-digest")
+                .long("dump-digest")
+                .takes_value(false)
+                .requires("dump-state")
+                .help("Include a hash of memory in --dump-state"),
+        )
+        .arg(
+            Arg::with_name("core")
+                .long("core")
+                .takes_value(true)
+                .value_name("file")
+                .help("Where halt with -1 on top of the data stack writes a core dump [default: core.bin]"),
+        )
+        .arg(
+            Arg::with_name("unchecked")
+                .long("unchecked")
+                .takes_value(false)
+                .requires("verify")
+                .help("Skip the bounds checks of each step; the image must pass --verify"),
+        )
+        .arg(
+            Arg::with_name("predecode")
+                .long("predecode")
+                .takes_value(false)
+                .help("Decode every opcode of the image before running it"),
+        )
+        .arg(
+            Arg::with_name("script")
+                .long("script")
+                .takes_value(true)
+                .value_name("file")
+                .help("Call the brk function of a Rhai script at each brk [needs the script feature]"),
+        )
+        .arg(
+            Arg::with_name("alloc-report")
+                .long("alloc-report")
+                .takes_value(false)
+                .help("Report the peak memory use of alloc devices, and the blocks never freed"),
+        )
+        .arg(
+            Arg::with_name("devices")
+                .long("devices")
+                .takes_value(true)
+                .help("A TOML file declaring the devices to attach [default: the [[devices]] of ./bear.toml]"),
+        )
+        .arg(
+            Arg::with_name("address-radix")
+                .long("address-radix")
+                .takes_value(true)
+                .possible_values(&["hex", "dec"])
+                .help("How addresses are displayed [env: BEAR_ADDRESS_RADIX] [default: hex]"),
+        )
+        .arg(
+            Arg::with_name("value-radix")
+                .long("value-radix")
+                .takes_value(true)
+                .possible_values(&["hex", "dec"])
+                .help("How cell values are displayed [env: BEAR_VALUE_RADIX] [default: dec]"),
+        )
         .get_matches();
-    let stdin: Box<dyn bear_vm::device::Device> = if args.is_present("stdin") {
-        Box::new(StdinDevice::new(
-            std::fs::File::open(args.value_of("stdin").unwrap()).unwrap(),
-        ))
-    } else {
-        Box::new(StdinDevice::new(std::io::stdin()))
+    if args.is_present("no-color") {
+        colored::control::set_override(false);
+    }
+    let mut format = NumberFormat::from_env().unwrap_or_else(|e| panic!("{}", e));
+    if let Some(radix) = args.value_of("address-radix") {
+        format.address = radix.parse().unwrap();
+    }
+    if let Some(radix) = args.value_of("value-radix") {
+        format.value = radix.parse().unwrap();
+    }
+    let mut devices = match args.value_of("devices") {
+        Some(path) => config::Devices::load(Path::new(path)),
+        None => config::Devices::from_manifest().map(Option::unwrap_or_default),
+    }
+    .unwrap_or_else(|e| panic!("Could not load devices: {}", e));
+    if let Some(path) = args.value_of("stdin") {
+        devices.override_path("stdin", Path::new(path));
+    }
+    if let Some(path) = args.value_of("stdout") {
+        devices.override_path("stdout", Path::new(path));
+    }
+    if let Some(path) = args.value_of("stderr") {
+        devices.override_path("stderr", Path::new(path));
+    }
+    if let Some(arguments) = args.values_of("arguments") {
+        devices.arguments = arguments.map(String::from).collect();
+    }
+    let path = Path::new(args.value_of("binary").unwrap());
+    let debug_path = match args.value_of("debug-info") {
+        Some(debug_path) => Some(Path::new(debug_path).to_path_buf()),
+        None if path == Path::new(STDIN) => None,
+        None => Some(path.with_extension("debug")),
     };
-    let stdout: Box<dyn bear_vm::device::Device> = if args.is_present("stdout") {
-        let file = std::fs::OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(args.value_of("stdout").unwrap())
-            .unwrap();
-        Box::new(StdoutDevice::new(file))
+    if args.is_present("watch") {
+        watch(path, &args, &devices, format);
+    }
+    // A source is assembled before the devices are created, since they need its strings.
+    let (image, debug_info) = if path.extension() == Some(SOURCE.as_ref()) {
+        let assembly = assemble(path, include_paths(&args)).unwrap_or_else(|e| {
+            eprint!("{}", e);
+            std::process::exit(1);
+        });
+        (Some(assembly.image), Some(assembly.debug))
     } else {
-        Box::new(StdoutDevice::new(std::io::stdout()))
+        (None, debug_path.as_deref().and_then(read_debug_info))
     };
-    let path = Path::new(args.value_of("binary").unwrap());
-    let vm = make_vm_from_path(path, vec![stdin, stdout], args.is_present("debug"));
-    let mut state = vm.start().expect("Could not start vm.");
-    match state.run() {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("IP: {}", state.ip());
-            eprintln!("Error: {:?}", e);
-        }
+    if args.is_present("debug") && debug_info.is_none() {
+        panic!("No debug info: {:?}", debug_path);
+    }
+    let status = execute(path, image, debug_info.as_ref(), &devices, &args, format);
+    if status != 0 {
+        std::process::exit(status);
     }
 }
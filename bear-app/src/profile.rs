@@ -0,0 +1,170 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use bear_ass::parser::ast;
+use bear_vm::vm::{BearVM, CallbackDebugger, Cell, DynDebugger, ExecutionState, OpCode};
+
+/// The number of times each address was executed, recorded by a `Profiler`.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Counts by address.
+    counts: Vec<u64>,
+}
+
+impl Profile {
+    fn hit(&mut self, address: usize) {
+        if address >= self.counts.len() {
+            self.counts.resize(address + 1, 0);
+        }
+        self.counts[address] += 1;
+    }
+
+    /// The number of instructions executed.
+    pub fn steps(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /**
+     * The report of `--profile`: the counts by address, and, with `debug`, by source line and by
+     * label, where an address counts for the line it was assembled from and the nearest label at
+     * or before it.  Lines of included files also name the file.  Each list is sorted by count,
+     * hottest first.
+     */
+    pub fn to_json(&self, debug: Option<&ast::Debug>) -> Value {
+        let mut entries: Vec<&ast::DebugEntry> =
+            debug.iter().flat_map(|d| d.entries.iter()).collect();
+        entries.sort_by_key(|e| e.address);
+        let mut addresses = Vec::new();
+        let mut lines: BTreeMap<(Option<&Path>, usize), u64> = BTreeMap::new();
+        let mut labels: BTreeMap<Option<&str>, u64> = BTreeMap::new();
+        // The debug entries and the addresses are both in order, so they are walked together.
+        let mut entry = 0;
+        let mut label = None;
+        for (address, &count) in self.counts.iter().enumerate() {
+            while entry < entries.len() && entries[entry].address <= address {
+                if let Some(name) = entries[entry].names.iter().min() {
+                    label = Some(name.as_str());
+                }
+                entry += 1;
+            }
+            if count == 0 {
+                continue;
+            }
+            let line = entry
+                .checked_sub(1)
+                .map(|i| (entries[i].file.as_deref(), entries[i].line));
+            let mut record = json!({ "address": address, "count": count });
+            if let Some((file, line)) = line {
+                if let Some(file) = file {
+                    record["file"] = json!(file);
+                }
+                record["line"] = json!(line);
+                *lines.entry((file, line)).or_default() += count;
+            }
+            if debug.is_some() {
+                record["label"] = json!(label);
+                *labels.entry(label).or_default() += count;
+            }
+            addresses.push((count, record));
+        }
+        let lines = lines.into_iter().map(|((file, line), count)| {
+            let mut record = json!({ "line": line, "count": count });
+            if let Some(file) = file {
+                record["file"] = json!(file);
+            }
+            (count, record)
+        });
+        let labels = labels
+            .into_iter()
+            .map(|(label, count)| (count, json!({ "label": label, "count": count })));
+        json!({
+            "steps": self.steps(),
+            "lines": hottest(lines),
+            "labels": hottest(labels),
+            "addresses": hottest(addresses),
+        })
+    }
+}
+
+/// Sorts records by their count, hottest first, keeping records with the same count in order.
+fn hottest(records: impl IntoIterator<Item = (u64, Value)>) -> Vec<Value> {
+    let mut records: Vec<(u64, Value)> = records.into_iter().collect();
+    records.sort_by(|(a, _), (b, _)| b.cmp(a));
+    records.into_iter().map(|(_, record)| record).collect()
+}
+
+/**
+ * Counts how many times each address is executed, to find where a guest spends its time.
+ *
+ * Every callback is forwarded to `inner`, so profiling can be combined with another debugger.
+ */
+pub struct Profiler {
+    profile: Rc<RefCell<Profile>>,
+    inner: Option<Box<dyn CallbackDebugger>>,
+}
+
+impl Profiler {
+    pub fn new(inner: Option<Box<dyn CallbackDebugger>>) -> (Profiler, Rc<RefCell<Profile>>) {
+        let profile = Rc::new(RefCell::new(Profile::default()));
+        let profiler = Profiler {
+            profile: profile.clone(),
+            inner,
+        };
+        (profiler, profile)
+    }
+}
+
+impl CallbackDebugger for Profiler {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
+        self.profile.borrow_mut().hit(state.ip());
+        if let Some(inner) = &self.inner {
+            inner.ip(state, op);
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.data_pop(vm);
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.data_push(vm, cell);
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.address_pop(vm);
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.address_push(vm, cell);
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store(address, value);
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store_8(address, value);
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.brk(state);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+
+#[cfg(feature = "window")]
+use crate::devices::Keys;
+
+/// Where the framebuffer device shows its frames.
+pub trait Screen {
+    /**
+     * Shows a frame of `width` × `height` pixels, row by row, each `0x00RRGGBB`.  Returns whether
+     * the screen is still open: `false` once a window has been closed, or a frame could not be
+     * written.
+     */
+    fn show(&mut self, pixels: &[u32], width: usize, height: usize) -> bool;
+
+    /// Called after the last frame, when the guest has halted.
+    fn linger(&mut self) {}
+}
+
+/// Writes each frame to a file as a binary PPM image, replacing the previous frame.
+pub struct PpmScreen {
+    path: PathBuf,
+}
+
+impl PpmScreen {
+    pub fn new(path: PathBuf) -> PpmScreen {
+        PpmScreen { path }
+    }
+}
+
+impl Screen for PpmScreen {
+    fn show(&mut self, pixels: &[u32], width: usize, height: usize) -> bool {
+        let mut bytes = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+        for pixel in pixels {
+            bytes.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+        }
+        std::fs::write(&self.path, bytes).is_ok()
+    }
+}
+
+/**
+ * A window, which stays open once the guest has halted until it is closed or Escape is pressed.
+ * The keys pressed and released while it has focus are pushed to its `Keys` as each frame is
+ * shown.
+ */
+#[cfg(feature = "window")]
+pub struct WindowScreen {
+    window: minifb::Window,
+    keys: Keys,
+}
+
+/// The code of a key, as reported by the keyboard device.
+#[cfg(feature = "window")]
+fn key_code(key: minifb::Key) -> Option<u8> {
+    use bear_vm::device::key;
+    use minifb::Key::*;
+    Some(match key {
+        Key0 | Key1 | Key2 | Key3 | Key4 | Key5 | Key6 | Key7 | Key8 | Key9 => b'0' + key as u8,
+        A | B | C | D | E | F | G | H | I | J | K | L | M | N | O | P | Q | R | S | T | U | V | W
+        | X | Y | Z => b'a' + (key as u8 - A as u8),
+        Up => key::UP,
+        Down => key::DOWN,
+        Left => key::LEFT,
+        Right => key::RIGHT,
+        LeftShift | RightShift => key::SHIFT,
+        LeftCtrl | RightCtrl => key::CONTROL,
+        Space => b' ',
+        Enter => b'\n',
+        Tab => b'\t',
+        Backspace => 8,
+        Escape => 27,
+        Apostrophe => b'\'',
+        Comma => b',',
+        Minus => b'-',
+        Period => b'.',
+        Slash => b'/',
+        Semicolon => b';',
+        Equal => b'=',
+        LeftBracket => b'[',
+        RightBracket => b']',
+        Backslash => b'\\',
+        Backquote => b'`',
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "window")]
+impl WindowScreen {
+    /// A window showing each pixel as a `scale` × `scale` square: 1, 2, 4, 8, 16 or 32.
+    pub fn new(width: usize, height: usize, scale: usize, keys: Keys) -> Result<WindowScreen, String> {
+        let scale = match scale {
+            1 => minifb::Scale::X1,
+            2 => minifb::Scale::X2,
+            4 => minifb::Scale::X4,
+            8 => minifb::Scale::X8,
+            16 => minifb::Scale::X16,
+            32 => minifb::Scale::X32,
+            _ => return Err(format!("The scale of a window can not be {}.", scale)),
+        };
+        let options = minifb::WindowOptions {
+            scale,
+            ..Default::default()
+        };
+        let window = minifb::Window::new("bear", width, height, options).map_err(|e| e.to_string())?;
+        Ok(WindowScreen { window, keys })
+    }
+}
+
+#[cfg(feature = "window")]
+impl Screen for WindowScreen {
+    fn show(&mut self, pixels: &[u32], width: usize, height: usize) -> bool {
+        if self.window.update_with_buffer(pixels, width, height).is_err() {
+            return false;
+        }
+        for key in self.window.get_keys_pressed(minifb::KeyRepeat::No) {
+            if let Some(code) = key_code(key) {
+                self.keys.press(code);
+            }
+        }
+        for key in self.window.get_keys_released() {
+            if let Some(code) = key_code(key) {
+                self.keys.release(code);
+            }
+        }
+        self.window.is_open()
+    }
+
+    fn linger(&mut self) {
+        self.window.set_target_fps(30);
+        while self.window.is_open() && !self.window.is_key_down(minifb::Key::Escape) {
+            self.window.update();
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::cell::RefCell;
+use std::path::Path;
+
+use rhai::{Array, CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+use bear_vm::device::{self, GenericDeviceCommand};
+use bear_vm::vm::{BearVM, CallbackDebugger, Cell, DynDebugger, ExecutionState, OpCode};
+
+/**
+ * A Rhai script, whose functions see the same object map as `this` from one call to the next, so
+ * that they can keep state.  The script's `init` function, if any, is called once it is loaded.
+ * `print` writes to stderr, and `decode(command)` decodes a generic device command into a map:
+ * `#{kind: "exec", command, argument}`, `#{kind: "get", index}`, `#{kind: "set", index, value}`
+ * or `#{kind: "reset"}`, or `()` if it does not decode.
+ */
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    this: Dynamic,
+    path: String,
+}
+
+/// Decodes a generic device command for scripts.
+fn decode(command: i64) -> Dynamic {
+    let mut map = Map::new();
+    let mut put = |key: &str, value: Dynamic| {
+        map.insert(key.into(), value);
+    };
+    match GenericDeviceCommand::decode(command as u32) {
+        Some(GenericDeviceCommand::Reset) => put("kind", "reset".into()),
+        Some(GenericDeviceCommand::GetRegister(index)) => {
+            put("kind", "get".into());
+            put("index", (index as i64).into());
+        }
+        Some(GenericDeviceCommand::SetRegister(index, value)) => {
+            put("kind", "set".into());
+            put("index", (index as i64).into());
+            put("value", (value as i64).into());
+        }
+        Some(GenericDeviceCommand::Execute { command, argument }) => {
+            put("kind", "exec".into());
+            put("command", (command as i64).into());
+            put("argument", (argument as i64).into());
+        }
+        None => return Dynamic::UNIT,
+    }
+    map.into()
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let mut engine = Engine::new();
+        engine.on_print(|text| eprintln!("{}", text));
+        engine.register_fn("decode", decode);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| format!("{:?}: {}", path, e))?;
+        let mut script = Script {
+            engine,
+            ast,
+            this: Map::new().into(),
+            path: path.display().to_string(),
+        };
+        if script.defines("init") {
+            let _ = script.call("init", ())?;
+        }
+        Ok(script)
+    }
+
+    pub fn defines(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|function| function.name == name)
+    }
+
+    /// Calls a function of the script with `this`.
+    pub fn call(&mut self, name: &str, args: impl FuncArgs) -> Result<Dynamic, String> {
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.this);
+        self.engine
+            .call_fn_with_options(options, &mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| format!("{}: {}", self.path, e))
+    }
+}
+
+/**
+ * A device whose commands are answered by the `ioctl(command)` function of a script, which returns
+ * the result; e.g. to mock hardware.  Errors in the script are reported on stderr, and the command
+ * fails with `0xFFFFFFFF`.  The script's `halt` function, if any, is called when the guest halts.
+ */
+pub struct ScriptDevice {
+    script: Script,
+}
+
+impl ScriptDevice {
+    pub fn new(script: Script) -> Result<ScriptDevice, String> {
+        if !script.defines("ioctl") {
+            return Err(format!("{}: a device script needs an ioctl function", script.path));
+        }
+        Ok(ScriptDevice { script })
+    }
+}
+
+impl device::Device for ScriptDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        match self.script.call("ioctl", (command as i64,)) {
+            Ok(result) => result.as_int().map_or(u32::MAX, |result| result as u32),
+            Err(e) => {
+                eprintln!("{}", e);
+                u32::MAX
+            }
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<device::DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn halt(&mut self) {
+        if self.script.defines("halt") {
+            if let Err(e) = self.script.call("halt", ()) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+fn cells(cells: impl Iterator<Item = Cell>) -> Array {
+    cells.map(|cell| Dynamic::from(cell.0 as i64)).collect()
+}
+
+/**
+ * Calls the `brk(vm)` function of a script at each `brk`, with a map of the `ip` and the `data` and
+ * `address` stacks, bottom first; e.g. to log or check values at points of interest.
+ *
+ * Every callback is forwarded to `inner`, so it can be combined with another debugger.
+ */
+pub struct ScriptDebugger {
+    script: RefCell<Script>,
+    inner: Option<Box<dyn CallbackDebugger>>,
+}
+
+impl ScriptDebugger {
+    pub fn new(
+        script: Script,
+        inner: Option<Box<dyn CallbackDebugger>>,
+    ) -> Result<ScriptDebugger, String> {
+        if !script.defines("brk") {
+            return Err(format!("{}: a debugger script needs a brk function", script.path));
+        }
+        Ok(ScriptDebugger {
+            script: RefCell::new(script),
+            inner,
+        })
+    }
+}
+
+impl CallbackDebugger for ScriptDebugger {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
+        if let Some(inner) = &self.inner {
+            inner.ip(state, op);
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.data_pop(vm);
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.data_push(vm, cell);
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.address_pop(vm);
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.address_push(vm, cell);
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store(address, value);
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store_8(address, value);
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        let mut vm = Map::new();
+        vm.insert("ip".into(), (state.ip() as i64).into());
+        vm.insert("data".into(), cells(state.vm.data.iter().copied()).into());
+        vm.insert("address".into(), cells(state.vm.address.iter().copied()).into());
+        if let Err(e) = self.script.borrow_mut().call("brk", (vm,)) {
+            eprintln!("{}", e);
+        }
+        if let Some(inner) = &self.inner {
+            inner.brk(state);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use bear_vm::device;
+
+/**
+ * The host's terminal, shared by the stdin and stdout devices attached to it, which switch it
+ * between raw and cooked mode, turn its echo off and on, and query its size for the guest.  The
+ * terminal is put back as it was when the last of them is dropped.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Terminal(Rc<RefCell<Changes>>);
+
+#[derive(Debug, Default)]
+struct Changes {
+    raw: bool,
+    /// The settings before the guest first changed them.
+    #[cfg(unix)]
+    original: Option<rustix::termios::Termios>,
+}
+
+impl Terminal {
+    /**
+     * Switches to raw mode, where input is read a byte at a time, without echo, and keys such as
+     * Ctrl-C are read rather than signalled; or back to cooked mode.
+     */
+    pub fn set_raw(&self, raw: bool) -> std::io::Result<()> {
+        self.save()?;
+        if raw {
+            crossterm::terminal::enable_raw_mode()?;
+        } else {
+            crossterm::terminal::disable_raw_mode()?;
+        }
+        self.0.borrow_mut().raw = raw;
+        Ok(())
+    }
+
+    /// Turns the echo of input on or off.
+    #[cfg(unix)]
+    pub fn set_echo(&self, echo: bool) -> std::io::Result<()> {
+        use rustix::termios::{tcgetattr, tcsetattr, LocalModes, OptionalActions};
+        self.save()?;
+        let stdin = std::io::stdin();
+        let mut termios = tcgetattr(&stdin)?;
+        termios.local_modes.set(LocalModes::ECHO, echo);
+        tcsetattr(&stdin, OptionalActions::Now, &termios)?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn set_echo(&self, _echo: bool) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// The number of columns and rows.
+    pub fn size(&self) -> std::io::Result<(u16, u16)> {
+        crossterm::terminal::size()
+    }
+
+    /// Answers the terminal commands of the stream devices, `None` for the others.
+    pub fn command(&self, command: u8, argument: u8) -> Option<u32> {
+        let done = |result: std::io::Result<()>| result.map_or(u32::MAX, |_| 0);
+        Some(if command == device::StreamCommand::Mode as u8 {
+            done(self.set_raw(argument != 0))
+        } else if command == device::StreamCommand::Echo as u8 {
+            done(self.set_echo(argument != 0))
+        } else if command == device::StreamCommand::Size as u8 {
+            self.size()
+                .map_or(u32::MAX, |(columns, rows)| (columns as u32) << 16 | rows as u32)
+        } else {
+            return None;
+        })
+    }
+
+    /// Remembers the settings, before they are first changed.
+    fn save(&self) -> std::io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mut changes = self.0.borrow_mut();
+            if changes.original.is_none() {
+                changes.original = Some(rustix::termios::tcgetattr(std::io::stdin())?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Changes {
+    fn drop(&mut self) {
+        if self.raw {
+            let _ = crossterm::terminal::disable_raw_mode();
+        }
+        #[cfg(unix)]
+        if let Some(original) = self.original.take() {
+            let stdin = std::io::stdin();
+            let _ = rustix::termios::tcsetattr(&stdin, rustix::termios::OptionalActions::Now, &original);
+        }
+    }
+}
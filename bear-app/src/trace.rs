@@ -0,0 +1,373 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use bear_ass::parser::ast;
+use bear_vm::radix::NumberFormat;
+use bear_vm::vm::{BearVM, CallbackDebugger, Cell, DynDebugger, ExecutionState, OpCode};
+
+/// The events recorded by a `ChromeTracer`.
+#[derive(Default)]
+pub struct Trace {
+    events: Vec<Value>,
+    /// The number of instructions executed, which stands in for time.
+    steps: u64,
+    /// The names of the calls which have not yet returned.
+    calls: Vec<String>,
+    /// The calls which have not yet returned as a folded stack, outermost first.
+    stack: String,
+    /// The number of instructions executed in each folded stack.
+    folded: HashMap<String, u64>,
+    /// The previous instruction, and the depth of the address stack before it executed.
+    previous: Option<(OpCode, usize)>,
+    ioctls: u64,
+}
+
+impl Trace {
+    /**
+     * The trace in the Chrome trace-event format, ending the calls which never returned.  Its
+     * timestamps are in microseconds, as the format defines them, one for each instruction.
+     */
+    pub fn to_json(&self) -> Value {
+        let mut events = self.events.clone();
+        for name in self.calls.iter().rev() {
+            events.push(event(name, "call", "E", self.steps));
+        }
+        json!({ "traceEvents": events })
+    }
+
+    /// Counts an instruction in the current stack of calls.
+    fn count_folded(&mut self) {
+        match self.folded.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.folded.insert(self.stack.clone(), 1);
+            }
+        }
+    }
+
+    /**
+     * The trace as folded stacks, for flamegraph.pl, inferno or speedscope: a line for each stack
+     * of calls, its frames separated by `;`, outermost first, with the number of instructions
+     * executed in it.
+     */
+    pub fn to_folded(&self) -> String {
+        let folded: BTreeMap<&String, &u64> = self.folded.iter().collect();
+        folded
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+}
+
+fn event(name: &str, category: &str, phase: &str, ts: u64) -> Value {
+    json!({ "name": name, "cat": category, "ph": phase, "ts": ts, "pid": 0, "tid": 0 })
+}
+
+/**
+ * Records guest calls (as begin/end events) and device ioctls (as async events) in the Chrome
+ * trace-event format, for chrome://tracing or Perfetto, and the instructions executed in each
+ * stack of calls, for flame graphs.  Each executed instruction counts as one microsecond.  Calls
+ * are named after the nearest label at or before their target, at the entry point for the code
+ * outside of any call.
+ *
+ * Every callback is forwarded to `inner`, so tracing can be combined with another debugger.
+ */
+pub struct ChromeTracer {
+    /// Label names by address.
+    names: BTreeMap<usize, String>,
+    trace: Rc<RefCell<Trace>>,
+    inner: Option<Box<dyn CallbackDebugger>>,
+}
+
+impl ChromeTracer {
+    pub fn new(
+        debug: Option<&ast::Debug>,
+        inner: Option<Box<dyn CallbackDebugger>>,
+    ) -> (ChromeTracer, Rc<RefCell<Trace>>) {
+        let mut names = BTreeMap::new();
+        for entry in debug.iter().flat_map(|d| d.entries.iter()) {
+            if let Some(name) = entry.names.iter().min() {
+                names.insert(entry.address, name.clone());
+            }
+        }
+        let trace = Rc::new(RefCell::new(Trace::default()));
+        let tracer = ChromeTracer {
+            names,
+            trace: trace.clone(),
+            inner,
+        };
+        trace.borrow_mut().stack = tracer.name(0);
+        (tracer, trace)
+    }
+
+    /// The nearest label at or before `address`, plus the offset from it if any.
+    fn name(&self, address: usize) -> String {
+        match self.names.range(..=address).next_back() {
+            Some((&label, name)) if label == address => name.clone(),
+            Some((&label, name)) => format!("{}+{}", name, address - label),
+            None => format!("{:#x}", address),
+        }
+    }
+}
+
+impl CallbackDebugger for ChromeTracer {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
+        let mut trace = self.trace.borrow_mut();
+        let depth = state.vm.address.len();
+        let ts = trace.steps;
+        match trace.previous {
+            Some((OpCode::Call, before)) | Some((OpCode::CallIfZ, before)) if depth > before => {
+                let name = self.name(state.ip());
+                trace.events.push(event(&name, "call", "B", ts));
+                trace.stack.push(';');
+                trace.stack.push_str(&name);
+                trace.calls.push(name);
+            }
+            Some((OpCode::Return, before)) | Some((OpCode::ReturnIfZ, before)) if depth < before => {
+                if let Some(name) = trace.calls.pop() {
+                    trace.events.push(event(&name, "call", "E", ts));
+                    let outer = trace.stack.len() - name.len() - 1;
+                    trace.stack.truncate(outer);
+                }
+            }
+            _ => {}
+        }
+        if let OpCode::Io = op {
+            let data = &state.vm.data;
+            let command = data.last().map(|c| c.0);
+            let device = data.len().checked_sub(2).map(|i| data[i].0);
+            let id = trace.ioctls;
+            for (phase, ts) in [("b", ts), ("e", ts + 1)] {
+                let mut e = event("ioctl", "device", phase, ts);
+                e["id"] = json!(id);
+                e["args"] = json!({ "device": device, "command": command });
+                trace.events.push(e);
+            }
+            trace.ioctls += 1;
+        }
+        trace.previous = Some((op, depth));
+        trace.steps += 1;
+        trace.count_folded();
+        drop(trace);
+        if let Some(inner) = &self.inner {
+            inner.ip(state, op);
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.data_pop(vm);
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.data_push(vm, cell);
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.address_pop(vm);
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.address_push(vm, cell);
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store(address, value);
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store_8(address, value);
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        {
+            let mut trace = self.trace.borrow_mut();
+            let ts = trace.steps;
+            trace.events.push(event("brk", "debugger", "i", ts));
+        }
+        if let Some(inner) = &self.inner {
+            inner.brk(state);
+        }
+    }
+}
+
+/// How an `InstructionTracer` writes each instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// A line of text: the address, the mnemonic and the top of the data stack, topmost first.
+    Line,
+    /**
+     * A record of bytes: the address as a little-endian `u32`, the opcode, the number of cells
+     * which follow as a byte, and those cells of the top of the data stack, topmost first, as
+     * little-endian `u32`s.
+     */
+    Binary,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(TraceFormat::Line),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format: {}", s)),
+        }
+    }
+}
+
+/**
+ * Writes every instruction as it executes, before it executes, with up to `tops` cells of the top
+ * of the data stack, for offline analysis or for diffing two runs.  Write errors are ignored.
+ *
+ * Every callback is forwarded to `inner`, so tracing can be combined with another debugger.
+ */
+pub struct InstructionTracer {
+    out: RefCell<Box<dyn Write>>,
+    format: TraceFormat,
+    tops: usize,
+    numbers: NumberFormat,
+    inner: Option<Box<dyn CallbackDebugger>>,
+}
+
+impl InstructionTracer {
+    pub fn new(
+        out: Box<dyn Write>,
+        format: TraceFormat,
+        tops: usize,
+        numbers: NumberFormat,
+        inner: Option<Box<dyn CallbackDebugger>>,
+    ) -> InstructionTracer {
+        InstructionTracer {
+            out: RefCell::new(out),
+            format,
+            tops: tops.min(u8::MAX as usize),
+            numbers,
+            inner,
+        }
+    }
+}
+
+impl CallbackDebugger for InstructionTracer {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
+        let tops = state.vm.data.iter().rev().take(self.tops).map(|cell| cell.0);
+        let mut out = self.out.borrow_mut();
+        let _ = match self.format {
+            TraceFormat::Line => {
+                let mut line = format!("{} {}", self.numbers.address(state.ip()), op);
+                for cell in tops {
+                    line.push(' ');
+                    line.push_str(&self.numbers.value(cell));
+                }
+                writeln!(out, "{}", line)
+            }
+            TraceFormat::Binary => {
+                let mut record = (state.ip() as u32).to_le_bytes().to_vec();
+                record.push(op as u8);
+                let tops: Vec<u32> = tops.collect();
+                record.push(tops.len() as u8);
+                for cell in tops {
+                    record.extend_from_slice(&cell.to_le_bytes());
+                }
+                out.write_all(&record)
+            }
+        };
+        drop(out);
+        if let Some(inner) = &self.inner {
+            inner.ip(state, op);
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.data_pop(vm);
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.data_push(vm, cell);
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.address_pop(vm);
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.address_push(vm, cell);
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store(address, value);
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(inner) = &self.inner {
+            inner.store_8(address, value);
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        if let Some(inner) = &self.inner {
+            inner.brk(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chrome_trace() {
+        let source = "push 1\ncall &double\nbrk\nhalt\n===:double\npush 2\nmul\nret\n";
+        let (image, debug) = bear_ass::assemble(source).unwrap();
+        let (tracer, trace) = ChromeTracer::new(Some(&debug), None);
+        let vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image))
+            .with_callback_debugger(Box::new(tracer));
+        vm.start().unwrap().run().unwrap();
+
+        let json: Value = serde_json::from_str(&trace.borrow().to_json().to_string()).unwrap();
+        assert_eq!(json.get("displayTimeUnit"), None);
+        let events: Vec<(&str, &str, u64)> = json["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["ph"].as_str().unwrap(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // `push 1`, the push of `&double` and `call` take the first three microseconds, `push 2`,
+        // `mul` and `ret` the next three, and `brk` the seventh.
+        assert_eq!(
+            events,
+            vec![("double", "B", 3), ("double", "E", 6), ("brk", "i", 7)]
+        );
+    }
+}
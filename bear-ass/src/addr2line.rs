@@ -0,0 +1,74 @@
+use std::env;
+use std::path::Path;
+
+use bear_ass::debug::Resolver;
+use bear_ass::parser::ast;
+use bear_ass::Error;
+
+const USAGE: &str = "bear-addr2line v1.0\n\
+\n\
+USAGE: bear-addr2line debug-file query...\n\
+\n\
+Prints the label and line each address was assembled from, e.g. `double+4 (lib.bear:3)`,\n\
+or ?? if it is before the first line.  A query is one of:\n\
+    address           An address, in decimal or in hex with 0x.\n\
+    :label            The address of a label.\n\
+    @[file:]line      The first address of a line of the program, or of an included file.\n";
+
+fn main() {
+    match go() {
+        Ok(()) => std::process::exit(0),
+        Err(Error::Usage) => eprintln!("{}", USAGE),
+        Err(error) => eprintln!("error: {}", error),
+    }
+    std::process::exit(-2)
+}
+
+fn go() -> Result<(), Error> {
+    let mut args = env::args().skip(1);
+    let debug_path = args.next().ok_or(Error::Usage)?;
+    let queries: Vec<String> = args.collect();
+    if queries.is_empty() {
+        return Err(Error::Usage);
+    }
+    let file = std::fs::File::open(&debug_path).map_err(Error::IOError)?;
+    let debug: ast::Debug =
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(Error::SerdeError)?;
+    let resolver = Resolver::new(&debug);
+    for query in queries {
+        println!("{}", resolve(&resolver, &query)?);
+    }
+    Ok(())
+}
+
+/// Answers a query, with `??` for a label or line which is not found.
+fn resolve(resolver: &Resolver, query: &str) -> Result<String, Error> {
+    let address = if let Some(label) = query.strip_prefix(':') {
+        resolver.address_of_label(label)
+    } else if let Some(place) = query.strip_prefix('@') {
+        let (file, line) = match place.rfind(':') {
+            Some(index) => (Some(Path::new(&place[..index])), &place[index + 1..]),
+            None => (None, place),
+        };
+        let line = line.parse().map_err(|_| Error::Usage)?;
+        resolver.address_of_line(file, line)
+    } else {
+        let address = parse_address(query).ok_or(Error::Usage)?;
+        let symbol = resolver.lookup(address);
+        if symbol.label.is_none() && symbol.entry.is_none() {
+            return Ok(String::from("??"));
+        }
+        return Ok(symbol.to_string());
+    };
+    Ok(match address {
+        Some(address) => format!("{:#x}", address),
+        None => String::from("??"),
+    })
+}
+
+fn parse_address(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
@@ -5,6 +5,33 @@ use crate::processor;
 pub enum Error {
     Unknown,
     ExpressionCannotBeSimplified(ast::Expression),
+    IOError(std::io::Error),
+    /// Two sections share the address, so one would overwrite the other when loaded.
+    SectionsOverlap(String, String, usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Unknown => write!(f, "unknown assembler error"),
+            Error::ExpressionCannotBeSimplified(expr) => {
+                write!(f, "cannot evaluate expression `{}`", expr)
+            }
+            Error::IOError(e) => write!(f, "{}", e),
+            Error::SectionsOverlap(a, b, address) => {
+                write!(f, "sections `{}` and `{}` overlap at {:#x}", a, b, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -25,44 +52,120 @@ impl ImageBuilder {
         self.bits.extend(&value.to_le_bytes());
     }
 
-    fn assemble_string(&mut self, value: String) {
-        self.bits.extend(value.as_bytes());
+    fn assemble_string(&mut self, value: Vec<u8>) {
+        self.bits.extend(value);
     }
 }
 
+/// The assembled bytes of a `processor::Section`.
+pub struct Image {
+    pub name: String,
+    /// The address at which the image is loaded.
+    pub base: usize,
+    pub bits: Vec<u8>,
+}
+
 #[derive(Default)]
 pub struct Assembler {}
 
 impl Assembler {
+    /// Assembles the ROM section of a program.
     pub fn assemble(p: processor::Processor) -> Result<Vec<u8>, Error> {
+        let mut images = Assembler::assemble_sections(p)?;
+        Ok(images.swap_remove(0).bits)
+    }
+
+    /// Assembles every section of a program.  The ROM is always the first image.
+    pub fn assemble_sections(p: processor::Processor) -> Result<Vec<Image>, Error> {
         let ass = Assembler {};
-        let mut bin = ImageBuilder::default();
+        let mut bins: Vec<ImageBuilder> = p.sections.iter().map(|_| Default::default()).collect();
 
         for proc in p.processed.iter() {
-            if bin.bits.len() < proc.address {
-                bin.bits.resize(proc.address, 0);
+            let bin = &mut bins[proc.section];
+            let offset = proc.address - p.sections[proc.section].base;
+            if bin.bits.len() < offset {
+                bin.bits.resize(offset, 0);
             }
-            if bin.bits.len() != proc.address {
+            if bin.bits.len() != offset {
                 panic!("stream malformed: {}, {:?}", bin.bits.len(), proc);
             }
-            match &proc.body {
-                ast::LineBody::Data(data) => ass.assemble_data(data.clone(), &mut bin)?,
-                ast::LineBody::Simple(op) => bin.assemble_u8(op.into_u8()),
-                // By this point all of the preprocessor directives should have been handled.
-                // If a preprocessor directive is encountered, then something has gone wrong.
-                ast::LineBody::Directive(dir) => {
-                    panic!("Preprocessor error; encountered directive: {:?}", dir)
+            ass.assemble_line(proc, bin)?;
+        }
+
+        let mut images = Vec::new();
+        for (section, mut bin) in p.sections.iter().zip(bins) {
+            // The output is padded to a multiple of 4.
+            while bin.bits.len() % 4 != 0 {
+                bin.assemble_u8(0);
+            }
+            images.push(Image {
+                name: section.name.clone(),
+                base: section.base,
+                bits: bin.bits,
+            });
+        }
+        Assembler::check_overlaps(&images)?;
+        Ok(images)
+    }
+
+    /// Fails if the bytes of any two images share an address.
+    fn check_overlaps(images: &[Image]) -> Result<(), Error> {
+        for (i, a) in images.iter().enumerate() {
+            for b in &images[i + 1..] {
+                let start = a.base.max(b.base);
+                if start < (a.base + a.bits.len()).min(b.base + b.bits.len()) {
+                    return Err(Error::SectionsOverlap(a.name.clone(), b.name.clone(), start));
                 }
-                body => panic!("Assembler encountered '{:?}'.", body),
             }
         }
+        Ok(())
+    }
 
-        // The output is padded to a multiple of 4.
-        while bin.bits.len() % 4 != 0 {
-            bin.assemble_u8(0);
+    /**
+     * Assembles the ROM section of a program into `out`, one line at a time, so that the image is
+     * never held in memory.  Like `assemble`, the output is padded to a multiple of 4.
+     */
+    pub fn write_rom<W: std::io::Write>(p: &processor::Processor, out: &mut W) -> Result<(), Error> {
+        let ass = Assembler {};
+        let mut bin = ImageBuilder::default();
+        let mut written = 0;
+        for proc in p.processed.iter().filter(|proc| proc.section == 0) {
+            if written > proc.address {
+                panic!("stream malformed: {}, {:?}", written, proc);
+            }
+            bin.bits.clear();
+            bin.bits.resize(proc.address - written, 0);
+            ass.assemble_line(proc, &mut bin)?;
+            out.write_all(&bin.bits).map_err(Error::IOError)?;
+            written += bin.bits.len();
+        }
+        let padding = (4 - written % 4) % 4;
+        out.write_all(&[0; 4][..padding]).map_err(Error::IOError)?;
+        Ok(())
+    }
+
+    fn assemble_line(&self, proc: &processor::ProcessedLine, bin: &mut ImageBuilder) -> Result<(), Error> {
+        match &proc.body {
+            ast::LineBody::Data(data) => self.assemble_data(data.clone(), bin)?,
+            ast::LineBody::Simple(op) => bin.assemble_u8(op.into_u8()),
+            // By this point all of the preprocessor directives should have been handled.
+            // If a preprocessor directive is encountered, then something has gone wrong.
+            ast::LineBody::Directive(dir) => {
+                panic!("Preprocessor error; encountered directive: {:?}", dir)
+            }
+            body => panic!("Assembler encountered '{:?}'.", body),
         }
+        Ok(())
+    }
 
-        Ok(bin.bits)
+    /// Packs every image but the ROM into a RAM-initialization blob for the loader.
+    pub fn make_init_blob(images: &[Image]) -> Vec<u8> {
+        let records: Vec<(u32, &[u8])> = images
+            .iter()
+            .skip(1)
+            .map(|image| (image.base as u32, image.bits.as_slice()))
+            .collect();
+        bear_vm::util::encode_init_blob(&records)
     }
 
     fn assemble_data(&self, data: ast::Data, bin: &mut ImageBuilder) -> Result<(), Error> {
@@ -88,7 +191,7 @@ impl Assembler {
                 bin.assemble_u8(0);
             }
             ast::Data::Str(ast::StringTag::S, text) => {
-                bin.assemble_u32(text.as_bytes().len() as u32);
+                bin.assemble_u32(text.len() as u32);
                 bin.assemble_string(text);
             }
         };
@@ -1,69 +1,176 @@
 use std::env;
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 
 
 use bear_ass::assembler::Assembler;
 use bear_ass::parser;
-use bear_ass::processor::Processor;
+use bear_ass::processor::{self, Processor};
 use bear_ass::Error;
+use bear_vm::checksum;
 
-pub fn go() -> Result<(), Error> {
-    let mut args: Vec<String> = env::args().collect();
+/// The path which stands for stdin or stdout.
+const STDIO: &str = "-";
 
-    args.reverse();
-    args.pop();
+pub fn go() -> Result<(), Error> {
+    let mut positional = Vec::new();
+    let mut options = processor::Options::default();
+    let mut xref_path = None;
+    let mut debug_path: Option<PathBuf> = None;
+    let mut checksum = false;
+    let mut embed_source = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-I" {
+            let dir = args.next().ok_or(Error::Usage)?;
+            options.include_paths.push(dir.into());
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            options.include_paths.push(dir.into());
+        } else if arg == "-D" {
+            let define = args.next().ok_or(Error::Usage)?;
+            options.defines.push(parse_define(&define)?);
+        } else if let Some(define) = arg.strip_prefix("-D") {
+            options.defines.push(parse_define(define)?);
+        } else if arg == "--check-sections" {
+            options.check_sections = true;
+        } else if arg == "--xref" {
+            xref_path = Some(args.next().ok_or(Error::Usage)?);
+        } else if arg == "--check-stacks" {
+            options.check_stacks = true;
+        } else if arg == "--strip-unused" {
+            options.strip_unused = true;
+        } else if arg == "-d" || arg == "--debug" {
+            // Debug symbols are always written.
+        } else if arg == "--debug-info" {
+            debug_path = Some(args.next().ok_or(Error::Usage)?.into());
+        } else if arg == "--checksum" {
+            checksum = true;
+        } else if arg == "--embed-source" {
+            embed_source = true;
+        } else {
+            positional.push(arg);
+        }
+    }
 
-    if args.len() != 2 && args.len() != 3 {
+    if positional.len() != 2 {
         return Err(Error::Usage);
     }
 
-    let arg1 = args.pop().ok_or(Error::Usage)?;
-    let arg2 = args.pop().ok_or(Error::Usage)?;
-    // let arg3 = args.pop();
-    let in_path = Path::new(&arg1);
+    let arg2 = positional.pop().ok_or(Error::Usage)?;
+    let arg1 = positional.pop().ok_or(Error::Usage)?;
+    // `-` reads the program from stdin, and writes the image to stdout.  Debug info is written
+    // next to the image, or to `--debug-info`.
+    let (name, contents) = if arg1 == STDIO {
+        let mut contents = String::new();
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(Error::IOError)?;
+        ("<stdin>".to_string(), contents)
+    } else {
+        let contents = std::fs::read_to_string(&arg1).map_err(Error::IOError)?;
+        (arg1, contents)
+    };
     let out_bin_path = Path::new(&arg2);
-    let out_debug_path = out_bin_path
-        .with_file_name(out_bin_path.file_stem().expect("No output filename."))
-        .with_extension("debug");
-    let output_debug_symbols = true; // !arg3.is_none() && (arg3 == Some("-d".to_string()) || arg3 == Some("--debug".to_string()));
-    let out_bin = std::fs::File::create(out_bin_path)
-        .unwrap_or_else(|_| panic!("Unable to create file: {:?}", out_bin_path));
-    let mut outbin_buf = std::io::BufWriter::new(out_bin);
-    let in_file = std::fs::File::open(in_path).expect("Can't open file.");
-    let mut reader = std::io::BufReader::new(in_file);
+    let to_stdout = arg2 == STDIO;
+    if !to_stdout && debug_path.is_none() {
+        let stem = out_bin_path.file_stem().expect("No output filename.");
+        debug_path = Some(out_bin_path.with_file_name(stem).with_extension("debug"));
+    }
 
-    let program = parse(&mut reader)?;
-    let processor = match Processor::process(program) {
+    let program = parser::Parser {}
+        .parse(&contents)
+        .map_err(Error::ParserError)?;
+    let processor = match Processor::process_with(program, options) {
         Err(e) => {
-            panic!("Processor error: {:?}", e)
+            eprint!("{}", e.render(&name, &contents));
+            return Err(Error::ProcessorError(e));
         }
         Ok(p) => p,
     };
-    if output_debug_symbols {
+    eprint!("{}", processor.render_warnings(&name, &contents));
+    if let Some(path) = xref_path {
+        let out = std::fs::File::create(&path).map_err(Error::IOError)?;
+        let mut out = std::io::BufWriter::new(out);
+        write_xref(&processor, Path::new(&path), &mut out)?;
+    }
+    let mut debug = processor.make_debug()?;
+    if embed_source {
+        debug.sources = processor.sources(&contents);
+    }
+    let mut images = Assembler::assemble_sections(processor)?;
+    // The debug info is written once the image is known, so it can record the image's checksum.
+    if let Some(out_debug_path) = debug_path {
+        debug.checksum = Some(checksum::crc32(&images[0].bits));
         let out_debug = std::fs::File::create(&out_debug_path)
             .unwrap_or_else(|_| panic!("Unable to create file: {:?}", out_debug_path));
         let mut outdebug_buf = std::io::BufWriter::new(out_debug);
-        write_debug(&processor, &mut outdebug_buf)?;
+        write_debug(&debug, &mut outdebug_buf)?;
+    }
+    if checksum {
+        images[0].bits = checksum::with_header(&images[0].bits);
+    }
+    if to_stdout {
+        if images.len() > 1 {
+            return Err(Error::Unknown(
+                "a program with sections other than the ROM cannot be written to stdout".into(),
+            ));
+        }
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&images[0].bits).map_err(Error::IOError)?;
+        return stdout.flush().map_err(Error::IOError);
+    }
+    let out_bin = std::fs::File::create(out_bin_path)
+        .unwrap_or_else(|_| panic!("Unable to create file: {:?}", out_bin_path));
+    let mut outbin_buf = std::io::BufWriter::new(out_bin);
+    outbin_buf.write_all(&images[0].bits).map_err(Error::IOError)?;
+    // A stale blob from an earlier build would otherwise be loaded with the new image.
+    let out_init_path = out_bin_path.with_extension("init");
+    if images.len() > 1 {
+        std::fs::write(&out_init_path, Assembler::make_init_blob(&images))
+            .map_err(Error::IOError)?;
+    } else if let Err(e) = std::fs::remove_file(&out_init_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(Error::IOError(e));
+        }
     }
-    let bits = Assembler::assemble(processor).expect("Assembler error");
-    outbin_buf.write_all(&bits).map_err(Error::IOError)?;
     Ok(())
 }
 
-pub fn parse(reader: &mut dyn Read) -> Result<parser::ast::Program, Error> {
-    let mut contents = String::new();
-    reader.read_to_string(&mut contents).unwrap();
-    let program = parser::Parser {}
-        .parse(&contents)
+/// Parses a `NAME=expr` command line definition.  A bare `NAME` is defined as `1`.
+fn parse_define(define: &str) -> Result<(String, parser::ast::Expression), Error> {
+    let (name, value) = match define.find('=') {
+        Some(index) => (&define[..index], &define[index + 1..]),
+        None => (define, "1"),
+    };
+    if name.is_empty() {
+        return Err(Error::Usage);
+    }
+    let expression = parser::Parser {}
+        .parse_expression_str(value)
         .map_err(Error::ParserError)?;
-    Ok(program)
+    Ok((name.to_string(), expression))
+}
+
+pub fn write_debug(debug: &parser::ast::Debug, buf: &mut dyn Write) -> Result<(), Error> {
+    serde_json::to_writer_pretty(buf, debug).map_err(Error::SerdeError)?;
+    Ok(())
 }
 
-pub fn write_debug(p: &Processor, buf: &mut dyn Write) -> Result<(), Error> {
-    let entries = p.make_debug().expect("Debug error.");
-    serde_json::to_writer_pretty(buf, &entries).map_err(Error::SerdeError)?;
+/// Writes which labels reference which, as DOT if `path` ends in `.dot`, and as JSON otherwise.
+pub fn write_xref(p: &Processor, path: &Path, buf: &mut dyn Write) -> Result<(), Error> {
+    let xref = p.xref();
+    if path.extension() == Some("dot".as_ref()) {
+        writeln!(buf, "digraph xref {{").map_err(Error::IOError)?;
+        for (label, references) in xref.iter() {
+            writeln!(buf, "    {:?};", label).map_err(Error::IOError)?;
+            for reference in references {
+                writeln!(buf, "    {:?} -> {:?};", label, reference).map_err(Error::IOError)?;
+            }
+        }
+        writeln!(buf, "}}").map_err(Error::IOError)?;
+    } else {
+        serde_json::to_writer_pretty(buf, &xref).map_err(Error::SerdeError)?;
+    }
     Ok(())
 }
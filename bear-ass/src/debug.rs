@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::parser::ast;
+
+/// Where an address was assembled from, found by `Resolver::lookup`.
+#[derive(Clone, Copy)]
+pub struct Symbol<'a> {
+    pub address: usize,
+    /// The nearest label at or before the address, and how far past it the address is.
+    pub label: Option<(&'a str, usize)>,
+    /// The entry of the line the address was assembled from.
+    pub entry: Option<&'a ast::DebugEntry>,
+}
+
+/// Formats the symbol as the label plus the offset, or the address in hex, and the line, after
+/// its file if it was included: `double+4 (lib.bear:3)`.
+impl std::fmt::Display for Symbol<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.label {
+            Some((name, offset)) => write!(f, "{}+{}", name, offset)?,
+            None => write!(f, "{:#x}", self.address)?,
+        }
+        match self.entry {
+            Some(ast::DebugEntry {
+                file: Some(file),
+                line,
+                ..
+            }) => write!(f, " ({}:{})", file.display(), line),
+            Some(entry) => write!(f, " (line {})", entry.line),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * Maps the addresses of an image to the labels and lines they were assembled from, and back, so
+ * debuggers and other tools need not search the debug info themselves.
+ *
+ * An address belongs to the nearest line and label at or before it.
+ */
+pub struct Resolver {
+    /// The entries, by address.
+    entries: Vec<ast::DebugEntry>,
+    /// The least of the names at each labelled address, by address.
+    labels: BTreeMap<usize, String>,
+    sources: Vec<ast::DebugSource>,
+}
+
+impl Resolver {
+    pub fn new(debug: &ast::Debug) -> Resolver {
+        let mut entries = debug.entries.clone();
+        entries.sort_by_key(|e| e.address);
+        let labels = entries
+            .iter()
+            .filter_map(|e| e.names.iter().min().map(|name| (e.address, name.clone())))
+            .collect();
+        Resolver {
+            entries,
+            labels,
+            sources: debug.sources.clone(),
+        }
+    }
+
+    /**
+     * The entry of the line which starts at exactly `address`.  Of several entries at the address,
+     * which debug info not written by the assembler may have, this is the last, as for `line`.
+     */
+    pub fn entry(&self, address: usize) -> Option<&ast::DebugEntry> {
+        self.line(address).filter(|e| e.address == address)
+    }
+
+    /// The entry of the line `address` was assembled from.
+    pub fn line(&self, address: usize) -> Option<&ast::DebugEntry> {
+        let index = self.entries.partition_point(|e| e.address <= address);
+        index.checked_sub(1).map(|index| &self.entries[index])
+    }
+
+    /// The nearest label at or before `address`, and how far past it `address` is.
+    pub fn label(&self, address: usize) -> Option<(&str, usize)> {
+        let (&start, name) = self.labels.range(..=address).next_back()?;
+        Some((name.as_str(), address - start))
+    }
+
+    pub fn lookup(&self, address: usize) -> Symbol<'_> {
+        Symbol {
+            address,
+            label: self.label(address),
+            entry: self.line(address),
+        }
+    }
+
+    /// Every label with its address, in order of address.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.entries
+            .iter()
+            .flat_map(|e| e.names.iter().map(move |name| (name.as_str(), e.address)))
+    }
+
+    /// The address of a label.
+    pub fn address_of_label(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|e| e.names.iter().any(|n| n == name))
+            .map(|e| e.address)
+    }
+
+    /**
+     * The first address assembled from a line of the program, or of an included file.  An included
+     * file matches by its last components, so `lib.bear` finds `/src/lib.bear`.
+     */
+    pub fn address_of_line(&self, file: Option<&Path>, line: ast::LineNumber) -> Option<usize> {
+        self.entries
+            .iter()
+            .filter(|e| e.line == line)
+            .find(|e| match (file, &e.file) {
+                (None, None) => true,
+                (Some(file), Some(included)) => included.ends_with(file),
+                _ => false,
+            })
+            .map(|e| e.address)
+    }
+
+    /// The text of a line, if the source was embedded in the debug info.
+    pub fn source_line(&self, file: Option<&Path>, line: ast::LineNumber) -> Option<&str> {
+        let source = self.sources.iter().find(|s| s.file.as_deref() == file)?;
+        source.line(line)
+    }
+}
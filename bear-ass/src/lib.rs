@@ -1,15 +1,130 @@
 pub mod assembler;
+pub mod debug;
+pub mod library;
 pub mod parser;
 pub mod processor;
+mod verify;
 
 extern crate bear_vm;
 
+use std::io::{Read, Write};
+use std::path::Path;
+
+use parser::ast;
+
 #[derive(Debug)]
 pub enum Error {
     Usage,
     Unknown(String),
     IOError(std::io::Error),
     ParserError(parser::Error),
+    ProcessorError(processor::Error),
     SerdeError(serde_json::Error),
     AssemblerError(assembler::Error),
 }
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Usage => write!(f, "invalid arguments"),
+            Error::Unknown(message) => write!(f, "{}", message),
+            Error::IOError(e) => write!(f, "{}", e),
+            // Errors from pest quote the source on their own lines.
+            Error::ParserError(e) if e.position.is_none() => write!(f, "syntax error\n{}", e),
+            Error::ParserError(e) => write!(f, "syntax error: {}", e),
+            Error::ProcessorError(e) => write!(f, "{}", e),
+            Error::SerdeError(e) => write!(f, "could not write debug info: {}", e),
+            Error::AssemblerError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Usage | Error::Unknown(_) => None,
+            Error::IOError(e) => Some(e),
+            Error::ParserError(e) => Some(e),
+            Error::ProcessorError(e) => Some(e),
+            Error::SerdeError(e) => Some(e),
+            Error::AssemblerError(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e)
+    }
+}
+
+impl From<parser::Error> for Error {
+    fn from(e: parser::Error) -> Self {
+        Error::ParserError(e)
+    }
+}
+
+impl From<processor::Error> for Error {
+    fn from(e: processor::Error) -> Self {
+        Error::ProcessorError(e)
+    }
+}
+
+impl From<assembler::Error> for Error {
+    fn from(e: assembler::Error) -> Self {
+        Error::AssemblerError(e)
+    }
+}
+
+/**
+ * Assembles a program, returning the ROM image and its debug info.
+ *
+ * `#include`s are resolved against the working directory.  Sections other than the ROM are not
+ * part of the image; use `assembler::Assembler::assemble_sections` to get them.
+ */
+pub fn assemble(source: &str) -> Result<(Vec<u8>, ast::Debug), Error> {
+    assemble_with(source, processor::Options::default())
+}
+
+/// Like `assemble`, but reads the program from `path` and also searches its directory for includes.
+pub fn assemble_file(path: &Path, mut options: processor::Options) -> Result<(Vec<u8>, ast::Debug), Error> {
+    let source = std::fs::read_to_string(path)?;
+    if let Some(dir) = path.parent() {
+        options.include_paths.push(dir.to_path_buf());
+    }
+    assemble_with(&source, options)
+}
+
+/// Like `assemble`, with include paths and definitions.
+pub fn assemble_with(
+    source: &str,
+    options: processor::Options,
+) -> Result<(Vec<u8>, ast::Debug), Error> {
+    let program = parser::Parser {}.parse(source)?;
+    let processor = processor::Processor::process_with(program, options)?;
+    let mut debug = processor.make_debug()?;
+    let image = assembler::Assembler::assemble(processor)?;
+    debug.checksum = Some(bear_vm::checksum::crc32(&image));
+    Ok((image, debug))
+}
+
+/**
+ * Assembles the program read from `input`, writing the ROM image to `output` as it is encoded, and
+ * returns the debug info.
+ *
+ * Labels may be used before they are defined, so the whole source is read before anything is
+ * written; the image itself is never held in memory.
+ */
+pub fn assemble_stream<R: Read, W: Write>(
+    mut input: R,
+    mut output: W,
+    options: processor::Options,
+) -> Result<ast::Debug, Error> {
+    let mut source = String::new();
+    input.read_to_string(&mut source)?;
+    let program = parser::Parser {}.parse(&source)?;
+    let processor = processor::Processor::process_with(program, options)?;
+    assembler::Assembler::write_rom(&processor, &mut output)?;
+    output.flush()?;
+    Ok(processor.make_debug()?)
+}
@@ -0,0 +1,28 @@
+/*!
+ * The standard library: routines which are embedded in the assembler, and included with
+ * `#include <std/name.bear>;`.
+ */
+
+use std::path::Path;
+
+/// Every file of the library, by the name it is included with.
+pub const FILES: &[(&str, &str)] = &[
+    ("std/device.bear", include_str!("../std/device.bear")),
+    ("std/io.bear", include_str!("../std/io.bear")),
+    ("std/math.bear", include_str!("../std/math.bear")),
+    ("std/string.bear", include_str!("../std/string.bear")),
+];
+
+/// The source of the named library file.
+pub fn source(name: &Path) -> Option<&'static str> {
+    FILES
+        .iter()
+        .find(|(file, _)| Path::new(file) == name)
+        .map(|(_, source)| *source)
+}
+
+/// The source of a library file as it appears in a `Location`, i.e. as `<std/name.bear>`.
+pub fn source_of_location(path: &Path) -> Option<&'static str> {
+    let path = path.to_str()?;
+    source(Path::new(path.strip_prefix('<')?.strip_suffix('>')?))
+}
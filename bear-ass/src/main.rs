@@ -6,7 +6,22 @@ use bear_ass::Error;
 
 const USAGE: &str = "bear-ass v1.0\n\
 \n\
-USAGE: bear-ass in out\n";
+USAGE: bear-ass [-I dir]... [-D name[=expr]]... [--check-sections] [--check-stacks]\n\
+                [--strip-unused] [--xref file] [--debug-info file] [--checksum]\n\
+                [--embed-source] in out\n\
+\n\
+OPTIONS:\n\
+    -I dir            Search dir for #include files, after the working directory.\n\
+    -D name[=expr]    Define name as expr (default 1) before processing, like #define.\n\
+    --check-sections  Warn when arithmetic on a label leaves the label's section.\n\
+    --check-stacks    Warn about probable stack underflows and unbalanced routines.\n\
+    --strip-unused    Leave out routines of included files which the program cannot reach.\n\
+    --xref file       Write which labels reference which to file, as DOT if it ends in .dot, else JSON.\n\
+    --debug-info file Write debug info to file, rather than next to out.\n\
+    --checksum        Start the image with a CRC-32 header, checked when it is loaded.\n\
+    --embed-source    Copy the program and the files it includes into the debug info.\n\
+\n\
+in and out may be -, for stdin and stdout.\n";
 
 fn main() {
     match cli::go() {
@@ -14,7 +29,9 @@ fn main() {
             std::process::exit(0);
         }
         Err(Error::Usage) => eprintln!("{}", USAGE),
-        Err(error) => eprintln!("{:?}", error),
+        // Already reported, with source snippets.
+        Err(Error::ProcessorError(_)) => {}
+        Err(error) => eprintln!("error: {}", error),
     };
     std::process::exit(-2)
 }
@@ -22,7 +39,8 @@ fn main() {
 #[cfg(test)]
 mod test {
     use bear_ass::{assembler, parser, processor, Error};
-    use bear_vm::vm::{BearVM, ExecutionState};
+    use bear_vm::stack::Stack;
+    use bear_vm::vm::{BearVM, Debugger, DynDebugger, ExecutionState};
 
     fn print_state(state: &ExecutionState) {
         eprintln!(
@@ -34,23 +52,37 @@ mod test {
         );
     }
 
+    /// Reports an error of the VM as a test failure.
+    fn vm_error(e: bear_vm::vm::Error) -> Error {
+        Error::Unknown(format!("{:?}", e))
+    }
+
     fn run(program: &str) -> Result<ExecutionState, Error> {
-        // let mut image = Vec::new();
-        // let mut program = program.as_bytes();
-        let program = parser::Parser {}
-            .parse(program)
-            .map_err(Error::ParserError)?;
-        let processor = processor::Processor::process(program).expect("Processor error.");
-        let image = assembler::Assembler::assemble(processor).expect("Assembler error.");
-        let vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image));
-        let mut state = vm.start().map_err(|e| Error::Unknown(format!("{:?}", e)))?;
-        state
-            .run()
-            .map_err(|e| Error::Unknown(format!("{:?}", e)))?;
+        let state = run_with(program, |vm| vm)?;
         print_state(&state);
         Ok(state)
     }
 
+    /// Like `run`, but `configure` sets the VM up first, e.g. with devices or options.
+    fn run_with<D: Debugger>(
+        program: &str,
+        configure: impl FnOnce(BearVM) -> BearVM<D>,
+    ) -> Result<ExecutionState<D>, Error> {
+        let mut state = start_with(program, configure)?;
+        state.run().map_err(vm_error)?;
+        Ok(state)
+    }
+
+    /// Like `run_with`, but only starts the VM, for tests which step it or expect it to trap.
+    fn start_with<D: Debugger>(
+        program: &str,
+        configure: impl FnOnce(BearVM) -> BearVM<D>,
+    ) -> Result<ExecutionState<D>, Error> {
+        let (image, _) = bear_ass::assemble(program)?;
+        let vm = configure(BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image)));
+        vm.start().map_err(vm_error)
+    }
+
     #[test]
     fn test_sext8_neg() -> Result<(), Error> {
         let state = run("
@@ -64,6 +96,649 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_xor() -> Result<(), Error> {
+        let state = run("
+            lit lit xor halt
+            d32 0b1100
+            d32 0b1010
+        ")?;
+        assert!(state.vm.data == vec![0b0110.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pseudo_instructions() -> Result<(), Error> {
+        let state = run("
+            push 20
+            call &double
+            jump &done
+            push 1
+            :double
+            dup add ret
+            :done
+            nop nop push 7
+            halt
+        ")?;
+        assert!(state.vm.data == vec![40.into(), 7.into()]);
+
+        // A `lit` in the last slot of a word takes its operand from the next word.
+        let state = run("
+            #define three 3;
+            #define twice [dup add];
+            nop nop nop push (!three)
+            !twice
+            halt
+        ")?;
+        assert!(state.vm.data == vec![6.into()]);
+
+        let program = parser::Parser {}
+            .parse("lit call &f\nd32 1\n:f halt")
+            .map_err(Error::ParserError)?;
+        let error = match processor::Processor::process(program) {
+            Err(error) => error,
+            Ok(_) => panic!("Expected errors."),
+        };
+        assert!(matches!(
+            error.diagnostics()[0].tag,
+            processor::ErrorTag::PseudoAfterLit
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_instruction_before_a_list() -> Result<(), Error> {
+        // A lone `!list` after `push` or `call` is the instruction followed by the list, byte for
+        // byte as before pseudo-instructions.
+        let (image, _) = bear_ass::assemble("#define l [d32 5];\nlit push !l\nhalt\n")?;
+        assert_eq!(image, vec![0x01, 0x05, 0x05, 0x00, 0x00, 0x00, 0x7F, 0x00]);
+        let (image, _) = bear_ass::assemble("#define l [nop];\nnop push !l nop\ncall !l\nhalt\n")?;
+        assert_eq!(image, vec![0x00, 0x05, 0x00, 0x00, 0x16, 0x00, 0x7F, 0x00]);
+
+        // An expression definition is pushed in parentheses, and is an error alone.
+        let state = run("#define x 5;\npush (!x) push !x + 1\nhalt\n")?;
+        assert!(state.vm.data == vec![5.into(), 6.into()]);
+        let program = parser::Parser {}
+            .parse("#define x 5;\npush !x\n")
+            .map_err(Error::ParserError)?;
+        let error = match processor::Processor::process(program) {
+            Err(error) => error,
+            Ok(_) => panic!("Expected errors."),
+        };
+        assert!(matches!(
+            &error.diagnostics()[0].tag,
+            processor::ErrorTag::ExpectedList(name) if name == "x"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_flow() -> Result<(), Error> {
+        // Sums 5 + 4 + 3 + 2 + 1, counting the odd numbers on the address stack.
+        let state = run("
+            push 0 push 5
+            #while; dup #do;
+                dup push 1 and
+                #if; push 1 push #end;
+                dup push add pop
+                push 1 swap sub
+            #end;
+            drop
+            dup push 15 eq
+            #if; push 1 #else; push 2 #end;
+            push 0
+            #if; push 3 #else; push 4 #end;
+            push 0
+            #loop; push 1 add dup push 3 eq #until;
+            halt
+        ")?;
+        assert!(state.vm.data == vec![15.into(), 1.into(), 4.into(), 3.into()]);
+        assert!(state.vm.address == vec![1.into(), 1.into(), 1.into()]);
+
+        for (source, expected) in [
+            ("#if; #else; #else; #end;", parser::ast::ControlFlow::Else),
+            ("#while; #end;", parser::ast::ControlFlow::End),
+            ("#if; #until;", parser::ast::ControlFlow::Until),
+        ] {
+            let program = parser::Parser {}.parse(source).map_err(Error::ParserError)?;
+            let error = match processor::Processor::process(program) {
+                Err(error) => error,
+                Ok(_) => panic!("Expected errors."),
+            };
+            assert!(matches!(
+                error.diagnostics()[0].tag,
+                processor::ErrorTag::UnmatchedControlFlow(control) if control == expected
+            ));
+        }
+        let program = parser::Parser {}
+            .parse("#loop; #if; #end;")
+            .map_err(Error::ParserError)?;
+        let error = match processor::Processor::process(program) {
+            Err(error) => error,
+            Ok(_) => panic!("Expected errors."),
+        };
+        assert!(matches!(
+            error.diagnostics()[..],
+            [processor::Diagnostic {
+                tag: processor::ErrorTag::UnclosedControlFlow(parser::ast::ControlFlow::Loop),
+                ..
+            }]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_depth() -> Result<(), Error> {
+        let state = run("
+            depth
+            push 7 depth
+            dup push
+            adepth
+            halt
+        ")?;
+        assert!(state.vm.data == vec![0.into(), 7.into(), 2.into(), 1.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_stack_ops() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 1 push push 2 push push 3 push
+            rpeek
+            rswap pop
+            rdrop
+            pop
+            halt
+        ")?;
+        assert!(state.vm.data == vec![3.into(), 2.into(), 1.into()]);
+        assert!(state.vm.address.is_empty());
+        assert!(run("nop\nrpeek\nhalt").is_err());
+        assert!(run("nop\npush 1 push\nrswap\nhalt").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shufflers() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 1 push 2 over
+            push 3 rot
+            nip
+            push 4 tuck
+            halt
+        ")?;
+        // 1 2 1 -> 1 2 1 3 -> 1 1 3 2 -> 1 1 2 -> 1 1 4 2 4
+        assert!(state.vm.data == vec![1.into(), 1.into(), 4.into(), 2.into(), 4.into()]);
+        assert!(run("nop\npush 1 over\nhalt").is_err());
+        assert!(run("nop\npush 1 push 2 rot\nhalt").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_comparisons() -> Result<(), Error> {
+        let state = run("
+            nop
+            push -1 push 1 lt
+            push -1 push 1 gt
+            push -1 push 1 lt.u
+            push -1 push 1 gt.u
+            halt
+        ")?;
+        assert!(state.vm.data == vec![0.into(), (-1).into(), (-1).into(), 0.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_arithmetic() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 0x10000 push 0x30000 mulh
+            push -1 push -1 mulh
+            push -1 push 2 push 1 addc
+            push 1 push 2 push 0 addc
+            push 2 push 1 push 1 subc
+            push 1 push 2 push 0 subc
+            halt
+        ")?;
+        assert!(
+            state.vm.data
+                == vec![
+                    3.into(),
+                    (-2).into(),
+                    2.into(),
+                    1.into(),
+                    3.into(),
+                    0.into(),
+                    (-2).into(),
+                    1.into(),
+                    1.into(),
+                    0.into(),
+                ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_max_abs_neg() -> Result<(), Error> {
+        let state = run("
+            nop
+            push -3 push 2 min
+            push -3 push 2 max
+            push -3 abs
+            push 3 abs
+            push 0x80000000 abs
+            push 3 neg
+            halt
+        ")?;
+        assert!(
+            state.vm.data
+                == vec![(-3).into(), 2.into(), 3.into(), 3.into(), 0x80000000u32.into(), (-3).into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_and_arithmetic_shift() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 0x80000001 push 1 rotl
+            push 0x80000001 push 1 rotr
+            push 0x12345678 push 36 rotl
+            push -8 push 2 shift.a
+            push 8 push 2 shift.a
+            push -8 push 40 shift.a
+            push 0
+            halt
+        ")?;
+        // Ends with 0 on top, as -1 would have halt write a core dump.
+        assert!(
+            state.vm.data
+                == vec![
+                    3.into(),
+                    0xC0000000u32.into(),
+                    0x23456781u32.into(),
+                    (-2).into(),
+                    2.into(),
+                    (-1).into(),
+                    0.into(),
+                ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_bulk_memory() -> Result<(), Error> {
+        let state = run("
+            nop
+            push &buf push 1 add push &buf push 5 memcopy
+            push &buf push 6 add push 0x1FF push 2 memfill
+            push &buf push 7 add push &buf push 1 memcopy
+            push &buf load
+            push &buf push 4 add load
+            halt
+            ===:buf
+            d32 0x04030201
+            d32 0x08070605
+        ")?;
+        // The overlapping copy moves the bytes as they were, not ones it already copied over.
+        assert!(state.vm.data == vec![0x03020101u32.into(), 0x01FF0504u32.into()]);
+        assert!(run("nop\npush 0x100000 push 0 push 1 memfill\nhalt").is_err());
+        assert!(run("nop\npush 0x100000 push 0 push 0 memcopy\nhalt").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_float_literals() -> Result<(), Error> {
+        let (image, _) = bear_ass::assemble("d32 1.5\nd32 -0.25e1")?;
+        let words = bear_vm::util::convert_slice8_to_vec32(&image);
+        assert_eq!(words, vec![1.5f32.to_bits(), (-2.5f32).to_bits()]);
+        assert!(bear_ass::assemble("d16 1.5").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "float")]
+    fn test_float_opcodes() -> Result<(), Error> {
+        let state = run("
+            nop
+            push &half load push &half load fadd
+            push 3 itof push &half load fdiv
+            push &half load push 3 itof fcmp
+            push &half load push &half load fsub
+            ftoi
+            push -7 itof push &half load fmul ftoi
+            halt
+            ===:half
+            d32 0.5
+        ")?;
+        let expected: Vec<u32> = vec![1.0f32.to_bits(), (1.0f32 / 6.0).to_bits(), 1, 0, -3i32 as u32];
+        assert!(state.vm.data.iter().map(|cell| cell.0).eq(expected));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "float"))]
+    fn test_float_opcodes_need_feature() {
+        assert!(run("nop\npush 1 push 2 fadd\nhalt").is_err());
+    }
+
+    #[test]
+    fn test_overflow_traps() -> Result<(), Error> {
+        let run_trapping = |source: &str| -> Result<Result<Vec<u32>, String>, Error> {
+            let mut state = start_with(source, |vm| vm.with_overflow_traps())?;
+            Ok(state
+                .run()
+                .map(|_| state.vm.data.iter().map(|cell| cell.0).collect())
+                .map_err(|e| e.message().to_string()))
+        };
+        let overflow = Err(String::from("Arithmetic overflow."));
+        assert_eq!(run_trapping("nop\npush -1 push 0x7FFFFFFF add\nhalt")?, Ok(vec![0x7FFFFFFE]));
+        assert_eq!(run_trapping("nop\npush 1 push 0x7FFFFFFF add\nhalt")?, overflow);
+        assert_eq!(run_trapping("nop\npush 1 push 0x80000000 sub\nhalt")?, overflow);
+        assert_eq!(run_trapping("nop\npush 0x10000 push 0x10000 mul\nhalt")?, overflow);
+        // Without the traps, it wraps around.
+        let state = run("nop\npush 1 push 0x7FFFFFFF add\nhalt")?;
+        assert!(state.vm.data == vec![0x80000000u32.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_division_by_zero_traps() -> Result<(), Error> {
+        for op in ["div", "mod"] {
+            let mut state = start_with(&format!("nop\npush 0 push 7 {}\nhalt", op), |vm| vm)?;
+            let error = state.run().expect_err("Dividing by zero did not trap.");
+            assert_eq!(error.message(), "Division by zero.");
+            assert_eq!(error.ip(), Some(state.ip()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_shift_amounts() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 1 push 31 shift
+            push 1 push 32 shift
+            push 1 push 33 shift
+            push 0x80000000 push -31 shift
+            push 0x80000000 push -32 shift
+            push 1 push 0x80000000 shift
+            push 1 push 0 shift
+            halt
+        ")?;
+        assert!(
+            state.vm.data
+                == vec![0x80000000u32.into(), 0.into(), 0.into(), 1.into(), 0.into(), 0.into(), 1.into()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unaligned_access() -> Result<(), Error> {
+        let source = "
+            nop
+            push &buf push 1 add load
+            push &buf push 3 add push 0xAABBCCDD store
+            push &buf load
+            push &buf push 4 add load
+            halt
+            ===:buf
+            d32 0x44332211
+            d32 0x88776655
+        ";
+        let state = run(source)?;
+        // Little-endian, the cell at `buf + 1` is the last three bytes of the first word and the
+        // first byte of the second.
+        assert!(state.vm.data == vec![0x55443322u32.into(), 0xDD332211u32.into(), 0x88AABBCCu32.into()]);
+        let mut state = start_with(source, |vm| vm.with_alignment_checks())?;
+        assert!(state.run().unwrap_err().message().starts_with("Unaligned access"));
+        // Aligned or not, the cell must be in memory.
+        let end = "nop\npush &end push 4 add load\nhalt\n===:end\nd32 0";
+        let mut state = start_with(end, |vm| vm.with_alignment_checks())?;
+        assert!(state.run().unwrap_err().message().starts_with("Memory access out of range"));
+        // The bytes of an unaligned access must all be in memory.
+        let Err(error) = run("nop\npush &end push 2 add load\nhalt\n===:end\nd32 0") else {
+            panic!("An unaligned load past the end of memory.");
+        };
+        assert!(error.to_string().contains("Memory access out of range"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_range_traps() -> Result<(), Error> {
+        // Guest code cannot panic the host with a wild address or device, only trap.
+        for (program, message) in [
+            ("nop\npush 0x100000 load\nhalt", "Memory access out of range: 0x100000"),
+            ("nop\npush 0x100000 load.8\nhalt", "Memory access out of range: 0x100000"),
+            ("nop\npush 0x100000 push 1 store\nhalt", "Memory access out of range: 0x100000"),
+            ("nop\npush 0x100001 push 1 store.8\nhalt", "Memory access out of range: 0x100001"),
+            ("nop\npush 77 push 0 io\nhalt", "No device 77."),
+        ] {
+            let mut state = start_with(program, |vm| vm)?;
+            let error = state.run().unwrap_err();
+            assert_eq!(error.message(), message);
+            assert_eq!(error.ip(), Some(state.ip()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_running_off_the_image_traps() -> Result<(), Error> {
+        // Running past the last word, or jumping to the end of the image, traps rather than
+        // panicking.
+        // A `lit` in the last word has no operand to read.
+        for (program, ip) in [("nop nop nop nop", 4), ("push 8 jump", 8), ("nop nop nop lit", 4)] {
+            let mut state = start_with(program, |vm| vm)?;
+            let error = state.run().unwrap_err();
+            assert_eq!(error.message(), "IP went out of bounds.");
+            assert_eq!(error.ip(), Some(ip));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension() -> Result<(), Error> {
+        let state = run("
+            nop
+            push 0x12345680 sext.8
+            push 0x1234567F sext.8
+            push 0x12348000 sext.16
+            push 0x12347FFF sext.16
+            push -2 zext.8
+            push -2 zext.16
+            halt
+        ")?;
+        assert!(
+            state.vm.data
+                == vec![
+                    (-128).into(),
+                    0x7F.into(),
+                    (-32768).into(),
+                    0x7FFF.into(),
+                    0xFE.into(),
+                    0xFFFE.into(),
+                ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sbrk() -> Result<(), Error> {
+        let source = "
+            nop
+            push 0 sbrk
+            push 5 sbrk
+            dup push 42 store
+            dup load
+            push 0 sbrk
+            push 5 sbrk
+            push 0
+            halt
+        ";
+        let mut end = 0;
+        let state = run_with(source, |vm| {
+            end = 4 * vm.image.len() as u32;
+            vm.with_memory_limit(end as usize + 12).with_predecode()
+        })?;
+        // Five bytes grow the image by two words, and then five more would pass the limit.
+        let data: Vec<u32> = state.vm.data.iter().map(|cell| cell.0).collect();
+        assert_eq!(data, vec![end, end, 42, end + 8, u32::MAX, 0]);
+        assert_eq!(state.vm.image.len() as u32 * 4, end + 8);
+
+        // Without a limit of its own, the guest still cannot have the host allocate gigabytes.
+        let state = run("nop push 0x7FFFFFF0 sbrk push 0 halt")?;
+        assert_eq!(state.vm.data, vec![u32::MAX.into(), 0.into()]);
+        assert!(state.vm.image.len() * 4 <= bear_vm::vm::DEFAULT_MEMORY_LIMIT);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_header() -> Result<(), Error> {
+        use bear_vm::checksum::{self, Mismatch};
+
+        assert_eq!(checksum::crc32(b"123456789"), 0xCBF4_3926);
+        let (image, debug) = bear_ass::assemble("push 42 halt")?;
+        assert_eq!(debug.checksum, Some(checksum::crc32(&image)));
+
+        let headed = checksum::with_header(&image);
+        assert_eq!(headed.len(), image.len() + checksum::HEADER_LEN);
+        assert_eq!(checksum::strip_header(&headed), Ok(&image[..]));
+        assert_eq!(checksum::strip_header(&image), Ok(&image[..]));
+        let mut vm = BearVM::new(vec![]);
+        vm.load_image(headed.clone())
+            .map_err(vm_error)?;
+        assert_eq!(vm.image, bear_vm::util::convert_slice8_to_vec32(&image));
+
+        // A corrupt image is refused.
+        let mut corrupt = headed.clone();
+        corrupt[checksum::HEADER_LEN] ^= 1;
+        let error = vm.load_image(corrupt).expect_err("The checksum should not match.");
+        assert!(error.message().starts_with("Image checksum mismatch"));
+        // So is one cut short at its end, or anywhere else.
+        let len = image.len() as u32;
+        let truncated = &headed[..headed.len() - 4];
+        let expected = Err(Mismatch::Length { expected: len, actual: len - 4 });
+        assert_eq!(checksum::strip_header(truncated), expected);
+        let error = vm.load_image(truncated.to_vec()).expect_err("The length should not match.");
+        assert!(error.message().starts_with("Image length mismatch"));
+        let mut cut = headed[..checksum::HEADER_LEN].to_vec();
+        cut.extend(&image[4..]);
+        assert!(checksum::strip_header(&cut).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_capacity() -> Result<(), Error> {
+        // Pushes 1 forever.  (Jumps cannot target address 0.)
+        let source = "
+            nop nop nop nop
+            ===:top
+            lit lit jump nop
+            d32 1
+            d32 &top
+        ";
+
+        let mut state = start_with(source, |vm| vm.with_stack_capacity(8))?;
+        let error = state.run().expect_err("The data stack should overflow.");
+        assert_eq!(error.message(), "Data stack overflow.");
+        assert_eq!(state.vm.data.len(), 8);
+
+        let mut state = start_with(source, |vm| vm.with_growable_stacks())?;
+        while state.vm.data.len() <= Stack::DEFAULT_CAPACITY {
+            state.step().map_err(vm_error)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_unchecked() -> Result<(), Error> {
+        // Counts down from 3.
+        let mut state = start_with("
+            lit nop nop nop
+            d32 3
+            ===:top
+            lit add dup lit
+            d32 -1
+            d32 &done
+            ifz:jump lit jump nop
+            d32 &top
+            ===:done
+            halt
+        ", |vm| {
+            assert!(vm.verify().is_empty());
+            vm
+        })?;
+        // SAFETY: the image verifies, and has no computed branches.
+        unsafe { state.run_unchecked() }.map_err(vm_error)?;
+        assert!(state.vm.data == vec![0.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_predecode() -> Result<(), Error> {
+        // Overwrites the `nop`s of `patched` with `lit add halt` before running it.
+        use bear_vm::vm::OpCode;
+        let patch = [OpCode::Lit, OpCode::Add, OpCode::Halt, OpCode::Nop].map(OpCode::into_u8);
+        let patch = u32::from_le_bytes(patch);
+        let source = format!("
+            nop nop nop nop
+            lit lit store nop
+            d32 &patched
+            d32 {}
+            lit lit jump nop
+            d32 2
+            d32 &patched
+            ===:patched
+            nop nop nop nop
+            d32 3
+        ", patch);
+        let state = run_with(&source, |vm| vm.with_predecode())?;
+        assert!(state.vm.data == vec![5.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_library() -> Result<(), Error> {
+        let state = run("
+            jump &main
+            #include <std/string.bear>;
+            #include <std/math.bear>;
+
+            :main
+            push &hello call &string:length
+            push &hello push &other call &string:equal
+            push &hello push &world call &string:equal
+            push &buffer push &hello call &string:copy
+            push &buffer push &hello call &string:equal
+            push 3 push 9 call &math:min
+            push 3 push 9 call &math:max
+            push -7 call &math:abs
+            halt
+
+            :hello c\"hello\"
+            :other c\"hello\"
+            :world c\"world\"
+            :buffer d32 0 d32 0
+        ")?;
+        let expected: Vec<i32> = vec![5, -1, 0, -1, 3, 9, 7];
+        assert!(state.vm.data == expected.into_iter().map(Into::into).collect::<Vec<_>>());
+        assert!(state.vm.address.is_empty());
+
+        let program = parser::Parser {}
+            .parse("#include <std/nothing.bear>;")
+            .map_err(Error::ParserError)?;
+        let error = match processor::Processor::process(program) {
+            Err(error) => error,
+            Ok(_) => panic!("Expected errors."),
+        };
+        assert!(matches!(
+            error.diagnostics()[0].tag,
+            processor::ErrorTag::UnknownLibraryFile(_)
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_sext8_pos() -> Result<(), Error> {
         let state = run("
@@ -325,4 +1000,1309 @@ mod test {
         assert!(state.vm.address.is_empty());
         Ok(())
     }
+
+    #[test]
+    fn test_number_literals() -> Result<(), Error> {
+        let state = run("
+            lit lit lit lit
+            d32 0b1010_0101
+            d32 0o17
+            d32 1_000_000
+            d32 0xFF_FF
+            halt
+        ")?;
+        assert!(state.vm.data == vec![0xA5.into(), 15.into(), 1_000_000.into(), 0xFFFF.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_8_into_loaded_word() -> Result<(), Error> {
+        // Overwrites the trailing `nop` of the executing word with a `halt`.
+        let state = run("
+            lit lit store.8 nop
+            d32 3
+            d32 `halt
+            lit halt nop nop
+            d32 7
+        ")?;
+        assert!(state.instruction_index == 3);
+        assert!(state.loaded_word_index == 0);
+        assert!(state.vm.data.is_empty());
+        assert!(state.vm.address.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_escapes() -> Result<(), Error> {
+        let state = run(r#"
+            lit halt nop nop
+            d32 '\u{263A}'
+            r"\n\t\x80\u{e9}\"\0\\"
+        "#)?;
+        assert!(state.vm.data == vec![0x263A.into()]);
+        let bytes = bear_vm::util::convert_slice32_to_vec8(&state.vm.image[2..]);
+        assert!(bytes == vec![b'\n', b'\t', 0x80, 0xC3, 0xA9, b'"', 0, b'\\']);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sections() -> Result<(), Error> {
+        let program = parser::Parser {}
+            .parse("
+                lit load halt nop
+                d32 &value
+                #section ram 0x100;
+                :value d32 42
+            ")
+            .map_err(Error::ParserError)?;
+        let processor = processor::Processor::process(program).expect("Processor error.");
+        let images = assembler::Assembler::assemble_sections(processor).expect("Assembler error.");
+        assert!(images.len() == 2);
+        assert!(images[0].bits.len() == 8);
+        assert!(images[1].base == 0x100 && images[1].bits == vec![42, 0, 0, 0]);
+
+        let mut vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&images[0].bits));
+        vm.load_init_blob(&assembler::Assembler::make_init_blob(&images))
+            .map_err(vm_error)?;
+        let mut state = vm.start().map_err(vm_error)?;
+        state
+            .run()
+            .map_err(vm_error)?;
+        assert!(state.vm.data == vec![42.into()]);
+
+        // Sections may not overlap the ROM, or each other.
+        for source in [
+            "nop nop nop nop\nd32 1\n#section ram 0x4;\nd32 2",
+            "halt\n#section a 0x100;\nd32 1 d32 2\n#section b 0x104;\nd32 3",
+        ] {
+            let program = parser::Parser {}.parse(source).map_err(Error::ParserError)?;
+            let processor = processor::Processor::process(program).expect("Processor error.");
+            assert!(matches!(
+                assembler::Assembler::assemble_sections(processor),
+                Err(assembler::Error::SectionsOverlap(..))
+            ));
+        }
+
+        // A blob cannot grow memory past the limit.
+        let blob = bear_vm::util::encode_init_blob(&[(0x7FFF_FFF0, &[1, 2, 3, 4])]);
+        let mut vm = BearVM::new(vec![0]);
+        assert!(vm.load_init_blob(&blob).is_err());
+        assert_eq!(vm.image.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_beyond_128k() -> Result<(), Error> {
+        // Return addresses used to hold word indices in 15 bits, limiting calls to the first 128 KiB.
+        let program = parser::Parser {}
+            .parse("
+                jump &far
+                #section far 0x40000;
+                :far push 1 call &add_two halt
+                :add_two push 2 add ret
+            ")
+            .map_err(Error::ParserError)?;
+        let processor = processor::Processor::process(program).expect("Processor error.");
+        let images = assembler::Assembler::assemble_sections(processor).expect("Assembler error.");
+
+        let mut vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&images[0].bits));
+        vm.load_init_blob(&assembler::Assembler::make_init_blob(&images))
+            .map_err(vm_error)?;
+        let mut state = vm.start().map_err(vm_error)?;
+        state
+            .run()
+            .map_err(vm_error)?;
+        assert!(state.vm.data == vec![3.into()]);
+        assert!(state.ip() > 0x40000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments() -> Result<(), Error> {
+        let state = run("
+            /* A block comment
+               /* which nests */ -- and hides line comments */
+            #define two /* inside a directive */ 2; -- after a directive
+            #define push_two [
+                lit -- inside a list
+                /* between items */ halt nop nop
+                d32 !two
+            ];
+            :a /* between labels */ :b
+            !push_two /* after a reference */
+        ")?;
+        assert!(state.vm.data == vec![2.into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_intern() -> Result<(), Error> {
+        let program = parser::Parser {}
+            .parse(r#"
+                #intern first "one";
+                #intern second "two\n";
+                lit lit halt nop
+                d32 !first
+                d32 !second
+            "#)
+            .map_err(Error::ParserError)?;
+        let processor = processor::Processor::process(program).expect("Processor error.");
+        let debug = processor.make_debug().expect("Debug error.");
+        assert!(debug.strings == vec!["one", "two\n"]);
+        let image = assembler::Assembler::assemble(processor).expect("Assembler error.");
+        assert!(bear_vm::util::convert_slice8_to_vec32(&image)[1..] == [0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_paths() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).map_err(Error::IOError)?;
+        std::fs::write(dir.join("lib.bear"), "#define answer 42;").map_err(Error::IOError)?;
+
+        let program = parser::Parser {}
+            .parse("
+                #include \"lib.bear\";
+                lit halt nop nop
+                d32 !answer
+            ")
+            .map_err(Error::ParserError)?;
+        assert!(processor::Processor::process(program.clone()).is_err());
+        let options = processor::Options {
+            include_paths: vec![dir.clone()],
+            ..Default::default()
+        };
+        let processor = processor::Processor::process_with(program, options).expect("Processor error.");
+        let image = assembler::Assembler::assemble(processor).expect("Assembler error.");
+        assert!(bear_vm::util::convert_slice8_to_vec32(&image)[1] == 42);
+
+        std::fs::remove_dir_all(&dir).map_err(Error::IOError)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_line_defines() -> Result<(), Error> {
+        let program = parser::Parser {}
+            .parse("
+                lit halt nop nop
+                d32 !size * 2
+            ")
+            .map_err(Error::ParserError)?;
+        let size = parser::Parser {}
+            .parse_expression_str("0x10 + 1")
+            .map_err(Error::ParserError)?;
+        let options = processor::Options {
+            defines: vec![(String::from("size"), size)],
+            ..Default::default()
+        };
+        let processor = processor::Processor::process_with(program, options).expect("Processor error.");
+        let image = assembler::Assembler::assemble(processor).expect("Assembler error.");
+        assert!(bear_vm::util::convert_slice8_to_vec32(&image)[1] == 34);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_errors() -> Result<(), Error> {
+        let source = "lit !nope\nlit halt\nd32 &missing\n";
+        let program = parser::Parser {}
+            .parse(source)
+            .map_err(Error::ParserError)?;
+        let error = match processor::Processor::process(program) {
+            Err(error) => error,
+            Ok(_) => panic!("Expected errors."),
+        };
+        let lines: Vec<(usize, usize)> = error
+            .diagnostics()
+            .iter()
+            .map(|d| d.location.as_ref().map(|l| (l.line, l.column)).unwrap())
+            .collect();
+        assert_eq!(lines, vec![(1, 5), (3, 1)]);
+        assert!(matches!(
+            &error.diagnostics()[1].tag,
+            processor::ErrorTag::UnknownLabel(name) if name == "missing"
+        ));
+        let rendered = error.render("test.bear", source);
+        assert!(rendered.contains(" --> test.bear:1:5\n  |\n1 | lit !nope\n  |     ^\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_warnings() -> Result<(), Error> {
+        let source = "
+            #section ram 0x100;
+            ===:buf
+            d32 0
+            d32 0
+            #section rom;
+            lit halt nop nop
+            d32 &buf + 8
+            d32 &buf + 12
+            d32 &buf - 0x200
+        ";
+        let warnings = |check_sections| -> Result<Vec<usize>, Error> {
+            let program = parser::Parser {}
+                .parse(source)
+                .map_err(Error::ParserError)?;
+            let options = processor::Options {
+                check_sections,
+                ..Default::default()
+            };
+            let processor = processor::Processor::process_with(program, options).expect("Processor error.");
+            Ok(processor
+                .warnings
+                .iter()
+                .map(|w| w.location.as_ref().unwrap().line)
+                .collect())
+        };
+        assert_eq!(warnings(false)?, vec![10]);
+        assert_eq!(warnings(true)?, vec![9, 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_align_padding() -> Result<(), Error> {
+        let program = parser::Parser {}
+            .parse("
+                nop
+                ===:after
+                halt
+                #section table 0x100;
+                d8 1
+                #align 4;
+                d8 2
+                #section stack 0x200 code;
+                nop
+                #align 4;
+            ")
+            .map_err(Error::ParserError)?;
+        let processor = processor::Processor::process(program).expect("Processor error.");
+        let padding: Vec<(usize, usize)> = processor
+            .processed
+            .iter()
+            .filter(|line| line.padding)
+            .map(|line| (line.section, line.address))
+            .collect();
+        assert_eq!(padding, vec![(0, 1), (0, 2), (0, 3), (2, 0x201), (2, 0x202), (2, 0x203)]);
+        let state = run("
+            nop
+            ===:after
+            halt
+        ")?;
+        assert!(state.loaded_word_index == 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_file() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.bear"), "#define answer 42;")?;
+        std::fs::write(dir.join("main.bear"), "#include \"lib.bear\";\nlit halt nop nop\nd32 !answer\n")?;
+
+        let (image, debug) = bear_ass::assemble_file(&dir.join("main.bear"), Default::default())?;
+        assert!(bear_vm::util::convert_slice8_to_vec32(&image)[1] == 42);
+        assert!(!debug.entries.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_debug_info() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-debug-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.bear"), "-- Doubles.\n===:double\ndup add ret nop\n")?;
+        std::fs::write(dir.join("main.bear"), "halt nop nop nop\n#include \"lib.bear\";\n")?;
+
+        let (_, debug) = bear_ass::assemble_file(&dir.join("main.bear"), Default::default())?;
+        let main = debug.entries.iter().find(|e| e.address == 0).expect("No entry for main.");
+        assert_eq!((main.file.as_deref(), main.line), (None, 1));
+        let double = debug
+            .entries
+            .iter()
+            .find(|e| e.names.contains(&String::from("double")))
+            .expect("No entry for double.");
+        let file = double.file.as_ref().expect("No file for double.");
+        assert_eq!(file.file_name(), Some("lib.bear".as_ref()));
+        assert_eq!(double.line, 2);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_sources() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-sources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.bear"), "-- Doubles.\n===:double\ndup add ret nop\n")?;
+        let main = "halt nop nop nop\n#include \"lib.bear\";\n#include <std/math.bear>;\n";
+
+        let program = parser::Parser {}.parse(main)?;
+        let options = processor::Options {
+            include_paths: vec![dir.clone()],
+            ..Default::default()
+        };
+        let processor = processor::Processor::process_with(program, options)?;
+        let mut debug = processor.make_debug()?;
+        assert!(debug.source_line(None, 1).is_none());
+        debug.sources = processor.sources(main);
+        let files: Vec<Option<String>> = debug
+            .sources
+            .iter()
+            .map(|s| s.file.as_ref().map(|f| f.display().to_string()))
+            .collect();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0], None);
+        assert!(files.contains(&Some(String::from("<std/math.bear>"))));
+
+        let double = debug.entries.iter().find(|e| e.names.contains(&String::from("double")));
+        let double = double.expect("No entry for double.");
+        assert_eq!(debug.source_line(double.file.as_deref(), double.line), Some("===:double"));
+        assert_eq!(debug.source_line(None, 1), Some("halt nop nop nop"));
+        assert_eq!(debug.source_line(None, 0), None);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-resolver-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.bear"), "-- Doubles.\n===:double\ndup add ret nop\n")?;
+        std::fs::write(dir.join("main.bear"), ":main halt nop nop nop\n#include \"lib.bear\";\n")?;
+
+        let (_, debug) = bear_ass::assemble_file(&dir.join("main.bear"), Default::default())?;
+        let resolver = bear_ass::debug::Resolver::new(&debug);
+        assert_eq!(resolver.address_of_label("main"), Some(0));
+        let double = resolver.address_of_label("double").expect("No label double.");
+        assert_eq!(resolver.address_of_label("triple"), None);
+        let labels: Vec<(&str, usize)> = resolver.labels().collect();
+        assert_eq!(labels, vec![("main", 0), ("double", double)]);
+        assert_eq!(resolver.address_of_line(None, 1), Some(0));
+        assert_eq!(resolver.address_of_line(Some("lib.bear".as_ref()), 2), Some(double));
+        assert_eq!(resolver.address_of_line(Some("other.bear".as_ref()), 2), None);
+
+        assert_eq!(resolver.lookup(2).to_string(), "main+2 (line 1)");
+        let symbol = resolver.lookup(double + 1);
+        assert_eq!(symbol.label, Some(("double", 1)));
+        let entry = symbol.entry.expect("No line.");
+        assert!(entry.file.as_ref().is_some_and(|f| f.ends_with("lib.bear")));
+        assert_eq!(entry.line, 3);
+        assert_eq!(resolver.entry(double).map(|e| e.line), Some(2));
+        assert!(resolver.entry(double + 64).is_none());
+
+        let (_, mut debug) = bear_ass::assemble("nop nop\n")?;
+        let mut again = debug.entries[1].clone();
+        again.line = 2;
+        debug.entries.push(again);
+        let resolver = bear_ass::debug::Resolver::new(&debug);
+        assert_eq!(resolver.entry(1).map(|e| e.line), Some(2));
+        assert_eq!(resolver.lookup(17).to_string(), "0x11 (line 2)");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_spans() -> Result<(), Error> {
+        let source = "lit halt nop nop\nd32 7\n:end  push 42 -- The answer.\n";
+        let (_, debug) = bear_ass::assemble(source)?;
+        let entry = |address: usize| {
+            let entry = debug.entries.iter().find(|e| e.address == address);
+            let entry = entry.expect("No entry.");
+            (entry.line, entry.column, &source[entry.span.clone()])
+        };
+        assert_eq!(entry(0), (1, 1, "lit"));
+        assert_eq!(entry(4), (2, 1, "d32 7"));
+        // The span leaves out the label, but the column is that of the line, as in errors.
+        assert_eq!(entry(8), (3, 1, "push 42"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_unused() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("bear-ass-strip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.bear"), "
+            ===:unused
+            lit add ret
+            ===
+            d32 1
+            ===:double
+            dup add lit jump
+            d32 &tail
+            ===:dead
+            lit jump
+            ===
+            d32 &unused
+            ===:tail
+            lit add ret
+            ===
+            d32 1
+        ")?;
+        std::fs::write(dir.join("main.bear"), "
+            lit lit call halt
+            d32 20
+            d32 &double
+            #include \"lib.bear\";
+        ")?;
+
+        let path = dir.join("main.bear");
+        let (full, _) = bear_ass::assemble_file(&path, Default::default())?;
+        let options = processor::Options {
+            strip_unused: true,
+            ..Default::default()
+        };
+        let (stripped, debug) = bear_ass::assemble_file(&path, options)?;
+        assert!(stripped.len() == full.len() - 16);
+        assert!(debug.entries.iter().all(|e| !e.names.contains(&"dead".to_string())));
+
+        let vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&stripped));
+        let mut state = vm.start().map_err(vm_error)?;
+        state.run().map_err(vm_error)?;
+        assert!(state.vm.data == vec![41.into()]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Claims a region of memory, as if a transfer to it were in progress.
+    struct BusyDevice(std::ops::Range<usize>);
+
+    impl bear_vm::device::Device for BusyDevice {
+        fn ioctl(&mut self, _command: u32) -> u32 {
+            0
+        }
+        fn dma_poll(&mut self) -> Option<bear_vm::device::DMARequest> {
+            None
+        }
+        fn dma_write_response(&mut self, _address: usize) {}
+        fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+        fn dma_pending(&self) -> Vec<std::ops::Range<usize>> {
+            vec![self.0.clone()]
+        }
+    }
+
+    #[test]
+    fn test_dma_race_detection() -> Result<(), Error> {
+        let source = "
+            lit load lit lit
+            d32 &buffer
+            d32 &buffer + 5
+            d32 7
+            store.8 lit load halt
+            d32 &other
+            ===:buffer
+            d32 0
+            d32 0
+            ===:other
+            d32 0
+        ";
+        let state = run_with(source, |vm| {
+            vm.with_device(Box::new(BusyDevice(24..32))).with_dma_race_detection()
+        })?;
+        let races: Vec<(usize, usize, bool)> = state
+            .vm
+            .dma_races
+            .iter()
+            .map(|race| (race.ip, race.address, race.store))
+            .collect();
+        assert_eq!(races, vec![(1, 24, false), (16, 29, true)]);
+        Ok(())
+    }
+
+    /// Writes each command it receives to `address` by DMA, and counts how often it is polled.
+    struct PokeDevice {
+        address: usize,
+        pending: Option<u32>,
+        polls: std::rc::Rc<std::cell::Cell<usize>>,
+        attention: bear_vm::device::Attention,
+    }
+
+    impl bear_vm::device::Device for PokeDevice {
+        fn ioctl(&mut self, command: u32) -> u32 {
+            self.pending = Some(command);
+            self.attention.raise();
+            0
+        }
+        fn dma_poll(&mut self) -> Option<bear_vm::device::DMARequest> {
+            self.polls.set(self.polls.get() + 1);
+            self.pending
+                .map(|value| bear_vm::device::DMARequest::Write(self.address, value))
+        }
+        fn dma_write_response(&mut self, _address: usize) {
+            self.pending = None;
+        }
+        fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+        fn attach(&mut self, attention: bear_vm::device::Attention) {
+            self.attention = attention;
+        }
+    }
+
+    #[test]
+    fn test_sync_policy() -> Result<(), Error> {
+        use bear_vm::vm::SyncPolicy;
+        // Has the device write 42 to `cell`, and loads it right away.
+        let source = "
+            lit lit io drop
+            d32 0
+            d32 42
+            lit load halt nop
+            d32 &cell
+            ===:cell
+            d32 0
+        ";
+        let mut polls = Vec::new();
+        for (policy, loaded) in [
+            (SyncPolicy::Always, 42),
+            (SyncPolicy::AfterIo, 42),
+            (SyncPolicy::Every(100), 0),
+            (SyncPolicy::OnAttention, 42),
+        ] {
+            let count = std::rc::Rc::new(std::cell::Cell::new(0));
+            let device = PokeDevice {
+                address: 20,
+                pending: None,
+                polls: count.clone(),
+                attention: Default::default(),
+            };
+            let state = run_with(source, |vm| vm.with_sync_policy(policy).with_device(Box::new(device)))?;
+            assert!(state.vm.data == vec![loaded.into()], "{:?}", policy);
+            // Synced when halting, whatever the policy.
+            assert_eq!(state.vm.image[5], 42, "{:?}", policy);
+            polls.push(count.get());
+        }
+        // Polled after each of 6 instructions, or once when halting, plus the polls which answer the write.
+        assert_eq!(polls, vec![8, 3, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_device() -> Result<(), Error> {
+        use bear_vm::device::{DMARequest, GenericDeviceCommand, HostDevice, HostDma};
+        use std::cell::Cell;
+        use std::rc::Rc;
+        // Multiplies register 0 by the argument, and also writes the product to `product`.
+        let source = format!("
+            lit lit io drop
+            d32 0
+            d32 {}
+            lit lit io halt
+            d32 0
+            d32 {}
+            ===:product
+            d32 0
+        ",
+            GenericDeviceCommand::set(0, 6).encode(),
+            GenericDeviceCommand::Execute { command: 0, argument: 7 }.encode(),
+        );
+        let pending = Rc::new(Cell::new(None));
+        let write = pending.clone();
+        let poll = pending.clone();
+        let device = HostDevice::generic(1, move |_command, argument, registers| {
+            let product = registers[0] as u32 * argument as u32;
+            write.set(Some(product));
+            product
+        })
+        .with_dma(HostDma {
+            poll: Box::new(move || poll.get().map(|value| DMARequest::Write(24, value))),
+            write_response: Box::new(move |_address| pending.set(None)),
+            read_response: Box::new(|_address, _value| {}),
+        });
+
+        let state = run_with(&source, |vm| vm.with_device(Box::new(device)))?;
+        assert!(state.vm.data == vec![42.into()]);
+        assert_eq!(state.vm.image[6], 42);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dma_bursts() -> Result<(), Error> {
+        use bear_vm::device::{DMARequest, Device, DmaError};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Makes its requests in order, and logs the answers.
+        struct Script {
+            requests: Vec<DMARequest>,
+            log: Rc<RefCell<Vec<String>>>,
+        }
+
+        impl Device for Script {
+            fn ioctl(&mut self, _message: u32) -> u32 {
+                0
+            }
+
+            fn dma_poll(&mut self) -> Option<DMARequest> {
+                (!self.requests.is_empty()).then(|| self.requests.remove(0))
+            }
+
+            fn dma_write_response(&mut self, address: usize) {
+                self.log.borrow_mut().push(format!("write {}", address));
+            }
+
+            fn dma_read_response(&mut self, address: usize, value: u32) {
+                self.log.borrow_mut().push(format!("read {} {}", address, value));
+            }
+
+            fn dma_read_burst_response(&mut self, address: usize, words: &[u32]) {
+                self.log.borrow_mut().push(format!("read {} {:?}", address, words));
+            }
+
+            fn dma_error(&mut self, request: DMARequest, error: DmaError) {
+                self.log.borrow_mut().push(format!("{:?}: {}", request, error));
+            }
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let device = Script {
+            requests: vec![
+                DMARequest::WriteBurst(4, vec![7, 8]),
+                DMARequest::ReadBurst(4, 3),
+                DMARequest::Read(12),
+                DMARequest::Write(16, 1),
+                // Not polled until the next sync, after the error.
+                DMARequest::Read(0),
+            ],
+            log: log.clone(),
+        };
+        let source = "
+            halt nop nop nop
+            d32 0
+            d32 0
+            d32 0
+        ";
+        let mut state = start_with(source, |vm| vm.with_device(Box::new(device)))?;
+        state.sync();
+        assert_eq!(&state.vm.image[1..], &[7, 8, 0]);
+        assert_eq!(*log.borrow(), vec![
+            "write 4",
+            "write 8",
+            "read 4 [7, 8, 0]",
+            "read 12 0",
+            "Write(16, 1): DMA outside of the image",
+        ]);
+
+        log.borrow_mut().clear();
+        state.sync();
+        state.vm.devices[0] = Box::new(Script {
+            requests: vec![DMARequest::Read(2)],
+            log: log.clone(),
+        });
+        state.sync();
+        assert_eq!(*log.borrow(), vec![
+            format!("read 0 {}", state.vm.image[0]),
+            "Read(2): unaligned DMA address".to_string(),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupts() -> Result<(), Error> {
+        use bear_vm::device::{DMARequest, Device, Interrupts};
+        use bear_vm::vm::Event;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Raises the vector it is sent.
+        #[derive(Default)]
+        struct Raiser(Interrupts);
+
+        impl Device for Raiser {
+            fn ioctl(&mut self, message: u32) -> u32 {
+                self.0.raise(message as u8);
+                0
+            }
+
+            fn dma_poll(&mut self) -> Option<DMARequest> {
+                None
+            }
+
+            fn dma_write_response(&mut self, _address: usize) {}
+
+            fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+            fn connect_interrupts(&mut self, interrupts: Interrupts) {
+                self.0 = interrupts;
+            }
+        }
+
+        // Vector 4 has no handler, so raising it does nothing.
+        let (image, debug) = bear_ass::assemble("
+            push 3 push &handler ivec
+            push 0 push 4 io drop
+            push 0 push 3 io drop
+            push 0 push 3 io drop
+            push 5 halt
+            :handler
+            push &count push &count load push 1 add store
+            iret
+            ===:count
+            d32 0
+        ")?;
+        let count = debug.entries.iter().find(|e| e.names.contains(&"count".to_string()));
+        let count = count.unwrap().address / 4;
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = events.clone();
+        let vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image))
+            .with_observer(Box::new(move |event: &Event| {
+                if let Event::Interrupted { vector, .. } = event {
+                    recorded.borrow_mut().push(*vector);
+                }
+            }))
+            .with_device(Box::new(Raiser::default()));
+        let mut state = vm.start().map_err(vm_error)?;
+        state.run().map_err(vm_error)?;
+        assert!(state.vm.data == vec![5.into()]);
+        assert!(state.vm.address.is_empty());
+        assert_eq!(state.vm.image[count], 2);
+        assert_eq!(*events.borrow(), vec![3, 3]);
+        assert_eq!(state.vm.interrupts().pending(), 1 << 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock() -> Result<(), Error> {
+        use bear_vm::device::{Clock, DMARequest, Device};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        /// Records the time of each command.
+        struct Watch(Clock, Rc<RefCell<Vec<u64>>>);
+
+        impl Device for Watch {
+            fn ioctl(&mut self, _message: u32) -> u32 {
+                self.1.borrow_mut().push(self.0.now());
+                0
+            }
+
+            fn dma_poll(&mut self) -> Option<DMARequest> {
+                None
+            }
+
+            fn dma_write_response(&mut self, _address: usize) {}
+
+            fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+            fn connect_clock(&mut self, clock: Clock) {
+                self.0 = clock;
+            }
+        }
+
+        let source = "
+            push 0 push 0 io drop
+            nop nop nop
+            push 0 push 0 io drop
+            halt
+        ";
+        let times = Rc::new(RefCell::new(Vec::new()));
+        let watch = Watch(Clock::default(), times.clone());
+        let state = run_with(source, |vm| vm.with_device(Box::new(watch)))?;
+        // The `io`s are the 3rd and the 10th instruction: `push n` is a `lit`.
+        assert_eq!(*times.borrow(), vec![3, 10]);
+        assert_eq!(state.executed(), 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sendable_vm() -> Result<(), Error> {
+        use bear_vm::device::{DMARequest, Device};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct Doubler;
+
+        impl Device for Doubler {
+            fn ioctl(&mut self, message: u32) -> u32 {
+                message * 2
+            }
+
+            fn dma_poll(&mut self) -> Option<DMARequest> {
+                None
+            }
+
+            fn dma_write_response(&mut self, _address: usize) {}
+
+            fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+        }
+
+        let events = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..4u32)
+            .map(|i| {
+                let source = format!("lit lit io halt d32 0 d32 {}", i);
+                let (image, _) = bear_ass::assemble(&source).unwrap();
+                let events = events.clone();
+                let vm = BearVM::new_sendable(bear_vm::util::convert_slice8_to_vec32(&image))
+                    .with_device(Box::new(Doubler))
+                    .with_observer(Box::new(move |_event: &bear_vm::vm::Event| {
+                        events.fetch_add(1, Ordering::Relaxed);
+                    }));
+                std::thread::spawn(move || {
+                    let mut state = vm.start().unwrap();
+                    state.run().unwrap();
+                    state.vm.data.iter().map(|cell| cell.0).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(results, vec![vec![0], vec![2], vec![4], vec![6]]);
+        // `Started` and `Halted` for each VM.
+        assert_eq!(events.load(Ordering::Relaxed), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xref() -> Result<(), Error> {
+        let program = parser::Parser {}.parse("
+            lit call halt
+            ===
+            d32 &main
+            ===:main
+            lit jump
+            ===
+            d32 &main
+            #section ram 0x100;
+            :table d32 &main
+        ")?;
+        let xref = processor::Processor::process(program)?.xref();
+        let refs = |label: &str| xref[label].iter().cloned().collect::<Vec<_>>();
+        assert_eq!(refs("<rom>"), vec!["main"]);
+        assert_eq!(refs("main"), vec!["main"]);
+        assert_eq!(refs("table"), vec!["main"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_stacks() -> Result<(), Error> {
+        let warnings = |source: &str| -> Result<Vec<String>, Error> {
+            let program = parser::Parser {}.parse(source)?;
+            let options = processor::Options {
+                check_stacks: true,
+                ..Default::default()
+            };
+            let p = processor::Processor::process_with(program, options)?;
+            Ok(p.warnings.iter().map(|w| w.tag.to_string()).collect())
+        };
+
+        // `two` takes one cell and leaves two, so `main` is balanced.
+        let balanced = warnings("
+            lit lit call halt
+            d32 1
+            d32 &two
+            ===:two
+            dup ret
+        ")?;
+        assert!(balanced.is_empty(), "{:?}", balanced);
+
+        let underflow = warnings("
+            lit call halt
+            ===
+            d32 &pair
+            ===:pair
+            drop drop ret
+        ")?;
+        assert_eq!(underflow, vec!["the data stack may underflow in `<start>`"]);
+
+        let unbalanced = warnings("
+            lit jump
+            ===
+            d32 &main
+            ===:main
+            lit ifz:ret dup ret
+            d32 0
+            ===:leak
+            push ret
+        ")?;
+        assert_eq!(
+            unbalanced,
+            vec![
+                "`main` changes the depth of the data stack by +2 here, but by +0 elsewhere",
+                "`leak` returns with cells left on the address stack",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), Error> {
+        let verify = |source: &str| -> Result<Vec<bear_vm::vm::Defect>, Error> {
+            let (image, _) = bear_ass::assemble(source)?;
+            Ok(BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image)).verify())
+        };
+        assert!(verify("
+            lit call halt
+            ===
+            d32 &f
+            ===:f
+            ret
+            d8 0xFF
+        ")?.is_empty());
+
+        let defects = verify("
+            lit call lit call
+            d32 &bad
+            d32 0x1000
+            lit lit ifz:jump halt
+            d32 0
+            d32 &tail
+            ===:bad
+            nop nop d8 0x70 nop
+            ===:tail
+            nop nop nop lit
+        ")?;
+        assert_eq!(
+            defects,
+            vec![
+                bear_vm::vm::Defect::TargetOutOfBounds {
+                    address: 3,
+                    target: 0x1000
+                },
+                bear_vm::vm::Defect::InvalidOpcode {
+                    address: 26,
+                    byte: 0x70
+                },
+                bear_vm::vm::Defect::MissingOperand { address: 31 },
+            ]
+        );
+        Ok(())
+    }
+
+    /// Counts the `brk`s it is called for.
+    struct BreakCounter(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl bear_vm::vm::Debugger for BreakCounter {
+        fn brk(&self, _state: &ExecutionState<Self>) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_brk() -> Result<(), Error> {
+        let source = "
+            lit brk brk halt
+            d32 1
+        ";
+        let state = run(source)?;
+        assert!(state.vm.data == vec![1.into()]);
+
+        let breaks = std::rc::Rc::new(std::cell::Cell::new(0));
+        run_with(source, |vm| vm.with_debugger(BreakCounter(breaks.clone())))?;
+        assert!(breaks.get() == 2);
+        Ok(())
+    }
+
+    struct DiffRecorder(
+        bear_vm::diff::Differ,
+        std::rc::Rc<std::cell::RefCell<Vec<bear_vm::diff::StateDiff>>>,
+    );
+
+    impl bear_vm::vm::CallbackDebugger for DiffRecorder {
+        fn ip(&self, state: &ExecutionState<DynDebugger>, op: bear_vm::vm::OpCode) {
+            self.1.borrow_mut().extend(self.0.step(state, op));
+        }
+        fn data_pop(&self, _vm: &BearVM<DynDebugger>) {}
+        fn data_push(&self, _vm: &BearVM<DynDebugger>, _cell: bear_vm::vm::Cell) {}
+        fn address_pop(&self, _vm: &BearVM<DynDebugger>) {}
+        fn address_push(&self, _vm: &BearVM<DynDebugger>, _cell: bear_vm::vm::Cell) {}
+        fn store(&self, address: bear_vm::vm::Cell, value: bear_vm::vm::Cell) {
+            self.0.store(address.0 as usize, value.0, false);
+        }
+    }
+
+    #[test]
+    fn test_state_diff() -> Result<(), Error> {
+        use bear_vm::diff::{StackDiff, Write};
+        let source = "
+            lit lit store lit
+            d32 8
+            d32 5
+            d32 &next
+            jump
+            ===:next
+            halt
+        ";
+        let diffs = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let recorder = DiffRecorder(Default::default(), diffs.clone());
+        run_with(source, |vm| vm.with_callback_debugger(Box::new(recorder)))?;
+
+        let diffs = diffs.borrow();
+        assert_eq!(diffs.len(), 5);
+        assert_eq!(diffs[1].data, StackDiff { popped: vec![], pushed: vec![5] });
+        assert_eq!(diffs[2].data, StackDiff { popped: vec![8, 5], pushed: vec![] });
+        assert_eq!(diffs[2].writes, vec![Write { address: 8, value: 5, byte: false }]);
+        assert!(diffs[..4].iter().all(|diff| !diff.jumped));
+        assert!(diffs[4].jumped);
+        assert_eq!((diffs[4].from, diffs[4].to), (16, 20));
+        Ok(())
+    }
+
+    #[test]
+    fn test_events() -> Result<(), Error> {
+        use bear_vm::vm::{Event, HaltReason};
+        let observe = |source: &str| -> Result<Vec<Event>, Error> {
+            let events = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            let log = events.clone();
+            let mut state = start_with(source, |vm| {
+                vm.with_observer(Box::new(move |event: &Event| log.borrow_mut().push(event.clone())))
+                    .with_device(Box::new(BusyDevice(0..0)))
+            })?;
+            state.run().ok();
+            let events = events.borrow().clone();
+            Ok(events)
+        };
+        assert_eq!(observe("nop brk halt")?, vec![
+            Event::DeviceAttached { id: 0 },
+            Event::Started,
+            Event::BreakpointHit { ip: 1 },
+            Event::Halted { reason: HaltReason::Halt },
+        ]);
+        assert_eq!(observe("nop drop halt")?, vec![
+            Event::DeviceAttached { id: 0 },
+            Event::Started,
+            Event::Trap { ip: 1, message: "Data stack underflow.".into() },
+            Event::Halted { reason: HaltReason::Trap },
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuel() -> Result<(), Error> {
+        use bear_vm::vm::{Event, HaltReason};
+        let spin = |configure: fn(BearVM) -> BearVM| -> Result<(u64, Vec<Event>), Error> {
+            let events = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+            let log = events.clone();
+            let state = run_with("nop\n===:spin\njump &spin", |vm| {
+                configure(vm).with_observer(Box::new(move |event: &Event| log.borrow_mut().push(event.clone())))
+            })?;
+            assert!(state.running);
+            let events = events.borrow().clone();
+            Ok((state.executed(), events))
+        };
+        let (executed, events) = spin(|vm| vm.with_fuel(100))?;
+        assert_eq!(executed, 100);
+        assert_eq!(events.last(), Some(&Event::Halted { reason: HaltReason::OutOfFuel }));
+
+        let (_, events) = spin(|vm| vm.with_timeout(std::time::Duration::ZERO))?;
+        assert_eq!(events.last(), Some(&Event::Halted { reason: HaltReason::TimedOut }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_core_dump() -> Result<(), Error> {
+        use bear_vm::coredump::CoreDump;
+        let path = std::env::temp_dir().join(format!("bear-ass-core-{}", std::process::id()));
+        let state = run_with("nop\npush 7\npush 0xFFFFFFFF\nhalt", |vm| vm.with_core_path(&path))?;
+        let bytes = std::fs::read(&path)?;
+        std::fs::remove_file(&path).ok();
+        let dump = CoreDump::decode(&bytes).expect("A malformed core dump.");
+        assert_eq!(dump, CoreDump::of(&state));
+        assert_eq!(dump.data, vec![7, u32::MAX]);
+        assert_eq!(dump.image, state.vm.image);
+        assert_eq!(CoreDump::decode(&bytes[..bytes.len() - 1]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoints() -> Result<(), Error> {
+        let source = "nop\npush &a\npush 5\nstore\npush &b\npush 6\nstore\nhalt\n===:a\nd32 1\n===:b\nd32 2";
+        let mut state = start_with(source, |vm| vm.with_predecode())?;
+        let original = state.vm.image.clone();
+        let start = state.checkpoint();
+        for _ in 0..4 {
+            state.step().map_err(vm_error)?;
+        }
+        let stored = state.vm.image.clone();
+        let middle = state.checkpoint();
+        state.run().map_err(vm_error)?;
+        let halted = state.vm.image.clone();
+        assert!(!state.running);
+
+        state.rollback(middle).map_err(vm_error)?;
+        assert_eq!(state.vm.image, stored);
+        assert!(state.running);
+        state.run().map_err(vm_error)?;
+        assert_eq!(state.vm.image, halted);
+
+        state.rollback(start).map_err(vm_error)?;
+        assert_eq!(state.vm.image, original);
+        assert_eq!((state.ip(), state.executed(), state.vm.data.len()), (0, 0, 0));
+        // Rolling back to `start` released `middle`.
+        assert!(state.rollback(middle).is_err());
+        state.run().map_err(vm_error)?;
+        assert_eq!(state.vm.image, halted);
+
+        // Releasing a checkpoint hands the pages it saved to the one before it.
+        state.rollback(start).map_err(vm_error)?;
+        let middle = state.checkpoint();
+        state.run().map_err(vm_error)?;
+        state.release(middle).map_err(vm_error)?;
+        assert_eq!(state.vm.image, halted);
+        state.rollback(start).map_err(vm_error)?;
+        assert_eq!(state.vm.image, original);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_memory() -> Result<(), Error> {
+        use bear_vm::shared::SharedMemory;
+        let shared = SharedMemory::new(0x10000, 4);
+        let spawn = |source: &str| -> Result<_, Error> {
+            let (image, _) = bear_ass::assemble(source)?;
+            let vm = BearVM::new_sendable(bear_vm::util::convert_slice8_to_vec32(&image))
+                .with_shared_memory(shared.clone());
+            Ok(std::thread::spawn(move || {
+                let mut state = vm.start().unwrap();
+                state.run().unwrap();
+                state.vm.data.iter().map(|cell| cell.0).collect::<Vec<_>>()
+            }))
+        };
+        // The store of the flag is seen after the store of the value before it.
+        let producer = spawn("nop\npush 0x10000\npush 42\nstore\npush 0x10004\npush 1\nstore\npush 0x10008\npush 1\nstore.8\nhalt")?;
+        let consumer = spawn("nop\n===:wait\npush 0x10004\nload\nifz:jump &wait\npush 0x10000\nload\npush 0x10009\npush 2\nstore.8\nhalt")?;
+        assert_eq!(producer.join().unwrap(), Vec::<u32>::new());
+        assert_eq!(consumer.join().unwrap(), vec![42]);
+        // Neither byte store undid the other.
+        assert_eq!(shared.load(0x10008), Some(0x0201));
+        assert_eq!(shared.load(0x10010), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mailbox() -> Result<(), Error> {
+        use bear_vm::device::{GenericDeviceCommand, MailboxCommand};
+        use bear_vm::shared::MailboxDevice;
+        let exec = |command: MailboxCommand| GenericDeviceCommand::Execute { command: command as u8, argument: 0 }.encode();
+        let (send, receive) = (exec(MailboxCommand::SendCell), exec(MailboxCommand::ReceiveCell));
+        let (set, waiting) = (GenericDeviceCommand::set(3, 0).encode(), GenericDeviceCommand::get(5).encode());
+        // Waits until a message is waiting, and then receives its cell.
+        let receive = format!("===:wait\npush 0\npush {waiting}\nio\nifz:jump &wait\npush 0\npush {receive}\nio");
+        let (a, b) = MailboxDevice::pair();
+        let vm = |source: String, end: MailboxDevice| -> Result<_, Error> {
+            let (image, _) = bear_ass::assemble(&source)?;
+            Ok(BearVM::new_sendable(bear_vm::util::convert_slice8_to_vec32(&image))
+                .with_device(Box::new(end))
+                .with_fuel(1_000_000))
+        };
+        // One VM sends 42, and the other answers with one more.
+        let asker = vm(format!("nop\npush 0\npush {}\nio\ndrop\npush 0\npush {send}\nio\ndrop\n{receive}\nhalt", set + 42), a)?;
+        let answerer = vm(format!("nop\n{receive}\npush 1\nadd\npush {set}\nadd\npush 0\nswap\nio\ndrop\npush 0\npush {send}\nio\ndrop\nhalt"), b)?;
+        let [asker, answerer] = [asker, answerer].map(|vm| {
+            std::thread::spawn(move || {
+                let mut state = vm.start().unwrap();
+                state.run().unwrap();
+                assert!(!state.running, "Out of fuel.");
+                state.vm.data.iter().map(|cell| cell.0).collect::<Vec<_>>()
+            })
+        });
+        assert_eq!(answerer.join().unwrap(), Vec::<u32>::new());
+        assert_eq!(asker.join().unwrap(), vec![43]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_syscalls() -> Result<(), Error> {
+        use bear_vm::vm::Cell;
+        let run = |source: &str| -> Result<Result<Vec<u32>, String>, Error> {
+            let mut state = start_with(source, |vm| {
+                vm.with_syscall(
+                    7,
+                    Box::new(|data| {
+                        let (a, b) = (data.pop().ok_or("underflow")?, data.pop().ok_or("underflow")?);
+                        data.push(Cell(a.0 + b.0)).map_err(|_| String::from("overflow"))
+                    }),
+                )
+                .with_syscall(8, Box::new(|_| Err(String::from("No such file."))))
+            })?;
+            Ok(state
+                .run()
+                .map(|_| state.vm.data.iter().map(|cell| cell.0).collect())
+                .map_err(|e| e.message().to_string()))
+        };
+        assert_eq!(run("nop\npush 2\npush 3\npush 7\nsys\nhalt")?, Ok(vec![5]));
+        assert_eq!(run("nop\npush 8\nsys\nhalt")?, Err(String::from("No such file.")));
+        assert_eq!(run("nop\npush 9\nsys\nhalt")?, Err(String::from("No handler for syscall 9.")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_opcode_mnemonics() -> Result<(), Error> {
+        use bear_vm::vm::OpCode;
+        for (&op, info) in OpCode::ALL.iter().zip(OpCode::TABLE) {
+            assert_eq!(info.opcode, op);
+            assert_eq!(OpCode::from_mnemonic(&op.to_string()), Some(op));
+            let (image, _) = bear_ass::assemble(&op.to_string())?;
+            assert_eq!(image[0], op.into_u8(), "{}", op);
+            let decoded = OpCode::decode(image[0]).map(|op| op.mnemonic());
+            if cfg!(feature = "float") || op.category() != bear_vm::vm::Category::Float {
+                assert_eq!(decoded, Some(op.mnemonic()));
+            } else {
+                assert_eq!(decoded, None);
+            }
+        }
+        assert_eq!(OpCode::from_mnemonic("loads"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_opcode_from_str() {
+        use bear_vm::vm::{OpCode, UnknownMnemonic};
+        assert_eq!("ifz:jump".parse::<OpCode>(), Ok(OpCode::JumpIfZ));
+        assert_eq!("lit".parse::<OpCode>(), Ok(OpCode::Lit));
+        let error = "loads".parse::<OpCode>().unwrap_err();
+        assert_eq!(error, UnknownMnemonic(String::from("loads")));
+        assert_eq!(error.to_string(), "unknown opcode `loads`");
+    }
+
+    #[test]
+    fn test_opcode_all() {
+        use bear_vm::vm::OpCode;
+        let all: Vec<OpCode> = OpCode::all().collect();
+        assert_eq!(all, OpCode::ALL);
+        assert_eq!(all.first(), Some(&OpCode::Nop));
+        assert!(all.iter().all(|op| op.mnemonic().parse() == Ok(*op)));
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        use bear_vm::vm::{Category, OpCode};
+        assert_eq!(OpCode::TABLE.len(), OpCode::ALL.len());
+        for (info, &op) in OpCode::TABLE.iter().zip(OpCode::ALL) {
+            assert_eq!(info.opcode, op);
+            assert_eq!(op.info(), info);
+        }
+        let operands: Vec<OpCode> = OpCode::ALL.iter().copied().filter(|op| op.has_operand()).collect();
+        assert_eq!(operands, vec![OpCode::Lit]);
+        assert_eq!(OpCode::Io.category(), Category::Io);
+        assert_eq!(OpCode::Call.stack_effect().address_out, 1);
+        // Images depend on these encodings, so new opcodes go at the end.
+        assert_eq!(OpCode::Nop.into_u8(), 0);
+        assert_eq!(OpCode::Sys.into_u8(), 38);
+        assert_eq!(OpCode::Min.into_u8(), 51);
+        assert_eq!(OpCode::Negate.into_u8(), 54);
+        assert_eq!(OpCode::Halt.into_u8(), 0x7F);
+    }
+
+    #[test]
+    fn test_assemble_stream() -> Result<(), Error> {
+        let source = "
+            lit jump
+            ===
+            d32 &main
+            #at 0x20;
+            ===:main
+            lit halt
+            d8 1
+            #section ram 0x100;
+            d32 2
+        ";
+        let mut image = Vec::new();
+        bear_ass::assemble_stream(source.as_bytes(), &mut image, Default::default())?;
+        let (expected, _) = bear_ass::assemble(source)?;
+        assert_eq!(image, expected);
+        Ok(())
+    }
 }
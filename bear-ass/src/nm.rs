@@ -0,0 +1,61 @@
+use std::env;
+
+use bear_ass::debug::Resolver;
+use bear_ass::parser::ast;
+use bear_ass::Error;
+
+const USAGE: &str = "bear-nm v1.0\n\
+\n\
+USAGE: bear-nm [-n] [--prefix prefix] debug-file\n\
+\n\
+Lists the labels of a program with their addresses, in hex, one per line, sorted by name.\n\
+\n\
+OPTIONS:\n\
+    -n                Sort by address instead, and by name at the same address.\n\
+    --prefix prefix   List only the labels which start with prefix, e.g. math:.\n";
+
+fn main() {
+    match go() {
+        Ok(()) => std::process::exit(0),
+        Err(Error::Usage) => eprintln!("{}", USAGE),
+        Err(error) => eprintln!("error: {}", error),
+    }
+    std::process::exit(-2)
+}
+
+fn go() -> Result<(), Error> {
+    let mut by_address = false;
+    let mut prefix = String::new();
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-n" {
+            by_address = true;
+        } else if arg == "--prefix" {
+            prefix = args.next().ok_or(Error::Usage)?;
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 1 {
+        return Err(Error::Usage);
+    }
+
+    let file = std::fs::File::open(&positional[0]).map_err(Error::IOError)?;
+    let debug: ast::Debug =
+        serde_json::from_reader(std::io::BufReader::new(file)).map_err(Error::SerdeError)?;
+    let resolver = Resolver::new(&debug);
+    let mut labels: Vec<(&str, usize)> = resolver
+        .labels()
+        .filter(|(name, _)| name.starts_with(&prefix))
+        .collect();
+    if by_address {
+        labels.sort_by_key(|&(name, address)| (address, name));
+    } else {
+        labels.sort();
+    }
+    for (name, address) in labels {
+        println!("{:08x} {}", address, name);
+    }
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
@@ -57,16 +57,16 @@ impl Size {
 #[derive(Clone, Debug)]
 pub enum Data {
     D(Size, Expression),
-    Str(StringTag, String),
+    Str(StringTag, Vec<u8>),
 }
 
 impl Data {
     pub fn size_in_bytes(&self) -> usize {
         match self {
             Data::D(size, _) => size.size_in_bytes(),
-            Data::Str(StringTag::R, content) => content.bytes().len(),
-            Data::Str(StringTag::C, content) => content.bytes().len() + 1,
-            Data::Str(StringTag::S, content) => content.bytes().len() + 4,
+            Data::Str(StringTag::R, content) => content.len(),
+            Data::Str(StringTag::C, content) => content.len() + 1,
+            Data::Str(StringTag::S, content) => content.len() + 4,
         }
     }
 }
@@ -80,10 +80,70 @@ pub enum Directive {
     AlignTo(Expression),
     /// Include the source file located at the given path.
     Include(PathBuf),
+    /// Include the named file of the standard library.
+    IncludeLibrary(PathBuf),
     /// Define a macro-block..
     DefineList(String, Vec<LineBody>),
     /// Define a macro-expression.
     DefineExpression(String, Expression),
+    /// Switch to the named section, creating it at the given address if it is new.
+    Section(String, Option<Expression>, Option<SectionKind>),
+    /// Register a string in the host string table, and define the name as its id.
+    Intern(String, Vec<u8>),
+    /// Open, continue or close a block of structured control flow.
+    ControlFlow(ControlFlow),
+}
+
+/// The directives of structured control flow, which expand to branches to generated labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlFlow {
+    /// Pops a flag, and runs the block if it is not zero.
+    If,
+    /// Runs the rest of an `#if` block if the flag was zero.
+    Else,
+    /// Begins a loop whose condition is computed before `#do`.
+    While,
+    /// Pops the flag of a `#while`, and leaves the loop if it is zero.
+    Do,
+    /// Begins a loop which runs until `#until`, or forever if closed by `#end`.
+    Loop,
+    /// Pops a flag, and repeats the loop if it is zero.
+    Until,
+    /// Closes an `#if`, `#while` or `#loop` block.
+    End,
+}
+
+impl std::fmt::Display for ControlFlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ControlFlow::If => "if",
+            ControlFlow::Else => "else",
+            ControlFlow::While => "while",
+            ControlFlow::Do => "do",
+            ControlFlow::Loop => "loop",
+            ControlFlow::Until => "until",
+            ControlFlow::End => "end",
+        };
+        write!(f, "#{};", name)
+    }
+}
+
+/// What a section holds, which decides how alignment is padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    /// Padded with `nop`s, so that execution can fall through alignment.
+    Code,
+    /// Padded with zeros.
+    Data,
+}
+
+impl std::fmt::Display for SectionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SectionKind::Code => write!(f, "code"),
+            SectionKind::Data => write!(f, "data"),
+        }
+    }
 }
 
 /// A program line.
@@ -94,6 +154,9 @@ pub struct Line {
     pub labels: Vec<String>,
     pub body: LineBody,
     pub number: usize,
+    pub column: usize,
+    /// The bytes of the source taken by the body of the line, leaving out its labels.
+    pub span: std::ops::Range<usize>,
 }
 
 /// The body of a program line.
@@ -101,6 +164,9 @@ pub struct Line {
 pub enum LineBody {
     Data(Data),
     Simple(vm::OpCode),
+    /// A pseudo-instruction, `op expr`, which pushes `expr` with a `lit` and then executes `op`.
+    /// `push expr` only pushes `expr`.
+    Pseudo(vm::OpCode, Expression),
     Directive(Directive),
     DefinitionRef(String),
     // Comment(String),
@@ -180,11 +246,11 @@ impl Primitive {
         if x <= u8::MAX as i64 {
             1
         } else if x <= u16::MAX as i64 {
-            return 2;
+            2
         } else if x <= u32::MAX as i64 {
-            return 4;
+            4
         } else {
-            return 8;
+            8
         }
     }
 
@@ -250,6 +316,7 @@ impl Primitive {
     }
 }
 
+#[allow(clippy::should_implement_trait)]
 impl Primitive {
     pub fn add(self, other: Self) -> Self {
         Primitive(self.0 + other.0)
@@ -316,6 +383,7 @@ impl std::fmt::Display for Directive {
             // TODO: Directive::Repeat(expr, data) => write!(f, "{} {}", data, expr),
             Directive::AlignTo(expr) => write!(f, "#align \"{}\";", expr),
             Directive::Include(path) => write!(f, "#include \"{}\";", path.display()),
+            Directive::IncludeLibrary(path) => write!(f, "#include <{}>;", path.display()),
             Directive::DefineList(name, lines) => {
                 write!(f, "#define {} [", name)?;
                 for line in lines.iter() {
@@ -325,6 +393,20 @@ impl std::fmt::Display for Directive {
                 write!(f, "];")
             }
             Directive::DefineExpression(name, expr) => write!(f, "#define {} {};", name, expr),
+            Directive::Section(name, base, kind) => {
+                write!(f, "#section {}", name)?;
+                if let Some(base) = base {
+                    write!(f, " {}", base)?;
+                }
+                if let Some(kind) = kind {
+                    write!(f, " {}", kind)?;
+                }
+                write!(f, ";")
+            }
+            Directive::Intern(name, text) => {
+                write!(f, "#intern {} \"{}\";", name, text.escape_ascii())
+            }
+            Directive::ControlFlow(control) => control.fmt(f),
         }
     }
 }
@@ -347,9 +429,9 @@ impl std::fmt::Display for Data {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Data::D(size, expr) => write!(f, "d{} {}", size.size_in_bits(), expr),
-            Data::Str(StringTag::C, content) => write!(f, "c\"{}\"", content),
-            Data::Str(StringTag::R, content) => write!(f, "r\"{}\"", content),
-            Data::Str(StringTag::S, content) => write!(f, "s\"{}\"", content),
+            Data::Str(StringTag::C, content) => write!(f, "c\"{}\"", content.escape_ascii()),
+            Data::Str(StringTag::R, content) => write!(f, "r\"{}\"", content.escape_ascii()),
+            Data::Str(StringTag::S, content) => write!(f, "s\"{}\"", content.escape_ascii()),
         }
     }
 }
@@ -359,6 +441,11 @@ impl std::fmt::Display for LineBody {
         match self {
             LineBody::Data(data) => data.fmt(f)?,
             LineBody::Simple(opcode) => opcode.fmt(f)?,
+            // A lone `!name` operand is parenthesized, as it would otherwise read as a list.
+            LineBody::Pseudo(opcode, operand @ Expression::DefinitionRef(_)) => {
+                write!(f, "{} ({})", opcode, operand)?
+            }
+            LineBody::Pseudo(opcode, operand) => write!(f, "{} {}", opcode, operand)?,
             LineBody::Directive(directive) => directive.fmt(f)?,
             LineBody::DefinitionRef(name) => write!(f, "!{}", name)?,
         };
@@ -392,6 +479,38 @@ impl std::fmt::Display for Program {
 pub struct Debug {
     pub body: Vec<DebugLine>,
     pub entries: Vec<DebugEntry>,
+    /// The strings registered with `#intern`, indexed by id.
+    #[serde(default)]
+    pub strings: Vec<String>,
+    /// The CRC-32 of the ROM image the info describes, so it is not used with another image.
+    #[serde(default)]
+    pub checksum: Option<u32>,
+    /// The text of the program and of the files it includes, if it was embedded.
+    #[serde(default)]
+    pub sources: Vec<DebugSource>,
+}
+
+impl Debug {
+    /// The text of a line of the program, or of an included file, if the source was embedded.
+    pub fn source_line(&self, file: Option<&Path>, line: LineNumber) -> Option<&str> {
+        let source = self.sources.iter().find(|s| s.file.as_deref() == file)?;
+        source.line(line)
+    }
+}
+
+/// The text of a source file, as it was assembled.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DebugSource {
+    /// The included file, or `None` for the program itself.
+    pub file: Option<PathBuf>,
+    pub text: String,
+}
+
+impl DebugSource {
+    /// The text of a line, counting from 1.
+    pub fn line(&self, line: LineNumber) -> Option<&str> {
+        self.text.lines().nth(line.checked_sub(1)?)
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
@@ -408,9 +527,18 @@ pub struct DebugLine {
     pub content: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DebugEntry {
+    /// The included file the line is in, or `None` for the file being assembled.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
     pub line: LineNumber,
+    /// The column the line starts at, counting from 1, as in error messages.
+    #[serde(default)]
+    pub column: usize,
+    /// The bytes of the file taken by the body of the line, e.g. to highlight the instruction.
+    #[serde(default)]
+    pub span: std::ops::Range<usize>,
     pub address: LineAddress,
     pub names: Vec<String>,
 }
@@ -0,0 +1,77 @@
+use std::str::Chars;
+
+/// A single unit of a string or character literal.
+enum Unit {
+    /// A raw byte, written as `\xNN`.
+    Byte(u8),
+    /// A unicode scalar value, written literally or as an escape sequence.
+    Char(char),
+}
+
+/// Scans the next unit of a literal, resolving the escape sequence if one starts here.
+///
+/// Supported escapes are `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'`, `\xNN` and `\u{...}`.
+fn next_unit(chars: &mut Chars) -> Result<Option<Unit>, String> {
+    let c = match chars.next() {
+        None => return Ok(None),
+        Some('\\') => chars.next().ok_or("Unterminated escape sequence.")?,
+        Some(c) => return Ok(Some(Unit::Char(c))),
+    };
+    Ok(Some(match c {
+        'n' => Unit::Char('\n'),
+        't' => Unit::Char('\t'),
+        'r' => Unit::Char('\r'),
+        '0' => Unit::Char('\0'),
+        '\\' => Unit::Char('\\'),
+        '"' => Unit::Char('"'),
+        '\'' => Unit::Char('\''),
+        'x' => {
+            let digits: String = chars.take(2).collect();
+            let byte = u8::from_str_radix(&digits, 16)
+                .map_err(|_| format!("Invalid escape sequence '\\x{}'.", digits))?;
+            Unit::Byte(byte)
+        }
+        'u' => {
+            let rest = chars.as_str();
+            let end = rest.find('}').ok_or("Unterminated escape sequence '\\u{'.")?;
+            if !rest.starts_with('{') {
+                return Err(String::from("Expected '{' after '\\u'."));
+            }
+            let digits = &rest[1..end];
+            let c = u32::from_str_radix(digits, 16)
+                .ok()
+                .and_then(std::char::from_u32)
+                .ok_or_else(|| format!("Invalid escape sequence '\\u{{{}}}'.", digits))?;
+            *chars = rest[end + 1..].chars();
+            Unit::Char(c)
+        }
+        c => return Err(format!("Unknown escape sequence '\\{}'.", c)),
+    }))
+}
+
+/// Resolves the escape sequences in the body of a string literal.
+pub fn unescape_string(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(unit) = next_unit(&mut chars)? {
+        match unit {
+            Unit::Byte(b) => bytes.push(b),
+            Unit::Char(c) => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Resolves the body of a character literal to its value.
+pub fn unescape_char(text: &str) -> Result<u32, String> {
+    let mut chars = text.chars();
+    let value = match next_unit(&mut chars)? {
+        Some(Unit::Byte(b)) => b as u32,
+        Some(Unit::Char(c)) => c as u32,
+        None => return Err(String::from("Empty character literal.")),
+    };
+    if chars.next().is_some() {
+        return Err(format!("Character literal '{}' is too long.", text));
+    }
+    Ok(value)
+}
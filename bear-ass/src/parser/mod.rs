@@ -5,6 +5,7 @@ use pest_derive::Parser;
 use bear_vm::vm;
 
 pub mod ast;
+mod escape;
 
 #[derive(Parser)]
 #[grammar = "bearasm.pest"] // relative to src
@@ -51,17 +52,36 @@ impl Error {
     }
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{}: {}", position, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug)]
 pub struct Position {
     pub line: usize,
     pub column: usize,
 }
 
-fn get_line_number(pair: &Pair<Rule>) -> usize {
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+fn get_line_col(pair: &Pair<Rule>) -> (usize, usize) {
+    pair.as_span().start_pos().line_col()
+}
+
+fn get_span(pair: &Pair<Rule>) -> std::ops::Range<usize> {
     let span = pair.as_span();
-    let start = span.start_pos();
-    let (number, _) = start.line_col();
-    number
+    span.start()..span.end()
 }
 
 pub struct Parser {}
@@ -74,6 +94,14 @@ impl Parser {
         self.parse_start(start)
     }
 
+    /// Parses a lone expression, such as the value of a definition given on the command line.
+    pub fn parse_expression_str(mut self, text: &str) -> Result<ast::Expression, Error> {
+        let mut start =
+            G::parse(Rule::start_expression, text).map_err(|e| Error::unknown(&e))?;
+        let expression = start.next().unwrap().into_inner().next().unwrap();
+        self.parse_expression(expression)
+    }
+
     fn parse_start(&mut self, mut start: Pairs<Rule>) -> Result<ast::Program, Error> {
         let mut body = Vec::new();
         let mut program = start.next().unwrap().into_inner();
@@ -87,14 +115,16 @@ impl Parser {
     }
 
     fn parse_line(&mut self, line: Pair<Rule>) -> Result<ast::Line, Error> {
-        let number = get_line_number(&line);
+        let (number, column) = get_line_col(&line);
         let line = line.into_inner().next().unwrap();
         match line.as_rule() {
             Rule::meta => Ok(ast::Line {
                 mark: false,
                 labels: Vec::new(),
+                span: get_span(&line),
                 body: self.parse_meta(line)?,
                 number,
+                column,
             }),
             Rule::normal => self.parse_normal(line),
             _ => Err(Error::unsupported(&line).with_position_from_pair(&line)),
@@ -115,7 +145,7 @@ impl Parser {
     }
 
     fn parse_normal(&mut self, line: Pair<Rule>) -> Result<ast::Line, Error> {
-        let number = get_line_number(&line);
+        let (number, column) = get_line_col(&line);
         let mut mark = false;
         let mut labels = Vec::new();
         let mut line = line.into_inner();
@@ -129,12 +159,15 @@ impl Parser {
                 labels.push(String::from(&lstr[1..]));
             }
         }
+        let span = get_span(&body);
         let body = self.parse_normal_body(body)?;
         Ok(ast::Line {
             mark,
             labels,
             body,
             number,
+            column,
+            span,
         })
     }
 
@@ -143,6 +176,12 @@ impl Parser {
             Rule::data => ast::LineBody::Data(self.parse_data(line)?),
             Rule::definition_ref => ast::LineBody::DefinitionRef(line.as_str()[1..].to_string()),
             Rule::instruction => ast::LineBody::Simple(self.parse_opcode(line.as_str())?),
+            Rule::pseudo => {
+                let mut inner = line.into_inner();
+                let op = self.parse_opcode(inner.next().unwrap().as_str())?;
+                let operand = inner.next().unwrap().into_inner().next().unwrap();
+                ast::LineBody::Pseudo(op, self.parse_expression(operand)?)
+            }
             _ => {
                 return Err(Error::unsupported(&line).with_position_from_pair(&line));
             }
@@ -157,6 +196,15 @@ impl Parser {
             "#align" => self.parse_command_align(name, directive),
             "#define" => self.parse_command_define(name, directive),
             "#include" => self.parse_command_include(name, directive),
+            "#section" => self.parse_command_section(name, directive),
+            "#intern" => self.parse_command_intern(name, directive),
+            "#if" => self.parse_command_control(name, directive, ast::ControlFlow::If),
+            "#else" => self.parse_command_control(name, directive, ast::ControlFlow::Else),
+            "#while" => self.parse_command_control(name, directive, ast::ControlFlow::While),
+            "#do" => self.parse_command_control(name, directive, ast::ControlFlow::Do),
+            "#loop" => self.parse_command_control(name, directive, ast::ControlFlow::Loop),
+            "#until" => self.parse_command_control(name, directive, ast::ControlFlow::Until),
+            "#end" => self.parse_command_control(name, directive, ast::ControlFlow::End),
             // TODO:
             // "#repeat" => self.parse_command_repeat(name, directive),
             _ => Err(Error::unknown(&name.as_str()).with_position_from_pair(&name)),
@@ -195,14 +243,14 @@ impl Parser {
         match definition.as_rule() {
             Rule::argument_list => {
                 let list = self.parse_argument_list(definition)?;
-                return Ok(ast::Directive::DefineList(name.as_str().to_string(), list));
+                Ok(ast::Directive::DefineList(name.as_str().to_string(), list))
             }
             _ => {
                 let expression = self.parse_expression(definition)?;
-                return Ok(ast::Directive::DefineExpression(
+                Ok(ast::Directive::DefineExpression(
                     name.as_str().to_string(),
                     expression,
-                ));
+                ))
             }
         }
     }
@@ -212,10 +260,70 @@ impl Parser {
         directive: Pair<Rule>,
         mut arguments: Pairs<Rule>,
     ) -> Result<ast::Directive, Error> {
-        let first = expect_argument(&directive, arguments.next())?.as_str();
+        let first = expect_argument(&directive, arguments.next())?;
         expect_no_argument(&directive, arguments, 1)?;
-        let path = std::path::PathBuf::from(&first[1..first.len() - 1]);
-        Ok(ast::Directive::Include(path))
+        let text = first.as_str();
+        let path = std::path::PathBuf::from(&text[1..text.len() - 1]);
+        match first.as_rule() {
+            Rule::library_path => Ok(ast::Directive::IncludeLibrary(path)),
+            _ => Ok(ast::Directive::Include(path)),
+        }
+    }
+
+    fn parse_command_section(
+        &mut self,
+        directive: Pair<Rule>,
+        mut arguments: Pairs<Rule>,
+    ) -> Result<ast::Directive, Error> {
+        let name = expect(directive, Rule::identifier, arguments.next())?;
+        let mut arguments = arguments.peekable();
+        let base = match arguments.peek() {
+            Some(base) if base.as_rule() != Rule::identifier => {
+                Some(self.parse_expression(arguments.next().unwrap())?)
+            }
+            _ => None,
+        };
+        let kind = match arguments.next() {
+            None => None,
+            Some(kind) => Some(match kind.as_str() {
+                "code" => ast::SectionKind::Code,
+                "data" => ast::SectionKind::Data,
+                other => {
+                    let message = format!("Expected 'code' or 'data', found '{}'.", other);
+                    return Err(Error::from_message(&message).with_position_from_pair(&kind));
+                }
+            }),
+        };
+        let count = arguments.count();
+        if count != 0 {
+            let message = format!("Expected at most 3 arguments, but found {}.", 3 + count);
+            return Err(Error::from_message(&message).with_position_from_pair(&name));
+        }
+        Ok(ast::Directive::Section(name.as_str().to_string(), base, kind))
+    }
+
+    fn parse_command_intern(
+        &mut self,
+        directive: Pair<Rule>,
+        mut arguments: Pairs<Rule>,
+    ) -> Result<ast::Directive, Error> {
+        let name = expect(directive, Rule::identifier, arguments.next())?;
+        let text = expect(name.clone(), Rule::raw_string, arguments.next())?;
+        expect_no_argument(&name, arguments, 2)?;
+        let s = text.as_str();
+        let text = escape::unescape_string(&s[1..s.len() - 1])
+            .map_err(|e| Error::from_message(&e).with_position_from_pair(&text))?;
+        Ok(ast::Directive::Intern(name.as_str().to_string(), text))
+    }
+
+    fn parse_command_control(
+        &mut self,
+        directive: Pair<Rule>,
+        arguments: Pairs<Rule>,
+        control: ast::ControlFlow,
+    ) -> Result<ast::Directive, Error> {
+        expect_no_argument(&directive, arguments, 0)?;
+        Ok(ast::Directive::ControlFlow(control))
     }
 
     fn parse_argument_list(&mut self, list: Pair<Rule>) -> Result<Vec<ast::LineBody>, Error> {
@@ -254,7 +362,20 @@ impl Parser {
             Rule::value => {
                 let mut data = data.clone().into_inner();
                 let size = data.next().unwrap();
-                let expr = self.parse_expression(data.next().unwrap())?;
+                let value = data.next().unwrap();
+                let expr = if value.as_rule() == Rule::number_float {
+                    if size.as_str() != "d32" {
+                        let message = "Only d32 takes a float literal.";
+                        return Err(Error::from_message(message).with_position_from_pair(&value));
+                    }
+                    let float: f32 = value
+                        .as_str()
+                        .parse()
+                        .map_err(|e| Error::unknown(&e).with_position_from_pair(&value))?;
+                    ast::Primitive::from(float.to_bits() as i64).to_expr()
+                } else {
+                    self.parse_expression(value)?
+                };
                 match size.as_str() {
                     "d8" => ast::Data::D(ast::Size::S8, expr),
                     "d16" => ast::Data::D(ast::Size::S16, expr),
@@ -266,16 +387,11 @@ impl Parser {
         })
     }
 
-    fn parse_string(&mut self, string: Pair<Rule>) -> Result<String, Error> {
-        // let (start, finish) = string.as_span().split();
-        // let hint = finish.pos() - start.pos();
-        let nl_regex = regex::Regex::new(r"[^\\]\\n").unwrap();
-        let slash_regex = regex::Regex::new(r"\\\\").unwrap();
-        let s = string.into_inner().next().unwrap().as_str();
-        let s = nl_regex.replace_all(s, "\n").to_string();
-        let s = slash_regex.replace_all(&s, "\\").to_string();
-        let len = s.len();
-        Ok(s[2..len - 1].to_string())
+    fn parse_string(&mut self, string: Pair<Rule>) -> Result<Vec<u8>, Error> {
+        let raw = string.into_inner().next().unwrap().into_inner().next().unwrap();
+        let s = raw.as_str();
+        escape::unescape_string(&s[1..s.len() - 1])
+            .map_err(|e| Error::from_message(&e).with_position_from_pair(&raw))
     }
 
     fn parse_expression(&mut self, expr: Pair<Rule>) -> Result<ast::Expression, Error> {
@@ -304,7 +420,7 @@ impl Parser {
 
     fn parse_expression_leaf(&mut self, leaf: Pair<Rule>) -> Result<ast::Expression, Error> {
         Ok(match leaf.as_rule() {
-            Rule::r#char => ast::Primitive::from(self.parse_char(leaf)? as u32).to_expr(),
+            Rule::r#char => ast::Primitive::from(self.parse_char(leaf)?).to_expr(),
             Rule::number => ast::Expression::Primitive(self.parse_number(leaf)?),
             Rule::address => ast::Expression::Address(self.parse_address(leaf)?),
             Rule::quoted => {
@@ -330,19 +446,21 @@ impl Parser {
 
     fn parse_number(&mut self, number: Pair<Rule>) -> Result<ast::Primitive, Error> {
         let number = number.into_inner().next().unwrap();
-        match number.as_rule() {
-            Rule::number_dec => {
-                Ok(ast::Primitive::from::<i64>(
-                    number.as_str().parse().map_err(|e| Error::unknown(&e))?,
-                ))
-            }
-            Rule::number_hex => {
-                let strip = number.as_str().trim_start_matches("0x");
-                let n = i64::from_str_radix(strip, 16).map_err(|e| Error::unknown(&e))?;
-                Ok(ast::Primitive::from(n))
-            }
+        let (prefix, radix) = match number.as_rule() {
+            Rule::number_dec => ("", 10),
+            Rule::number_hex => ("0x", 16),
+            Rule::number_bin => ("0b", 2),
+            Rule::number_oct => ("0o", 8),
             rule => panic!("unreachable: {:?}", rule),
-        }
+        };
+        // Digit separators are purely visual.
+        let digits: String = number.as_str()[prefix.len()..]
+            .chars()
+            .filter(|c| *c != '_')
+            .collect();
+        let n = i64::from_str_radix(&digits, radix)
+            .map_err(|e| Error::unknown(&e).with_position_from_pair(&number))?;
+        Ok(ast::Primitive::from(n))
     }
 
     fn parse_address(&mut self, address: Pair<Rule>) -> Result<ast::Address, Error> {
@@ -357,65 +475,15 @@ impl Parser {
 }
 
 impl Parser {
-    fn parse_char(&mut self, c: Pair<Rule>) -> Result<char, Error> {
+    fn parse_char(&mut self, c: Pair<Rule>) -> Result<u32, Error> {
         assert!(c.as_rule() == Rule::r#char);
-        match c.as_str() {
-            "'\\''" => Ok('\''),
-            "'\\n'" => Ok('\n'),
-            "'\\r'" => Ok('\r'),
-            "'\\t'" => Ok('\t'),
-            string => Ok(string.chars().nth(1).unwrap()),
-        }
+        let s = c.as_str();
+        escape::unescape_char(&s[1..s.len() - 1])
+            .map_err(|e| Error::from_message(&e).with_position_from_pair(&c))
     }
 
     fn parse_opcode(&mut self, text: &str) -> Result<vm::OpCode, Error> {
-        Ok(match text {
-            "halt" => vm::OpCode::Halt,
-            "lit" => vm::OpCode::Lit,
-
-            "add" => vm::OpCode::Add,
-            "sub" => vm::OpCode::Sub,
-            "mul" => vm::OpCode::Mul,
-            "shift" => vm::OpCode::Shift,
-            "div" => vm::OpCode::Div,
-            "mod" => vm::OpCode::Mod,
-
-            "dup" => vm::OpCode::Dup,
-            "drop" => vm::OpCode::Drop,
-            "swap" => vm::OpCode::Swap,
-
-            "call" => vm::OpCode::Call,
-            "jump" => vm::OpCode::Jump,
-            "ret" => vm::OpCode::Return,
-            "ifz:call" => vm::OpCode::CallIfZ,
-            "ifz:jump" => vm::OpCode::JumpIfZ,
-            "ifz:ret" => vm::OpCode::ReturnIfZ,
-            "io" => vm::OpCode::Io,
-
-            "pop" => vm::OpCode::MoveAddrToData,
-            "push" => vm::OpCode::MoveDataToAddr,
-
-            "load" => vm::OpCode::Load,
-            "store" => vm::OpCode::Store,
-            "load.8" => vm::OpCode::Load8,
-            "store.8" => vm::OpCode::Store8,
-            "sext.8" => vm::OpCode::Sext8,
-            "sext.16" => vm::OpCode::Sext16,
-
-            "eq" => vm::OpCode::Equal,
-            "lt" => vm::OpCode::LessThan,
-            "gt" => vm::OpCode::GreaterThan,
-
-            "and" => vm::OpCode::And,
-            "or" => vm::OpCode::Or,
-            "not" => vm::OpCode::Not,
-
-            "nop" => vm::OpCode::Nop,
-
-            _ => {
-                return Err(Error::unknown(&text));
-            }
-        })
+        text.parse::<vm::OpCode>().map_err(|_| Error::unknown(&text))
     }
 }
 
@@ -424,17 +492,16 @@ fn expect<'a>(
     rule: Rule,
     on: Option<Pair<'a, Rule>>,
 ) -> Result<Pair<'a, Rule>, Error> {
-    if on.is_none() {
-        let message = format!("Expected '{:?}'.", rule);
-        Err(Error::from_message(&message).with_position_from_pair(&pair))
-    } else {
-        let on = on.unwrap();
-        if on.as_rule() != rule {
+    match on {
+        None => {
+            let message = format!("Expected '{:?}'.", rule);
+            Err(Error::from_message(&message).with_position_from_pair(&pair))
+        }
+        Some(on) if on.as_rule() != rule => {
             let message = format!("Expected identifier, fonud '{:?}'.", on.as_rule());
             Err(Error::from_message(&message).with_position_from_pair(&pair))
-        } else {
-            Ok(on)
         }
+        Some(on) => Ok(on),
     }
 }
 
@@ -442,11 +509,7 @@ fn expect_argument<'a>(
     pair: &Pair<Rule>,
     on: Option<Pair<'a, Rule>>,
 ) -> Result<Pair<'a, Rule>, Error> {
-    if on.is_none() {
-        Err(Error::from_message("Expected argument.").with_position_from_pair(pair))
-    } else {
-        Ok(on.unwrap())
-    }
+    on.ok_or_else(|| Error::from_message("Expected argument.").with_position_from_pair(pair))
 }
 
 fn expect_no_argument(
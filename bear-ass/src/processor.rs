@@ -1,6 +1,9 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
+use bear_vm::vm;
+
 use crate::parser::ast;
 
 /// This exists to make the code more readable.  It cannot be changed.
@@ -8,16 +11,20 @@ const WORD_SIZE: usize = std::mem::size_of::<u32>();
 
 #[derive(Debug)]
 pub enum ErrorTag {
-    Unknown,
     IOError(std::io::Error),
     ParserError(crate::parser::Error),
+    /// An included file could not be found; `searched` lists every candidate, in order.
+    IncludeNotFound { path: PathBuf, searched: Vec<PathBuf> },
+    /// A `<name>` include which is not a file of the standard library.
+    UnknownLibraryFile(PathBuf),
 
     NextMarkNotSet,
     PreviousMarkNotSet,
     UnknownLabel(String),
     LabelAlreadyDefined(String),
 
-    ExpectedList,
+    /// An expression definition used as a line, e.g. after `push`, where a list is expected.
+    ExpectedList(String),
     ExpectedExpression,
 
     ExpressionCannotBeSimplified(ast::Expression),
@@ -27,18 +34,339 @@ pub enum ErrorTag {
 
     CannotAtToBeforeCurrentPosition,
 
+    UnknownSection(String),
+    SectionAlreadyDefined(String),
+
     DataSizeMismatch { expected: u8, actual: u8 },
+
+    /// A pseudo-instruction follows a hand-written `lit` in the same word, so the operands would
+    /// be laid out in the wrong order.
+    PseudoAfterLit,
+
+    /// A control-flow directive which does not continue or close the innermost open block.
+    UnmatchedControlFlow(ast::ControlFlow),
+    /// A control-flow block which is still open at the end of the program.
+    UnclosedControlFlow(ast::ControlFlow),
+}
+
+impl std::fmt::Display for ErrorTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorTag::IOError(e) => write!(f, "{}", e),
+            ErrorTag::ParserError(e) => write!(f, "{}", e.message),
+            ErrorTag::IncludeNotFound { path, searched } => {
+                write!(f, "cannot find included file {:?} (searched", path)?;
+                for candidate in searched {
+                    write!(f, " {:?}", candidate)?;
+                }
+                write!(f, ")")
+            }
+            ErrorTag::UnknownLibraryFile(path) => {
+                write!(f, "the standard library has no file <{}> (it has", path.display())?;
+                for (name, _) in crate::library::FILES {
+                    write!(f, " <{}>", name)?;
+                }
+                write!(f, ")")
+            }
+            ErrorTag::NextMarkNotSet => write!(f, "no following `$` mark"),
+            ErrorTag::PreviousMarkNotSet => write!(f, "no preceding `$` mark"),
+            ErrorTag::UnknownLabel(name) => write!(f, "unknown label `{}`", name),
+            ErrorTag::LabelAlreadyDefined(name) => write!(f, "label `{}` is already defined", name),
+            ErrorTag::ExpectedList(name) => write!(
+                f,
+                "`!{0}` is not a list definition; to push its value, write `push (!{0})`",
+                name
+            ),
+            ErrorTag::ExpectedExpression => write!(f, "expected an expression definition"),
+            ErrorTag::ExpressionCannotBeSimplified(expr) => {
+                write!(f, "cannot evaluate expression `{}`", expr)
+            }
+            ErrorTag::UnknownDefinition(name) => write!(f, "unknown definition `{}`", name),
+            ErrorTag::DefinitionAlreadyDefined(name) => {
+                write!(f, "definition `{}` is already defined", name)
+            }
+            ErrorTag::PseudoAfterLit => write!(
+                f,
+                "a pseudo-instruction cannot follow a hand-written `lit` in the same word"
+            ),
+            ErrorTag::UnmatchedControlFlow(control) => match control {
+                ast::ControlFlow::Else => write!(f, "`#else;` must be inside an `#if;` block"),
+                ast::ControlFlow::Do => write!(f, "`#do;` must be inside a `#while;` block"),
+                ast::ControlFlow::Until => write!(f, "`#until;` must close a `#loop;` block"),
+                ast::ControlFlow::End => write!(
+                    f,
+                    "`#end;` must close an `#if;`, a `#while;` after its `#do;`, or a `#loop;`"
+                ),
+                _ => write!(f, "unexpected `{}`", control),
+            },
+            ErrorTag::UnclosedControlFlow(control) => write!(f, "`{}` is never closed", control),
+            ErrorTag::CannotAtToBeforeCurrentPosition => {
+                write!(f, "`#at` cannot move before the current position")
+            }
+            ErrorTag::UnknownSection(name) => {
+                write!(f, "unknown section `{}`; give an address to create it", name)
+            }
+            ErrorTag::SectionAlreadyDefined(name) => {
+                write!(f, "section `{}` is already defined at another address", name)
+            }
+            ErrorTag::DataSizeMismatch { expected, actual } => write!(
+                f,
+                "value needs {} bytes but the data is only {} bytes wide",
+                actual, expected
+            ),
+        }
+    }
 }
 
-impl ErrorTag {
-    fn to_error(self) -> Error {
-        Error { tags: vec![self] }
+impl std::error::Error for ErrorTag {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ErrorTag::IOError(e) => Some(e),
+            ErrorTag::ParserError(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
+/// A position in the source.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// The included file, or `None` for the file being assembled.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// An error and where it occurred, if known.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub tag: ErrorTag,
+    pub location: Option<Location>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.tag),
+            None => write!(f, "{}", self.tag),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.tag)
+    }
+}
+
+/// Every error found while processing a program.
 #[derive(Debug)]
 pub struct Error {
-    tags: Vec<ErrorTag>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Error {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /**
+     * Formats the errors like rustc, quoting the offending line with a caret under the column:
+     *
+     * ```text
+     * error: unknown definition `foo`
+     *  --> main.bear:3:5
+     *   |
+     * 3 | lit !foo
+     *   |     ^
+     * ```
+     *
+     * `name` and `source` are those of the file being assembled; included files are read again.
+     */
+    pub fn render(&self, name: &str, source: &str) -> String {
+        let mut out = String::new();
+        for diagnostic in self.diagnostics.iter() {
+            render(&mut out, "error", &diagnostic.tag, diagnostic.location.as_ref(), name, source);
+        }
+        out
+    }
+}
+
+/// One diagnostic per line.  Use `render` to quote the source as well.
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    /// The first error.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.diagnostics
+            .first()
+            .map(|d| d as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Appends one message, and the source line it points at, to `out`.
+fn render(
+    out: &mut String,
+    severity: &str,
+    message: &dyn std::fmt::Display,
+    location: Option<&Location>,
+    name: &str,
+    source: &str,
+) {
+    out.push_str(&format!("{}: {}\n", severity, message));
+    let location = match location {
+        Some(location) => location,
+        None => return,
+    };
+    let (name, text) = match &location.file {
+        None => (name.to_string(), Some(source.to_string())),
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .ok()
+                .or_else(|| crate::library::source_of_location(path).map(String::from));
+            (path.display().to_string(), text)
+        }
+    };
+    let number = location.line.to_string();
+    let gutter = " ".repeat(number.len());
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        gutter, name, location.line, location.column
+    ));
+    let line = text.as_ref().and_then(|t| t.lines().nth(location.line.wrapping_sub(1)));
+    if let Some(line) = line {
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", number, line));
+        let indent: String = line
+            .chars()
+            .take(location.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} | {}^\n", gutter, indent));
+    }
+}
+
+/// Problems which do not stop a program from assembling.
+#[derive(Debug)]
+pub enum WarningTag {
+    /// An expression computed from addresses does not fit in 32 bits.
+    AddressOutOfRange(i64),
+    /// Arithmetic on a label produced an address outside of the label's section.
+    AddressOutsideSection {
+        label: String,
+        section: String,
+        value: i64,
+    },
+    /// A routine may take more cells from the data stack than it holds.
+    DataStackUnderflow(String),
+    /// A routine may pop more cells from the address stack than it pushed.
+    AddressStackUnderflow(String),
+    /// A routine returns with cells left on the address stack.
+    AddressStackUnbalanced(String),
+    /// A routine returns with different data stack depths.
+    UnbalancedReturns {
+        routine: String,
+        here: i64,
+        first: i64,
+    },
+    /// A loop changes the depth of the stacks on every iteration.
+    UnbalancedLoop {
+        routine: String,
+        data: i64,
+        address: i64,
+    },
+}
+
+impl std::fmt::Display for WarningTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarningTag::AddressOutOfRange(value) => {
+                write!(f, "address {} is outside of the 32-bit address space", value)
+            }
+            WarningTag::AddressOutsideSection {
+                label,
+                section,
+                value,
+            } => write!(
+                f,
+                "address {:#x} is outside of section `{}`, which contains `{}`",
+                value, section, label
+            ),
+            WarningTag::DataStackUnderflow(routine) => {
+                write!(f, "the data stack may underflow in `{}`", routine)
+            }
+            WarningTag::AddressStackUnderflow(routine) => write!(
+                f,
+                "`{}` may pop more from the address stack than it pushed",
+                routine
+            ),
+            WarningTag::AddressStackUnbalanced(routine) => {
+                write!(f, "`{}` returns with cells left on the address stack", routine)
+            }
+            WarningTag::UnbalancedReturns {
+                routine,
+                here,
+                first,
+            } => write!(
+                f,
+                "`{}` changes the depth of the data stack by {:+} here, but by {:+} elsewhere",
+                routine, here, first
+            ),
+            WarningTag::UnbalancedLoop {
+                routine,
+                data,
+                address,
+            } => write!(
+                f,
+                "each iteration of `{}` changes the depth of the data stack by {:+} and of the address stack by {:+}",
+                routine, data, address
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    pub tag: WarningTag,
+    pub location: Option<Location>,
+}
+
+/// A value computed from addresses, which is checked once every section has been laid out.
+struct AddressUse {
+    location: Option<Location>,
+    /// The labels the value was computed from.
+    labels: Vec<String>,
+    value: Option<i64>,
+}
+
+/// A block of structured control flow which is still open.
+struct Block {
+    /// The directive which opened the block: `If`, `While` or `Loop`.
+    kind: ast::ControlFlow,
+    /// Numbers the labels generated for the block.
+    id: usize,
+    /// Whether the `#else;` of an `#if;`, or the `#do;` of a `#while;`, has been seen.
+    middle: bool,
+    location: Option<Location>,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +379,10 @@ enum Definition {
 #[derive(Default)]
 struct Includes {
     files: HashMap<PathBuf, ast::Program>,
+    /// The text of each file, by the same paths.
+    texts: HashMap<PathBuf, String>,
+    /// Directories searched for included files, after the working directory.
+    paths: Vec<PathBuf>,
     // TODO: Give an error for circular references.
     // references: HashMap<PathBuf, HashSet<PathBuf>>
 }
@@ -59,18 +391,54 @@ impl Includes {
     // TODO: Errors
     fn parse(&mut self, path: &Path) -> Result<ast::Program, ErrorTag> {
         let contents = std::fs::read_to_string(path).map_err(ErrorTag::IOError)?;
-        return crate::parser::Parser {}
+        let program = crate::parser::Parser {}
             .parse(&contents)
-            .map_err(ErrorTag::ParserError);
+            .map_err(ErrorTag::ParserError)?;
+        self.texts.insert(path.to_path_buf(), contents);
+        Ok(program)
     }
 
-    fn include_file(&mut self, path: &Path) -> Result<ast::Program, ErrorTag> {
-        let full = path.canonicalize().unwrap();
+    /** Finds an included file.
+     *
+     * Relative paths are resolved against the working directory first, and then against each
+     * include path in the order given.  Absolute paths are used as-is.
+     */
+    fn resolve(&self, path: &Path) -> Result<PathBuf, ErrorTag> {
+        let searched: Vec<PathBuf> = std::iter::once(path.to_path_buf())
+            .chain(self.paths.iter().map(|dir| dir.join(path)))
+            .collect();
+        match searched.iter().find(|candidate| candidate.is_file()) {
+            Some(found) => found.canonicalize().map_err(ErrorTag::IOError),
+            None => Err(ErrorTag::IncludeNotFound {
+                path: path.to_path_buf(),
+                searched,
+            }),
+        }
+    }
+
+    /// Parses a resolved include, once.
+    fn include_file(&mut self, full: &Path) -> Result<ast::Program, ErrorTag> {
+        if !self.files.contains_key(full) {
+            let program = self.parse(full)?;
+            self.files.insert(full.to_path_buf(), program);
+        }
+        Ok(self.files.get(full).cloned().unwrap())
+    }
+
+    /// Parses a file of the standard library, once.  Its path is `<name>`.
+    fn include_library(&mut self, name: &Path) -> Result<(PathBuf, ast::Program), ErrorTag> {
+        let full = PathBuf::from(format!("<{}>", name.display()));
         if !self.files.contains_key(&full) {
-            let program = self.parse(&full)?;
+            let source = crate::library::source(name)
+                .ok_or_else(|| ErrorTag::UnknownLibraryFile(name.to_path_buf()))?;
+            let program = crate::parser::Parser {}
+                .parse(source)
+                .map_err(ErrorTag::ParserError)?;
             self.files.insert(full.clone(), program);
+            self.texts.insert(full.clone(), source.to_string());
         }
-        return Ok(self.files.get(&full).cloned().unwrap());
+        let program = self.files.get(&full).cloned().unwrap();
+        Ok((full, program))
     }
 }
 
@@ -80,30 +448,97 @@ pub struct ProcessedLine {
     pub body: ast::LineBody,
     /// The computed address in the binary of the instruction encoded by the line.
     pub address: ast::LineAddress,
+    /// The index of the section the line belongs to.
+    pub section: usize,
+    /// Where the line came from.
+    pub location: Option<Location>,
+    /// The line was inserted to align the next one.
+    pub padding: bool,
 }
 
-impl ProcessedLine {
-    fn new(body: ast::LineBody, address: ast::LineAddress) -> ProcessedLine {
-        ProcessedLine { body, address }
-    }
+/// A label in the ROM, and the lines which follow it up to the next label.
+pub(crate) struct Routine<'a> {
+    /// The labels of the routine, which share its address.
+    pub names: Vec<String>,
+    pub address: usize,
+    pub lines: Vec<&'a ProcessedLine>,
+}
+
+/// The name of the section which is active at the start of a program.
+pub const ROM_SECTION: &str = "rom";
+
+/** A named region of the address space, assembled into its own image.
+ *
+ * The first section is always the ROM, which begins at address `0` and is the image the VM
+ * executes.  Every other section is placed into memory by the loader before execution starts.
+ */
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    /// The address of the first byte of the section.
+    pub base: usize,
+    pub kind: ast::SectionKind,
+    /// The next free address in the section, while the section is not active.
+    position: usize,
+}
+
+/// Options which control how a `Program` is processed.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Directories searched for included files, after the working directory.
+    pub include_paths: Vec<PathBuf>,
+    /// Expressions defined before processing begins, as if by `#define`.
+    pub defines: Vec<(String, ast::Expression)>,
+    /// Warn when arithmetic on a label leaves the label's section.
+    pub check_sections: bool,
+    /// Leave out routines of included files which the program cannot reach.
+    pub strip_unused: bool,
+    /// Warn about probable stack underflows and unbalanced routines.
+    pub check_stacks: bool,
 }
 
 /// A `Processor` consumes a `Program` and is converted into a binary by an `Assembler`.
 #[derive(Default)]
 pub struct Processor {
     position: usize,
+    /// The index of the active section.
+    section: usize,
+    pub sections: Vec<Section>,
 
     marks: Vec<usize>,
     /// Maps label names to addresses.
     labels: HashMap<String, usize>,
+    /// The labels generated for control-flow blocks, which cannot clash with the program's labels.
+    targets: HashMap<String, usize>,
+    /// The open control-flow blocks, innermost last.
+    blocks: Vec<Block>,
+    /// The number of control-flow blocks opened so far.
+    block_count: usize,
     /// Maps names to definitions.
     definitions: HashMap<String, Definition>,
-    // TODO: Handle included files.
-    /** Maps addresses in the binary to lines in the source code.
-     * Primarily used to generate debugging info.
+    /// Strings registered with `#intern`, indexed by id.
+    strings: Vec<String>,
+    /** Maps addresses in the binary to lines in the source code, with the included files they are
+     * in and the bytes they take.  Primarily used to generate debugging info.
      */
-    addresses: HashMap<ast::LineAddress, ast::LineNumber>,
+    addresses: HashMap<ast::LineAddress, (Location, std::ops::Range<usize>)>,
     includes: Includes,
+    /// The included file being processed, or `None` for the program itself.
+    file: Option<PathBuf>,
+    /// The location of the line being processed.
+    location: Option<Location>,
+    errors: Vec<Diagnostic>,
+    /// Address-valued data, by section and address.
+    address_uses: BTreeMap<(usize, usize), AddressUse>,
+    pub warnings: Vec<Warning>,
+    /// Lines of unreachable routines, which are left out.
+    skip: HashSet<Location>,
+    /// The labels of the routines which were left out by `Options::strip_unused`.
+    pub stripped: Vec<String>,
+    /// The operands of pseudo-instructions, which are laid out once their code word is complete.
+    pending: Vec<(ast::Expression, Option<Location>)>,
+    /// The address of the last `lit` written by hand.
+    hand_lit: Option<usize>,
 
     original: ast::Program,
     pub processed: Vec<ProcessedLine>,
@@ -111,7 +546,73 @@ pub struct Processor {
 
 impl Processor {
     fn add_mark(&mut self, position: usize) {
-        self.marks.push(position);
+        // Sections may place marks out of order, and `resolve_*` needs them sorted.
+        let index = self.marks.partition_point(|m| *m <= position);
+        self.marks.insert(index, position);
+    }
+
+    /// Records that the current position was assembled from `line`, for the debug info.
+    fn record_address(&mut self, line: &ast::Line) {
+        let location = Location {
+            file: self.file.clone(),
+            line: line.number,
+            column: line.column,
+        };
+        self.addresses.insert(self.position, (location, line.span.clone()));
+    }
+
+    /// Records an error at the line being processed, and carries on.
+    fn report(&mut self, tag: ErrorTag) {
+        self.errors.push(Diagnostic {
+            tag,
+            location: self.location.clone(),
+        });
+    }
+
+    fn line(&self, body: ast::LineBody, address: ast::LineAddress) -> ProcessedLine {
+        ProcessedLine {
+            body,
+            address,
+            section: self.section,
+            location: self.location.clone(),
+            padding: false,
+        }
+    }
+
+    /** Makes the named section active, creating it at `base` if it does not yet exist.
+     *
+     * New sections hold data unless `kind` says otherwise.
+     */
+    fn enter_section(
+        &mut self,
+        name: &str,
+        base: Option<usize>,
+        kind: Option<ast::SectionKind>,
+    ) -> Result<(), ErrorTag> {
+        self.sections[self.section].position = self.position;
+        match self.sections.iter().position(|s| s.name == name) {
+            Some(index) => {
+                let section = &self.sections[index];
+                if (base.is_some() && base != Some(section.base))
+                    || (kind.is_some() && kind != Some(section.kind))
+                {
+                    return Err(ErrorTag::SectionAlreadyDefined(name.into()));
+                }
+                self.section = index;
+            }
+            None => {
+                let base = base.ok_or_else(|| ErrorTag::UnknownSection(name.into()))?;
+                self.sections.push(Section {
+                    name: name.into(),
+                    base,
+                    kind: kind.unwrap_or(ast::SectionKind::Data),
+                    position: base,
+                });
+                self.section = self.sections.len() - 1;
+            }
+        }
+        self.position = self.sections[self.section].position;
+        Ok(())
     }
 
     fn resolve_prev(&self) -> Result<usize, ErrorTag> {
@@ -137,7 +638,21 @@ impl Processor {
     }
 
     fn resolve_label(&self, label: &str) -> Option<usize> {
-        self.labels.get(label).cloned()
+        self.labels.get(label).or_else(|| self.targets.get(label)).cloned()
+    }
+
+    fn define(
+        &mut self,
+        name: String,
+        definition: Definition,
+    ) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        match self.definitions.entry(name) {
+            Entry::Occupied(entry) => Err(ErrorTag::DefinitionAlreadyDefined(entry.key().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(definition);
+                Ok(vec![])
+            }
+        }
     }
 
     fn resolve_definition(&self, name: &str) -> Option<Definition> {
@@ -149,7 +664,7 @@ impl Processor {
     fn expect_definition_list(&self, name: &str) -> Result<Vec<ast::LineBody>, ErrorTag> {
         match self.resolve_definition(name) {
             Some(Definition::DefList(list)) => Ok(list),
-            Some(_) => Err(ErrorTag::ExpectedList),
+            Some(_) => Err(ErrorTag::ExpectedList(name.into())),
             None => Err(ErrorTag::UnknownDefinition(name.into())),
         }
     }
@@ -176,6 +691,10 @@ impl Processor {
                     content: x.to_string(),
                     tag: ast::DebugTag::Instruction,
                 },
+                body @ ast::LineBody::Pseudo(_, _) => ast::DebugLine {
+                    content: body.to_string(),
+                    tag: ast::DebugTag::Instruction,
+                },
                 ast::LineBody::Directive(x) => ast::DebugLine {
                     content: x.to_string(),
                     tag: ast::DebugTag::Directive,
@@ -189,79 +708,532 @@ impl Processor {
         let mut entries = Vec::new();
         let mut rev: HashMap<usize, Vec<String>> = HashMap::new();
         for (label, address) in &self.labels {
-            let names = rev.entry(*address).or_insert(Vec::new());
+            let names = rev.entry(*address).or_default();
             names.push(label.clone());
         }
-        for item in &self.addresses {
+        for (address, (location, span)) in &self.addresses {
             let empty = &Vec::new();
-            let names = rev.get(item.0).unwrap_or(empty);
+            let names = rev.get(address).unwrap_or(empty);
             entries.push(ast::DebugEntry {
-                address: *item.0,
-                line: *item.1,
+                address: *address,
+                file: location.file.clone(),
+                line: location.line,
+                column: location.column,
+                span: span.clone(),
                 names: names.to_vec(),
             });
         }
         entries.sort_by_key(|e| e.address);
-        Ok(ast::Debug { entries, body })
+        Ok(ast::Debug {
+            entries,
+            body,
+            strings: self.strings.clone(),
+            checksum: None,
+            sources: Vec::new(),
+        })
     }
 }
 
 impl Processor {
-    fn align_to(&mut self, boundary: usize) -> usize {
+    /// Advances to the next multiple of `boundary`.  Code sections are padded with `nop`s.
+    fn align_to(&mut self, boundary: usize) -> Vec<ProcessedLine> {
+        let mut lines = Vec::new();
         let padding = boundary - (self.position % boundary);
         if padding != boundary {
+            if self.sections[self.section].kind == ast::SectionKind::Code {
+                for address in self.position..self.position + padding {
+                    let mut line = self.line(ast::LineBody::Simple(vm::OpCode::Nop), address);
+                    line.padding = true;
+                    lines.push(line);
+                }
+            }
             self.position += padding;
         }
-        self.position
+        lines
+    }
+
+    /// Lays out the operands of pseudo-instructions after their code word, padding it if needed.
+    fn flush_operands(&mut self) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        if self.pending.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut lines = self.align_to(WORD_SIZE);
+        let location = self.location.clone();
+        for (operand, operand_location) in std::mem::take(&mut self.pending) {
+            // Errors are reported at the pseudo-instruction.
+            self.location = operand_location;
+            let position = self.position;
+            let body = ast::LineBody::Data(self.process_data(ast::Data::D(ast::Size::S32, operand))?);
+            lines.push(self.line(body, position));
+        }
+        self.location = location;
+        Ok(lines)
+    }
+
+    /// Lays out an instruction, followed by the pending operands if it completes a word.
+    fn emit_op(&mut self, op: vm::OpCode) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        self.position += 1;
+        let mut lines = vec![self.line(ast::LineBody::Simple(op), self.position - 1)];
+        if self.position.is_multiple_of(WORD_SIZE) {
+            lines.extend(self.flush_operands()?);
+        }
+        Ok(lines)
+    }
+
+    /// Expands `op operand` to a `lit` of `operand`, followed by `op` unless it is `push`.
+    fn process_pseudo(
+        &mut self,
+        op: vm::OpCode,
+        operand: ast::Expression,
+    ) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        let same_word = |address: usize| address / WORD_SIZE == self.position / WORD_SIZE;
+        if !self.position.is_multiple_of(WORD_SIZE) && self.hand_lit.is_some_and(same_word) {
+            return Err(ErrorTag::PseudoAfterLit);
+        }
+        self.pending.push((operand, self.location.clone()));
+        let mut lines = self.emit_op(vm::OpCode::Lit)?;
+        if op != vm::OpCode::MoveDataToAddr {
+            lines.extend(self.emit_op(op)?);
+        }
+        Ok(lines)
+    }
+
+    /// The label `part` of the block `id`.  `#` cannot begin the program's labels.
+    fn target(id: usize, part: &str) -> String {
+        format!("#{}:{}", id, part)
+    }
+
+    /// Branches with `op` to the label `part` of the block `id`, unless `emit` is false.
+    fn branch(
+        &mut self,
+        op: vm::OpCode,
+        id: usize,
+        part: &str,
+        emit: bool,
+    ) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        if !emit {
+            return Ok(vec![]);
+        }
+        let label = format!("&{}", Processor::target(id, part));
+        self.process_pseudo(op, ast::Expression::Address(ast::Address::LabelRef(label)))
+    }
+
+    /** Defines the label `part` of the block `id` here.
+     *
+     * Branch targets begin a word, so that the `lit`s after them find their operands; the word is
+     * padded with `nop`s unless `emit` is false.
+     */
+    fn place(&mut self, id: usize, part: &str, emit: bool) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        let mut lines = Vec::new();
+        if emit {
+            lines = self.flush_operands()?;
+            lines.extend(self.align_to(WORD_SIZE));
+        }
+        self.targets.insert(Processor::target(id, part), self.position);
+        Ok(lines)
+    }
+
+    /// The innermost block, if it was opened by `kind`.
+    fn innermost(&mut self, kind: ast::ControlFlow) -> Option<&mut Block> {
+        self.blocks.last_mut().filter(|block| block.kind == kind)
+    }
+
+    /** Expands a control-flow directive to branches to the labels of its block.
+     *
+     * Only the labels are defined when `emit` is false, i.e. when the directive belongs to a
+     * routine which was left out, so that the blocks still match up and every label exists.
+     */
+    fn process_control_flow(
+        &mut self,
+        control: ast::ControlFlow,
+        emit: bool,
+    ) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        use ast::ControlFlow::*;
+        use vm::OpCode::{Jump, JumpIfZ};
+        let unmatched = ErrorTag::UnmatchedControlFlow(control);
+        match control {
+            If | While | Loop => {
+                self.block_count += 1;
+                let id = self.block_count;
+                self.blocks.push(Block {
+                    kind: control,
+                    id,
+                    middle: false,
+                    location: self.location.clone(),
+                });
+                match control {
+                    If => self.branch(JumpIfZ, id, "else", emit),
+                    _ => self.place(id, "top", emit),
+                }
+            }
+            Else => {
+                let block = self.innermost(If).filter(|b| !b.middle).ok_or(unmatched)?;
+                block.middle = true;
+                let id = block.id;
+                let mut lines = self.branch(Jump, id, "end", emit)?;
+                lines.extend(self.place(id, "else", emit)?);
+                Ok(lines)
+            }
+            Do => {
+                let block = self.innermost(While).filter(|b| !b.middle).ok_or(unmatched)?;
+                block.middle = true;
+                let id = block.id;
+                self.branch(JumpIfZ, id, "end", emit)
+            }
+            Until => {
+                let id = self.innermost(Loop).ok_or(unmatched)?.id;
+                self.blocks.pop();
+                self.branch(JumpIfZ, id, "top", emit)
+            }
+            End => {
+                let block = match self.blocks.last() {
+                    Some(block) if block.kind != While || block.middle => block,
+                    _ => return Err(unmatched),
+                };
+                let (kind, id, middle) = (block.kind, block.id, block.middle);
+                self.blocks.pop();
+                let mut lines = Vec::new();
+                if kind == If && !middle {
+                    lines.extend(self.place(id, "else", emit)?);
+                }
+                if kind != If {
+                    lines.extend(self.branch(Jump, id, "top", emit)?);
+                }
+                lines.extend(self.place(id, "end", emit)?);
+                Ok(lines)
+            }
+        }
+    }
+
+    /// Reports the blocks which are still open, and closes them.
+    fn close_blocks(&mut self) {
+        for block in std::mem::take(&mut self.blocks) {
+            for part in ["top", "else", "end"] {
+                self.targets.entry(Processor::target(block.id, part)).or_insert(self.position);
+            }
+            self.errors.push(Diagnostic {
+                tag: ErrorTag::UnclosedControlFlow(block.kind),
+                location: block.location,
+            });
+        }
     }
 }
 
 impl Processor {
     pub fn process(program: ast::Program) -> Result<Processor, Error> {
+        Processor::process_with(program, Options::default())
+    }
+
+    pub fn process_with(program: ast::Program, options: Options) -> Result<Processor, Error> {
+        if !options.strip_unused {
+            return Processor::process_skipping(program, options, HashSet::new());
+        }
+        let first = Processor::process_skipping(program.clone(), options.clone(), HashSet::new())?;
+        let (skip, stripped) = first.unused_routines();
+        if skip.is_empty() {
+            return Ok(first);
+        }
+        let mut second = Processor::process_skipping(program, options, skip)?;
+        second.stripped = stripped;
+        Ok(second)
+    }
+
+    fn process_skipping(
+        program: ast::Program,
+        options: Options,
+        skip: HashSet<Location>,
+    ) -> Result<Processor, Error> {
         let mut lines = Vec::new();
         let mut preproc = Processor::default();
-        let mut is_error = false;
-        let mut errors = Error { tags: Vec::new() };
+        preproc.includes.paths = options.include_paths;
+        preproc.skip = skip;
         preproc.original = program.clone();
+        preproc.sections.push(Section {
+            name: ROM_SECTION.into(),
+            base: 0,
+            kind: ast::SectionKind::Code,
+            position: 0,
+        });
+        for (name, expr) in options.defines {
+            if let Err(error) = preproc.define(name, Definition::DefExpr(expr)) {
+                preproc.report(error);
+            }
+        }
         for line in program.body.into_iter() {
-            preproc.addresses.insert(preproc.position, line.number);
+            preproc.record_address(&line);
             match preproc.process_line(line) {
-                Err(error) => {
-                    errors.tags.push(error);
-                    is_error = true;
-                }
+                Err(error) => preproc.report(error),
                 Ok(newlines) => lines.extend(newlines),
             }
         }
-        // TODO: Aggregate errors.
-        if is_error {
-            return Err(ErrorTag::Unknown.to_error());
+        match preproc.flush_operands() {
+            Err(error) => preproc.report(error),
+            Ok(newlines) => lines.extend(newlines),
         }
+        preproc.close_blocks();
         for processed in lines {
-            let newline = preproc.fixup(processed);
-            match newline {
-                Err(error) => {
-                    errors.tags.push(error);
-                    is_error = true;
+            preproc.location = processed.location.clone();
+            match preproc.fixup(processed) {
+                Err(error) => preproc.report(error),
+                Ok(line) => preproc.processed.push(line),
+            }
+        }
+        if !preproc.errors.is_empty() {
+            return Err(Error {
+                diagnostics: std::mem::take(&mut preproc.errors),
+            });
+        }
+        preproc.check_addresses(options.check_sections);
+        if options.check_stacks {
+            let warnings = crate::verify::check_stacks(&preproc);
+            preproc.warnings.extend(warnings);
+        }
+        Ok(preproc)
+    }
+
+    /// The files included by the program, directly or not, leaving out the standard library.
+    pub fn included_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self
+            .includes
+            .files
+            .keys()
+            .filter(|path| !path.to_string_lossy().starts_with('<'))
+            .cloned()
+            .collect();
+        files.sort();
+        files
+    }
+
+    /**
+     * The text of the program, given as `main`, and of every file it includes, including those of
+     * the standard library, for `ast::Debug::sources`.
+     */
+    pub fn sources(&self, main: &str) -> Vec<ast::DebugSource> {
+        let mut included: Vec<(&PathBuf, &String)> = self.includes.texts.iter().collect();
+        included.sort();
+        let main = ast::DebugSource {
+            file: None,
+            text: main.to_string(),
+        };
+        std::iter::once(main)
+            .chain(included.into_iter().map(|(file, text)| ast::DebugSource {
+                file: Some(file.clone()),
+                text: text.clone(),
+            }))
+            .collect()
+    }
+
+    /// Formats the warnings like `Error::render`.
+    pub fn render_warnings(&self, name: &str, source: &str) -> String {
+        let mut out = String::new();
+        for warning in self.warnings.iter() {
+            render(&mut out, "warning", &warning.tag, warning.location.as_ref(), name, source);
+        }
+        out
+    }
+
+    /// The section whose addresses include `address`.  One past the end counts, as in C.
+    fn section_of(&self, address: i64) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|s| s.base as i64 <= address && address <= s.position as i64)
+    }
+
+    fn check_addresses(&mut self, check_sections: bool) {
+        self.sections[self.section].position = self.position;
+        let mut warnings = Vec::new();
+        for use_ in self.address_uses.values() {
+            let value = match use_.value {
+                Some(value) => value,
+                None => continue,
+            };
+            let tag = if value < 0 || value > u32::MAX as i64 {
+                WarningTag::AddressOutOfRange(value)
+            } else if let (true, [label]) = (check_sections, use_.labels.as_slice()) {
+                let home = self.resolve_label(label).and_then(|a| self.section_of(a as i64));
+                match home {
+                    Some(section) if self.section_of(value).map(|s| &s.name) != Some(&section.name) => {
+                        WarningTag::AddressOutsideSection {
+                            label: label.clone(),
+                            section: section.name.clone(),
+                            value,
+                        }
+                    }
+                    _ => continue,
                 }
-                Ok(line) => {
-                    preproc.processed.push(line);
+            } else {
+                continue;
+            };
+            warnings.push(Warning {
+                tag,
+                location: use_.location.clone(),
+            });
+        }
+        self.warnings = warnings;
+    }
+
+    /// The labels of a section, by address.
+    fn labels_in(&self, section: &Section) -> BTreeMap<usize, Vec<String>> {
+        let mut starts: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (label, &address) in self.labels.iter() {
+            if section.base <= address && address < section.position {
+                starts.entry(address).or_default().push(label.clone());
+            }
+        }
+        starts
+    }
+
+    /** Maps each label to the labels referenced by the data which follows it, up to the next label.
+     *
+     * Data which precedes every label of a section is attributed to the section, as `<name>`.
+     */
+    pub fn xref(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let mut xref: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for label in self.labels.keys() {
+            xref.entry(label.clone()).or_default();
+        }
+        let starts: Vec<_> = self.sections.iter().map(|s| self.labels_in(s)).collect();
+        for (&(section, address), use_) in self.address_uses.iter() {
+            let owners = match starts[section].range(..=address).next_back() {
+                Some((_, names)) => names.clone(),
+                None => vec![format!("<{}>", self.sections[section].name)],
+            };
+            for owner in owners {
+                let labels = use_.labels.iter().filter(|l| self.labels.contains_key(*l));
+                xref.entry(owner).or_default().extend(labels.cloned());
+            }
+        }
+        xref
+    }
+
+    /** Splits the ROM into routines, in address order.
+     *
+     * The first routine holds the lines before the first label, and has no names.
+     */
+    pub(crate) fn routines(&self) -> Vec<Routine<'_>> {
+        let starts = self.labels_in(&self.sections[0]);
+        let mut lines: Vec<&ProcessedLine> = self
+            .processed
+            .iter()
+            .filter(|line| line.section == 0 && !line.padding)
+            .collect();
+        lines.sort_by_key(|line| line.address);
+
+        let mut routines = vec![Routine {
+            names: vec![],
+            address: 0,
+            lines: vec![],
+        }];
+        let mut next = starts.into_iter().peekable();
+        for line in lines {
+            while let Some((address, names)) = next.next_if(|(address, _)| *address <= line.address) {
+                routines.push(Routine {
+                    names,
+                    address,
+                    lines: vec![],
+                });
+            }
+            routines.last_mut().unwrap().lines.push(line);
+        }
+        routines
+    }
+
+    /** Finds the routines of included files which cannot be reached from the program.
+     *
+     * A routine runs from a label in the ROM to the next one.  The routine at address `0`, the
+     * routines of the program itself, and every label referenced outside of the ROM are reachable.
+     * So is every label referenced by a reachable routine, and the routine after one which does not
+     * end with `jump`, `ret` or `halt`.  Returns the source lines of the unreachable routines, and
+     * their labels.
+     */
+    fn unused_routines(&self) -> (HashSet<Location>, Vec<String>) {
+        let routines = self.routines();
+        let routine_of: HashMap<&str, usize> = routines
+            .iter()
+            .enumerate()
+            .flat_map(|(index, r)| r.names.iter().map(move |name| (name.as_str(), index)))
+            .collect();
+
+        let mut work = vec![0];
+        for (&(section, _), use_) in self.address_uses.iter() {
+            if section != 0 {
+                work.extend(use_.labels.iter().filter_map(|l| routine_of.get(l.as_str())));
+            }
+        }
+        for (index, routine) in routines.iter().enumerate() {
+            let included = routine.lines.iter().all(|line| {
+                matches!(&line.location, Some(Location { file: Some(_), .. }))
+            });
+            if !included {
+                work.push(index);
+            }
+        }
+
+        let mut reachable = vec![false; routines.len()];
+        while let Some(index) = work.pop() {
+            if std::mem::replace(&mut reachable[index], true) {
+                continue;
+            }
+            let lines = &routines[index].lines;
+            for line in lines.iter() {
+                if let Some(use_) = self.address_uses.get(&(0, line.address)) {
+                    work.extend(use_.labels.iter().filter_map(|l| routine_of.get(l.as_str())));
                 }
             }
+            let last = lines.iter().rev().find_map(|line| match line.body {
+                ast::LineBody::Simple(op) => Some(op),
+                _ => None,
+            });
+            let ends = matches!(
+                last,
+                Some(vm::OpCode::Jump)
+                    | Some(vm::OpCode::Return)
+                    | Some(vm::OpCode::InterruptReturn)
+                    | Some(vm::OpCode::Halt)
+            );
+            if !ends && index + 1 < routines.len() {
+                work.push(index + 1);
+            }
         }
-        if is_error {
-            return Err(errors);
+
+        let mut skip = HashSet::new();
+        let mut stripped = Vec::new();
+        for (routine, reachable) in routines.into_iter().zip(reachable) {
+            if !reachable {
+                skip.extend(routine.lines.into_iter().filter_map(|line| line.location.clone()));
+                stripped.extend(routine.names);
+            }
         }
-        Ok(preproc)
+        stripped.sort();
+        (skip, stripped)
     }
 
     fn process_line(&mut self, line: ast::Line) -> Result<Vec<ProcessedLine>, ErrorTag> {
+        self.location = Some(Location {
+            file: self.file.clone(),
+            line: line.number,
+            column: line.column,
+        });
+        let skipped = self.skip.contains(self.location.as_ref().unwrap());
+        match line.body {
+            ast::LineBody::Directive(ast::Directive::ControlFlow(control)) if skipped => {
+                return self.process_control_flow(control, false);
+            }
+            ast::LineBody::Directive(_) => {}
+            _ if skipped => return Ok(vec![]),
+            _ => {}
+        }
+        // Labels never point into a word whose operands are pending.
+        let mut lines = Vec::new();
+        if line.mark || !line.labels.is_empty() {
+            lines = self.flush_operands()?;
+            self.record_address(&line);
+        }
         let processed = self.process_line_body(line.body)?;
         if line.mark || !line.labels.is_empty() {
-            let position = if processed.is_empty() {
-                self.position
-            } else {
-                processed[0].address
+            let position = match processed.iter().find(|line| !line.padding) {
+                None => self.position,
+                Some(line) => line.address,
             };
             if line.mark {
                 self.add_mark(position);
@@ -273,28 +1245,45 @@ impl Processor {
                 self.labels.insert(label.to_string(), position);
             }
         }
-        Ok(processed)
+        lines.extend(processed);
+        Ok(lines)
     }
 
     fn process_line_body(&mut self, line: ast::LineBody) -> Result<Vec<ProcessedLine>, ErrorTag> {
         let newlines = match line {
             // Sized strings are word aligned
             ast::LineBody::Data(data @ ast::Data::Str(_, _)) => {
-                let position = self.align_to(WORD_SIZE);
+                let mut lines = self.flush_operands()?;
+                lines.extend(self.align_to(WORD_SIZE));
+                let position = self.position;
                 let body = ast::LineBody::Data(self.process_data(data)?);
-                vec![ProcessedLine::new(body, position)]
+                lines.push(self.line(body, position));
+                lines
             }
             ast::LineBody::Data(data) => {
+                let mut lines = self.flush_operands()?;
                 let position = self.position;
                 let body = ast::LineBody::Data(self.process_data(data)?);
-                vec![ProcessedLine::new(body, position)]
+                lines.push(self.line(body, position));
+                lines
+            }
+            ast::LineBody::Directive(
+                dir @ (ast::Directive::At(_)
+                | ast::Directive::AlignTo(_)
+                | ast::Directive::Section(_, _, _)),
+            ) => {
+                let mut lines = self.flush_operands()?;
+                lines.extend(self.process_directive(dir)?);
+                lines
             }
             ast::LineBody::Directive(dir) => self.process_directive(dir)?,
             ast::LineBody::Simple(op) => {
-                self.position += 1;
-                let body = ast::LineBody::Simple(op);
-                vec![ProcessedLine::new(body, self.position - 1)]
+                if op == vm::OpCode::Lit {
+                    self.hand_lit = Some(self.position);
+                }
+                self.emit_op(op)?
             }
+            ast::LineBody::Pseudo(op, operand) => self.process_pseudo(op, operand)?,
             ast::LineBody::DefinitionRef(name) => {
                 let mut lines = Vec::new();
                 let list = self.expect_definition_list(&name)?;
@@ -312,6 +1301,17 @@ impl Processor {
         Ok(match data {
             ast::Data::D(size, expr) => {
                 let expr = self.process_expression(expr)?;
+                if let Some(labels) = address_labels(&expr) {
+                    let key = (self.section, self.position - size.size_in_bytes());
+                    self.address_uses.insert(
+                        key,
+                        AddressUse {
+                            location: self.location.clone(),
+                            labels,
+                            value: None,
+                        },
+                    );
+                }
                 let expr = self.simplify_expression(expr, self.position)?;
                 if let Some(p) = expr.as_primitive() {
                     if size.size_in_bytes() < p.min_bytes() {
@@ -320,6 +1320,10 @@ impl Processor {
                             actual: p.min_bytes() as u8,
                         });
                     }
+                    let key = (self.section, self.position - size.size_in_bytes());
+                    if let Some(use_) = self.address_uses.get_mut(&key) {
+                        use_.value = p.try_into();
+                    }
                 }
                 ast::Data::D(size, expr)
             }
@@ -344,34 +1348,74 @@ impl Processor {
                     .simplify_expression(expr, self.position)?
                     .as_primitive()
                     .unwrap();
-                self.align_to(expr.try_into::<usize>().unwrap());
-                Ok(vec![])
+                Ok(self.align_to(expr.try_into::<usize>().unwrap()))
             }
             ast::Directive::Include(path) => {
-                let mut lines = Vec::new();
-                let program = self.includes.include_file(&path)?;
-                for line in program.body {
-                    lines.extend(self.process_line(line)?);
-                }
+                let full = self.includes.resolve(&path)?;
+                let program = match self.includes.include_file(&full) {
+                    Err(ErrorTag::ParserError(e)) => {
+                        // Point at the syntax error, rather than at the `#include`.
+                        if let Some(position) = &e.position {
+                            self.location = Some(Location {
+                                file: Some(full),
+                                line: position.line,
+                                column: position.column,
+                            });
+                        }
+                        return Err(ErrorTag::ParserError(e));
+                    }
+                    result => result?,
+                };
+                Ok(self.process_included(full, program))
+            }
+            ast::Directive::IncludeLibrary(name) => {
+                let (full, program) = self.includes.include_library(&name)?;
+                Ok(self.process_included(full, program))
+            }
+            ast::Directive::Section(name, base, kind) => {
+                let base = match base {
+                    None => None,
+                    Some(expr) => {
+                        let expr = self.simplify_expression(expr, self.position)?;
+                        let value = expr
+                            .as_primitive()
+                            .and_then(|p| p.try_into::<u32>())
+                            .ok_or(ErrorTag::ExpressionCannotBeSimplified(expr))?;
+                        Some(value as usize)
+                    }
+                };
+                self.enter_section(&name, base, kind)?;
+                Ok(vec![])
+            }
+            ast::Directive::Intern(name, text) => {
+                let id = ast::Primitive::from(self.strings.len() as u32).to_expr();
+                let lines = self.define(name, Definition::DefExpr(id))?;
+                self.strings.push(String::from_utf8_lossy(&text).into_owned());
                 Ok(lines)
             }
             ast::Directive::DefineList(name, list) => {
-                if self.definitions.contains_key(&name) {
-                    Err(ErrorTag::DefinitionAlreadyDefined(name))
-                } else {
-                    self.definitions.insert(name, Definition::DefList(list));
-                    Ok(vec![])
-                }
+                self.define(name, Definition::DefList(list))
             }
             ast::Directive::DefineExpression(name, expr) => {
-                if self.definitions.contains_key(&name) {
-                    Err(ErrorTag::DefinitionAlreadyDefined(name))
-                } else {
-                    self.definitions.insert(name, Definition::DefExpr(expr));
-                    Ok(vec![])
-                }
+                self.define(name, Definition::DefExpr(expr))
+            }
+            ast::Directive::ControlFlow(control) => self.process_control_flow(control, true),
+        }
+    }
+
+    /// Processes the lines of an included file, reporting their errors at the file.
+    fn process_included(&mut self, full: PathBuf, program: ast::Program) -> Vec<ProcessedLine> {
+        let mut lines = Vec::new();
+        let outer = self.file.replace(full);
+        for line in program.body {
+            self.record_address(&line);
+            match self.process_line(line) {
+                Err(error) => self.report(error),
+                Ok(newlines) => lines.extend(newlines),
             }
         }
+        self.file = outer;
+        lines
     }
 
     fn process_expression(&self, expr: ast::Expression) -> Result<ast::Expression, ErrorTag> {
@@ -440,7 +1484,7 @@ impl Processor {
     // Some expressions cannot be evaluated at the time they are encountered,
     // and so we circle back around and evaluate them once everything else has
     // been accomplished.
-    fn fixup(&self, processed: ProcessedLine) -> Result<ProcessedLine, ErrorTag> {
+    fn fixup(&mut self, processed: ProcessedLine) -> Result<ProcessedLine, ErrorTag> {
         match processed.body {
             ast::LineBody::Data(ast::Data::D(size, expr)) => {
                 let expr = self.simplify_expression(expr, processed.address)?;
@@ -451,16 +1495,49 @@ impl Processor {
                             expected: size.size_in_bytes() as u8,
                         });
                     }
+                    let key = (processed.section, processed.address);
+                    if let Some(use_) = self.address_uses.get_mut(&key) {
+                        use_.value = p.try_into();
+                    }
                     let body = ast::LineBody::Data(ast::Data::D(size, p.to_expr()));
                     Ok(ProcessedLine {
                         address: processed.address,
+                        section: processed.section,
+                        location: processed.location,
+                        padding: processed.padding,
                         body,
                     })
                 } else {
-                    Err(ErrorTag::ExpressionCannotBeSimplified(expr))
+                    Err(match unresolved_label(&expr) {
+                        Some(name) => ErrorTag::UnknownLabel(name),
+                        None => ErrorTag::ExpressionCannotBeSimplified(expr),
+                    })
                 }
             }
             _ => Ok(processed),
         }
     }
 }
+
+/// Finds a label which is referenced by an expression but was never defined.
+fn unresolved_label(expr: &ast::Expression) -> Option<String> {
+    match expr {
+        ast::Expression::Address(ast::Address::LabelRef(name)) => Some(name[1..].to_string()),
+        ast::Expression::Tree(_, lhs, rhs) => unresolved_label(lhs).or_else(|| unresolved_label(rhs)),
+        _ => None,
+    }
+}
+
+/// The labels an expression is computed from, or `None` if it does not involve any addresses.
+fn address_labels(expr: &ast::Expression) -> Option<Vec<String>> {
+    match expr {
+        ast::Expression::Address(ast::Address::LabelRef(name)) => Some(vec![name[1..].to_string()]),
+        ast::Expression::ForwardLabelRef(name) => Some(vec![name.clone()]),
+        ast::Expression::Address(_) | ast::Expression::ForwardMarkRef(_) => Some(vec![]),
+        ast::Expression::Tree(_, lhs, rhs) => match (address_labels(lhs), address_labels(rhs)) {
+            (None, None) => None,
+            (lhs, rhs) => Some(lhs.into_iter().chain(rhs).flatten().collect()),
+        },
+        _ => None,
+    }
+}
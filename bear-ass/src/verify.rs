@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use bear_vm::vm::OpCode;
+
+use crate::parser::ast;
+use crate::processor::{ProcessedLine, Processor, Routine, Warning, WarningTag};
+
+/// What running a routine until it returns does to its caller's data stack.
+#[derive(Debug, Clone, Copy)]
+struct Effect {
+    /// The number of cells the routine takes from its caller.
+    needs: i64,
+    /// The change in the depth of the data stack.
+    net: i64,
+}
+
+enum State {
+    InProgress,
+    /// `None` if the effect could not be worked out.
+    Done(Option<Effect>),
+}
+
+/// The depths of the stacks, relative to the start of a routine.
+#[derive(Default)]
+struct Depth {
+    data: i64,
+    address: i64,
+    /// The most cells taken from the caller's data stack so far.
+    needs: i64,
+}
+
+/** Follows the stack depths of each routine along its straight-line code, and across `call`s and
+ * `jump`s to constant targets, to find probable stack underflows and unbalanced routines.
+ *
+ * Anything which cannot be followed (computed targets, conditional calls of routines which change
+ * the depth, recursion) ends the analysis of a routine without a warning.
+ */
+struct Verifier<'a> {
+    routines: Vec<Routine<'a>>,
+    by_address: HashMap<usize, usize>,
+    states: Vec<Option<State>>,
+    /// The routine which runs when the program starts, with empty stacks.
+    entry: Option<usize>,
+    warnings: Vec<Warning>,
+}
+
+/// Checks the stack effects of the routines of a processed program.
+pub(crate) fn check_stacks(p: &Processor) -> Vec<Warning> {
+    let routines = p.routines();
+    let by_address = routines
+        .iter()
+        .enumerate()
+        .map(|(index, routine)| (routine.address, index))
+        .collect();
+    let mut verifier = Verifier {
+        entry: routines.iter().position(|routine| !routine.lines.is_empty()),
+        states: routines.iter().map(|_| None).collect(),
+        routines,
+        by_address,
+        warnings: Vec::new(),
+    };
+    for index in verifier.entry.into_iter().chain(0..verifier.routines.len()) {
+        verifier.effect(index);
+    }
+    verifier.warnings
+}
+
+impl<'a> Verifier<'a> {
+    fn effect(&mut self, index: usize) -> Option<Effect> {
+        match &self.states[index] {
+            Some(State::InProgress) => None,
+            Some(State::Done(effect)) => *effect,
+            None => {
+                self.states[index] = Some(State::InProgress);
+                let effect = self.simulate(index);
+                self.states[index] = Some(State::Done(effect));
+                effect
+            }
+        }
+    }
+
+    /// The effect of the routine which starts at `target`, if it can be worked out.
+    fn effect_at(&mut self, target: Option<u32>) -> Option<Effect> {
+        let index = *self.by_address.get(&(target? as usize))?;
+        self.effect(index)
+    }
+
+    fn name(&self, index: usize) -> String {
+        match self.routines[index].names.iter().min() {
+            Some(name) => name.clone(),
+            None => "<start>".into(),
+        }
+    }
+
+    fn warn(&mut self, tag: WarningTag, line: &ProcessedLine) {
+        self.warnings.push(Warning {
+            tag,
+            location: line.location.clone(),
+        });
+    }
+
+    /// Takes `count` cells off of the data stack, and returns `false` if that underflows the stack.
+    fn take(&mut self, index: usize, depth: &mut Depth, count: i64, line: &ProcessedLine) -> bool {
+        depth.data -= count;
+        depth.needs = depth.needs.max(-depth.data);
+        if depth.needs > 0 && Some(index) == self.entry {
+            let routine = self.name(index);
+            self.warn(WarningTag::DataStackUnderflow(routine), line);
+            return false;
+        }
+        true
+    }
+
+    /// Runs a called routine, and returns `false` if that underflows the stack.
+    fn run(&mut self, index: usize, depth: &mut Depth, effect: Effect, line: &ProcessedLine) -> bool {
+        if !self.take(index, depth, effect.needs, line) {
+            return false;
+        }
+        depth.data += effect.needs + effect.net;
+        true
+    }
+
+    fn simulate(&mut self, index: usize) -> Option<Effect> {
+        let lines = self.routines[index].lines.clone();
+        let address = self.routines[index].address;
+        let end = lines.last().map_or(address, |line| line.address + 1);
+        // The cells which `lit` pushes, in order.
+        let mut operands = lines.iter().filter_map(|line| match &line.body {
+            ast::LineBody::Data(ast::Data::D(ast::Size::S32, expr)) => {
+                Some(expr.as_primitive().and_then(|p| p.try_into::<u32>()))
+            }
+            _ => None,
+        });
+        let mut depth = Depth::default();
+        let mut exits: Vec<(i64, &ProcessedLine)> = Vec::new();
+        let mut literal = None;
+        let mut falls_through = true;
+        for &line in lines.iter() {
+            let op = match line.body {
+                ast::LineBody::Simple(op) => op,
+                _ => continue,
+            };
+            let target = literal.take();
+            match op {
+                OpCode::Lit => {
+                    literal = operands.next().flatten();
+                    depth.data += 1;
+                }
+                OpCode::Call | OpCode::CallIfZ => {
+                    let pops = if op == OpCode::Call { 1 } else { 2 };
+                    if !self.take(index, &mut depth, pops, line) {
+                        return None;
+                    }
+                    let effect = self.effect_at(target)?;
+                    if op == OpCode::CallIfZ && effect.net != 0 {
+                        return None;
+                    }
+                    if !self.run(index, &mut depth, effect, line) {
+                        return None;
+                    }
+                }
+                OpCode::Jump => {
+                    if !self.take(index, &mut depth, 1, line) {
+                        return None;
+                    }
+                    falls_through = false;
+                    let target = match target {
+                        Some(target) => target as usize,
+                        None => break,
+                    };
+                    if target == address && (depth.data != 0 || depth.address != 0) {
+                        let tag = WarningTag::UnbalancedLoop {
+                            routine: self.name(index),
+                            data: depth.data,
+                            address: depth.address,
+                        };
+                        self.warn(tag, line);
+                    } else if address < target && target < end {
+                        // A branch within the routine.
+                    } else if let Some(effect) = self.effect_at(Some(target as u32)) {
+                        if !self.run(index, &mut depth, effect, line) {
+                            return None;
+                        }
+                        exits.push((depth.data, line));
+                    }
+                    break;
+                }
+                OpCode::Return | OpCode::InterruptReturn | OpCode::ReturnIfZ => {
+                    if op == OpCode::ReturnIfZ && !self.take(index, &mut depth, 1, line) {
+                        return None;
+                    }
+                    if depth.address != 0 || Some(index) == self.entry {
+                        let routine = self.name(index);
+                        let tag = if depth.address > 0 {
+                            WarningTag::AddressStackUnbalanced(routine)
+                        } else {
+                            WarningTag::AddressStackUnderflow(routine)
+                        };
+                        self.warn(tag, line);
+                        return None;
+                    }
+                    exits.push((depth.data, line));
+                    if op != OpCode::ReturnIfZ {
+                        falls_through = false;
+                        break;
+                    }
+                    depth.data += 1;
+                }
+                OpCode::Halt => {
+                    falls_through = false;
+                    break;
+                }
+                _ => {
+                    let effect = op.stack_effect();
+                    if !self.take(index, &mut depth, effect.data_in as i64, line) {
+                        return None;
+                    }
+                    depth.data += effect.data_out as i64;
+                    depth.address += effect.address_out as i64 - effect.address_in as i64;
+                    if depth.address < 0 {
+                        let routine = self.name(index);
+                        self.warn(WarningTag::AddressStackUnderflow(routine), line);
+                        return None;
+                    }
+                }
+            }
+        }
+        if falls_through && index + 1 < self.routines.len() {
+            let effect = self.effect(index + 1)?;
+            let line = *lines.last()?;
+            if !self.run(index, &mut depth, effect, line) {
+                return None;
+            }
+            exits.push((depth.data, line));
+        }
+
+        let (net, _) = *exits.first()?;
+        if let Some((other, line)) = exits.iter().find(|(data, _)| *data != net) {
+            let tag = WarningTag::UnbalancedReturns {
+                routine: self.name(index),
+                here: *other,
+                first: net,
+            };
+            self.warn(tag, line);
+            return None;
+        }
+        Some(Effect {
+            needs: depth.needs,
+            net,
+        })
+    }
+}
@@ -0,0 +1,287 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use bear_app::config::{Devices, Redirect};
+use bear_app::devices::{RecordingDevice, Transcript};
+use bear_ass::debug::Resolver;
+use bear_vm::device::Device;
+
+use crate::manifest::Manifest;
+use crate::test::{boot, build, finish, Outcome};
+
+/// The directory of golden tests.
+pub const GOLDEN_DIR: &str = "tests";
+
+/// The extensions of the sources of golden tests: `.bass`, and `.bear` as `bear new` writes.
+const SOURCE_EXTENSIONS: [&str; 2] = ["bass", "bear"];
+
+/// The exit status of a guest which traps, as `bear-app` exits with.
+const TRAP_STATUS: i32 = 1;
+
+/// The result of a golden test: why it failed, if it did.
+pub type Verdict = Result<(), String>;
+
+/**
+ * Runs the golden tests in `dir`: each `name.bass` or `name.bear` with a `name.stdout`,
+ * `name.stderr`, `name.status` or `name.devices` file is run from its start until it halts or
+ * traps, or runs out of fuel, with the devices of `bear.toml` and the assert device.
+ *
+ * The stdin, stdout and stderr devices which have no path read `name.stdin` (or nothing) and are
+ * captured; the output must be exactly `name.stdout`, and the diagnostics `name.stderr`.  The exit
+ * status, as set through an exit device, must be the one in `name.status`, or 0.  Every command a
+ * device receives is recorded, and the commands received by each device listed in `name.devices`
+ * must be exactly those listed.
+ *
+ * Returns the result of each test, by path.
+ */
+pub fn run_all(
+    dir: &Path,
+    manifest: &Manifest,
+    devices: &Devices,
+    assert_id: usize,
+) -> Result<Vec<(String, Verdict)>, String> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut sources: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("{:?}: {}", dir, e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|extension| {
+                SOURCE_EXTENSIONS.iter().any(|source| extension == *source)
+            })
+        })
+        .filter(|path| {
+            ["stdout", "stderr", "status", "devices"]
+                .iter()
+                .any(|extension| path.with_extension(extension).is_file())
+        })
+        .collect();
+    sources.sort();
+
+    let mut results = Vec::new();
+    for source in sources.iter() {
+        let result = run(source, manifest, devices, assert_id)?;
+        results.push((source.display().to_string(), result));
+    }
+    Ok(results)
+}
+
+/// Runs one golden test.  Errors are for tests which cannot be run, failures are in the `Ok`.
+fn run(
+    source: &Path,
+    manifest: &Manifest,
+    devices: &Devices,
+    assert_id: usize,
+) -> Result<Verdict, String> {
+    let build = build(source, manifest, assert_id)?;
+    let redirect = Redirect {
+        stdin: read_optional(&source.with_extension("stdin"))?.unwrap_or_default(),
+        ..Default::default()
+    };
+    let transcript = Transcript::default();
+    let exit = devices.exit.clone();
+    let devices = devices
+        .build_redirected(&build.debug.strings, &Default::default(), &redirect)?
+        .into_iter()
+        .enumerate()
+        .map(|(id, device)| {
+            Box::new(RecordingDevice::new(id, device, transcript.clone())) as Box<dyn Device>
+        })
+        .collect();
+    let failed = Rc::new(RefCell::new(Vec::new()));
+    let state = boot(&build, devices, &failed, manifest.test.fuel)?;
+    let (status, trap) = match finish(state, &build, &failed, 0) {
+        Outcome::Pass => (exit.get().map_or(0, i32::from), None),
+        Outcome::Fail { ip, message } => {
+            let message = format!("{} at {}", message, Resolver::new(&build.debug).lookup(ip));
+            if !failed.borrow().is_empty() {
+                return Ok(Err(message));
+            }
+            (TRAP_STATUS, Some(message))
+        }
+        Outcome::OutOfFuel { ip, fuel } => {
+            let resolver = Resolver::new(&build.debug);
+            return Ok(Err(format!(
+                "out of fuel after {} instructions, at {}",
+                fuel,
+                resolver.lookup(ip)
+            )));
+        }
+    };
+    let path = source.with_extension("status");
+    let expected = match read_optional(&path)? {
+        Some(text) => String::from_utf8_lossy(&text)
+            .trim()
+            .parse::<i32>()
+            .map_err(|e| format!("{:?}: {}", path, e))?,
+        None => 0,
+    };
+    if status != expected {
+        return Ok(Err(match trap {
+            Some(message) => message,
+            None => format!("exit status: expected {}, got {}", expected, status),
+        }));
+    }
+
+    for (stream, capture) in [("stdout", &redirect.stdout), ("stderr", &redirect.stderr)] {
+        if let Some(expected) = read_optional(&source.with_extension(stream))? {
+            if let Err(message) = compare_output(stream, &expected, &capture.0.borrow()) {
+                return Ok(Err(message));
+            }
+        }
+    }
+    let path = source.with_extension("devices");
+    if let Some(expected) = read_optional(&path)? {
+        let expected = parse_transcript(&String::from_utf8_lossy(&expected))
+            .map_err(|e| format!("{:?}: {}", path, e))?;
+        if let Err(message) = compare_transcripts(&expected, &transcript.borrow()) {
+            return Ok(Err(message));
+        }
+    }
+    Ok(Ok(()))
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    std::fs::read(path)
+        .map(Some)
+        .map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// Describes the first line of the output to `stream` which differs.
+fn compare_output(stream: &str, expected: &[u8], actual: &[u8]) -> Result<(), String> {
+    if expected == actual {
+        return Ok(());
+    }
+    let expected = String::from_utf8_lossy(expected);
+    let actual = String::from_utf8_lossy(actual);
+    let mut expected_lines = expected.split_inclusive('\n');
+    let mut actual_lines = actual.split_inclusive('\n');
+    let mut line = 1;
+    loop {
+        let (expected, actual) = (expected_lines.next(), actual_lines.next());
+        if expected != actual {
+            return Err(format!(
+                "{} line {}: expected {}, got {}",
+                stream,
+                line,
+                describe(expected),
+                describe(actual)
+            ));
+        }
+        line += 1;
+    }
+}
+
+fn describe(line: Option<&str>) -> String {
+    match line {
+        Some(line) => format!("{:?}", line),
+        None => "the end of the output".to_string(),
+    }
+}
+
+/**
+ * Parses an expected transcript: a line `id: command -> result` for each command, as recorded by
+ * `RecordingDevice`.  Blank lines and lines starting with `--` are ignored.
+ */
+pub fn parse_transcript(text: &str) -> Result<Vec<(usize, String)>, String> {
+    let mut commands = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("--") {
+            continue;
+        }
+        let parsed = line.split_once(':').and_then(|(id, command)| {
+            let id = id.trim().parse::<usize>().ok()?;
+            Some((id, command.trim().to_string()))
+        });
+        match parsed {
+            Some(command) => commands.push(command),
+            None => {
+                return Err(format!(
+                    "line {}: expected `id: command -> result`, found {:?}",
+                    number + 1,
+                    line
+                ))
+            }
+        }
+    }
+    Ok(commands)
+}
+
+/**
+ * Compares the commands of each device in `expected`, ignoring the order of commands to different
+ * devices.  Devices which are not in `expected` are not checked.
+ */
+pub fn compare_transcripts(
+    expected: &[(usize, String)],
+    actual: &[(usize, String)],
+) -> Result<(), String> {
+    let mut ids: Vec<usize> = expected.iter().map(|(id, _)| *id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    for id in ids {
+        let of = |commands: &[(usize, String)]| -> Vec<String> {
+            commands
+                .iter()
+                .filter(|(device, _)| *device == id)
+                .map(|(_, command)| command.clone())
+                .collect()
+        };
+        let (expected, actual) = (of(expected), of(actual));
+        for index in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(index), actual.get(index));
+            if expected != actual {
+                let describe = |command: Option<&String>| match command {
+                    Some(command) => format!("`{}`", command),
+                    None => "nothing".to_string(),
+                };
+                return Err(format!(
+                    "device {} command {}: expected {}, got {}",
+                    id,
+                    index + 1,
+                    describe(expected),
+                    describe(actual)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare_transcripts() -> Result<(), String> {
+        let expected = parse_transcript(
+            "-- Only stdout is checked.\n\
+             1: exec 1 72 -> 0\n\
+             \n\
+             1: exec 1 105 -> 0\n",
+        )?;
+        let actual = |commands: &[(usize, &str)]| -> Vec<(usize, String)> {
+            commands
+                .iter()
+                .map(|(id, command)| (*id, command.to_string()))
+                .collect()
+        };
+
+        let interleaved = actual(&[(1, "exec 1 72 -> 0"), (2, "exec 0 3 -> 0"), (1, "exec 1 105 -> 0")]);
+        assert_eq!(compare_transcripts(&expected, &interleaved), Ok(()));
+
+        let short = actual(&[(1, "exec 1 72 -> 0")]);
+        assert_eq!(
+            compare_transcripts(&expected, &short),
+            Err("device 1 command 2: expected `exec 1 105 -> 0`, got nothing".to_string())
+        );
+
+        assert!(parse_transcript("exec 1 72 -> 0").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use clap::{App, AppSettings, Arg, SubCommand};
+
+mod golden;
+mod manifest;
+mod new;
+mod test;
+
+fn main() {
+    let args = App::new("bear")
+        .version("0.1.0")
+        .author("John Connor <john.theman.connor@gmail.com>")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("new")
+                .about("Create a new project")
+                .arg(Arg::with_name("path").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("test")
+                .about("Run the tests of the project, or the test_ labels of the given files and the golden tests of the given directories")
+                .arg(Arg::with_name("files").multiple(true)),
+        )
+        .get_matches();
+
+    let result = match args.subcommand() {
+        ("new", Some(args)) => {
+            let path = Path::new(args.value_of("path").unwrap());
+            new::new(path).map(|_| eprintln!("Created project {:?}.", path))
+        }
+        ("test", Some(args)) => {
+            let files: Vec<PathBuf> = args
+                .values_of("files")
+                .map(|files| files.map(PathBuf::from).collect())
+                .unwrap_or_default();
+            match test::test(&files) {
+                Ok(true) => Ok(()),
+                Ok(false) => std::process::exit(1),
+                Err(e) => Err(e),
+            }
+        }
+        _ => unreachable!(),
+    };
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        std::process::exit(1);
+    }
+}
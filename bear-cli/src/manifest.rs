@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+pub use bear_app::config::MANIFEST;
+
+/// The contents of `bear.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    pub build: Build,
+    #[serde(default)]
+    pub test: Test,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Build {
+    /// The source file that is assembled.
+    pub entry: PathBuf,
+    /// Directories searched for included files, like `bear-ass -I`.
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+}
+
+impl Default for Build {
+    fn default() -> Self {
+        Build {
+            entry: PathBuf::from("src/main.bear"),
+            include: vec![PathBuf::from("src")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Test {
+    /// The most instructions a test may execute before it fails, so that one which loops forever
+    /// does not hang `bear test`.
+    #[serde(default = "Test::default_fuel")]
+    pub fuel: u64,
+}
+
+impl Test {
+    pub const DEFAULT_FUEL: u64 = 100_000_000;
+
+    fn default_fuel() -> u64 {
+        Test::DEFAULT_FUEL
+    }
+}
+
+impl Default for Test {
+    fn default() -> Self {
+        Test { fuel: Test::DEFAULT_FUEL }
+    }
+}
+
+impl Manifest {
+    /// Reads `bear.toml` from the working directory.
+    pub fn load() -> Result<Manifest, String> {
+        let path = Path::new(MANIFEST);
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("{:?}: {} (is this a bear project?)", path, e))?;
+        toml::from_str(&text).map_err(|e| format!("{:?}: {}", path, e))
+    }
+}
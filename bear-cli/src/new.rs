@@ -0,0 +1,70 @@
+use std::path::Path;
+
+const MANIFEST: &str = include_str!("../templates/bear.toml");
+const MAIN: &str = include_str!("../templates/main.bear");
+const GITIGNORE: &str = include_str!("../templates/gitignore");
+const TEST: &str = include_str!("../templates/hello.bear");
+const TEST_STDOUT: &str = include_str!("../templates/hello.stdout");
+
+/**
+ * Creates a project skeleton in a new directory:
+ *
+ * - `bear.toml`, the project manifest.
+ * - `src/main.bear`, the entry point, which prints a greeting with `io:puts` from the standard
+ *   library.
+ * - `tests/`, golden tests: each `name.bear` is run and its output compared to `name.stdout`, and
+ *   the commands its devices receive to `name.devices`, if there is one.
+ * - `.gitignore`, ignoring build products.
+ */
+pub fn new(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return Err(format!("Destination {:?} already exists.", path));
+    }
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid project name: {:?}", path))?;
+
+    let files = [
+        ("bear.toml", MANIFEST.replace("{name}", name)),
+        ("src/main.bear", MAIN.to_string()),
+        ("tests/hello.bear", TEST.to_string()),
+        ("tests/hello.stdout", TEST_STDOUT.to_string()),
+        (".gitignore", GITIGNORE.to_string()),
+    ];
+    for (file, contents) in files.iter() {
+        let file = path.join(file);
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{:?}: {}", dir, e))?;
+        }
+        std::fs::write(&file, contents).map_err(|e| format!("{:?}: {}", file, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden;
+    use crate::manifest::{Build, Manifest};
+    use bear_app::config::Devices;
+
+    #[test]
+    fn test_new_project_passes_its_tests() -> Result<(), String> {
+        let dir = std::env::temp_dir().join(format!("bear-cli-new-{}", std::process::id()));
+        new(&dir)?;
+        let manifest = Manifest {
+            build: Build { entry: dir.join("src/main.bear"), include: vec![dir.join("src")] },
+            test: Default::default(),
+        };
+        let devices = Devices::default();
+        let results = golden::run_all(&dir.join(golden::GOLDEN_DIR), &manifest, &devices, devices.count()?);
+        let _ = std::fs::remove_dir_all(&dir);
+        let results = results?;
+        assert_eq!(results.len(), 1);
+        for (name, result) in results {
+            result.map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use bear_app::config::Devices;
+use bear_app::devices::AssertDevice;
+use bear_ass::assembler::{Assembler, Image};
+use bear_ass::debug::Resolver;
+use bear_ass::parser::{self, ast};
+use bear_ass::processor::{self, Processor};
+use bear_vm::device::Device;
+use bear_vm::vm::{BearVM, Cell, ExecutionState};
+
+use crate::golden;
+use crate::manifest::Manifest;
+
+/// Labels starting with this prefix are run as tests.
+pub const TEST_PREFIX: &str = "test_";
+/// The name defined as the id of the assert device.
+pub const ASSERT_DEVICE: &str = "test:assert";
+
+/// An assembled source file.
+pub struct Build {
+    images: Vec<Image>,
+    pub debug: ast::Debug,
+}
+
+pub enum Outcome {
+    Pass,
+    Fail { ip: usize, message: String },
+    /// The guest executed `fuel` instructions without finishing.
+    OutOfFuel { ip: usize, fuel: u64 },
+}
+
+/**
+ * Runs the unit tests of a project: every label starting with `test_` is called as a subroutine,
+ * each in a fresh VM, and passes if it returns or halts without failing an assertion.
+ *
+ * Tests are discovered in `files`, or in the manifest's entry if none are given, in which case the
+ * golden tests of `tests/` are run too.  The golden tests of any directories in `files` are run
+ * instead of discovering tests in them.
+ * The devices of `bear.toml` are attached, followed by the assert device, whose id is defined as
+ * `test:assert`.
+ *
+ * Returns `false` if any test failed.
+ */
+pub fn test(files: &[PathBuf]) -> Result<bool, String> {
+    let manifest = Manifest::load()?;
+    let devices = Devices::from_manifest()?.unwrap_or_default();
+    let assert_id = devices.count()?;
+    let (mut golden, files): (Vec<PathBuf>, Vec<PathBuf>) =
+        files.iter().cloned().partition(|file| file.is_dir());
+    let files = if files.is_empty() && golden.is_empty() {
+        golden.push(PathBuf::from(golden::GOLDEN_DIR));
+        vec![manifest.build.entry.clone()]
+    } else {
+        files
+    };
+
+    let mut passed = 0;
+    let mut failures = Vec::new();
+    for file in files.iter() {
+        let build = build(file, &manifest, assert_id)?;
+        let mut tests: Vec<(usize, &String)> = build
+            .debug
+            .entries
+            .iter()
+            .flat_map(|e| e.names.iter().map(move |name| (e.address, name)))
+            .filter(|(_, name)| name.starts_with(TEST_PREFIX))
+            .collect();
+        tests.sort();
+        for (address, name) in tests {
+            match run(&build, &devices, address, manifest.test.fuel)? {
+                Outcome::Pass => {
+                    println!("test {} ... ok", name);
+                    passed += 1;
+                }
+                Outcome::Fail { ip, message } => {
+                    println!("test {} ... FAILED", name);
+                    failures.push(format!("{}: {} at {}", name, message, Resolver::new(&build.debug).lookup(ip)));
+                }
+                Outcome::OutOfFuel { ip, fuel } => {
+                    println!("test {} ... FAILED", name);
+                    let resolver = Resolver::new(&build.debug);
+                    failures.push(format!(
+                        "{}: out of fuel after {} instructions, at {}",
+                        name,
+                        fuel,
+                        resolver.lookup(ip)
+                    ));
+                }
+            }
+        }
+    }
+    for dir in golden.iter() {
+        for (name, result) in golden::run_all(dir, &manifest, &devices, assert_id)? {
+            match result {
+                Ok(()) => {
+                    println!("test {} ... ok", name);
+                    passed += 1;
+                }
+                Err(message) => {
+                    println!("test {} ... FAILED", name);
+                    failures.push(format!("{}: {}", name, message));
+                }
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        println!("\nfailures:");
+        for failure in failures.iter() {
+            println!("    {}", failure);
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        passed,
+        failures.len()
+    );
+    Ok(failures.is_empty())
+}
+
+pub fn build(path: &Path, manifest: &Manifest, assert_id: usize) -> Result<Build, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+    let program = parser::Parser {}
+        .parse(&text)
+        .map_err(|e| format!("{:?}: {}", path, e))?;
+    let assert_id = parser::Parser {}
+        .parse_expression_str(&assert_id.to_string())
+        .map_err(|e| e.to_string())?;
+    let options = processor::Options {
+        include_paths: manifest.build.include.clone(),
+        defines: vec![(ASSERT_DEVICE.to_string(), assert_id)],
+        ..Default::default()
+    };
+    let processor = Processor::process_with(program, options)
+        .map_err(|e| e.render(&path.display().to_string(), &text))?;
+    eprint!("{}", processor.render_warnings(&path.display().to_string(), &text));
+    let debug = processor
+        .make_debug()
+        .map_err(|e| format!("{:?}: {}", path, e))?;
+    let images =
+        Assembler::assemble_sections(processor).map_err(|e| format!("{:?}: {}", path, e))?;
+    Ok(Build { images, debug })
+}
+
+/// Calls the test at `address` in a fresh VM, which may execute `fuel` instructions.
+fn run(build: &Build, devices: &Devices, address: usize, fuel: u64) -> Result<Outcome, String> {
+    let devices = devices.build(&build.debug.strings, &Default::default())?;
+    let failed = Rc::new(RefCell::new(Vec::new()));
+    let mut state = boot(build, devices, &failed, fuel)?;
+    // Call the test with a dummy return address; it has returned once that is popped.
+    let ip = state.ip_get_encoded().map_err(|e| e.to_string())?;
+    state.vm.address.push(Cell::from(ip)).map_err(|e| e.to_string())?;
+    let depth = state.vm.address.len();
+    let (word, index) = (address / 4, address % 4);
+    if let Err(e) = state.ip_set(word, word, index) {
+        return Ok(Outcome::Fail { ip: address, message: e.message().to_string() });
+    }
+    Ok(finish(state, build, &failed, depth))
+}
+
+/**
+ * Creates a fresh VM running `build`, with `devices` attached and then the assert device, which
+ * `finish` stops once it has executed `fuel` instructions.
+ */
+pub fn boot(
+    build: &Build,
+    devices: Vec<Box<dyn Device>>,
+    failed: &Rc<RefCell<Vec<u32>>>,
+    fuel: u64,
+) -> Result<ExecutionState, String> {
+    let image = bear_vm::util::convert_slice8_to_vec32(&build.images[0].bits);
+    let mut vm = BearVM::new(image).with_fuel(fuel);
+    if build.images.len() > 1 {
+        vm.load_init_blob(&Assembler::make_init_blob(&build.images))
+            .map_err(|e| e.to_string())?;
+    }
+    for device in devices {
+        vm = vm.with_device(device);
+    }
+    vm = vm.with_device(Box::new(AssertDevice::new(failed.clone())));
+    vm.start().map_err(|e| e.to_string())
+}
+
+/**
+ * Steps `state` until it halts, fails an assertion or traps, or returns from the frame that left
+ * `depth` cells on the address stack.  Running out of fuel fails too.
+ */
+pub fn finish(
+    mut state: ExecutionState,
+    build: &Build,
+    failed: &Rc<RefCell<Vec<u32>>>,
+    depth: usize,
+) -> Outcome {
+    loop {
+        let ip = state.ip();
+        if let Err(e) = state.step() {
+            return Outcome::Fail { ip, message: e.message().to_string() };
+        }
+        if let Some(id) = failed.borrow().first() {
+            let message = match build.debug.strings.get(*id as usize) {
+                Some(text) => format!("{:?}", text),
+                None => format!("<string #{}>", id),
+            };
+            return Outcome::Fail { ip, message };
+        }
+        if !state.running || state.vm.address.len() < depth {
+            return Outcome::Pass;
+        }
+        if let Some(fuel) = state.vm.fuel.filter(|fuel| state.executed() >= *fuel) {
+            return Outcome::OutOfFuel { ip: state.ip(), fuel };
+        }
+        state.sync_if_due();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_out_of_fuel_fails() -> Result<(), String> {
+        let path = std::env::temp_dir().join(format!("bear-cli-spin-{}.bear", std::process::id()));
+        std::fs::write(&path, "halt\n===:test_spin\njump &test_spin\n").map_err(|e| e.to_string())?;
+        let manifest = Manifest { build: Default::default(), test: Default::default() };
+        let build = build(&path, &manifest, 0);
+        let _ = std::fs::remove_file(&path);
+        let build = build?;
+        let entry = build.debug.entries.iter().find(|e| e.names.iter().any(|name| name == "test_spin"));
+        let devices = Devices { devices: Vec::new(), ..Default::default() };
+        match run(&build, &devices, entry.unwrap().address, 1000)? {
+            Outcome::OutOfFuel { fuel, .. } => assert_eq!(fuel, 1000),
+            _ => panic!("A test which spins forever did not run out of fuel."),
+        }
+        Ok(())
+    }
+}
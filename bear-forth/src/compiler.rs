@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::lexer::{Located, Token};
+use crate::Error;
+
+/// What a name defined by the program compiles to.
+#[derive(Debug, Clone)]
+enum Entry {
+    /// A colon definition, called at its label.
+    Routine(String),
+    /// A `variable`, which pushes the address of its label.
+    Variable(String),
+    /// A `constant`, which pushes its value.
+    Constant(i64),
+}
+
+/// An open control structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    /// `if`, and whether its `else` has been seen.
+    If(bool),
+    /// `begin`, which is closed by the given word.  For `repeat`, whether `while` has been seen.
+    Begin(&'static str, bool),
+    Do,
+}
+
+impl Control {
+    /// The word which opened the structure.
+    fn word(self) -> &'static str {
+        match self {
+            Control::If(_) => "if",
+            Control::Begin(_, _) => "begin",
+            Control::Do => "do",
+        }
+    }
+}
+
+/// The code being compiled for a definition or the main program.
+#[derive(Default)]
+struct Body {
+    lines: Vec<String>,
+    /// The open control structures, and the lines which opened them.
+    controls: Vec<(Control, usize)>,
+    /// The label of the definition, or `None` for the main program.
+    routine: Option<String>,
+}
+
+impl Body {
+    fn innermost(&mut self) -> Option<&mut Control> {
+        self.controls.last_mut().map(|(control, _)| control)
+    }
+
+    fn inside_do(&self) -> bool {
+        self.controls.iter().any(|(control, _)| *control == Control::Do)
+    }
+
+    /// The error for the innermost control structure, if one is still open.
+    fn unclosed(&self, of: &str) -> Result<(), Error> {
+        match self.controls.last() {
+            None => Ok(()),
+            Some((control, line)) => Err(Error::Compile {
+                line: *line,
+                message: format!("`{}`{} is never closed", control.word(), of),
+            }),
+        }
+    }
+}
+
+/// The primitives which compile to a fixed sequence of instructions.
+const PRIMITIVES: &[(&str, &str)] = &[
+    ("dup", "dup"),
+    ("drop", "drop"),
+    ("swap", "swap"),
+    ("over", "over"),
+    ("rot", "rot"),
+    ("nip", "nip"),
+    ("tuck", "tuck"),
+    ("2dup", "over over"),
+    ("2drop", "drop drop"),
+    ("+", "add"),
+    ("-", "swap sub"),
+    ("*", "mul"),
+    ("/", "swap div"),
+    ("mod", "swap mod"),
+    ("negate", "neg"),
+    ("abs", "abs"),
+    ("min", "min"),
+    ("max", "max"),
+    ("1+", "push 1 add"),
+    ("1-", "push 1 swap sub"),
+    ("and", "and"),
+    ("or", "or"),
+    ("xor", "xor"),
+    ("invert", "not"),
+    ("lshift", "shift"),
+    ("rshift", "push 0 sub shift"),
+    ("=", "eq"),
+    ("<>", "eq not"),
+    ("0=", "push 0 eq"),
+    ("0<", "push -31 shift push 0 sub"),
+    ("<", "gt"),
+    (">", "lt"),
+    ("u<", "gt.u"),
+    ("u>", "lt.u"),
+    ("true", "push -1"),
+    ("false", "push 0"),
+    ("@", "load"),
+    ("!", "swap store"),
+    ("c@", "load.8"),
+    ("c!", "swap store.8"),
+    ("+!", "dup push load add pop swap store"),
+    ("cells", "push 4 mul"),
+    ("cell+", "push 4 add"),
+    (">r", "push"),
+    ("r>", "pop"),
+    ("r@", "rpeek"),
+    ("depth", "depth"),
+    ("emit", "push 1 swap call &io:putc"),
+    ("key", "push 0 call &io:getc"),
+    ("cr", "push 1 push 10 call &io:putc"),
+    ("space", "push 1 push 32 call &io:putc"),
+    ("bye", "halt"),
+];
+
+/**
+ * Compiles a program, one token at a time, into the bear-ass source of its routines, its main
+ * program, and its data.
+ */
+#[derive(Default)]
+pub struct Compiler {
+    words: HashMap<String, Entry>,
+    /// The text of each `."`, by number.
+    strings: Vec<String>,
+    /// The labels of the variables.
+    variables: Vec<String>,
+    routines: String,
+    main: Body,
+}
+
+/// Makes a label from a word, which may contain any character.
+fn mangle(prefix: &str, name: &str) -> String {
+    let mut label = format!("{}:", prefix);
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c);
+        } else {
+            write!(label, "_{:x}_", c as u32).unwrap();
+        }
+    }
+    label
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None if digits.starts_with(|c: char| c.is_ascii_digit()) => digits.parse().ok()?,
+        None => return None,
+    };
+    let value = if negative { -value } else { value };
+    if (i32::MIN as i64..=u32::MAX as i64).contains(&value) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Appends `lines` to `out`, after `label`.  Labels cannot be attached to directives.
+fn write_routine(out: &mut String, label: &str, lines: &[String], end: &str) {
+    writeln!(out, ":{}", label).unwrap();
+    if lines.first().is_some_and(|line| line.trim_start().starts_with('#')) {
+        writeln!(out, "nop").unwrap();
+    }
+    for line in lines {
+        writeln!(out, "{}", line).unwrap();
+    }
+    writeln!(out, "{}", end).unwrap();
+}
+
+impl Compiler {
+    /// Compiles the tokens of one source file.
+    pub fn compile(&mut self, tokens: &[Located]) -> Result<(), Error> {
+        let mut index = 0;
+        while index < tokens.len() {
+            let Located { token, line } = &tokens[index];
+            let line = *line;
+            index += 1;
+            let word = match token {
+                Token::Word(word) => word.as_str(),
+                Token::Print(_) => "",
+            };
+            match word {
+                ":" => {
+                    let name = self.name_after(tokens, index, word, line)?;
+                    let end = tokens[index..]
+                        .iter()
+                        .position(|t| t.token == Token::Word(";".into()))
+                        .ok_or_else(|| Error::Compile {
+                            line,
+                            message: format!("`: {}` is never closed by `;`", name),
+                        })?;
+                    self.define(&name, &tokens[index + 1..index + end], line)?;
+                    index += end + 1;
+                }
+                "variable" => {
+                    let name = self.name_after(tokens, index, word, line)?;
+                    let label = mangle("var", &name);
+                    self.variables.push(label.clone());
+                    self.insert(name, Entry::Variable(label), line)?;
+                    index += 1;
+                }
+                _ => {
+                    // `value constant name`
+                    let next = tokens.get(index).map(|t| &t.token);
+                    if let (Some(value), Some(Token::Word(next))) = (parse_number(word), next) {
+                        if next == "constant" {
+                            let name = self.name_after(tokens, index + 1, next, line)?;
+                            self.insert(name, Entry::Constant(value), line)?;
+                            index += 2;
+                            continue;
+                        }
+                    }
+                    if word == "constant" {
+                        return Err(Error::Compile {
+                            line,
+                            message: "`constant` must follow a number".into(),
+                        });
+                    }
+                    let mut main = std::mem::take(&mut self.main);
+                    let result = self.compile_token(token, line, tokens, index, &mut main);
+                    self.main = main;
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn name_after(
+        &self,
+        tokens: &[Located],
+        index: usize,
+        word: &str,
+        line: usize,
+    ) -> Result<String, Error> {
+        match tokens.get(index) {
+            Some(Located {
+                token: Token::Word(name),
+                ..
+            }) => Ok(name.clone()),
+            _ => Err(Error::Compile {
+                line,
+                message: format!("`{}` must be followed by a name", word),
+            }),
+        }
+    }
+
+    fn insert(&mut self, name: String, entry: Entry, line: usize) -> Result<(), Error> {
+        if self.words.contains_key(&name) {
+            return Err(Error::Compile {
+                line,
+                message: format!("`{}` is already defined", name),
+            });
+        }
+        self.words.insert(name, entry);
+        Ok(())
+    }
+
+    /// Compiles the body of `: name ... ;`.
+    fn define(&mut self, name: &str, body: &[Located], line: usize) -> Result<(), Error> {
+        let label = mangle("word", name);
+        let mut routine = Body {
+            routine: Some(label.clone()),
+            ..Body::default()
+        };
+        let mut index = 0;
+        while index < body.len() {
+            let Located { token, line } = &body[index];
+            index += 1;
+            if let Token::Word(word) = token {
+                if word == ":" || word == "variable" || word == "constant" {
+                    return Err(Error::Compile {
+                        line: *line,
+                        message: format!("`{}` inside of the definition of `{}`", word, name),
+                    });
+                }
+            }
+            self.compile_token(token, *line, body, index, &mut routine)?;
+        }
+        routine.unclosed(&format!(" in `{}`", name))?;
+        writeln!(self.routines, "-- : {}", name).unwrap();
+        write_routine(&mut self.routines, &label, &routine.lines, "ret");
+        writeln!(self.routines).unwrap();
+        self.insert(name.to_string(), Entry::Routine(label), line)
+    }
+
+    /**
+     * Compiles one token to the lines of `body`.  The tokens from `rest` on follow it, and are
+     * searched by `begin` for its closing word.
+     */
+    fn compile_token(
+        &mut self,
+        token: &Token,
+        line: usize,
+        tokens: &[Located],
+        rest: usize,
+        body: &mut Body,
+    ) -> Result<(), Error> {
+        let error = |message: String| Error::Compile { line, message };
+        let word = match token {
+            Token::Print(text) => {
+                let label = format!("forth:string:{}", self.strings.len());
+                self.strings.push(text.clone());
+                let indent = "    ".repeat(body.controls.len());
+                body.lines.push(format!("{}push 1 push &{} call &io:puts", indent, label));
+                return Ok(());
+            }
+            Token::Word(word) => word.as_str(),
+        };
+        let unmatched = || error(format!("`{}` does not match an open control structure", word));
+        let mut depth = body.controls.len();
+        let code = match word {
+            "if" => {
+                body.controls.push((Control::If(false), line));
+                "#if;".to_string()
+            }
+            "else" => match body.innermost() {
+                Some(Control::If(seen @ false)) => {
+                    *seen = true;
+                    depth -= 1;
+                    "#else;".to_string()
+                }
+                _ => return Err(unmatched()),
+            },
+            "then" => match body.innermost() {
+                Some(Control::If(_)) => {
+                    body.controls.pop();
+                    depth -= 1;
+                    "#end;".to_string()
+                }
+                _ => return Err(unmatched()),
+            },
+            "begin" => {
+                let closer = closing_word(&tokens[rest..])
+                    .ok_or_else(|| error("`begin` is never closed".into()))?;
+                body.controls.push((Control::Begin(closer, false), line));
+                if closer == "repeat" { "#while;" } else { "#loop;" }.to_string()
+            }
+            "while" => match body.innermost() {
+                Some(Control::Begin("repeat", seen @ false)) => {
+                    *seen = true;
+                    depth -= 1;
+                    "#do;".to_string()
+                }
+                _ => return Err(unmatched()),
+            },
+            "until" | "again" | "repeat" => match body.innermost() {
+                Some(Control::Begin(closer, seen)) if *closer == word && (word != "repeat" || *seen) => {
+                    body.controls.pop();
+                    depth -= 1;
+                    if word == "until" { "#until;" } else { "#end;" }.to_string()
+                }
+                _ => return Err(unmatched()),
+            },
+            "do" => {
+                body.controls.push((Control::Do, line));
+                "swap push push #loop;".to_string()
+            }
+            "loop" => match body.innermost() {
+                Some(Control::Do) => {
+                    body.controls.pop();
+                    depth -= 1;
+                    let indent = "    ".repeat(depth + 1);
+                    body.lines.push(format!("{}pop push 1 add dup pop dup push swap push eq", indent));
+                    "#until; rdrop rdrop".to_string()
+                }
+                _ => return Err(unmatched()),
+            },
+            "i" if body.inside_do() => "rpeek".to_string(),
+            "i" => return Err(error("`i` outside of `do ... loop`".into())),
+            "exit" if body.inside_do() => {
+                return Err(error("`exit` inside of `do ... loop`".into()))
+            }
+            "exit" if body.routine.is_some() => "ret".to_string(),
+            "exit" => "halt".to_string(),
+            "recurse" => match &body.routine {
+                Some(label) => format!("call &{}", label),
+                None => return Err(error("`recurse` outside of a definition".into())),
+            },
+            _ => match self.words.get(word) {
+                Some(Entry::Routine(label)) => format!("call &{}", label),
+                Some(Entry::Variable(label)) => format!("push &{}", label),
+                Some(Entry::Constant(value)) => format!("push {}", value),
+                None => match PRIMITIVES.iter().find(|(name, _)| *name == word) {
+                    Some((_, code)) => code.to_string(),
+                    None => match parse_number(word) {
+                        Some(value) => format!("push {}", value),
+                        None => return Err(error(format!("unknown word `{}`", word))),
+                    },
+                },
+            },
+        };
+        body.lines.push(format!("{}{}", "    ".repeat(depth), code));
+        Ok(())
+    }
+
+    /// The bear-ass source of the program.
+    pub fn finish(self) -> Result<String, Error> {
+        self.main.unclosed("")?;
+        let mut out = String::new();
+        writeln!(out, "-- Compiled by bear-forth.").unwrap();
+        writeln!(out, "jump &forth:main").unwrap();
+        writeln!(out, "#include <std/io.bear>;").unwrap();
+        writeln!(out).unwrap();
+        out.push_str(&self.routines);
+        write_routine(&mut out, "forth:main", &self.main.lines, "halt");
+        for (number, text) in self.strings.iter().enumerate() {
+            let escaped: String = text.chars().flat_map(char::escape_default).collect();
+            writeln!(out, "\n:forth:string:{} c\"{}\"", number, escaped).unwrap();
+        }
+        for label in self.variables.iter() {
+            writeln!(out, "\n===:{}\nd32 0", label).unwrap();
+        }
+        Ok(out)
+    }
+}
+
+/// The word which closes a `begin` whose body starts with `tokens`.
+fn closing_word(tokens: &[Located]) -> Option<&'static str> {
+    let mut depth = 0;
+    for located in tokens {
+        let word = match &located.token {
+            Token::Word(word) => word.as_str(),
+            Token::Print(_) => continue,
+        };
+        match word {
+            "begin" => depth += 1,
+            "until" | "again" | "repeat" if depth > 0 => depth -= 1,
+            "until" => return Some("until"),
+            "again" => return Some("again"),
+            "repeat" => return Some("repeat"),
+            ";" => return None,
+            _ => {}
+        }
+    }
+    None
+}
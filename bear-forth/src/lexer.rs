@@ -0,0 +1,90 @@
+use crate::Error;
+
+/// A word of the source, or the text of a string literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    /// The text of `." text"`.
+    Print(String),
+}
+
+/// A token, and the line it starts on.
+#[derive(Debug, Clone)]
+pub struct Located {
+    pub token: Token,
+    pub line: usize,
+}
+
+/**
+ * Splits the source into whitespace separated words, skipping `\ ...` and `( ... )` comments, and
+ * reading the text of `." ..."` up to the closing quote.
+ */
+pub fn tokenize(source: &str) -> Result<Vec<Located>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    loop {
+        while let Some(&c) = chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            if c == '\n' {
+                line += 1;
+            }
+            chars.next();
+        }
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        if word.is_empty() {
+            return Ok(tokens);
+        }
+        let start = line;
+        match word.as_str() {
+            "\\" => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            "(" | ".\"" => {
+                let close = if word == "(" { ')' } else { '"' };
+                // The space after the word is not part of the text.
+                chars.next_if(|c| c.is_whitespace() && *c != '\n');
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        None => {
+                            let message = format!("`{}` is never closed by `{}`", word, close);
+                            return Err(Error::Compile { line: start, message });
+                        }
+                        Some(c) if c == close => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                    }
+                }
+                if word != "(" {
+                    tokens.push(Located {
+                        token: Token::Print(text),
+                        line: start,
+                    });
+                }
+            }
+            _ => tokens.push(Located {
+                token: Token::Word(word.to_lowercase()),
+                line,
+            }),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+/*!
+ * Compiles a small Forth-like language to bear-ass source, which `bear_ass` assembles to an image.
+ *
+ * A program is a sequence of words.  `: name ... ;` defines a routine, `variable name` a cell of
+ * memory, and `value constant name` a constant; the remaining words form the main program, which
+ * runs when the image starts and halts at its end.
+ */
+
+mod compiler;
+mod lexer;
+
+use bear_ass::parser::ast;
+
+/// Definitions written in the language itself, which are compiled before every program.
+pub const PRELUDE: &str = r#"
+: (.) dup 9 u> if dup 10 / recurse then 10 mod 48 + emit ;
+: . dup 0< if 45 emit negate then (.) space ;
+: u. (.) space ;
+: type begin dup while swap dup c@ emit 1+ swap 1- repeat 2drop ;
+"#;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The program is invalid; `line` is 1-based.
+    Compile { line: usize, message: String },
+    /// The generated source did not assemble.
+    Assembler(bear_ass::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Compile { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Assembler(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Compile { .. } => None,
+            Error::Assembler(e) => Some(e),
+        }
+    }
+}
+
+impl From<bear_ass::Error> for Error {
+    fn from(e: bear_ass::Error) -> Self {
+        Error::Assembler(e)
+    }
+}
+
+/// Compiles a program to bear-ass source.
+pub fn compile(source: &str) -> Result<String, Error> {
+    let mut compiler = compiler::Compiler::default();
+    compiler.compile(&lexer::tokenize(PRELUDE)?)?;
+    compiler.compile(&lexer::tokenize(source)?)?;
+    compiler.finish()
+}
+
+/// Compiles a program, and assembles it to an image and its debug info.
+pub fn compile_image(source: &str) -> Result<(Vec<u8>, ast::Debug), Error> {
+    Ok(bear_ass::assemble(&compile(source)?)?)
+}
@@ -0,0 +1,146 @@
+use std::io::Write;
+
+use bear_forth::Error;
+
+const USAGE: &str = "bear-forth v1.0\n\
+\n\
+USAGE: bear-forth [--emit-asm] in out\n\
+\n\
+OPTIONS:\n\
+    --emit-asm  Write the generated bear-ass source to out, rather than the image.\n\
+\n\
+The debug info of the image is written next to out.\n";
+
+fn go() -> Result<bool, Error> {
+    let mut emit_asm = false;
+    let mut positional = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if arg == "--emit-asm" {
+            emit_asm = true;
+        } else {
+            positional.push(arg);
+        }
+    }
+    if positional.len() != 2 {
+        return Ok(false);
+    }
+    let io = |e: std::io::Error| Error::Assembler(bear_ass::Error::IOError(e));
+    let source = std::fs::read_to_string(&positional[0]).map_err(io)?;
+    let out = std::path::Path::new(&positional[1]);
+    if emit_asm {
+        std::fs::write(out, bear_forth::compile(&source)?).map_err(io)?;
+        return Ok(true);
+    }
+    let (image, debug) = bear_forth::compile_image(&source)?;
+    std::fs::write(out, image).map_err(io)?;
+    let mut debug_file = std::fs::File::create(out.with_extension("debug")).map_err(io)?;
+    serde_json::to_writer_pretty(&mut debug_file, &debug)
+        .map_err(|e| Error::Assembler(bear_ass::Error::SerdeError(e)))?;
+    debug_file.flush().map_err(io)?;
+    Ok(true)
+}
+
+fn main() {
+    match go() {
+        Ok(true) => std::process::exit(0),
+        Ok(false) => eprintln!("{}", USAGE),
+        Err(error) => eprintln!("error: {}", error),
+    }
+    std::process::exit(-2)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use bear_forth::Error;
+    use bear_vm::device::{DMARequest, Device};
+    use bear_vm::vm::BearVM;
+
+    /// Collects the bytes written to the stdout device.
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Device for Output {
+        fn ioctl(&mut self, command: u32) -> u32 {
+            self.0.borrow_mut().push(command as u8);
+            0
+        }
+
+        fn dma_poll(&mut self) -> Option<DMARequest> {
+            None
+        }
+
+        fn dma_write_response(&mut self, _address: usize) {}
+
+        fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+    }
+
+    /// Runs a program, and returns its data stack and what it printed.
+    fn run(source: &str) -> Result<(Vec<u32>, String), Error> {
+        let (image, _) = bear_forth::compile_image(source)?;
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let vm = BearVM::new(bear_vm::util::convert_slice8_to_vec32(&image))
+            .with_device(Box::new(Output(Rc::default())))
+            .with_device(Box::new(Output(output.clone())));
+        let mut state = vm.start().expect("the image does not start");
+        state.run().expect("the program traps");
+        let data = state.vm.data.iter().map(|cell| cell.0).collect();
+        let text = String::from_utf8(output.borrow().clone()).unwrap();
+        Ok((data, text))
+    }
+
+    #[test]
+    fn test_arithmetic() -> Result<(), Error> {
+        let (data, _) = run("7 3 - 7 3 / 7 3 mod 2 3 4 rot over -1 0< 1 2 < 2 1 <")?;
+        assert_eq!(data, vec![4, 2, 1, 3, 4, 2, 4, u32::MAX, u32::MAX, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_definitions() -> Result<(), Error> {
+        let (data, text) = run("
+            \\ Words, variables and constants.
+            : square ( n -- n*n ) dup * ;
+            : fact dup 1 > if dup 1- recurse * then ;
+            variable total
+            10 constant ten
+            ten 0 do i square total +! loop
+            total @ 5 fact
+            .\" done\" cr -42 .
+        ")?;
+        assert_eq!(data, vec![285, 120]);
+        assert_eq!(text, "done\n-42 ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_loops() -> Result<(), Error> {
+        let (data, text) = run("
+            : count begin dup 3 < while dup . 1+ repeat ;
+            0 count
+            0 begin 1+ dup 5 = until
+            2 0 if 1 else 3 then
+        ")?;
+        assert_eq!(data, vec![3, 5, 2, 3]);
+        assert_eq!(text, "0 1 2 ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        for (source, line) in [
+            ("1 2\nfrobnicate", 2),
+            (": f if ;", 1),
+            ("then", 1),
+            (": f\n  1 do loop i ;", 2),
+            ("\n: square dup * ; : square ;", 2),
+            ("\n\nbegin 1", 3),
+        ] {
+            match bear_forth::compile(source) {
+                Err(Error::Compile { line: found, .. }) => assert_eq!(found, line, "{}", source),
+                result => panic!("{} compiled to {:?}", source, result),
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+/*!
+ * The `bearvm` Python module, for scripting test benches over guest programs.  Built with the
+ * `python` feature, e.g. by `maturin develop --features python`.
+ *
+ * ```python
+ * import bearvm
+ *
+ * image, labels = bearvm.assemble_str(source)
+ * vm = bearvm.VM(image, devices=[lambda command: 0])
+ * vm.run(fuel=10_000)
+ * print(vm.data, vm.ip, vm.read_word(labels["result"]))
+ * ```
+ */
+#![cfg(feature = "python")]
+// The code generated by `#[pyfunction]` / `#[pymethods]` converts every `PyResult`.
+#![allow(clippy::useless_conversion)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIndexError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use bear_vm::cell::{self, Cell};
+use bear_vm::device::{DMARequest, Device};
+use bear_vm::stack::Stack;
+use bear_vm::vm::{BearVM, ExecutionState};
+
+create_exception!(bearvm, BearError, PyException);
+
+/// The exception raised by a device callable, which is raised again by `run` or `step`.
+type Pending = Rc<RefCell<Option<PyErr>>>;
+
+/// A device whose `io`s are answered by a Python callable taking the command.
+struct PythonDevice {
+    callable: PyObject,
+    pending: Pending,
+}
+
+impl Device for PythonDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        Python::with_gil(|py| {
+            let result = self
+                .callable
+                .call1(py, (command,))
+                .and_then(|value| value.extract::<u32>(py));
+            result.unwrap_or_else(|e| {
+                self.pending.borrow_mut().get_or_insert(e);
+                u32::MAX
+            })
+        })
+    }
+
+    fn dma_poll(&mut self) -> Option<DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// Assembles a program, returning the ROM image.
+#[pyfunction]
+fn assemble(py: Python<'_>, source: &str) -> PyResult<PyObject> {
+    let (image, _) = bear_ass::assemble(source).map_err(|e| BearError::new_err(e.to_string()))?;
+    Ok(PyBytes::new_bound(py, &image).into())
+}
+
+/// Assembles a program, returning the ROM image and the address of each label.
+#[pyfunction]
+fn assemble_str(py: Python<'_>, source: &str) -> PyResult<(PyObject, HashMap<String, usize>)> {
+    let (image, debug) = bear_ass::assemble(source).map_err(|e| BearError::new_err(e.to_string()))?;
+    let labels = debug
+        .entries
+        .iter()
+        .flat_map(|entry| entry.names.iter().map(move |name| (name.clone(), entry.address)))
+        .collect();
+    Ok((PyBytes::new_bound(py, &image).into(), labels))
+}
+
+/// Replaces the cells of `stack`, bottom first.
+fn fill(stack: &mut Stack, cells: Vec<u32>) -> PyResult<()> {
+    stack.clear();
+    for value in cells {
+        stack
+            .push(Cell(value))
+            .map_err(|e| BearError::new_err(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// A VM, ready to run its image from address `0`.  Device `i` of `devices` has id `i`.
+#[pyclass(unsendable)]
+struct VM {
+    state: ExecutionState,
+    pending: Pending,
+}
+
+impl VM {
+    fn step_once(&mut self) -> PyResult<()> {
+        let result = self.state.step();
+        if let Some(e) = self.pending.borrow_mut().take() {
+            return Err(e);
+        }
+        result.map_err(|e| BearError::new_err(e.to_string()))?;
+        if self.state.running {
+            self.state.sync_if_due();
+        }
+        Ok(())
+    }
+
+    fn word_index(&self, address: usize) -> PyResult<usize> {
+        let index = address / cell::SIZE;
+        if !address.is_multiple_of(cell::SIZE) || index >= self.state.vm.image.len() {
+            return Err(PyIndexError::new_err(format!("no word at {:#x}", address)));
+        }
+        Ok(index)
+    }
+}
+
+#[pymethods]
+impl VM {
+    #[new]
+    #[pyo3(signature = (image, devices = Vec::new()))]
+    fn new(image: &[u8], devices: Vec<PyObject>) -> PyResult<VM> {
+        let image = bear_vm::util::convert_slice8_to_vec32(image);
+        if image.is_empty() {
+            return Err(BearError::new_err("the image is empty"));
+        }
+        let pending = Pending::default();
+        let mut vm = BearVM::new(image);
+        for callable in devices {
+            let pending = pending.clone();
+            vm = vm.with_device(Box::new(PythonDevice { callable, pending }));
+        }
+        let state = vm.start().map_err(|e| BearError::new_err(e.to_string()))?;
+        Ok(VM { state, pending })
+    }
+
+    /// Executes one instruction, unless the guest has halted.
+    fn step(&mut self) -> PyResult<()> {
+        if self.state.running {
+            self.step_once()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the guest halts, or for at most `fuel` instructions.  Returns whether it halted.
+    #[pyo3(signature = (fuel = None))]
+    fn run(&mut self, fuel: Option<u64>) -> PyResult<bool> {
+        let mut remaining = fuel;
+        while self.state.running && remaining != Some(0) {
+            self.step_once()?;
+            remaining = remaining.map(|fuel| fuel - 1);
+        }
+        Ok(!self.state.running)
+    }
+
+    #[getter]
+    fn halted(&self) -> bool {
+        !self.state.running
+    }
+
+    /// The address of the next instruction.
+    #[getter]
+    fn ip(&self) -> usize {
+        self.state.ip()
+    }
+
+    /// The data stack, bottom first.
+    #[getter]
+    fn data(&self) -> Vec<u32> {
+        self.state.vm.data.iter().map(|cell| cell.0).collect()
+    }
+
+    #[setter]
+    fn set_data(&mut self, data: Vec<u32>) -> PyResult<()> {
+        fill(&mut self.state.vm.data, data)
+    }
+
+    /// The address stack, bottom first.
+    #[getter]
+    fn address(&self) -> Vec<u32> {
+        self.state.vm.address.iter().map(|cell| cell.0).collect()
+    }
+
+    #[setter]
+    fn set_address(&mut self, address: Vec<u32>) -> PyResult<()> {
+        fill(&mut self.state.vm.address, address)
+    }
+
+    /// Reads the word at a word-aligned byte address.
+    fn read_word(&self, address: usize) -> PyResult<u32> {
+        Ok(self.state.vm.image[self.word_index(address)?])
+    }
+
+    /// Writes the word at a word-aligned byte address.
+    fn write_word(&mut self, address: usize, value: u32) -> PyResult<()> {
+        let index = self.word_index(address)?;
+        self.state.write_word(index, value);
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn bearvm(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(assemble, m)?)?;
+    m.add_function(wrap_pyfunction!(assemble_str, m)?)?;
+    m.add_class::<VM>()?;
+    m.add("BearError", m.py().get_type_bound::<BearError>())?;
+    Ok(())
+}
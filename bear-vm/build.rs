@@ -0,0 +1,17 @@
+/// Writes the C header of the `ffi` feature, which declares the items of `src/ffi.rs`.
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        cbindgen::Builder::new()
+            .with_src(format!("{}/src/ffi.rs", dir))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("BEAR_VM_H")
+            .with_parse_deps(false)
+            .with_documentation(true)
+            .generate()
+            .expect("Could not generate the C header.")
+            .write_to_file(format!("{}/include/bear_vm.h", dir));
+    }
+}
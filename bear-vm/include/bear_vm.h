@@ -0,0 +1,182 @@
+#ifndef BEAR_VM_H
+#define BEAR_VM_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The outcome of a call.
+ */
+typedef enum BearStatus {
+  /**
+   * The call succeeded; for `bear_vm_run`, the fuel ran out before the guest halted.
+   */
+  BearOk = 0,
+  /**
+   * The guest has halted.
+   */
+  BearHalted = 1,
+  /**
+   * The guest failed with a runtime error.
+   */
+  BearTrap = -1,
+  /**
+   * An argument was invalid, e.g. an address outside of memory, or an empty or full stack.
+   */
+  BearInvalid = -2,
+} BearStatus;
+
+/**
+ * A VM and its execution state.
+ */
+typedef struct BearVm BearVm;
+
+/**
+ * A device implemented by the host.  `user_data` is passed back to every callback.
+ */
+typedef struct BearDeviceCallbacks {
+  void *user_data;
+  /**
+   * Answers an `io` with the given command.
+   */
+  uint32_t (*ioctl)(void *user_data, uint32_t command);
+  /**
+   * Called when the guest halts, if not null.
+   */
+  void (*halt)(void *user_data);
+} BearDeviceCallbacks;
+
+/**
+ * Creates a VM with the image of `len` bytes at `image`, ready to run from address `0`.
+ * Returns null if the image is empty.
+ *
+ * # Safety
+ *
+ * `image` must point to `len` readable bytes.
+ */
+struct BearVm *bear_vm_new(const uint8_t *image, uintptr_t len);
+
+/**
+ * Destroys a VM.
+ *
+ * # Safety
+ *
+ * `vm` must be null or a handle from `bear_vm_new`, which is not used again.
+ */
+void bear_vm_free(struct BearVm *vm);
+
+/**
+ * Copies the records of a RAM-initialization blob (the `.init` file of `bear-ass`) into memory.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle, and `blob` must point to `len` readable bytes.
+ */
+enum BearStatus bear_vm_load_init_blob(struct BearVm *vm, const uint8_t *blob, uintptr_t len);
+
+/**
+ * Attaches a device implemented by the host, and returns its id.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle, and the callbacks must be safe to call with `user_data` for as long
+ * as the VM lives.
+ */
+uintptr_t bear_vm_attach_device(struct BearVm *vm, struct BearDeviceCallbacks callbacks);
+
+/**
+ * Executes a single instruction.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+enum BearStatus bear_vm_step(struct BearVm *vm);
+
+/**
+ * Executes at most `fuel` instructions, stopping early if the guest halts or traps.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+enum BearStatus bear_vm_run(struct BearVm *vm, uint64_t fuel);
+
+/**
+ * The address of the next instruction.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+uintptr_t bear_vm_ip(const struct BearVm *vm);
+
+/**
+ * The message of the last error, or null.  It is valid until the next call with `vm`.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+const char *bear_vm_last_error(const struct BearVm *vm);
+
+/**
+ * Reads the word at the byte address `address`, which must be word aligned.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle, and `value` must be writable.
+ */
+enum BearStatus bear_vm_read_word(struct BearVm *vm, uintptr_t address, uint32_t *value);
+
+/**
+ * Writes the word at the byte address `address`, which must be word aligned.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+enum BearStatus bear_vm_write_word(struct BearVm *vm, uintptr_t address, uint32_t value);
+
+/**
+ * The number of cells on the data stack, or the address stack if `address` is true.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+uintptr_t bear_vm_stack_len(struct BearVm *vm, bool address);
+
+/**
+ * Reads the cell `depth` below the top of a stack; `0` is the top.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle, and `value` must be writable.
+ */
+enum BearStatus bear_vm_stack_peek(struct BearVm *vm,
+                                   bool address,
+                                   uintptr_t depth,
+                                   uint32_t *value);
+
+/**
+ * Pushes a cell onto a stack.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle.
+ */
+enum BearStatus bear_vm_stack_push(struct BearVm *vm, bool address, uint32_t value);
+
+/**
+ * Pops a cell off of a stack.
+ *
+ * # Safety
+ *
+ * `vm` must be a live handle, and `value` must be writable.
+ */
+enum BearStatus bear_vm_stack_pop(struct BearVm *vm, bool address, uint32_t *value);
+
+#endif /* BEAR_VM_H */
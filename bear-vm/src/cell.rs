@@ -1,61 +1,82 @@
+// Casts between the cell types and fixed-width integers are no-ops in one profile or the other.
+#![allow(clippy::unnecessary_cast)]
+
 use std::convert::TryFrom;
 use std::convert::TryInto;
-use std::mem::transmute_copy;
+
+/// The unsigned integer held by a cell: 32 bits, or 16 bits with the `small` feature.
+#[cfg(not(feature = "small"))]
+pub type CellType = u32;
+/// The unsigned integer held by a cell: 32 bits, or 16 bits with the `small` feature.
+#[cfg(feature = "small")]
+pub type CellType = u16;
+/// `CellType`, as an atomic.
+#[cfg(not(feature = "small"))]
+pub type AtomicCellType = std::sync::atomic::AtomicU32;
+/// `CellType`, as an atomic.
+#[cfg(feature = "small")]
+pub type AtomicCellType = std::sync::atomic::AtomicU16;
+/// `CellType`, signed.
+#[cfg(not(feature = "small"))]
+pub type SignedCellType = i32;
+/// `CellType`, signed.
+#[cfg(feature = "small")]
+pub type SignedCellType = i16;
 
 /**
  * Represents a cell of memory.
  *
  * Size conversions, signed and unsigned operations, etc. are all here.
+ * Conversions from wider integers truncate, like the machine does.
  */
-
-pub type CellType = u32;
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Cell(pub u32);
-pub const SIZE: usize = std::mem::size_of::<u32>();
+pub struct Cell(pub CellType);
+/// The size of a cell in bytes, which is also the number of instructions packed in a word.
+pub const SIZE: usize = std::mem::size_of::<CellType>();
 
 impl From<u32> for Cell {
     fn from(x: u32) -> Cell {
-        Cell(x)
+        Cell(x as CellType)
     }
 }
 impl From<u16> for Cell {
     fn from(x: u16) -> Cell {
-        Cell(x as u32)
+        Cell(x as CellType)
     }
 }
 impl From<u8> for Cell {
     fn from(x: u8) -> Cell {
-        Cell(x as u32)
+        Cell(x as CellType)
     }
 }
 impl From<i32> for Cell {
     fn from(x: i32) -> Cell {
-        Cell(unsafe { transmute_copy(&x) })
+        Cell(x as CellType)
     }
 }
 impl From<i16> for Cell {
     fn from(x: i16) -> Cell {
-        Cell::from(x as i32)
+        Cell(x as SignedCellType as CellType)
     }
 }
 impl From<i8> for Cell {
     fn from(x: i8) -> Cell {
-        Cell::from(x as i32)
+        Cell(x as SignedCellType as CellType)
     }
 }
 
 impl From<Cell> for u32 {
     fn from(val: Cell) -> Self {
         let Cell(x) = val;
-        x
+        x as u32
     }
 }
 
+/// Sign-extends the cell.
 impl From<Cell> for i32 {
     fn from(val: Cell) -> Self {
         let Cell(x) = val;
-        unsafe { transmute_copy(&x) }
+        x as SignedCellType as i32
     }
 }
 
@@ -66,11 +87,11 @@ impl From<Cell> for usize {
     }
 }
 
+/// Sign-extends the cell.
 impl From<Cell> for isize {
     fn from(val: Cell) -> Self {
         let Cell(x) = val;
-        let x: i32 = unsafe { transmute_copy(&x) };
-        x as isize
+        x as SignedCellType as isize
     }
 }
 
@@ -86,7 +107,7 @@ impl TryInto<u16> for Cell {
     type Error = std::num::TryFromIntError;
     fn try_into(self) -> Result<u16, Self::Error> {
         let Cell(x) = self;
-        x.try_into()
+        (x as u32).try_into()
     }
 }
 
@@ -109,16 +130,16 @@ impl TryInto<i16> for Cell {
 impl TryFrom<isize> for Cell {
     type Error = std::num::TryFromIntError;
     fn try_from(x: isize) -> Result<Self, Self::Error> {
-        let x: i32 = x.try_into()?;
-        Ok(Cell::from(x))
+        let x: SignedCellType = x.try_into()?;
+        Ok(Cell(x as CellType))
     }
 }
 
 impl TryFrom<usize> for Cell {
     type Error = std::num::TryFromIntError;
     fn try_from(x: usize) -> Result<Self, Self::Error> {
-        let x: u32 = x.try_into()?;
-        Ok(Cell::from(x))
+        let x: CellType = x.try_into()?;
+        Ok(Cell(x))
     }
 }
 
@@ -158,10 +179,9 @@ impl std::ops::Neg for Cell {
     type Output = Self;
 
     fn neg(self) -> Self {
-        let x: i32 = unsafe { transmute_copy(&self.0) };
-        let x = -x;
-        let x: u32 = unsafe { transmute_copy(&x) };
-        Cell(x)
+        let x = self.0 as SignedCellType;
+        let x = x.wrapping_neg();
+        Cell(x as CellType)
     }
 }
 
@@ -197,6 +217,7 @@ impl std::ops::Mul for Cell {
     }
 }
 
+#[allow(clippy::should_implement_trait)]
 impl Cell {
     pub fn rem(self, other: Self) -> Cell {
         let r = self.0 % other.0;
@@ -208,4 +229,24 @@ impl Cell {
         let r = self.0 % other.0;
         (q.into(), r.into())
     }
+
+    /// The high cell of the unsigned product, i.e. the product shifted right by the bits in a cell.
+    pub fn mul_high(self, other: Self) -> Cell {
+        let product = self.0 as u64 * other.0 as u64;
+        Cell((product >> (SIZE * 8)) as CellType)
+    }
+
+    /// The sum of two cells and a carry, and whether it carried out of the cell.
+    pub fn add_carry(self, other: Self, carry: bool) -> (Cell, bool) {
+        let (sum, c1) = self.0.overflowing_add(other.0);
+        let (sum, c2) = sum.overflowing_add(carry as CellType);
+        (Cell(sum), c1 || c2)
+    }
+
+    /// `other` and a borrow subtracted from this cell, and whether it borrowed from outside it.
+    pub fn sub_borrow(self, other: Self, borrow: bool) -> (Cell, bool) {
+        let (difference, b1) = self.0.overflowing_sub(other.0);
+        let (difference, b2) = difference.overflowing_sub(borrow as CellType);
+        (Cell(difference), b1 || b2)
+    }
 }
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use crate::cell::CellType;
+
+/// The number of words in a page, the unit in which checkpoints copy the image.
+pub const PAGE_WORDS: usize = 256;
+
+/**
+ * A handle on a checkpoint taken by `ExecutionState::checkpoint`.  It is stale once the
+ * checkpoint is released, or discarded by a rollback to an earlier checkpoint.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Checkpoint {
+    /// The number of checkpoints taken before it which are still live.
+    pub(crate) depth: usize,
+    /// Tells the checkpoint from the others which had the same depth.
+    pub(crate) serial: u64,
+}
+
+/**
+ * The pages of the image written since a checkpoint was taken, as they were then.  Only the
+ * newest checkpoint saves pages: a page written after a later checkpoint was taken is saved by
+ * that checkpoint, and holds the same words as when the earlier one was taken.
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Pages(HashMap<usize, Vec<CellType>>);
+
+impl Pages {
+    /// Saves the page of the word at `index`, before it is first written.
+    pub(crate) fn save(&mut self, index: usize, image: &[CellType]) {
+        let page = index / PAGE_WORDS;
+        self.0.entry(page).or_insert_with(|| {
+            let start = page * PAGE_WORDS;
+            image[start..(start + PAGE_WORDS).min(image.len())].to_vec()
+        });
+    }
+
+    /// Writes the saved words back, with the index of each.
+    pub(crate) fn restore(self, mut write: impl FnMut(usize, CellType)) {
+        for (page, words) in self.0 {
+            for (offset, word) in words.into_iter().enumerate() {
+                write(page * PAGE_WORDS + offset, word);
+            }
+        }
+    }
+
+    /**
+     * Takes over the pages saved by the checkpoint taken after this one, which is released, unless
+     * this one saved them first.
+     */
+    pub(crate) fn merge(&mut self, later: Pages) {
+        for (page, words) in later.0 {
+            self.0.entry(page).or_insert(words);
+        }
+    }
+}
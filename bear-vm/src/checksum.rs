@@ -0,0 +1,81 @@
+use std::convert::TryInto;
+
+/**
+ * Marks the checksum header which starts an image: these bytes, then the length in bytes of the
+ * image after the header and its CRC-32, little-endian.  The length is in the header, rather than
+ * a footer, so an image which loses its end still has it, and is caught.
+ */
+pub const HEADER_MAGIC: &[u8; 4] = b"BCRC";
+/// The length of the header in bytes, three words, so the image stays a whole number of words.
+pub const HEADER_LEN: usize = 12;
+
+/// The CRC-32 (IEEE 802.3, as used by zip and PNG) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// The image preceded by its checksum header.
+pub fn with_header(image: &[u8]) -> Vec<u8> {
+    let mut bytes = HEADER_MAGIC.to_vec();
+    bytes.extend(&(image.len() as u32).to_le_bytes());
+    bytes.extend(&crc32(image).to_le_bytes());
+    bytes.extend(image);
+    bytes
+}
+
+/// A header which did not match the image after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The image is not as long as the header says.
+    Length { expected: u32, actual: u32 },
+    /// The image does not have the checksum the header says.
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Length { expected, actual } => write!(
+                f,
+                "Image length mismatch: expected {} bytes, got {}; the image is truncated or corrupt.",
+                expected, actual
+            ),
+            Mismatch::Checksum { expected, actual } => write!(
+                f,
+                "Image checksum mismatch: expected {:08x}, got {:08x}; the image is truncated or corrupt.",
+                expected, actual
+            ),
+        }
+    }
+}
+
+/**
+ * Splits the checksum header off of an image, once it is checked, or returns the image as it is
+ * if it has none.
+ */
+pub fn strip_header(image: &[u8]) -> Result<&[u8], Mismatch> {
+    if image.len() < HEADER_LEN || &image[..4] != HEADER_MAGIC {
+        return Ok(image);
+    }
+    let (header, body) = image.split_at(HEADER_LEN);
+    let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if body.len() != expected as usize {
+        return Err(Mismatch::Length {
+            expected,
+            actual: body.len() as u32,
+        });
+    }
+    let expected = u32::from_le_bytes(header[8..].try_into().unwrap());
+    let actual = crc32(body);
+    if expected != actual {
+        return Err(Mismatch::Checksum { expected, actual });
+    }
+    Ok(body)
+}
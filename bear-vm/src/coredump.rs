@@ -0,0 +1,83 @@
+use std::convert::TryInto;
+
+use crate::cell::{self, CellType};
+use crate::vm::{Debugger, ExecutionState, Threading};
+
+/// The first bytes of an encoded core dump.
+pub const MAGIC: &[u8; 8] = b"BEARCORE";
+
+/**
+ * The state of the VM written by `halt` with `-1` on top of the data stack, or by
+ * `ExecutionState::dump_to`: the instruction pointer, both stacks, bottom first, and the image.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDump {
+    pub ip: usize,
+    pub data: Vec<CellType>,
+    pub address: Vec<CellType>,
+    pub image: Vec<CellType>,
+}
+
+impl CoreDump {
+    pub fn of<D: Debugger<T>, T: Threading>(state: &ExecutionState<D, T>) -> CoreDump {
+        CoreDump {
+            ip: state.ip(),
+            data: state.vm.data.iter().map(|cell| cell.0).collect(),
+            address: state.vm.address.iter().map(|cell| cell.0).collect(),
+            image: state.vm.image.clone(),
+        }
+    }
+
+    /**
+     * Encodes the dump as `MAGIC`, the size of a cell in bytes, and then, as little-endian `u32`s,
+     * the instruction pointer and the lengths of the data stack, the address stack and the image
+     * in cells, each followed by its cells, little-endian.
+     */
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(cell::SIZE as u8);
+        bytes.extend(&(self.ip as u32).to_le_bytes());
+        for cells in [&self.data, &self.address, &self.image] {
+            bytes.extend(&(cells.len() as u32).to_le_bytes());
+            bytes.extend(crate::util::convert_cells_to_vec8(cells));
+        }
+        bytes
+    }
+
+    /// Decodes a dump written by `encode`, or returns `None` if it is malformed or has other cells.
+    pub fn decode(mut bytes: &[u8]) -> Option<CoreDump> {
+        if take(&mut bytes, MAGIC.len())? != MAGIC || take(&mut bytes, 1)? != [cell::SIZE as u8] {
+            return None;
+        }
+        let ip = take_u32(&mut bytes)?;
+        let mut cells = || {
+            let len = take_u32(&mut bytes)?.checked_mul(cell::SIZE)?;
+            take(&mut bytes, len).map(crate::util::convert_slice8_to_cells)
+        };
+        let dump = CoreDump {
+            ip,
+            data: cells()?,
+            address: cells()?,
+            image: cells()?,
+        };
+        if !bytes.is_empty() {
+            return None;
+        }
+        Some(dump)
+    }
+}
+
+/// Splits off the first `len` bytes.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if bytes.len() < len {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Some(head)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Option<usize> {
+    let word = take(bytes, 4)?;
+    Some(u32::from_le_bytes(word.try_into().ok()?) as usize)
+}
@@ -17,6 +17,169 @@ pub enum StreamCommand {
     Read = 0,
     Write = 1,
     Seek = 2,
+    /// Switch the terminal to raw mode if the argument is `1`, or back to cooked mode if it is `0`.
+    Mode = 3,
+    /// Turn the echo of the terminal on if the argument is `1`, or off if it is `0`.
+    Echo = 4,
+    /// Return the size of the terminal, as `(columns << 16) | rows`.
+    Size = 5,
+}
+
+/// Commands understood by the log device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogCommand {
+    /// Log the interned string whose id is `(bank << 8) | argument`.
+    Message = 0,
+}
+
+/// Commands understood by the assert device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssertCommand {
+    /// Fail the running test with the interned string whose id is `(bank << 8) | argument`.
+    Fail = 0,
+}
+
+/// Commands understood by the allocator device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocCommand {
+    /// Allocate a block of the size in registers 0 and 1, and return its address.
+    Alloc = 0,
+    /// Free the block at the address in registers 0 and 1.
+    Free = 1,
+    /// Return the number of free bytes.
+    Available = 2,
+    /// Return the size of the largest free block, i.e. of the largest block which can be allocated.
+    Largest = 3,
+}
+
+/// Commands understood by the mapped-file device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapCommand {
+    /// Write the mapped window back to the file, and map the window described by the registers.
+    Map = 0,
+    /// Write the words of the mapped window which the guest has changed back to the file.
+    Sync = 1,
+}
+
+/// Commands understood by the timer device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimerCommand {
+    /// Start counting down the number of instructions in registers 0 and 1.
+    StartInstructions = 0,
+    /// Start counting down the number of milliseconds in registers 0 and 1.
+    StartMilliseconds = 1,
+    /// Stop counting down, without expiring.
+    Cancel = 2,
+    /// Return `1` if the countdown has run out since it was started, and `0` otherwise.
+    Expired = 3,
+}
+
+/// Commands understood by the UART device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UartCommand {
+    /// Return the status flags: bit 0 is set when a byte has been received, and bit 1 when a byte
+    /// can be transmitted.
+    Status = 0,
+    /// Return the next byte received, or `0xFFFFFFFF` if there is none.
+    Receive = 1,
+    /// Transmit the argument.
+    Transmit = 2,
+}
+
+/// Commands understood by the framebuffer device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramebufferCommand {
+    /// Show the pixels at the next sync, however recently they were shown.
+    Present = 0,
+    /// Return `1` while the screen is open, and `0` once it has been closed.
+    Status = 1,
+}
+
+/// Commands understood by the keyboard device.
+#[repr(u8)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyboardCommand {
+    /**
+     * Return the oldest key event, or `0xFFFFFFFF` if there is none: the key code in the low byte,
+     * with `KEY_RELEASED` set for a key-up.  Key codes are ASCII, letters lower case, or one of
+     * the `key` codes.
+     */
+    Next = 0,
+    /// Return the number of key events waiting.
+    Pending = 1,
+}
+
+/// Set in the key events of key-ups.
+pub const KEY_RELEASED: u32 = 0x100;
+
+/// The codes of the keys which have no ASCII code.
+pub mod key {
+    pub const UP: u8 = 0x80;
+    pub const DOWN: u8 = 0x81;
+    pub const LEFT: u8 = 0x82;
+    pub const RIGHT: u8 = 0x83;
+    pub const SHIFT: u8 = 0x84;
+    pub const CONTROL: u8 = 0x85;
+}
+
+/// Commands understood by the key-value device.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvCommand {
+    /// Copy the value of the key into the value buffer.
+    Get = 0,
+    /// Store the value buffer under the key.
+    Put = 1,
+    /// Remove the key.
+    Delete = 2,
+}
+
+/// Commands understood by the environment device.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvCommand {
+    /// Return the number of arguments.
+    Count = 0,
+    /// Copy the argument whose index is the command's argument into the buffer.
+    Argument = 1,
+    /// Copy the value of the environment variable named by the name buffer into the buffer.
+    Variable = 2,
+}
+
+/// Commands understood by the mailbox device.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxCommand {
+    /// Return the status flags: bit 0 is set when a message is waiting, and bit 1 while the peer
+    /// is connected.
+    Status = 0,
+    /// Send the buffer to the peer.
+    Send = 1,
+    /// Send the cell in registers 3 and 4 to the peer, as a message of its 4 bytes.
+    SendCell = 2,
+    /**
+     * Copy the next message into the buffer, up to its length, and return the length of the
+     * message, or `0xFFFFFFFF` if there is none.
+     */
+    Receive = 3,
+    /// Return the first cell of the next message, and drop the message; `0xFFFFFFFF` if there is
+    /// none.
+    ReceiveCell = 4,
+}
+
+/// Commands understood by the exit device.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCommand {
+    /// Set the exit status of the host to the command's argument.
+    Set = 0,
 }
 
 /**
@@ -62,15 +225,15 @@ impl GenericDeviceCommand {
         } else if command == CommandTag::Set as u8 {
             let index = ((value & 0x00FF0000) >> 16) as u8;
             let value = (value & 0x0000FFFF) as u16;
-            return Some(GenericDeviceCommand::SetRegister(index, value));
+            Some(GenericDeviceCommand::SetRegister(index, value))
         } else if command == CommandTag::Exec as u8 {
             let command = ((value & 0x0000FF00) >> 8) as u8;
             let argument = (value & 0x000000FF) as u8;
-            return Some(GenericDeviceCommand::Execute { command, argument });
+            Some(GenericDeviceCommand::Execute { command, argument })
         } else if value == 0 {
-            return Some(GenericDeviceCommand::Reset);
+            Some(GenericDeviceCommand::Reset)
         } else {
-            return None;
+            None
         }
     }
 
@@ -88,17 +251,316 @@ impl GenericDeviceCommand {
     }
 }
 
+/**
+ * Widens a command of the `small` profile to a 32-bit command.  The tag is in the top 2 bits, the
+ * register index or command in the next 6, and the value or argument in the low byte, so only
+ * 8-bit values can be written to registers:
+ *
+ * ```text
+ * 00 000000 00000000  reset
+ * 01 iiiiii 00000000  get register i
+ * 10 iiiiii vvvvvvvv  set register i to v
+ * 11 cccccc aaaaaaaa  execute command c with argument a
+ * ```
+ *
+ * Commands which do not decode are widened to a 32-bit command which does not decode either.
+ */
+pub fn widen_command(command: u16) -> u32 {
+    let index = ((command >> 8) & 0x3F) as u8;
+    let low = (command & 0xFF) as u8;
+    let decoded = match command >> 14 {
+        0 if command == 0 => Some(GenericDeviceCommand::Reset),
+        1 if low == 0 => Some(GenericDeviceCommand::GetRegister(index)),
+        2 => Some(GenericDeviceCommand::SetRegister(index, low as u16)),
+        3 => Some(GenericDeviceCommand::Execute {
+            command: index,
+            argument: low,
+        }),
+        _ => None,
+    };
+    match decoded {
+        Some(decoded) => decoded.encode(),
+        None => command as u32,
+    }
+}
+
+/// Formats a command as it appears in device transcripts, e.g. `exec 1 72` or `set 0 16`.
+impl std::fmt::Display for GenericDeviceCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenericDeviceCommand::Reset => write!(f, "reset"),
+            GenericDeviceCommand::GetRegister(index) => write!(f, "get {}", index),
+            GenericDeviceCommand::SetRegister(index, value) => write!(f, "set {} {}", index, value),
+            GenericDeviceCommand::Execute { command, argument } => {
+                write!(f, "exec {} {}", command, argument)
+            }
+        }
+    }
+}
+
+/**
+ * A flag which devices raise when they have DMA requests, so that a VM whose sync policy is
+ * `SyncPolicy::OnAttention` only polls its devices when one of them asks.  The VM and its devices
+ * share one flag.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Attention(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+impl Attention {
+    pub fn raise(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Whether the flag was raised, lowering it.
+    pub fn take(&self) -> bool {
+        // Checked every instruction, so only pay for the swap when the flag is up.
+        use std::sync::atomic::Ordering::Relaxed;
+        self.0.load(Relaxed) && self.0.swap(false, Relaxed)
+    }
+}
+
+/**
+ * The number of instructions a VM has executed, as of its last `io` or sync: the times its devices
+ * are called.  The VM and its devices share one clock.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Clock(std::sync::Arc<std::sync::atomic::AtomicU64>);
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub(crate) fn set(&self, now: u64) {
+        self.0.store(now, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/**
+ * The interrupt lines of a VM, which its devices raise to interrupt the guest: one per vector, from
+ * `0` to `Interrupts::COUNT - 1`.  The VM and its devices share the lines.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct Interrupts(std::sync::Arc<std::sync::atomic::AtomicU32>);
+
+impl Interrupts {
+    pub const COUNT: u8 = 32;
+
+    /// Raises the line of `vector`, until the VM enters its handler.
+    pub fn raise(&self, vector: u8) {
+        assert!(vector < Interrupts::COUNT, "no interrupt vector {}", vector);
+        self.0.fetch_or(1 << vector, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Lowers the line of `vector`, e.g. when the device no longer needs attention.
+    pub fn lower(&self, vector: u8) {
+        self.0.fetch_and(!(1 << vector), std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// The raised lines, as a mask with bit `n` for vector `n`.
+    pub fn pending(&self) -> u32 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
 pub trait Device {
     fn ioctl(&mut self, message: u32) -> u32;
     fn dma_poll(&mut self) -> Option<DMARequest>;
     fn dma_write_response(&mut self, address: usize);
     fn dma_read_response(&mut self, address: usize, value: u32);
+
+    /// Answers a `WriteBurst` of `count` words.  By default, each word is answered on its own.
+    fn dma_write_burst_response(&mut self, address: usize, count: usize) {
+        for i in 0..count {
+            self.dma_write_response(address + i * crate::cell::SIZE);
+        }
+    }
+
+    /// Answers a `ReadBurst`, with the words read.  By default, each word is answered on its own.
+    fn dma_read_burst_response(&mut self, address: usize, words: &[u32]) {
+        for (i, word) in words.iter().enumerate() {
+            self.dma_read_response(address + i * crate::cell::SIZE, *word);
+        }
+    }
+
+    /**
+     * Answers a request which was refused, and so transferred nothing.  The device is not polled
+     * again until the next sync, so a device which ignores errors is not polled forever.
+     */
+    fn dma_error(&mut self, _request: DMARequest, _error: DmaError) {}
+
+    /**
+     * The memory the device is transferring to or from, in bytes: from the command that starts a
+     * transfer until its last DMA request has been answered.  Only used to detect races.
+     */
+    fn dma_pending(&self) -> Vec<std::ops::Range<usize>> {
+        Vec::new()
+    }
+
+    /// Called when the guest halts.  DMA requests made here are still answered.
+    fn halt(&mut self) {}
+
+    /// Called when the device is attached to a VM, with the flag to raise when it makes DMA requests.
+    fn attach(&mut self, _attention: Attention) {}
+
+    /// Called when the device is attached to a VM, with the interrupt lines it may raise.
+    fn connect_interrupts(&mut self, _interrupts: Interrupts) {}
+
+    /// Called when the device is attached to a VM, with the clock of the VM.
+    fn connect_clock(&mut self, _clock: Clock) {}
+}
+
+/// The DMA handlers of a `HostDevice`, standing for the DMA methods of `Device`.
+pub struct HostDma {
+    pub poll: Box<dyn FnMut() -> Option<DMARequest>>,
+    pub write_response: Box<dyn FnMut(usize)>,
+    pub read_response: Box<dyn FnMut(usize, u32)>,
 }
 
 /**
- * DMA requests are only partially implemented.
+ * A device whose commands are answered by a closure, so that embedders can expose host functions
+ * to guests without implementing `Device`.  It makes no DMA requests unless given `HostDma`
+ * handlers; a device with them raises its attention flag after every command, since any command
+ * may start a transfer.
  */
+pub struct HostDevice {
+    ioctl: Box<dyn FnMut(u32) -> u32>,
+    dma: Option<HostDma>,
+    attention: Attention,
+}
+
+impl HostDevice {
+    /// Answers every command with `ioctl`.
+    pub fn new(ioctl: impl FnMut(u32) -> u32 + 'static) -> HostDevice {
+        HostDevice {
+            ioctl: Box::new(ioctl),
+            dma: None,
+            attention: Attention::default(),
+        }
+    }
+
+    /**
+     * Implements the generic device interface with `count` 16-bit registers, which the guest can
+     * get and set, and `reset` zeroes.  `execute` answers the `exec` commands, with the command,
+     * its argument and the registers.  Other commands fail with `u32::MAX`.
+     */
+    pub fn generic(
+        count: usize,
+        mut execute: impl FnMut(u8, u8, &mut [u16]) -> u32 + 'static,
+    ) -> HostDevice {
+        let mut registers = vec![0u16; count];
+        HostDevice::new(move |message| match GenericDeviceCommand::decode(message) {
+            Some(GenericDeviceCommand::Reset) => {
+                registers.fill(0);
+                0
+            }
+            Some(GenericDeviceCommand::GetRegister(index)) => registers
+                .get(index as usize)
+                .map_or(u32::MAX, |value| *value as u32),
+            Some(GenericDeviceCommand::SetRegister(index, value)) => {
+                match registers.get_mut(index as usize) {
+                    Some(register) => {
+                        *register = value;
+                        0
+                    }
+                    None => u32::MAX,
+                }
+            }
+            Some(GenericDeviceCommand::Execute { command, argument }) => {
+                execute(command, argument, &mut registers)
+            }
+            None => u32::MAX,
+        })
+    }
+
+    pub fn with_dma(mut self, dma: HostDma) -> HostDevice {
+        self.dma = Some(dma);
+        self
+    }
+}
+
+impl Device for HostDevice {
+    fn ioctl(&mut self, message: u32) -> u32 {
+        let result = (self.ioctl)(message);
+        if self.dma.is_some() {
+            self.attention.raise();
+        }
+        result
+    }
+
+    fn dma_poll(&mut self) -> Option<DMARequest> {
+        self.dma.as_mut().and_then(|dma| (dma.poll)())
+    }
+
+    fn dma_write_response(&mut self, address: usize) {
+        if let Some(dma) = self.dma.as_mut() {
+            (dma.write_response)(address)
+        }
+    }
+
+    fn dma_read_response(&mut self, address: usize, value: u32) {
+        if let Some(dma) = self.dma.as_mut() {
+            (dma.read_response)(address, value)
+        }
+    }
+
+    fn attach(&mut self, attention: Attention) {
+        self.attention = attention;
+    }
+}
+
+/**
+ * A transfer between a device and guest memory, which the VM makes when it polls the device.
+ * Addresses are in bytes and must be word aligned, and every word transferred must be inside the
+ * image; requests which are not are refused with `Device::dma_error`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DMARequest {
+    /// Read the word at the address, answered by `Device::dma_read_response`.
     Read(usize),
+    /// Write the word to the address, answered by `Device::dma_write_response`.
     Write(usize, u32),
+    /// Read the given number of consecutive words, answered by `Device::dma_read_burst_response`.
+    ReadBurst(usize, usize),
+    /// Write consecutive words, answered by `Device::dma_write_burst_response`.
+    WriteBurst(usize, Vec<u32>),
+}
+
+impl DMARequest {
+    /// The address of the first word transferred.
+    pub fn address(&self) -> usize {
+        match self {
+            DMARequest::Read(address)
+            | DMARequest::Write(address, _)
+            | DMARequest::ReadBurst(address, _)
+            | DMARequest::WriteBurst(address, _) => *address,
+        }
+    }
+
+    /// The number of words transferred.
+    pub fn count(&self) -> usize {
+        match self {
+            DMARequest::Read(_) | DMARequest::Write(..) => 1,
+            DMARequest::ReadBurst(_, count) => *count,
+            DMARequest::WriteBurst(_, words) => words.len(),
+        }
+    }
+}
+
+/// Why a `DMARequest` was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// The address is not word aligned.
+    Unaligned,
+    /// Some of the words are outside of the image.
+    OutOfBounds,
+}
+
+impl std::fmt::Display for DmaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DmaError::Unaligned => write!(f, "unaligned DMA address"),
+            DmaError::OutOfBounds => write!(f, "DMA outside of the image"),
+        }
+    }
 }
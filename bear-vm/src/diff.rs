@@ -0,0 +1,138 @@
+use std::cell::RefCell;
+
+use crate::cell;
+use crate::vm::{Debugger, ExecutionState, OpCode, Threading};
+
+/// The parts of the VM which a debugger shows at each step, captured before `op` executes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub ip: usize,
+    pub op: OpCode,
+    /// The address executed next if `op` does not branch, unless `op` reads an operand.
+    pub fallthrough: Option<usize>,
+    pub data: Vec<u32>,
+    pub address: Vec<u32>,
+}
+
+impl Snapshot {
+    pub fn of<D: Debugger<T>, T: Threading>(state: &ExecutionState<D, T>, op: OpCode) -> Snapshot {
+        let ip = state.ip();
+        let fallthrough = if op.has_operand() {
+            None
+        } else if state.instruction_index + 1 < cell::SIZE {
+            Some(ip + 1)
+        } else {
+            Some((state.current_word_index + 1) * cell::SIZE)
+        };
+        Snapshot {
+            ip,
+            op,
+            fallthrough,
+            data: state.vm.data.iter().map(|cell| u32::from(*cell)).collect(),
+            address: state.vm.address.iter().map(|cell| u32::from(*cell)).collect(),
+        }
+    }
+}
+
+/// How a stack changed: the cells above the common bottom of the stack before and after.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackDiff {
+    /// The cells removed, bottom first.
+    pub popped: Vec<u32>,
+    /// The cells added, bottom first.
+    pub pushed: Vec<u32>,
+}
+
+impl StackDiff {
+    pub fn between(before: &[u32], after: &[u32]) -> StackDiff {
+        let common = before
+            .iter()
+            .zip(after.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        StackDiff {
+            popped: before[common..].to_vec(),
+            pushed: after[common..].to_vec(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.popped.is_empty() && self.pushed.is_empty()
+    }
+}
+
+/// A store to guest memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Write {
+    pub address: usize,
+    pub value: u32,
+    /// `store.8` rather than `store`.
+    pub byte: bool,
+}
+
+/// What one instruction changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDiff {
+    /// The instruction which made the changes.
+    pub op: OpCode,
+    pub from: usize,
+    /// The address of the next instruction.
+    pub to: usize,
+    /// Whether the instruction branched, rather than falling through to `to`.
+    pub jumped: bool,
+    pub data: StackDiff,
+    pub address: StackDiff,
+    pub writes: Vec<Write>,
+}
+
+impl StateDiff {
+    /// The changes made between `before` and `after`, given the stores made in between.
+    pub fn between(before: &Snapshot, after: &Snapshot, writes: Vec<Write>) -> StateDiff {
+        StateDiff {
+            op: before.op,
+            from: before.ip,
+            to: after.ip,
+            jumped: before.fallthrough.is_some_and(|next| next != after.ip),
+            data: StackDiff::between(&before.data, &after.data),
+            address: StackDiff::between(&before.address, &after.address),
+            writes,
+        }
+    }
+}
+
+/**
+ * Turns the callbacks of a debugger into a `StateDiff` per instruction.
+ *
+ * Call `store` from the debugger's `store` / `store_8`, and `step` from its `ip`, which returns
+ * what the previous instruction changed.
+ */
+#[derive(Debug, Default)]
+pub struct Differ {
+    previous: RefCell<Option<Snapshot>>,
+    writes: RefCell<Vec<Write>>,
+}
+
+impl Differ {
+    pub fn store(&self, address: usize, value: u32, byte: bool) {
+        self.writes.borrow_mut().push(Write {
+            address,
+            value,
+            byte,
+        });
+    }
+
+    /// Records the state before `op` executes, and returns the changes made by the previous
+    /// instruction, if there was one.
+    pub fn step<D: Debugger<T>, T: Threading>(
+        &self,
+        state: &ExecutionState<D, T>,
+        op: OpCode,
+    ) -> Option<StateDiff> {
+        let snapshot = Snapshot::of(state, op);
+        let writes = std::mem::take(&mut *self.writes.borrow_mut());
+        let previous = self.previous.replace(Some(snapshot));
+        let previous = previous?;
+        let current = self.previous.borrow();
+        Some(StateDiff::between(&previous, current.as_ref().unwrap(), writes))
+    }
+}
@@ -0,0 +1,361 @@
+/*!
+ * A C ABI for embedding the VM in hosts which are not written in Rust, enabled by the `ffi`
+ * feature.  `cargo build -p bear-vm --features ffi` builds the shared library, and writes its
+ * header to `bear-vm/include/bear_vm.h`.
+ *
+ * Every function takes the handle returned by `bear_vm_new`, which must not be used after
+ * `bear_vm_free`.  Functions which can fail return a negative `BearStatus`, and the message of the
+ * last error is available from `bear_vm_last_error`.  A panic while the guest runs is caught
+ * rather than unwound into the host, and leaves the VM trapped for good.
+ */
+
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::cell::{self, Cell};
+use crate::stack::Stack;
+use crate::device::{DMARequest, Device};
+use crate::vm::{BearVM, Error, ExecutionState};
+
+/// The outcome of a call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BearStatus {
+    /// The call succeeded; for `bear_vm_run`, the fuel ran out before the guest halted.
+    BearOk = 0,
+    /// The guest has halted.
+    BearHalted = 1,
+    /// The guest failed with a runtime error.
+    BearTrap = -1,
+    /// An argument was invalid, e.g. an address outside of memory, or an empty or full stack.
+    BearInvalid = -2,
+}
+
+/// A device implemented by the host.  `user_data` is passed back to every callback.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BearDeviceCallbacks {
+    pub user_data: *mut c_void,
+    /// Answers an `io` with the given command.
+    pub ioctl: extern "C" fn(user_data: *mut c_void, command: u32) -> u32,
+    /// Called when the guest halts, if not null.
+    pub halt: Option<extern "C" fn(user_data: *mut c_void)>,
+}
+
+impl std::fmt::Debug for BearDeviceCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BearDeviceCallbacks({:?})", self.user_data)
+    }
+}
+
+struct CallbackDevice(BearDeviceCallbacks);
+
+impl Device for CallbackDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        (self.0.ioctl)(self.0.user_data, command)
+    }
+
+    fn dma_poll(&mut self) -> Option<DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn halt(&mut self) {
+        if let Some(halt) = self.0.halt {
+            halt(self.0.user_data)
+        }
+    }
+}
+
+/// A VM and its execution state.
+pub struct BearVm {
+    state: ExecutionState,
+    error: Option<CString>,
+    /// Whether the VM panicked, after which its state cannot be trusted.
+    panicked: bool,
+}
+
+impl BearVm {
+    fn fail(&mut self, status: BearStatus, message: String) -> BearStatus {
+        self.error = CString::new(message).ok();
+        status
+    }
+
+    fn step(&mut self) -> BearStatus {
+        if self.panicked {
+            return BearStatus::BearTrap;
+        }
+        if !self.state.running {
+            return BearStatus::BearHalted;
+        }
+        let state = &mut self.state;
+        let result = catch_unwind(AssertUnwindSafe(|| -> Result<(), Error> {
+            state.step()?;
+            if state.running {
+                state.sync_if_due();
+            }
+            Ok(())
+        }));
+        match result {
+            Ok(Ok(())) if !self.state.running => BearStatus::BearHalted,
+            Ok(Ok(())) => BearStatus::BearOk,
+            Ok(Err(e)) => self.fail(BearStatus::BearTrap, e.to_string()),
+            Err(_) => {
+                self.panicked = true;
+                self.fail(BearStatus::BearTrap, String::from("the VM panicked"))
+            }
+        }
+    }
+
+    fn stack(&mut self, address: bool) -> &mut Stack {
+        if address {
+            &mut self.state.vm.address
+        } else {
+            &mut self.state.vm.data
+        }
+    }
+}
+
+unsafe fn slice<'a>(bytes: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(bytes, len)
+    }
+}
+
+/// Creates a VM with the image of `len` bytes at `image`, ready to run from address `0`.
+/// Returns null if the image is empty.
+///
+/// # Safety
+///
+/// `image` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_new(image: *const u8, len: usize) -> *mut BearVm {
+    let image = crate::util::convert_slice8_to_cells(slice(image, len));
+    if image.is_empty() {
+        return std::ptr::null_mut();
+    }
+    let state = match BearVM::new(image).start() {
+        Ok(state) => state,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(BearVm {
+        state,
+        error: None,
+        panicked: false,
+    }))
+}
+
+/// Destroys a VM.
+///
+/// # Safety
+///
+/// `vm` must be null or a handle from `bear_vm_new`, which is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_free(vm: *mut BearVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Copies the records of a RAM-initialization blob (the `.init` file of `bear-ass`) into memory.
+///
+/// # Safety
+///
+/// `vm` must be a live handle, and `blob` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_load_init_blob(
+    vm: *mut BearVm,
+    blob: *const u8,
+    len: usize,
+) -> BearStatus {
+    let vm = &mut *vm;
+    match vm.state.vm.load_init_blob(slice(blob, len)) {
+        Ok(()) => BearStatus::BearOk,
+        Err(e) => vm.fail(BearStatus::BearInvalid, e.to_string()),
+    }
+}
+
+/// Attaches a device implemented by the host, and returns its id.
+///
+/// # Safety
+///
+/// `vm` must be a live handle, and the callbacks must be safe to call with `user_data` for as long
+/// as the VM lives.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_attach_device(
+    vm: *mut BearVm,
+    callbacks: BearDeviceCallbacks,
+) -> usize {
+    let vm = &mut *vm;
+    vm.state.vm.devices.push(Box::new(CallbackDevice(callbacks)));
+    vm.state.vm.devices.len() - 1
+}
+
+/// Executes a single instruction.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_step(vm: *mut BearVm) -> BearStatus {
+    (*vm).step()
+}
+
+/// Executes at most `fuel` instructions, stopping early if the guest halts or traps.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_run(vm: *mut BearVm, fuel: u64) -> BearStatus {
+    let vm = &mut *vm;
+    for _ in 0..fuel {
+        let status = vm.step();
+        if status != BearStatus::BearOk {
+            return status;
+        }
+    }
+    BearStatus::BearOk
+}
+
+/// The address of the next instruction.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_ip(vm: *const BearVm) -> usize {
+    (*vm).state.ip()
+}
+
+/// The message of the last error, or null.  It is valid until the next call with `vm`.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_last_error(vm: *const BearVm) -> *const c_char {
+    match &(*vm).error {
+        Some(message) => message.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Reads the word at the byte address `address`, which must be word aligned.
+///
+/// # Safety
+///
+/// `vm` must be a live handle, and `value` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_read_word(
+    vm: *mut BearVm,
+    address: usize,
+    value: *mut u32,
+) -> BearStatus {
+    let vm = &mut *vm;
+    match vm.state.vm.image.get(address / cell::SIZE) {
+        Some(word) if address.is_multiple_of(cell::SIZE) => {
+            *value = Cell(*word).into();
+            BearStatus::BearOk
+        }
+        _ => vm.fail(BearStatus::BearInvalid, format!("cannot read {:#x}", address)),
+    }
+}
+
+/// Writes the word at the byte address `address`, which must be word aligned.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_write_word(
+    vm: *mut BearVm,
+    address: usize,
+    value: u32,
+) -> BearStatus {
+    let vm = &mut *vm;
+    let index = address / cell::SIZE;
+    if !address.is_multiple_of(cell::SIZE) || index >= vm.state.vm.image.len() {
+        return vm.fail(BearStatus::BearInvalid, format!("cannot write {:#x}", address));
+    }
+    vm.state.write_word(index, Cell::from(value).0);
+    BearStatus::BearOk
+}
+
+/// The number of cells on the data stack, or the address stack if `address` is true.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_stack_len(vm: *mut BearVm, address: bool) -> usize {
+    (*vm).stack(address).len()
+}
+
+/// Reads the cell `depth` below the top of a stack; `0` is the top.
+///
+/// # Safety
+///
+/// `vm` must be a live handle, and `value` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_stack_peek(
+    vm: *mut BearVm,
+    address: bool,
+    depth: usize,
+    value: *mut u32,
+) -> BearStatus {
+    let vm = &mut *vm;
+    let stack = vm.stack(address);
+    match stack.len().checked_sub(depth + 1).map(|index| stack[index]) {
+        Some(cell) => {
+            *value = cell.into();
+            BearStatus::BearOk
+        }
+        None => vm.fail(BearStatus::BearInvalid, format!("no cell at depth {}", depth)),
+    }
+}
+
+/// Pushes a cell onto a stack.
+///
+/// # Safety
+///
+/// `vm` must be a live handle.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_stack_push(
+    vm: *mut BearVm,
+    address: bool,
+    value: u32,
+) -> BearStatus {
+    let vm = &mut *vm;
+    match vm.stack(address).push(Cell::from(value)) {
+        Ok(()) => BearStatus::BearOk,
+        Err(e) => vm.fail(BearStatus::BearInvalid, e.to_string()),
+    }
+}
+
+/// Pops a cell off of a stack.
+///
+/// # Safety
+///
+/// `vm` must be a live handle, and `value` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn bear_vm_stack_pop(
+    vm: *mut BearVm,
+    address: bool,
+    value: *mut u32,
+) -> BearStatus {
+    let vm = &mut *vm;
+    match vm.stack(address).pop() {
+        Some(cell) => {
+            *value = cell.into();
+            BearStatus::BearOk
+        }
+        None => vm.fail(BearStatus::BearInvalid, String::from("the stack is empty")),
+    }
+}
@@ -2,3 +2,15 @@ pub mod cell;
 pub mod vm;
 pub mod device;
 pub mod util;
+pub mod radix;
+pub mod stack;
+pub mod diff;
+pub mod checkpoint;
+pub mod checksum;
+pub mod coredump;
+pub mod shared;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(all(feature = "small", feature = "float"))]
+compile_error!("The floating-point opcodes need 32-bit cells, so `float` and `small` cannot both be enabled.");
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+
+/// The radix used by tooling to display a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radix {
+    Hex,
+    Dec,
+}
+
+impl Radix {
+    pub fn format(self, value: u32) -> String {
+        match self {
+            Radix::Hex => format!("{:#x}", value),
+            Radix::Dec => value.to_string(),
+        }
+    }
+}
+
+impl FromStr for Radix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" | "16" => Ok(Radix::Hex),
+            "dec" | "10" => Ok(Radix::Dec),
+            _ => Err(format!("Unknown radix '{}', expected 'hex' or 'dec'.", s)),
+        }
+    }
+}
+
+/**
+ * How addresses and cell values are displayed by tooling (debuggers, traces, error messages).
+ *
+ * Addresses default to hex so they can be correlated with source constants, values to decimal.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub address: Radix,
+    pub value: Radix,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            address: Radix::Hex,
+            value: Radix::Dec,
+        }
+    }
+}
+
+impl NumberFormat {
+    /// The environment variables which configure the format when no option is given.
+    pub const ADDRESS_VAR: &'static str = "BEAR_ADDRESS_RADIX";
+    pub const VALUE_VAR: &'static str = "BEAR_VALUE_RADIX";
+
+    /// Reads the format from the environment, falling back to the defaults.
+    pub fn from_env() -> Result<Self, String> {
+        let mut format = NumberFormat::default();
+        if let Ok(radix) = std::env::var(Self::ADDRESS_VAR) {
+            format.address = radix.parse()?;
+        }
+        if let Ok(radix) = std::env::var(Self::VALUE_VAR) {
+            format.value = radix.parse()?;
+        }
+        Ok(format)
+    }
+
+    pub fn address(&self, address: usize) -> String {
+        self.address.format(address as u32)
+    }
+
+    pub fn value(&self, value: u32) -> String {
+        self.value.format(value)
+    }
+}
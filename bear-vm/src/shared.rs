@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::cell::{self, AtomicCellType, CellType};
+use crate::device::{
+    Attention, DMARequest, Device, DmaError, GenericDeviceCommand, GenericDeviceState,
+    Interrupts, MailboxCommand,
+};
+
+/**
+ * Memory shared by VMs, e.g. by several running the same program on threads of their own, to
+ * model a multiprocessor.  Attached with `BearVM::with_shared_memory`, it covers `len` words of
+ * guest addresses from `base`, shadowing the image there for `load`, `store` and their 8-bit
+ * forms.  Instructions are still fetched from, and devices still transfer to, each VM's own image.
+ *
+ * Every access is sequentially consistent: all the VMs see the loads and stores of each other in
+ * one order, which agrees with the order in which each VM executes them.  `store.8` replaces its
+ * byte atomically, without disturbing the other bytes of the word, even when they are stored to
+ * at the same time.
+ */
+#[derive(Debug, Clone)]
+pub struct SharedMemory {
+    base: usize,
+    words: Arc<[AtomicCellType]>,
+}
+
+impl SharedMemory {
+    /// Zeroed memory.  Panics if `base` is not word aligned.
+    pub fn new(base: usize, len: usize) -> SharedMemory {
+        assert!(
+            base.is_multiple_of(cell::SIZE),
+            "Shared memory must be word aligned."
+        );
+        SharedMemory {
+            base,
+            words: (0..len).map(|_| AtomicCellType::new(0)).collect(),
+        }
+    }
+
+    /// The address of the first word.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// The number of words.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// The word at `address`, if the memory covers it.
+    fn word(&self, address: usize) -> Option<&AtomicCellType> {
+        self.words.get(address.checked_sub(self.base)? / cell::SIZE)
+    }
+
+    /// Loads the word at the word-aligned `address`, or returns `None` if it is not covered.
+    pub fn load(&self, address: usize) -> Option<CellType> {
+        Some(self.word(address)?.load(Ordering::SeqCst))
+    }
+
+    /// Stores the word at the word-aligned `address`, or returns `false` if it is not covered.
+    pub fn store(&self, address: usize, value: CellType) -> bool {
+        self.word(address)
+            .map(|word| word.store(value, Ordering::SeqCst))
+            .is_some()
+    }
+
+    /// Loads the byte at `address`, or returns `None` if it is not covered.
+    pub fn load_8(&self, address: usize) -> Option<u8> {
+        let word = self.load(address - address % cell::SIZE)?;
+        Some(word.to_le_bytes()[address % cell::SIZE])
+    }
+
+    /// Stores the byte at `address`, or returns `false` if it is not covered.
+    pub fn store_8(&self, address: usize, byte: u8) -> bool {
+        let shift = (address % cell::SIZE) * 8;
+        let mask = (0xFF as CellType) << shift;
+        let value = (byte as CellType) << shift;
+        self.word(address)
+            .map(|word| {
+                word.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                    Some((old & !mask) | value)
+                })
+            })
+            .is_some()
+    }
+}
+
+/// The messages waiting for one end of a mailbox, and the attention flag of its VM.
+#[derive(Debug, Default)]
+struct Inbox {
+    messages: Mutex<VecDeque<Vec<u8>>>,
+    attention: Mutex<Attention>,
+}
+
+/// A message being sent: its buffer is read from guest memory.
+#[derive(Debug)]
+struct Sending {
+    address: usize,
+    length: usize,
+}
+
+/**
+ * One end of a channel between two VMs, which may run on different threads, made by
+ * `MailboxDevice::pair`: each end sends messages of bytes to the other, where they wait in order
+ * until they are received.
+ *
+ * Registers 0 and 1 are the low and high halves of the address of a buffer, which is word
+ * aligned, and 2 its length in bytes; `Send` sends the bytes of the buffer, and `Receive` copies
+ * the next message into it, up to its length.  Registers 3 and 4 are the low and high halves of
+ * the cell sent by `SendCell`.  Register 5, read only, is the number of messages waiting, and 6,
+ * read only, the length of the next one, or `0xFFFFFFFF` if there is none.  Register 7, if set,
+ * is an interrupt vector, raised at each sync while a message is waiting.  The device is busy
+ * until the buffer has been transferred.
+ */
+#[derive(Debug)]
+pub struct MailboxDevice {
+    state: GenericDeviceState,
+    /// Registers 5 and 6 are computed when read, so their slots stay `None`.
+    registers: [Option<u32>; 8],
+    inbox: Arc<Inbox>,
+    /// The inbox of the peer.
+    outbox: Arc<Inbox>,
+    sending: Option<Sending>,
+    /// The words of a message being received.
+    receiving: Option<Vec<u32>>,
+    /// Raised when a transfer starts.
+    attention: Attention,
+    interrupts: Interrupts,
+}
+
+impl MailboxDevice {
+    /// The two ends of a channel.
+    pub fn pair() -> (MailboxDevice, MailboxDevice) {
+        let a = Arc::new(Inbox::default());
+        let b = Arc::new(Inbox::default());
+        (MailboxDevice::new(a.clone(), b.clone()), MailboxDevice::new(b, a))
+    }
+
+    fn new(inbox: Arc<Inbox>, outbox: Arc<Inbox>) -> MailboxDevice {
+        MailboxDevice {
+            state: GenericDeviceState::ReadyForCommand,
+            registers: [None; 8],
+            inbox,
+            outbox,
+            sending: None,
+            receiving: None,
+            attention: Default::default(),
+            interrupts: Default::default(),
+        }
+    }
+
+    /// Forgets the registers and any transfer in progress.  The messages waiting are kept.
+    pub fn reset(&mut self) {
+        self.state = GenericDeviceState::ReadyForCommand;
+        self.registers = [None; 8];
+        self.sending = None;
+        self.receiving = None;
+    }
+
+    fn register_pair(&self, low: usize) -> usize {
+        let value = |index: usize| self.registers[index].unwrap_or(0);
+        (value(low) | (value(low + 1) << 16)) as usize
+    }
+
+    /// The address, length in bytes, and length in words of the buffer.
+    fn buffer(&self) -> (usize, usize, usize) {
+        let length = self.registers[2].unwrap_or(0) as usize;
+        (self.register_pair(0), length, length.div_ceil(4))
+    }
+
+    /// The peer is still connected while it holds the other end of its inbox.
+    fn connected(&self) -> bool {
+        Arc::strong_count(&self.outbox) > 1
+    }
+
+    /// Queues a message for the peer, and raises its attention.
+    fn send(&self, message: Vec<u8>) -> u32 {
+        if !self.connected() {
+            return u32::MAX;
+        }
+        self.outbox.messages.lock().unwrap().push_back(message);
+        self.outbox.attention.lock().unwrap().raise();
+        0
+    }
+
+    fn next(&self) -> Option<Vec<u8>> {
+        self.inbox.messages.lock().unwrap().pop_front()
+    }
+
+    fn start(&mut self) {
+        self.state = GenericDeviceState::Busy;
+        self.attention.raise();
+    }
+
+    fn execute(&mut self, command: u8) -> u32 {
+        if command == MailboxCommand::Status as u8 {
+            let waiting = !self.inbox.messages.lock().unwrap().is_empty();
+            waiting as u32 | (self.connected() as u32) << 1
+        } else if command == MailboxCommand::Send as u8 {
+            if !self.connected() {
+                return u32::MAX;
+            }
+            let (address, length, _) = self.buffer();
+            self.sending = Some(Sending { address, length });
+            self.start();
+            0
+        } else if command == MailboxCommand::SendCell as u8 {
+            let cell = self.register_pair(3) as u32;
+            self.send(cell.to_le_bytes().to_vec())
+        } else if command == MailboxCommand::Receive as u8 {
+            let Some(message) = self.next() else {
+                return u32::MAX;
+            };
+            let length = message.len().min(self.buffer().1);
+            self.receiving = Some(crate::util::convert_slice8_to_vec32(&message[..length]));
+            self.start();
+            message.len() as u32
+        } else if command == MailboxCommand::ReceiveCell as u8 {
+            self.next().map_or(u32::MAX, |mut message| {
+                message.resize(4, 0);
+                u32::from_le_bytes([message[0], message[1], message[2], message[3]])
+            })
+        } else {
+            u32::MAX
+        }
+    }
+}
+
+impl Device for MailboxDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let command = GenericDeviceCommand::decode(command);
+        match self.state {
+            GenericDeviceState::ReadyForCommand => match command {
+                Some(GenericDeviceCommand::Reset) => {
+                    self.reset();
+                    0
+                }
+                Some(GenericDeviceCommand::GetRegister(5)) => {
+                    self.inbox.messages.lock().unwrap().len() as u32
+                }
+                Some(GenericDeviceCommand::GetRegister(6)) => {
+                    let messages = self.inbox.messages.lock().unwrap();
+                    messages.front().map_or(u32::MAX, |message| message.len() as u32)
+                }
+                Some(GenericDeviceCommand::GetRegister(index)) => {
+                    match self.registers.get(index as usize) {
+                        Some(value) => value.unwrap_or(0),
+                        None => u32::MAX,
+                    }
+                }
+                Some(GenericDeviceCommand::SetRegister(index, value)) => {
+                    let valid = match index {
+                        5 | 6 => false,
+                        7 => value < Interrupts::COUNT as u16,
+                        _ => true,
+                    };
+                    match self.registers.get_mut(index as usize) {
+                        Some(register) if valid => {
+                            *register = Some(value as u32);
+                            0
+                        }
+                        _ => u32::MAX,
+                    }
+                }
+                Some(GenericDeviceCommand::Execute { command, .. }) => self.execute(command),
+                None => u32::MAX,
+            },
+            GenericDeviceState::Error(_code) => u32::MAX,
+            GenericDeviceState::Busy => u32::MAX,
+        }
+    }
+
+    fn dma_poll(&mut self) -> Option<DMARequest> {
+        if let Some(sending) = self.sending.as_ref() {
+            let words = sending.length.div_ceil(4);
+            return Some(DMARequest::ReadBurst(sending.address, words));
+        }
+        if let Some(words) = self.receiving.as_ref() {
+            return Some(DMARequest::WriteBurst(self.buffer().0, words.clone()));
+        }
+        if let Some(vector) = self.registers[7] {
+            if !self.inbox.messages.lock().unwrap().is_empty() {
+                self.interrupts.raise(vector as u8);
+            }
+        }
+        None
+    }
+
+    /// Only burst requests are made.
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+
+    fn dma_write_burst_response(&mut self, _address: usize, _count: usize) {
+        self.receiving = None;
+        self.state = GenericDeviceState::ReadyForCommand;
+    }
+
+    fn dma_read_burst_response(&mut self, _address: usize, words: &[u32]) {
+        if let Some(sending) = self.sending.take() {
+            let mut message = crate::util::convert_slice32_to_vec8(words);
+            message.truncate(sending.length);
+            self.send(message);
+        }
+        self.state = GenericDeviceState::ReadyForCommand;
+    }
+
+    /// The buffer is not in guest memory: the device fails with error `2`.
+    fn dma_error(&mut self, _request: DMARequest, _error: DmaError) {
+        self.sending = None;
+        self.receiving = None;
+        self.state = GenericDeviceState::Error(2);
+    }
+
+    fn attach(&mut self, attention: Attention) {
+        *self.inbox.attention.lock().unwrap() = attention.clone();
+        self.attention = attention;
+    }
+
+    fn connect_interrupts(&mut self, interrupts: Interrupts) {
+        self.interrupts = interrupts;
+    }
+}
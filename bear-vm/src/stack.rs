@@ -0,0 +1,114 @@
+use crate::cell::Cell;
+
+/// Pushing onto a full stack of fixed capacity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow;
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stack overflow.")
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+/**
+ * A stack of cells.
+ *
+ * A fixed stack allocates its capacity up front and never reallocates, so pushing only compares
+ * the depth against it.  A growable stack grows as needed, like a `Vec`.
+ * Stacks dereference to their cells, bottom first.
+ */
+#[derive(Clone)]
+pub struct Stack {
+    /// The cells, of which the first `depth` are on the stack.
+    cells: Vec<Cell>,
+    depth: usize,
+    growable: bool,
+}
+
+impl Stack {
+    /// The capacity of each stack of a VM, in cells, unless set with `BearVM::with_stack_capacity`.
+    pub const DEFAULT_CAPACITY: usize = 4096;
+
+    pub fn fixed(capacity: usize) -> Stack {
+        Stack {
+            cells: vec![Cell(0); capacity],
+            depth: 0,
+            growable: false,
+        }
+    }
+
+    pub fn growable() -> Stack {
+        Stack {
+            cells: Vec::new(),
+            depth: 0,
+            growable: true,
+        }
+    }
+
+    /// The number of cells the stack can hold, or `None` if it grows as needed.
+    pub fn capacity(&self) -> Option<usize> {
+        if self.growable {
+            None
+        } else {
+            Some(self.cells.len())
+        }
+    }
+
+    #[inline]
+    pub fn push(&mut self, cell: Cell) -> Result<(), Overflow> {
+        if let Some(slot) = self.cells.get_mut(self.depth) {
+            *slot = cell;
+        } else if self.growable {
+            self.cells.push(cell);
+        } else {
+            return Err(Overflow);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn pop(&mut self) -> Option<Cell> {
+        self.depth = self.depth.checked_sub(1)?;
+        Some(self.cells[self.depth])
+    }
+
+    pub fn clear(&mut self) {
+        self.depth = 0;
+    }
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::fixed(Stack::DEFAULT_CAPACITY)
+    }
+}
+
+impl std::ops::Deref for Stack {
+    type Target = [Cell];
+
+    fn deref(&self) -> &[Cell] {
+        &self.cells[..self.depth]
+    }
+}
+
+impl std::ops::DerefMut for Stack {
+    fn deref_mut(&mut self) -> &mut [Cell] {
+        &mut self.cells[..self.depth]
+    }
+}
+
+/// Shows the cells on the stack, like a `Vec`.
+impl std::fmt::Debug for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq<Vec<Cell>> for Stack {
+    fn eq(&self, other: &Vec<Cell>) -> bool {
+        **self == other[..]
+    }
+}
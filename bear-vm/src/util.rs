@@ -1,3 +1,5 @@
+use crate::cell::{self, CellType};
+
 pub fn convert_slice8_to_vec32(v8: &[u8]) -> Vec<u32> {
     let mut v32 = Vec::new();
     let iter = v8.chunks_exact(4);
@@ -21,3 +23,54 @@ pub fn convert_slice32_to_vec8(v32: &[u32]) -> Vec<u8> {
     }
     v8
 }
+
+/// Packs bytes into cells of the VM's size, little-endian, padding the last cell with zeros.
+pub fn convert_slice8_to_cells(v8: &[u8]) -> Vec<CellType> {
+    v8.chunks(cell::SIZE)
+        .map(|chunk| {
+            let mut bytes = [0; cell::SIZE];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            CellType::from_le_bytes(bytes)
+        })
+        .collect()
+}
+
+pub fn convert_cells_to_vec8(cells: &[CellType]) -> Vec<u8> {
+    cells.iter().flat_map(|cell| cell.to_le_bytes()).collect()
+}
+
+/**
+ * Encodes a RAM-initialization blob.
+ *
+ * The blob starts with a loader table: the number of records, followed by an `(address, length)`
+ * pair for each record.  The bytes of each record follow the table in order, each padded to a
+ * multiple of 4.  All values are 32-bit little-endian.
+ */
+pub fn encode_init_blob(records: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob.extend(&(records.len() as u32).to_le_bytes());
+    for (address, bytes) in records.iter() {
+        blob.extend(&address.to_le_bytes());
+        blob.extend(&(bytes.len() as u32).to_le_bytes());
+    }
+    for (_, bytes) in records.iter() {
+        blob.extend(bytes.iter());
+        blob.resize(blob.len() + (4 - bytes.len() % 4) % 4, 0);
+    }
+    blob
+}
+
+/// Decodes a blob produced by `encode_init_blob`, returning `None` if it is malformed.
+pub fn decode_init_blob(blob: &[u8]) -> Option<Vec<(u32, Vec<u8>)>> {
+    let words = convert_slice8_to_vec32(blob);
+    let count = *words.first()? as usize;
+    let table = words.get(1..1 + 2 * count)?;
+    let mut offset = 4 * (1 + 2 * count);
+    let mut records = Vec::new();
+    for entry in table.chunks_exact(2) {
+        let (address, length) = (entry[0], entry[1] as usize);
+        records.push((address, blob.get(offset..offset + length)?.to_vec()));
+        offset += length + (4 - length % 4) % 4;
+    }
+    Some(records)
+}
@@ -1,10 +1,15 @@
-use std::mem::transmute_copy;
-// use std::convert::TryInto;
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use crate::cell;
+use crate::cell::{self, CellType, SignedCellType};
 pub use crate::cell::Cell;
-use crate::device::{DMARequest, Device};
+use crate::checkpoint::{Checkpoint, Pages};
+use crate::coredump::CoreDump;
+use crate::shared::SharedMemory;
+use crate::stack::Stack;
+use crate::device::{Attention, Clock, DMARequest, Device, DmaError, Interrupts};
 
 // TODO: Traps and Trap Handlers.
 
@@ -32,6 +37,20 @@ impl Error {
         }
     }
 
+    fn data_overflow() -> Error {
+        Error {
+            message: String::from("Data stack overflow."),
+            ip: None,
+        }
+    }
+
+    fn address_overflow() -> Error {
+        Error {
+            message: String::from("Address stack overflow."),
+            ip: None,
+        }
+    }
+
     fn ip_oob(ip: usize) -> Error {
         Error {
             message: String::from("IP went out of bounds."),
@@ -39,6 +58,20 @@ impl Error {
         }
     }
 
+    fn return_address_oob(ip: usize) -> Error {
+        Error {
+            message: String::from("Return address out of range."),
+            ip: Some(ip),
+        }
+    }
+
+    fn invalid_vector(vector: CellType) -> Error {
+        Error {
+            message: format!("Invalid interrupt vector: {}", vector),
+            ip: None,
+        }
+    }
+
     fn invalid_instruction(byte: u8) -> Error {
         Error {
             message: format!("Invalid opcode: 0x{:x}", byte),
@@ -46,17 +79,102 @@ impl Error {
         }
     }
 
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn ip(&self) -> Option<usize> {
+        self.ip
+    }
+
+    fn unknown_syscall(number: CellType) -> Error {
+        Error {
+            message: format!("No handler for syscall {}.", number),
+            ip: None,
+        }
+    }
+
+    fn unknown_checkpoint() -> Error {
+        Error {
+            message: String::from("Unknown checkpoint."),
+            ip: None,
+        }
+    }
+
+    fn overflow() -> Error {
+        Error {
+            message: String::from("Arithmetic overflow."),
+            ip: None,
+        }
+    }
+
+    fn division_by_zero() -> Error {
+        Error {
+            message: String::from("Division by zero."),
+            ip: None,
+        }
+    }
+
+    fn unaligned(address: usize) -> Error {
+        Error {
+            message: format!("Unaligned access: {:#x}", address),
+            ip: None,
+        }
+    }
+
+    fn unknown_device(id: CellType) -> Error {
+        Error {
+            message: format!("No device {}.", id),
+            ip: None,
+        }
+    }
+
+    fn memory_oob(address: usize) -> Error {
+        Error {
+            message: format!("Memory access out of range: {:#x}", address),
+            ip: None,
+        }
+    }
+
+    fn checksum(mismatch: crate::checksum::Mismatch) -> Error {
+        Error {
+            message: mismatch.to_string(),
+            ip: None,
+        }
+    }
+
+    fn malformed_init_blob() -> Error {
+        Error {
+            message: String::from("Malformed RAM-initialization blob."),
+            ip: None,
+        }
+    }
+
     fn with_ip(mut self, ip: usize) -> Self {
         self.ip = Some(ip);
         self
     }
 
-    fn with_ip_from_state(mut self, state: &ExecutionState) -> Self {
+    fn with_ip_from_state<D: Debugger<T>, T: Threading>(
+        mut self,
+        state: &ExecutionState<D, T>,
+    ) -> Self {
         self.ip = Some(state.ip());
         self
     }
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.ip {
+            Some(ip) => write!(f, "{} (ip: {:#x})", self.message, ip),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<std::num::TryFromIntError> for Error {
     fn from(e: std::num::TryFromIntError) -> Self {
         Error {
@@ -66,166 +184,466 @@ impl From<std::num::TryFromIntError> for Error {
     }
 }
 
-// WARN: If this enum changes, make sure to update `impl TryFrom<u8> for OpCode`.
 /**
- * The opCodes recognized by the VM.
+ * Declares `OpCode` along with its `OpInfo`, so that the mnemonics, operands, stack effects and
+ * categories used by the VM, the assembler and the tooling are generated from one table.
  */
-#[repr(u8)]
-#[derive(Copy, Clone, Debug)]
-pub enum OpCode {
+macro_rules! opcodes {
+    (@operand) => { false };
+    (@operand operand) => { true };
+    (@info $name:ident, $mnemonic:literal, [$($operand:ident)?], $category:ident,
+        ($data_in:literal, $data_out:literal, $address_in:literal, $address_out:literal)) => {
+        OpInfo {
+            opcode: OpCode::$name,
+            mnemonic: $mnemonic,
+            operand: opcodes!(@operand $($operand)?),
+            stack: StackEffect {
+                data_in: $data_in,
+                data_out: $data_out,
+                address_in: $address_in,
+                address_out: $address_out,
+            },
+            category: Category::$category,
+        }
+    };
+    ($($(#[$meta:meta])* $name:ident $(= $value:expr)? => $mnemonic:literal $($operand:ident)?,
+        $category:ident, $effect:tt,)*) => {
+        /**
+         * The opCodes recognized by the VM.
+         */
+        #[repr(u8)]
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        pub enum OpCode {
+            $($(#[$meta])* $name $(= $value)?,)*
+        }
+
+        impl OpCode {
+            /// Every opcode, in encoding order.
+            pub const ALL: &'static [OpCode] = &[$(OpCode::$name,)*];
+
+            /// The description of every opcode, in encoding order.
+            pub const TABLE: &'static [OpInfo] = &[
+                $(opcodes!(@info $name, $mnemonic, [$($operand)?], $category, $effect),)*
+            ];
+
+            /// The description of the opcode.
+            pub fn info(self) -> &'static OpInfo {
+                match self {
+                    $(OpCode::$name => &opcodes!(@info $name, $mnemonic, [$($operand)?], $category, $effect),)*
+                }
+            }
+
+            /// The inverse of `mnemonic`.
+            pub fn from_mnemonic(text: &str) -> Option<OpCode> {
+                match text {
+                    $($mnemonic => Some(OpCode::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// Each opcode is listed with its mnemonic, `operand` if it reads the next word of the image, its
+// category, and its stack effect as (data in, data out, address in, address out) when it does not
+// branch.  The effect of the code a `call` runs is not included.
+//
+// WARN: If this table changes, make sure to update `OpCode::decode`.
+opcodes! {
     /// Do nothing.
-    Nop,
+    Nop => "nop", Control, (0, 0, 0, 0),
 
     /// Push the next cell in memory onto the data stack.
-    Lit,
+    Lit => "lit" operand, Stack, (0, 1, 0, 0),
 
     /// Duplicate the top of the data stack.
-    Dup,
+    Dup => "dup", Stack, (1, 2, 0, 0),
     /// Drop the value on the top of the data stack.
-    Drop,
+    Drop => "drop", Stack, (1, 0, 0, 0),
     /// Swap the values on the top of the data stack.
-    Swap,
+    Swap => "swap", Stack, (2, 2, 0, 0),
     /// Remove the value on the top of the data stack and push it onto the top of the address
     /// stack.
-    MoveDataToAddr,
+    MoveDataToAddr => "push", Stack, (1, 0, 0, 1),
     /// Remove the value on the top of the address stack and push it onto the top of the data
     /// stack.
-    MoveAddrToData,
+    MoveAddrToData => "pop", Stack, (0, 1, 1, 0),
 
     /// Perform the bitwise NOT operation on the value on top of the data stack.
-    Not,
+    Not => "not", Logic, (1, 1, 0, 0),
     /// Perform the logical AND operation on the top two values of the data stack.
-    And,
+    And => "and", Logic, (2, 1, 0, 0),
     /// Perform the logical OR operation on the top two values of the data stack.
-    Or,
+    Or => "or", Logic, (2, 1, 0, 0),
     /// Perform the logical XOR operation on the top two values of the data stack.
-    Xor,
+    Xor => "xor", Logic, (2, 1, 0, 0),
     /// If the top two values of the data stack are equal then replace them with a `1`, otherwise a
     /// replace them with a `0`.
-    Equal,
-    /// If the top of the data stack is less than the second value on the data stack, replace them
-    /// with a `1` otherwise replace them with a `0`.
-    LessThan,
-    /// If the top of the data stack is greater than the second value on the data stack, replace them
-    /// with a `1` otherwise replace them with a `0`.
-    GreaterThan,
+    Equal => "eq", Logic, (2, 1, 0, 0),
+    /// If the top of the data stack is less than the second value on the data stack, both taken as
+    /// signed, replace them with a `1` otherwise replace them with a `0`.
+    LessThan => "lt", Logic, (2, 1, 0, 0),
+    /// If the top of the data stack is greater than the second value on the data stack, both taken
+    /// as signed, replace them with a `1` otherwise replace them with a `0`.
+    GreaterThan => "gt", Logic, (2, 1, 0, 0),
 
     /// Replace the top two values on the data stack with their sum.
-    Add,
+    Add => "add", Arithmetic, (2, 1, 0, 0),
     /// Replace the top two values on the the data stack with their difference (tos - nos).
-    Sub,
+    Sub => "sub", Arithmetic, (2, 1, 0, 0),
     /// Replace the top two values on the the data stack with their product.
-    Mul,
+    Mul => "mul", Arithmetic, (2, 1, 0, 0),
     /// Replace the top two values on the the data stack with their quotient (tos / nos).
-    Div,
+    Div => "div", Arithmetic, (2, 1, 0, 0),
     /// Replace the top two values on the the data stack with their "modulus" (tos % nos).
-    Mod,
-    // TODO: Signed Shift?
-    /// Shift the second value on the data stack by the value on top of the data stack (nos << tos).
-    Shift,
-    /// Sign extend the 8 bit value on the top of the data stack to a 32 bit signed value.
-    Sext8,
-    /// Sign extend the 16 bit value on the top of the data stack to a 32 bit signed value.
-    Sext16,
+    Mod => "mod", Arithmetic, (2, 1, 0, 0),
+    /// Shift the second value on the data stack by the value on top of the data stack (nos << tos),
+    /// or right if it is negative, filling with zeros.  Shifting by the bits in a cell or more, in
+    /// either direction, gives `0`.
+    Shift => "shift", Arithmetic, (2, 1, 0, 0),
+    /// Sign extend the low 8 bits of the value on the top of the data stack to a cell, ignoring
+    /// the others.
+    Sext8 => "sext.8", Arithmetic, (1, 1, 0, 0),
+    /// Sign extend the low 16 bits of the value on the top of the data stack to a cell, ignoring
+    /// the others.
+    Sext16 => "sext.16", Arithmetic, (1, 1, 0, 0),
 
     /// Pop the value on top of the data stack,
     /// push the current address to the address stack and set `ip` to the value poped off of the data stack.
-    Call,
+    Call => "call", Control, (1, 0, 0, 1),
     /// Pop the value on top of the data stack,
     /// and set `ip` to the value poped off of the data stack.
-    Jump,
+    Jump => "jump", Control, (1, 0, 0, 0),
     /// Pop a value off of the address stack and set `ip` to the value.
-    Return,
+    Return => "ret", Control, (0, 0, 1, 0),
 
+    // The condition and the target are popped whether or not the branch is taken.
     /// Conditional `Call`.  Do a call only if the value on top of the data stack is `0`.
-    CallIfZ,
+    CallIfZ => "ifz:call", Control, (2, 0, 0, 0),
     /// Conditional `Jump`.  Do a jump only if the value on top of the data stack is `0`.
-    JumpIfZ,
+    JumpIfZ => "ifz:jump", Control, (2, 0, 0, 0),
+    // The condition is only popped if it is `0`, and then the branch is taken.
     /// Conditional `Return`.  Do a return only if the value on top of the data stack is `0`.
-    ReturnIfZ,
-
-    Load,
-    Store,
-    Load8,
-    Store8,
+    ReturnIfZ => "ifz:ret", Control, (1, 1, 0, 0),
+
+    Load => "load", Memory, (1, 1, 0, 0),
+    Store => "store", Memory, (2, 0, 0, 0),
+    Load8 => "load.8", Memory, (1, 1, 0, 0),
+    Store8 => "store.8", Memory, (2, 0, 0, 0),
+
+    Io => "io", Io, (2, 1, 0, 0),
+
+    /// Push the number of values on the data stack, before the push.
+    Depth => "depth", Stack, (0, 1, 0, 0),
+    /// Push the number of values on the address stack.
+    AddressDepth => "adepth", Stack, (0, 1, 0, 0),
+
+    /// Pop the address of a handler, and then an interrupt vector, and register the handler for
+    /// the vector.  Address `0` removes the handler.
+    InterruptVector => "ivec", Control, (2, 0, 0, 0),
+    /// Return from an interrupt handler, like `ret`, and allow interrupts again.
+    InterruptReturn => "iret", Control, (0, 0, 1, 0),
+
+    /// Pause execution in the attached debugger, if any.  Otherwise, do nothing.
+    Brk => "brk", Debug, (0, 0, 0, 0),
+
+    /// Pop a number, and call the host's handler for it, registered with `BearVM::with_syscall`,
+    /// which may pop and push cells of its own.
+    Sys => "sys", Io, (1, 0, 0, 0),
+
+    /// Push a copy of the value on top of the address stack onto the data stack.
+    AddressPeek => "rpeek", Stack, (0, 1, 1, 1),
+    /// Drop the value on top of the address stack.
+    AddressDrop => "rdrop", Stack, (0, 0, 1, 0),
+    /// Swap the values on top of the address stack.
+    AddressSwap => "rswap", Stack, (0, 0, 2, 2),
+
+    /// Push a copy of the second value on the data stack.
+    Over => "over", Stack, (2, 3, 0, 0),
+    /// Move the third value on the data stack to the top.
+    Rot => "rot", Stack, (3, 3, 0, 0),
+    /// Drop the second value on the data stack.
+    Nip => "nip", Stack, (2, 1, 0, 0),
+    /// Insert a copy of the value on top of the data stack below the second value.
+    Tuck => "tuck", Stack, (2, 3, 0, 0),
+
+    /// `lt`, with both values taken as unsigned, e.g. to compare addresses.
+    LessThanUnsigned => "lt.u", Logic, (2, 1, 0, 0),
+    /// `gt`, with both values taken as unsigned.
+    GreaterThanUnsigned => "gt.u", Logic, (2, 1, 0, 0),
+
+    /// Replace the top two values on the data stack with the high cell of their unsigned product,
+    /// whose low cell `mul` gives.
+    MulHigh => "mulh", Arithmetic, (2, 1, 0, 0),
+    /// Pop a carry, and replace the next two values on the data stack with their sum plus the
+    /// carry (any value but `0` counts as `1`), and push the carry out, `1` or `0`, on top.
+    AddCarry => "addc", Arithmetic, (3, 2, 0, 0),
+    /// Pop a borrow, and replace the next two values on the data stack with their difference
+    /// (tos - nos) less the borrow (any value but `0` counts as `1`), and push the borrow out, `1`
+    /// or `0`, on top.
+    SubBorrow => "subc", Arithmetic, (3, 2, 0, 0),
+
+    /// Replace the top two values on the data stack with the lesser, taken as signed.
+    Min => "min", Arithmetic, (2, 1, 0, 0),
+    /// Replace the top two values on the data stack with the greater, taken as signed.
+    Max => "max", Arithmetic, (2, 1, 0, 0),
+    /// Replace the value on top of the data stack with its absolute value, taken as signed.  The
+    /// most negative value is left as it is.
+    Abs => "abs", Arithmetic, (1, 1, 0, 0),
+    /// Negate the value on top of the data stack.
+    Negate => "neg", Arithmetic, (1, 1, 0, 0),
+
+    /// Rotate the second value on the data stack left by the value on top, modulo the bits in a
+    /// cell.
+    RotateLeft => "rotl", Arithmetic, (2, 1, 0, 0),
+    /// Rotate the second value on the data stack right by the value on top, modulo the bits in a
+    /// cell.
+    RotateRight => "rotr", Arithmetic, (2, 1, 0, 0),
+    /// Shift the second value on the data stack right by the value on top, copying its sign bit
+    /// into the bits shifted in.  Shifting by the bits in a cell or more leaves only copies of the
+    /// sign bit.
+    ShiftArithmetic => "shift.a", Arithmetic, (2, 1, 0, 0),
+
+    /// Pop a length, a source address and a destination address, and copy that many bytes from
+    /// the source to the destination, as if through a buffer when they overlap.
+    MemCopy => "memcopy", Memory, (3, 0, 0, 0),
+    /// Pop a length, a value and a destination address, and store the low byte of the value to
+    /// that many bytes from the destination.
+    MemFill => "memfill", Memory, (3, 0, 0, 0),
+
+    // The floating-point opcodes take cells as the bits of IEEE 754 single-precision numbers.
+    // Without the `float` feature, `OpCode::decode` rejects them, so they trap as invalid.
+    /// Replace the top two values on the data stack with their sum.
+    FloatAdd => "fadd", Float, (2, 1, 0, 0),
+    /// Replace the top two values on the data stack with their difference (tos - nos).
+    FloatSub => "fsub", Float, (2, 1, 0, 0),
+    /// Replace the top two values on the data stack with their product.
+    FloatMul => "fmul", Float, (2, 1, 0, 0),
+    /// Replace the top two values on the data stack with their quotient (tos / nos).
+    FloatDiv => "fdiv", Float, (2, 1, 0, 0),
+    /// Replace the top two values on the data stack with `-1` if tos < nos, `0` if they are
+    /// equal, `1` if tos > nos, or `2` if either is a NaN.
+    FloatCompare => "fcmp", Float, (2, 1, 0, 0),
+    /// Convert the signed integer on top of the data stack to the nearest float.
+    IntToFloat => "itof", Float, (1, 1, 0, 0),
+    /// Convert the float on top of the data stack to a signed integer, rounding toward zero,
+    /// saturating at the most negative and positive integers, and taking NaN to `0`.
+    FloatToInt => "ftoi", Float, (1, 1, 0, 0),
+
+    /// Clear all but the low 8 bits of the value on the top of the data stack.
+    ZeroExtend8 => "zext.8", Arithmetic, (1, 1, 0, 0),
+    /// Clear all but the low 16 bits of the value on the top of the data stack.
+    ZeroExtend16 => "zext.16", Arithmetic, (1, 1, 0, 0),
 
     // Note:
-    // `Io` needs to be the next to last instruction, or the check in `TryFrom<u8> for OpCode`
-    // needs to change.
-    Io,
+    // `Sbrk` needs to be the next to last instruction, or the check in `OpCode::decode` needs to
+    // change.
+    /// Pop a number of bytes, grow the image by them, rounded up to whole words of zeros, and push
+    /// the address of the end of the image before it grew, or `-1` if it cannot grow that much.
+    /// `0` leaves the image as it is, to find its end.
+    Sbrk => "sbrk", Memory, (1, 1, 0, 0),
     /// Halt execution.  If the value on top of the data stack is `-1` then perform a core dump.
-    Halt = 0b_0111_1111,
+    Halt = 0b_0111_1111 => "halt", Control, (0, 0, 0, 0),
+}
+
+/// How an instruction changes the stacks, in cells, when it does not branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub data_in: u8,
+    pub data_out: u8,
+    pub address_in: u8,
+    pub address_out: u8,
+}
+
+/// The groups of opcodes, as they are documented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// Moves cells between the stacks and the image.
+    Stack,
+    /// Bitwise operations and comparisons.
+    Logic,
+    Arithmetic,
+    /// Branches, `nop` and `halt`.
+    Control,
+    Memory,
+    Io,
+    Debug,
+    /// Floating-point arithmetic, with the `float` feature.
+    Float,
+}
+
+/// Everything the assembler and the tooling need to know about an opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpInfo {
+    pub opcode: OpCode,
+    /// The name of the opcode in assembly.
+    pub mnemonic: &'static str,
+    /// Whether the instruction consumes the next word of the image as its operand.
+    pub operand: bool,
+    pub stack: StackEffect,
+    pub category: Category,
+}
+
+impl OpCode {
+    /// Every opcode, in encoding order.
+    pub fn all() -> impl Iterator<Item = OpCode> {
+        OpCode::ALL.iter().copied()
+    }
+
+    /// The name of the opcode in assembly.
+    pub fn mnemonic(self) -> &'static str {
+        self.info().mnemonic
+    }
+
+    /// The stack effect of the instruction.  The effect of the code a `call` runs is not included.
+    pub fn stack_effect(self) -> StackEffect {
+        self.info().stack
+    }
+
+    /// Whether the instruction consumes the next word of the image as its operand.
+    pub fn has_operand(self) -> bool {
+        self.info().operand
+    }
+
+    pub fn category(self) -> Category {
+        self.info().category
+    }
 }
 
 impl TryFrom<u8> for OpCode {
     type Error = Error;
 
     fn try_from(byte: u8) -> Result<OpCode, Self::Error> {
-        if (OpCode::Io as u8) < byte && byte != OpCode::Halt as u8 {
-            Err(Error::invalid_instruction(byte))
-        } else {
-            Ok(unsafe { ::std::mem::transmute(byte) })
-        }
+        OpCode::decode(byte).ok_or_else(|| Error::invalid_instruction(byte))
     }
 }
 
 impl std::fmt::Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            OpCode::Halt => write!(f, "halt"),
-            OpCode::Lit => write!(f, "lit"),
-            OpCode::Sext8 => write!(f, "sext.8"),
-            OpCode::Sext16 => write!(f, "sext.16"),
-
-            OpCode::Dup => write!(f, "dup"),
-            OpCode::Drop => write!(f, "drop"),
-            OpCode::Swap => write!(f, "swap"),
-            OpCode::MoveDataToAddr => write!(f, "push"),
-            OpCode::MoveAddrToData => write!(f, "pop"),
-
-            OpCode::Call => write!(f, "call"),
-            OpCode::Jump => write!(f, "jump"),
-            OpCode::Return => write!(f, "ret"),
-            OpCode::CallIfZ => write!(f, "ifz:call"),
-            OpCode::JumpIfZ => write!(f, "ifz:jump"),
-            OpCode::ReturnIfZ => write!(f, "ifz:ret"),
+        write!(f, "{}", self.mnemonic())
+    }
+}
 
-            OpCode::Load => write!(f, "load"),
-            OpCode::Store => write!(f, "store"),
-            OpCode::Load8 => write!(f, "load.8"),
-            OpCode::Store8 => write!(f, "store.8"),
+/// The error of parsing a string which is not the mnemonic of any opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMnemonic(pub String);
 
-            OpCode::And => write!(f, "and"),
-            OpCode::Or => write!(f, "or"),
-            OpCode::Xor => write!(f, "xor"),
-            OpCode::Not => write!(f, "not"),
-            OpCode::Equal => write!(f, "eq"),
-            OpCode::LessThan => write!(f, "lt"),
-            OpCode::GreaterThan => write!(f, "gt"),
+impl std::fmt::Display for UnknownMnemonic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown opcode `{}`", self.0)
+    }
+}
 
-            OpCode::Add => write!(f, "add"),
-            OpCode::Sub => write!(f, "sub"),
-            OpCode::Mul => write!(f, "mul"),
-            OpCode::Div => write!(f, "div"),
-            OpCode::Mod => write!(f, "mod"),
-            OpCode::Shift => write!(f, "shift"),
+impl std::error::Error for UnknownMnemonic {}
 
-            OpCode::Io => write!(f, "io"),
+/// Parses a mnemonic, the inverse of `Display`.
+impl std::str::FromStr for OpCode {
+    type Err = UnknownMnemonic;
 
-            OpCode::Nop => write!(f, "nop"),
-        }
+    fn from_str(text: &str) -> Result<OpCode, Self::Err> {
+        OpCode::from_mnemonic(text).ok_or_else(|| UnknownMnemonic(text.to_string()))
     }
 }
 
 impl OpCode {
+    /// Like `try_from`, but without building an error for invalid bytes.
+    pub fn decode(byte: u8) -> Option<OpCode> {
+        let invalid = (OpCode::Sbrk as u8) < byte && byte != OpCode::Halt as u8;
+        let disabled = !cfg!(feature = "float")
+            && (OpCode::FloatAdd as u8..=OpCode::FloatToInt as u8).contains(&byte);
+        if invalid || disabled {
+            None
+        } else {
+            Some(unsafe { ::std::mem::transmute::<u8, OpCode>(byte) })
+        }
+    }
+
     pub fn into_u8(self) -> u8 {
-        unsafe { ::std::mem::transmute(self) }
+        self as u8
+    }
+}
+
+/**
+ * Receives a callback for each instruction and stack operation of the VM.
+ *
+ * VMs are generic over their debugger, and the callbacks of `NoopDebugger` do nothing, so a VM
+ * without a debugger is compiled without them.  Debuggers chosen at run time implement
+ * `CallbackDebugger` instead, and are attached with `BearVM::with_callback_debugger`.
+ */
+pub trait Debugger<T: Threading = Local>: Sized {
+    fn ip(&self, _state: &ExecutionState<Self, T>, _op: OpCode) {}
+    fn data_pop(&self, _vm: &BearVM<Self, T>) {}
+    fn data_push(&self, _vm: &BearVM<Self, T>, _cell: Cell) {}
+    fn address_pop(&self, _vm: &BearVM<Self, T>) {}
+    fn address_push(&self, _vm: &BearVM<Self, T>, _cell: Cell) {}
+    fn store(&self, _address: Cell, _value: Cell) {}
+    fn store_8(&self, _address: Cell, _value: Cell) {}
+    /// Called by `brk`, which does nothing unless a debugger is attached.
+    fn brk(&self, _state: &ExecutionState<Self, T>) {}
+}
+
+/// The debugger of a VM without one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopDebugger;
+
+impl<T: Threading> Debugger<T> for NoopDebugger {}
+
+/// The debugger of a VM whose debugger is chosen at run time, if it has one.
+pub type DynDebugger = Option<Box<dyn CallbackDebugger>>;
+
+impl Debugger for DynDebugger {
+    fn ip(&self, state: &ExecutionState<Self>, op: OpCode) {
+        if let Some(d) = self {
+            d.ip(state, op)
+        }
+    }
+
+    fn data_pop(&self, vm: &BearVM<Self>) {
+        if let Some(d) = self {
+            d.data_pop(vm)
+        }
+    }
+
+    fn data_push(&self, vm: &BearVM<Self>, cell: Cell) {
+        if let Some(d) = self {
+            d.data_push(vm, cell)
+        }
+    }
+
+    fn address_pop(&self, vm: &BearVM<Self>) {
+        if let Some(d) = self {
+            d.address_pop(vm)
+        }
+    }
+
+    fn address_push(&self, vm: &BearVM<Self>, cell: Cell) {
+        if let Some(d) = self {
+            d.address_push(vm, cell)
+        }
+    }
+
+    fn store(&self, address: Cell, value: Cell) {
+        if let Some(d) = self {
+            d.store(address, value)
+        }
+    }
+
+    fn store_8(&self, address: Cell, value: Cell) {
+        if let Some(d) = self {
+            d.store_8(address, value)
+        }
+    }
+
+    fn brk(&self, state: &ExecutionState<Self>) {
+        if let Some(d) = self {
+            d.brk(state)
+        }
     }
 }
 
-/// Allows for very simple debugging.
+/// Allows for very simple debugging, with a debugger chosen at run time.
 pub trait CallbackDebugger {
-    fn ip(&self, state: &ExecutionState, op: OpCode) {
+    fn ip(&self, state: &ExecutionState<DynDebugger>, op: OpCode) {
         let ii = state.instruction_index;
         let lw = state.loaded_word_index;
         let cw = state.current_word_index;
@@ -234,19 +652,19 @@ pub trait CallbackDebugger {
         eprintln!("\taddr: {:?}", state.vm.address);
     }
 
-    fn data_pop(&self, vm: &BearVM) {
+    fn data_pop(&self, vm: &BearVM<DynDebugger>) {
         eprintln!("data.pop: {:?}", vm.data);
     }
 
-    fn data_push(&self, vm: &BearVM, cell: Cell) {
+    fn data_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
         eprintln!("data.push: {:?} {:?}", cell, vm.data);
     }
 
-    fn address_pop(&self, vm: &BearVM) {
+    fn address_pop(&self, vm: &BearVM<DynDebugger>) {
         eprintln!("address.pop: {:?}", vm.address);
     }
 
-    fn address_push(&self, vm: &BearVM, cell: Cell) {
+    fn address_push(&self, vm: &BearVM<DynDebugger>, cell: Cell) {
         eprintln!("address.push: {:?} {:?}", cell, vm.address);
     }
 
@@ -257,11 +675,161 @@ pub trait CallbackDebugger {
     fn store_8(&self, address: Cell, value: Cell) {
         eprintln!("store.8: {:?} <- {:?}", address, value);
     }
+
+    /// Called by `brk`, which does nothing unless a debugger is attached.
+    fn brk(&self, state: &ExecutionState<DynDebugger>) {
+        eprintln!("brk: ip: {}", state.ip());
+    }
+}
+
+/// A guest load or store to memory which a device was transferring at the time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmaRace {
+    /// The address of the `load` or `store` instruction.
+    pub ip: usize,
+    /// The address accessed.
+    pub address: usize,
+    /// The id of the device.
+    pub device: usize,
+    /// The access was a store, rather than a load.
+    pub store: bool,
+}
+
+/// A problem with an image, found by `BearVM::verify` before it runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Defect {
+    /// An instruction slot which can be executed holds a byte which is not an opcode.
+    InvalidOpcode { address: usize, byte: u8 },
+    /// The operand of a `lit` would be read from past the end of the image.
+    MissingOperand { address: usize },
+    /// A `call` or `jump` to a constant target outside of the image.
+    TargetOutOfBounds { address: usize, target: usize },
+    /// Execution can continue past the last word of the image.
+    RunsOffEnd { address: usize },
+}
+
+impl std::fmt::Display for Defect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Defect::InvalidOpcode { address, byte } => {
+                write!(f, "0x{:x}: invalid opcode 0x{:x}", address, byte)
+            }
+            Defect::MissingOperand { address } => {
+                write!(f, "0x{:x}: `lit` has no operand word", address)
+            }
+            Defect::TargetOutOfBounds { address, target } => {
+                write!(f, "0x{:x}: target 0x{:x} is outside of the image", address, target)
+            }
+            Defect::RunsOffEnd { address } => {
+                write!(f, "0x{:x}: execution can run past the end of the image", address)
+            }
+        }
+    }
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The guest executed `halt`.
+    Halt,
+    /// `ExecutionState::run` stopped at a trap.
+    Trap,
+    /// `ExecutionState::run` executed `BearVM::fuel` instructions, with the guest still running.
+    OutOfFuel,
+    /// `ExecutionState::run` passed `BearVM::deadline`, with the guest still running.
+    TimedOut,
+}
+
+/**
+ * The lifecycle events of a VM, delivered to the observers added with `BearVM::with_observer`, so
+ * that embedders can follow a run without wrapping `run` or polling the state.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A device was attached with the given id.  Only observers added before the device see this.
+    DeviceAttached { id: usize },
+    /// `BearVM::start` created the execution state.
+    Started,
+    /// The guest executed `brk`.
+    BreakpointHit { ip: usize },
+    /// The handler of `vector` was entered, before the instruction at `ip`.
+    Interrupted { vector: u8, ip: usize },
+    /// An instruction failed with a runtime error.
+    Trap { ip: usize, message: String },
+    /// A core dump was written to `path`, by `halt` with `-1` on top of the data stack.
+    SnapshotTaken { path: String },
+    Halted { reason: HaltReason },
+}
+
+/// The most bytes `sbrk` may grow the image to, unless `BearVM::with_memory_limit` says otherwise.
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 << 20;
+
+/// How often, in instructions, `ExecutionState::run` checks `BearVM::deadline`.
+const DEADLINE_INTERVAL: u64 = 4096;
+
+/// The low bits of a return address, which count operand words rather than address the call.
+const OPERAND_BITS: u32 = cell::SIZE.trailing_zeros();
+
+/// The address of the call which pushed the return address `encoded`.
+pub fn call_site(encoded: CellType) -> usize {
+    encoded as usize >> OPERAND_BITS
+}
+
+/**
+ * When a VM services the DMA requests of its devices, by polling each of them.  Whatever the
+ * policy, devices are also synced when the guest halts.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// After every instruction.
+    #[default]
+    Always,
+    /// After every `io` instruction.
+    AfterIo,
+    /// After every `n` instructions.
+    Every(usize),
+    /// After an instruction during which a device raised its `Attention` flag.
+    /// Devices which never raise it are only synced when the guest halts.
+    OnAttention,
+}
+
+/// Receives the `Event`s of a VM.
+pub type Observer = Box<dyn FnMut(&Event)>;
+
+/**
+ * Whether a VM can be moved to another thread, which decides how it boxes its devices and
+ * observers.  A `Local` VM takes any device.  A `Sendable` VM only takes devices and observers
+ * which are `Send`, and is itself `Send` when its debugger is, so that a host can run many VMs on a
+ * thread pool.
+ */
+pub trait Threading {
+    type Device: Device + ?Sized;
+    type Observer: FnMut(&Event) + ?Sized;
+    type Syscall: FnMut(&mut Stack) -> Result<(), String> + ?Sized;
+}
+
+/// The threading of a VM which stays on the thread that built it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Local;
+
+impl Threading for Local {
+    type Device = dyn Device;
+    type Observer = dyn FnMut(&Event);
+    type Syscall = dyn FnMut(&mut Stack) -> Result<(), String>;
+}
+
+/// The threading of a VM which can be sent to another thread.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sendable;
+
+impl Threading for Sendable {
+    type Device = dyn Device + Send;
+    type Observer = dyn FnMut(&Event) + Send;
+    type Syscall = dyn FnMut(&mut Stack) -> Result<(), String> + Send;
 }
 
 /// The runtime state of the VM.
-#[derive(Default)]
-pub struct ExecutionState {
+pub struct ExecutionState<D: Debugger<T> = NoopDebugger, T: Threading = Local> {
     /// The index in the current cell of the address being executed.
     pub instruction_index: usize,
     /// The index in the binary image of the currently loaded cell.
@@ -269,66 +837,187 @@ pub struct ExecutionState {
     /// The index in the binary image of the next word to be loaded.
     pub current_word_index: usize,
     /// The loaded cell as an array of bytes.
-    pub word: [u8; 4],
+    pub word: [u8; cell::SIZE],
+    /// The number of instructions executed since the devices were last synced.
+    unsynced: usize,
+    /// The number of instructions executed, published to the devices by `clock`.
+    executed: u64,
+    /// The address of the handler of each interrupt vector, registered by `ivec`, or `0`.
+    handlers: [usize; Interrupts::COUNT as usize],
+    /// The vectors which have a handler, as a mask.
+    handled: u32,
+    /// An interrupt handler is running, so no other is entered until its `iret`.
+    in_interrupt: bool,
     /// Indicates if the VM is running or halted.
     pub running: bool,
     /// The VM that this is the execution state of.
-    pub vm: BearVM,
+    pub vm: BearVM<D, T>,
 }
 
 // TODO: Make everything private and expose through interface.
 // TODO: The original design forced the image to be present at construction.
 // Because of web-assembly shenanigans, this was changed.
 // It would be nice to go back.
-#[derive(Default)]
-pub struct BearVM {
+pub struct BearVM<D: Debugger<T> = NoopDebugger, T: Threading = Local> {
     /// The binary image being executed.
-    pub image: Vec<u32>,
+    pub image: Vec<CellType>,
     /// The data stack.
-    pub data: Vec<Cell>,
+    pub data: Stack,
     /// The address stack.
-    pub address: Vec<Cell>,
+    pub address: Stack,
     /// The external devices.
-    pub devices: Vec<Box<dyn Device>>,
+    pub devices: Vec<Box<T::Device>>,
 
     /// Optional logger.
     pub debug_logger: Option<fn(&str)>,
-    /// Optional debuger.
-    pub callback_debugger: Option<Box<dyn CallbackDebugger>>,
+    /// The debugger.
+    pub debugger: D,
+
+    /// Check guest memory accesses against the devices' pending DMA transfers.
+    pub dma_race_detection: bool,
+    /// The races found by `dma_race_detection`.
+    pub dma_races: Vec<DmaRace>,
+    /// Trap when `add`, `sub` or `mul` overflows as signed, rather than wrapping around.
+    pub overflow_traps: bool,
+    /// Trap on a `load` or `store` whose address is not a multiple of the size of a cell.
+    pub alignment_checks: bool,
+    /// The most bytes `sbrk` may grow the image to, `DEFAULT_MEMORY_LIMIT` by default.
+    pub memory_limit: usize,
+
+    /// Receive the lifecycle events of the VM.
+    pub observers: Vec<Box<T::Observer>>,
+
+    /// When the devices are synced.
+    pub sync_policy: SyncPolicy,
+    /// The number of instructions after which `ExecutionState::run` stops, if any.
+    pub fuel: Option<u64>,
+    /// The time after which `ExecutionState::run` stops, if any.
+    pub deadline: Option<Instant>,
+    /// Where `halt` writes a core dump, `core.bin` by default.
+    pub core_path: PathBuf,
+    /// Memory shared with other VMs, which shadows the image where it is mapped.
+    pub shared: Option<SharedMemory>,
+    /// The handlers of `sys`, by number.
+    pub syscalls: HashMap<CellType, Box<T::Syscall>>,
+    /// Raised by the devices, for `SyncPolicy::OnAttention`.
+    attention: Attention,
+    /// Raised by the devices to interrupt the guest.
+    interrupts: Interrupts,
+    /// The instructions executed, as the devices see them.
+    clock: Clock,
+
+    /// The opcode of each byte of the image, decoded ahead of time by `with_predecode`.
+    /// Kept in step with the image by `write_word`, so the image must be written through it.
+    decoded: Option<Vec<Option<OpCode>>>,
+    /// The live checkpoints of the execution state, oldest first.  The newest saves the pages
+    /// which `write_word` writes.
+    checkpoints: Vec<Saved>,
+    /// The number of checkpoints taken.
+    checkpoints_taken: u64,
+}
+
+/// The state when a checkpoint was taken, but for the image, whose pages are saved as written.
+struct Saved {
+    serial: u64,
+    instruction_index: usize,
+    loaded_word_index: usize,
+    current_word_index: usize,
+    word: [u8; cell::SIZE],
+    unsynced: usize,
+    executed: u64,
+    handlers: [usize; Interrupts::COUNT as usize],
+    handled: u32,
+    in_interrupt: bool,
+    running: bool,
+    data: Vec<Cell>,
+    address: Vec<Cell>,
+    pages: Pages,
+}
+
+// Not derived, which would require the boxed device and observer types to be `Default`.
+impl<D: Debugger<T> + Default, T: Threading> Default for ExecutionState<D, T> {
+    fn default() -> Self {
+        ExecutionState {
+            instruction_index: 0,
+            loaded_word_index: 0,
+            current_word_index: 0,
+            word: Default::default(),
+            unsynced: 0,
+            executed: 0,
+            handlers: [0; Interrupts::COUNT as usize],
+            handled: 0,
+            in_interrupt: false,
+            running: false,
+            vm: BearVM::default(),
+        }
+    }
+}
+
+impl<D: Debugger<T> + Default, T: Threading> Default for BearVM<D, T> {
+    fn default() -> Self {
+        BearVM {
+            image: Vec::new(),
+            data: Stack::default(),
+            address: Stack::default(),
+            devices: Vec::new(),
+            debug_logger: None,
+            debugger: D::default(),
+            dma_race_detection: false,
+            overflow_traps: false,
+            alignment_checks: false,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            dma_races: Vec::new(),
+            observers: Vec::new(),
+            sync_policy: SyncPolicy::default(),
+            fuel: None,
+            deadline: None,
+            core_path: PathBuf::from("core.bin"),
+            shared: None,
+            syscalls: HashMap::new(),
+            attention: Attention::default(),
+            interrupts: Interrupts::default(),
+            clock: Clock::default(),
+            decoded: None,
+            checkpoints: Vec::new(),
+            checkpoints_taken: 0,
+        }
+    }
 }
 
 /// Wraps calls to push and pop the stacks with calls to the debugger and error handling code.
-impl BearVM {
+impl<D: Debugger<T>, T: Threading> BearVM<D, T> {
     pub fn data_pop(&mut self) -> Result<Cell, Error> {
-        if let Some(d) = self.callback_debugger.as_ref() { d.data_pop(self) }
-        self.data.pop().ok_or(Error::data_underflow())
+        self.debugger.data_pop(self);
+        self.data.pop().ok_or_else(Error::data_underflow)
     }
 
     fn data_peek(&mut self) -> Result<Cell, Error> {
-        self.data.last().cloned().ok_or(Error::data_underflow())
+        self.data.last().cloned().ok_or_else(Error::data_underflow)
     }
 
-    pub fn data_push(&mut self, cell: Cell) {
-        if let Some(d) = self.callback_debugger
-            .as_ref() { d.data_push(self, cell) }
-        self.data.push(cell);
+    pub fn data_push(&mut self, cell: Cell) -> Result<(), Error> {
+        self.debugger.data_push(self, cell);
+        self.data.push(cell).map_err(|_| Error::data_overflow())
     }
 
     fn address_pop(&mut self) -> Result<Cell, Error> {
-        if let Some(d) = self.callback_debugger
-            .as_ref() { d.address_pop(self) }
-        let value = self.address.pop().ok_or(Error::address_underflow())?;
+        self.debugger.address_pop(self);
+        let value = self.address.pop().ok_or_else(Error::address_underflow)?;
         Ok(value as Cell)
     }
 
-    fn address_push(&mut self, cell: Cell) {
-        if let Some(d) = self.callback_debugger
-            .as_ref() { d.address_push(self, cell) }
-        self.address.push(unsafe { ::std::mem::transmute(cell) });
+    fn address_push(&mut self, cell: Cell) -> Result<(), Error> {
+        self.debugger.address_push(self, cell);
+        self.address.push(cell).map_err(|_| Error::address_overflow())
     }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    /// The number of instructions executed, or begun, since the state was created.
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
     pub fn ip(&self) -> usize {
         self.loaded_word_index * cell::SIZE + self.instruction_index
     }
@@ -339,7 +1028,7 @@ impl ExecutionState {
         current_word_index: usize,
         instruction_index: usize,
     ) -> Result<(), Error> {
-        if self.vm.image.len() < loaded_word_index {
+        if self.vm.image.len() <= loaded_word_index {
             Err(Error::ip_oob(
                 loaded_word_index * cell::SIZE + instruction_index,
             ))
@@ -347,37 +1036,51 @@ impl ExecutionState {
             self.loaded_word_index = loaded_word_index;
             self.current_word_index = current_word_index;
             self.instruction_index = instruction_index;
-            self.word = self.vm.image[self.loaded_word_index].to_le_bytes();
+            self.load_word();
             Ok(())
         }
     }
 
-    pub fn ip_get_encoded(&self) -> u32 {
-        assert!(self.instruction_index < 4);
-        assert!(self.loaded_word_index & 0x7FFF == self.loaded_word_index);
-        assert!(self.current_word_index & 0x7FFF == self.current_word_index);
-        
-        ((self.loaded_word_index << 17)
-            | (self.current_word_index << 2)
-            | self.instruction_index) as u32
+    /**
+     * Encodes the instruction pointer in a cell, as a return address: the address of the
+     * instruction being executed, followed by the number of operand words read by the instructions
+     * before it in its word.  That is less than `cell::SIZE`, so it takes `OPERAND_BITS`, and calls
+     * can be made from the first 1 GiB of the image (32 KiB with the `small` feature).
+     */
+    pub fn ip_get_encoded(&self) -> Result<CellType, Error> {
+        let operands = self.current_word_index - self.loaded_word_index;
+        assert!(operands < cell::SIZE);
+        CellType::try_from((self.ip() << OPERAND_BITS) | operands)
+            .map_err(|_| Error::return_address_oob(self.ip()))
     }
 
-    pub fn ip_set_encoded(&mut self, ip: u32) -> Result<(), Error> {
-        let ii = ip & 3;
-        let lw = ip >> 17;
-        let cw = (ip >> 2) & 0x7FFF;
-        self.ip_set(lw as usize, cw as usize, ii as usize)
+    pub fn ip_set_encoded(&mut self, ip: CellType) -> Result<(), Error> {
+        let address = call_site(ip);
+        let word = address / cell::SIZE;
+        let operands = ip as usize & (cell::SIZE - 1);
+        self.ip_set(word, word + operands, address % cell::SIZE)
     }
 
     pub fn ip_inc(&mut self) -> Result<(), Error> {
+        self.advance::<true>()
+    }
+
+    /// `ip_inc`, which only checks that the next word is in the image if `CHECKED`.
+    #[inline]
+    fn advance<const CHECKED: bool>(&mut self) -> Result<(), Error> {
         if self.instruction_index == (cell::SIZE - 1) {
             self.current_word_index += 1;
             self.loaded_word_index = self.current_word_index;
             self.instruction_index = 0;
-            if self.vm.image.len() < self.loaded_word_index {
-                return Err(Error::ip_oob(self.ip()));
+            if CHECKED {
+                if self.vm.image.len() <= self.loaded_word_index {
+                    return Err(Error::ip_oob(self.ip()));
+                }
+                self.load_word();
+            } else {
+                // SAFETY: the caller of `run_unchecked` promises execution stays in the image.
+                self.word = unsafe { self.vm.image.get_unchecked(self.loaded_word_index) }.to_le_bytes();
             }
-            self.word = self.vm.image[self.loaded_word_index].to_le_bytes();
         } else {
             self.instruction_index += 1;
         }
@@ -392,13 +1095,33 @@ impl ExecutionState {
         }
     }
 
+    /// Loads the word at `loaded_word_index`.
+    pub fn load_word(&mut self) {
+        self.word = self.vm.image[self.loaded_word_index].to_le_bytes();
+    }
+
+    /// Writes a word of the image.
+    /// If the word is the one currently being executed, it is reloaded so that
+    /// self-modifying code sees its own changes.
+    pub fn write_word(&mut self, index: usize, value: CellType) {
+        self.vm.write_word(index, value);
+        if index == self.loaded_word_index {
+            self.load_word();
+        }
+    }
+
     pub fn instruction(&self) -> Result<OpCode, Error> {
-        let byte = self.word[self.instruction_index];
-        OpCode::try_from(byte).map_err(|e| e.with_ip(self.ip()))
+        let op = match &self.vm.decoded {
+            Some(decoded) => decoded[self.ip()],
+            None => OpCode::decode(self.word[self.instruction_index]),
+        };
+        op.ok_or_else(|| {
+            Error::invalid_instruction(self.word[self.instruction_index]).with_ip(self.ip())
+        })
     }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
     fn data_pop(&mut self) -> Result<Cell, Error> {
         self.vm.data_pop().map_err(|e| e.with_ip_from_state(self))
     }
@@ -408,55 +1131,145 @@ impl ExecutionState {
     }
 }
 
-impl ExecutionState {
-    fn inst_lit_next_word(&mut self) -> Result<(), Error> {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    fn inst_lit_next_word<const CHECKED: bool>(&mut self) -> Result<(), Error> {
         self.current_word_index += 1;
-        let value = self.vm.image[self.current_word_index];
-        self.vm.data_push(value.into());
+        let value = if CHECKED {
+            match self.vm.image.get(self.current_word_index) {
+                Some(value) => *value,
+                None => return Err(Error::ip_oob(self.current_word_index * cell::SIZE)),
+            }
+        } else {
+            // SAFETY: the caller of `run_unchecked` promises operands are in the image.
+            unsafe { *self.vm.image.get_unchecked(self.current_word_index) }
+        };
+        self.vm.data_push(value.into())?;
         Ok(())
     }
 
     fn inst_sext_8(&mut self) -> Result<(), Error> {
-        let value: u32 = self.data_pop()?.into();
-        if value <= u8::MAX as u32 {
-            let value = value as u8;
-            let value: i8 = unsafe { transmute_copy(&value) };
-            let value: i32 = value as i32;
-            let value: u32 = unsafe { transmute_copy(&value) };
-            self.vm.data_push(value.into());
-        } else {
-            self.vm.data_push(value.into());
-        }
+        let value = u32::from(self.data_pop()?);
+        self.vm.data_push(Cell::from(value as u8 as i8))?;
         Ok(())
     }
 
     fn inst_sext_16(&mut self) -> Result<(), Error> {
-        let value: u32 = self.data_pop()?.into();
-        if value <= u16::MAX as u32 {
-            let value = value as u16;
-            let value: i16 = unsafe { transmute_copy(&value) };
-            let value: i32 = value as i32;
-            let value: u32 = unsafe { transmute_copy(&value) };
-            self.vm.data_push(value.into());
-        } else {
-            self.vm.data_push(value.into());
-        }
+        let value = u32::from(self.data_pop()?);
+        self.vm.data_push(Cell::from(value as u16 as i16))?;
+        Ok(())
+    }
+
+    fn inst_sbrk(&mut self) -> Result<(), Error> {
+        let bytes: usize = self.data_pop()?.into();
+        let end = self.vm.grow(bytes).and_then(|end| Cell::try_from(end).ok());
+        self.vm.data_push(end.unwrap_or_else(|| Cell::from(-1)))?;
+        Ok(())
+    }
+
+    fn inst_zext(&mut self, mask: u32) -> Result<(), Error> {
+        let value = u32::from(self.data_pop()?);
+        self.vm.data_push(Cell::from(value & mask))?;
         Ok(())
     }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    /// Writes a core dump to `BearVM::core_path`.
     pub fn dump(&self) -> Result<(), std::io::Error> {
-        let v = crate::util::convert_slice32_to_vec8(&self.vm.image);
-        std::fs::write("core.bin", v)
+        self.dump_to(&self.vm.core_path)
+    }
+
+    /// Writes a core dump of the state to `path`, in the format of `CoreDump::encode`.
+    pub fn dump_to(&self, path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        std::fs::write(path, CoreDump::of(self).encode())
+    }
+
+    /**
+     * Takes a checkpoint, to which `rollback` returns, e.g. to fork execution in a debugger or a
+     * fuzzer.  The image is not copied: each of its pages is saved when it is first written after
+     * the newest checkpoint, so checkpoints are cheap, and stores cost a lookup while any is live.
+     * Devices are not checkpointed.
+     */
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let checkpoint = Checkpoint {
+            depth: self.vm.checkpoints.len(),
+            serial: self.vm.checkpoints_taken,
+        };
+        self.vm.checkpoints_taken += 1;
+        self.vm.checkpoints.push(Saved {
+            serial: checkpoint.serial,
+            instruction_index: self.instruction_index,
+            loaded_word_index: self.loaded_word_index,
+            current_word_index: self.current_word_index,
+            word: self.word,
+            unsynced: self.unsynced,
+            executed: self.executed,
+            handlers: self.handlers,
+            handled: self.handled,
+            in_interrupt: self.in_interrupt,
+            running: self.running,
+            data: self.vm.data.to_vec(),
+            address: self.vm.address.to_vec(),
+            pages: Pages::default(),
+        });
+        checkpoint
+    }
+
+    /**
+     * Returns to the state of `checkpoint`, but for the devices.  The checkpoint stays live, so it
+     * can be returned to again, and the checkpoints taken after it are released.
+     */
+    pub fn rollback(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.vm.check_checkpoint(checkpoint)?;
+        let mut later = self.vm.checkpoints.split_off(checkpoint.depth);
+        // Newest first, so that each page ends up as the oldest checkpoint which saved it saw it.
+        for saved in later.drain(1..).rev() {
+            self.vm.restore_pages(saved.pages);
+        }
+        let mut saved = later.pop().ok_or_else(Error::unknown_checkpoint)?;
+        self.vm.restore_pages(std::mem::take(&mut saved.pages));
+        self.instruction_index = saved.instruction_index;
+        self.loaded_word_index = saved.loaded_word_index;
+        self.current_word_index = saved.current_word_index;
+        self.word = saved.word;
+        self.unsynced = saved.unsynced;
+        self.executed = saved.executed;
+        self.handlers = saved.handlers;
+        self.handled = saved.handled;
+        self.in_interrupt = saved.in_interrupt;
+        self.running = saved.running;
+        let stacks = [(&mut self.vm.data, &saved.data), (&mut self.vm.address, &saved.address)];
+        for (stack, cells) in stacks {
+            stack.clear();
+            for cell in cells.iter() {
+                // The stack held these cells, and its capacity has not changed.
+                let _ = stack.push(*cell);
+            }
+        }
+        self.vm.checkpoints.push(saved);
+        Ok(())
+    }
+
+    /// Releases `checkpoint`, and the checkpoints taken after it, keeping the state as it is.
+    pub fn release(&mut self, checkpoint: Checkpoint) -> Result<(), Error> {
+        self.vm.check_checkpoint(checkpoint)?;
+        let later = self.vm.checkpoints.split_off(checkpoint.depth);
+        if let Some(previous) = self.vm.checkpoints.last_mut() {
+            for saved in later {
+                previous.pages.merge(saved.pages);
+            }
+        }
+        Ok(())
     }
 
     /**
-     * Halt.
+     * Halt.  With `-1` on top of the data stack, first write a core dump.
      */
     fn inst_halt(&mut self) {
-        if Some(Cell(u32::MAX)) == self.vm.data.last().copied() {
-            self.dump().ok();
+        if Some(Cell(CellType::MAX)) == self.vm.data.last().copied() && self.dump().is_ok() {
+            self.vm.emit(Event::SnapshotTaken {
+                path: self.vm.core_path.display().to_string(),
+            });
         }
     }
 
@@ -472,7 +1285,26 @@ impl ExecutionState {
      */
     fn inst_dup(&mut self) -> Result<(), Error> {
         let tos = self.data_peek()?;
-        self.vm.data_push(tos);
+        self.vm.data_push(tos)?;
+        Ok(())
+    }
+
+    fn inst_address_peek(&mut self) -> Result<(), Error> {
+        let tos = self.vm.address.last().copied().ok_or_else(Error::address_underflow)?;
+        self.vm.data_push(tos)?;
+        Ok(())
+    }
+
+    fn inst_address_drop(&mut self) -> Result<(), Error> {
+        self.vm.address_pop()?;
+        Ok(())
+    }
+
+    fn inst_address_swap(&mut self) -> Result<(), Error> {
+        let a = self.vm.address_pop()?;
+        let b = self.vm.address_pop()?;
+        self.vm.address_push(a)?;
+        self.vm.address_push(b)?;
         Ok(())
     }
 
@@ -481,14 +1313,60 @@ impl ExecutionState {
         Ok(())
     }
 
+    fn inst_depth(&mut self) -> Result<(), Error> {
+        let depth = self.vm.data.len() as CellType;
+        self.vm.data_push(depth.into())?;
+        Ok(())
+    }
+
+    fn inst_address_depth(&mut self) -> Result<(), Error> {
+        let depth = self.vm.address.len() as CellType;
+        self.vm.data_push(depth.into())?;
+        Ok(())
+    }
+
     /**
      * Swap the top two values on the data stack.
      */
     fn inst_swap(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        self.vm.data_push(tos);
-        self.vm.data_push(nos);
+        self.vm.data_push(tos)?;
+        self.vm.data_push(nos)?;
+        Ok(())
+    }
+
+    fn inst_over(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_peek()?;
+        self.vm.data_push(tos)?;
+        self.vm.data_push(nos)?;
+        Ok(())
+    }
+
+    fn inst_rot(&mut self) -> Result<(), Error> {
+        let c = self.data_pop()?;
+        let b = self.data_pop()?;
+        let a = self.data_pop()?;
+        self.vm.data_push(b)?;
+        self.vm.data_push(c)?;
+        self.vm.data_push(a)?;
+        Ok(())
+    }
+
+    fn inst_nip(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        self.data_pop()?;
+        self.vm.data_push(tos)?;
+        Ok(())
+    }
+
+    fn inst_tuck(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        self.vm.data_push(tos)?;
+        self.vm.data_push(nos)?;
+        self.vm.data_push(tos)?;
         Ok(())
     }
 
@@ -497,7 +1375,7 @@ impl ExecutionState {
      */
     fn inst_move_data_to_address(&mut self) -> Result<(), Error> {
         let data = self.data_pop()?;
-        self.vm.address_push(data);
+        self.vm.address_push(data)?;
         Ok(())
     }
 
@@ -506,131 +1384,242 @@ impl ExecutionState {
      */
     fn inst_move_address_to_data(&mut self) -> Result<(), Error> {
         let addr = self.vm.address_pop()?;
-        self.vm.data_push(addr);
+        self.vm.data_push(addr)?;
+        Ok(())
+    }
+}
+
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    fn inst_or(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        let value = tos | nos;
+        self.vm.data_push(value)?;
+        Ok(())
+    }
+
+    fn inst_and(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        let value = tos & nos;
+        self.vm.data_push(value)?;
+        Ok(())
+    }
+
+    fn inst_xor(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        let value = tos ^ nos;
+        self.vm.data_push(value)?;
+        Ok(())
+    }
+
+    fn inst_not(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let value = !tos;
+        self.vm.data_push(value)?;
+        Ok(())
+    }
+
+    fn inst_equal(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        self.vm
+            .data_push(if tos == nos { (-1).into() } else { 0.into() })?;
+        Ok(())
+    }
+
+    fn inst_less_than(&mut self) -> Result<(), Error> {
+        let tos = i32::from(self.data_pop()?);
+        let nos = i32::from(self.data_pop()?);
+        self.vm
+            .data_push(if tos < nos { (-1).into() } else { 0.into() })?;
+        Ok(())
+    }
+
+    fn inst_greater_than(&mut self) -> Result<(), Error> {
+        let tos = i32::from(self.data_pop()?);
+        let nos = i32::from(self.data_pop()?);
+        self.vm
+            .data_push(if tos > nos { (-1).into() } else { 0.into() })?;
+        Ok(())
+    }
+
+    fn inst_less_than_unsigned(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        self.vm
+            .data_push(if tos < nos { (-1).into() } else { 0.into() })?;
+        Ok(())
+    }
+
+    fn inst_greater_than_unsigned(&mut self) -> Result<(), Error> {
+        let tos = self.data_pop()?;
+        let nos = self.data_pop()?;
+        self.vm
+            .data_push(if tos > nos { (-1).into() } else { 0.into() })?;
         Ok(())
     }
-}
 
-impl ExecutionState {
-    fn inst_or(&mut self) -> Result<(), Error> {
+    /// Traps if `overflow_traps` is set and the signed result of an operation did not fit.
+    fn check_overflow(&self, result: Option<SignedCellType>) -> Result<(), Error> {
+        if self.vm.overflow_traps && result.is_none() {
+            return Err(Error::overflow().with_ip_from_state(self));
+        }
+        Ok(())
+    }
+
+    fn inst_add(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        let value = tos | nos;
-        self.vm.data_push(value);
+        self.check_overflow((tos.0 as SignedCellType).checked_add(nos.0 as SignedCellType))?;
+        let value = tos + nos;
+        self.vm.data_push(value)?;
         Ok(())
     }
 
-    fn inst_and(&mut self) -> Result<(), Error> {
+    fn inst_sub(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        let value = tos & nos;
-        self.vm.data_push(value);
+        self.check_overflow((tos.0 as SignedCellType).checked_sub(nos.0 as SignedCellType))?;
+        let value = tos - nos;
+        self.vm.data_push(value)?;
         Ok(())
     }
 
-    fn inst_xor(&mut self) -> Result<(), Error> {
+    fn inst_mul(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        let value = tos ^ nos;
-        self.vm.data_push(value);
+        self.check_overflow((tos.0 as SignedCellType).checked_mul(nos.0 as SignedCellType))?;
+        let value = tos * nos;
+        self.vm.data_push(value)?;
         Ok(())
     }
 
-    fn inst_not(&mut self) -> Result<(), Error> {
+    fn inst_mul_high(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
-        let value = !tos;
-        self.vm.data_push(value);
+        let nos = self.data_pop()?;
+        self.vm.data_push(tos.mul_high(nos))?;
         Ok(())
     }
 
-    fn inst_equal(&mut self) -> Result<(), Error> {
+    fn inst_add_carry(&mut self) -> Result<(), Error> {
+        let carry = self.data_pop()?;
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        self.vm
-            .data_push(if tos == nos { (-1).into() } else { 0.into() });
+        let (sum, carry) = tos.add_carry(nos, carry.0 != 0);
+        self.vm.data_push(sum)?;
+        self.vm.data_push((carry as u32).into())?;
         Ok(())
     }
 
-    fn inst_less_than(&mut self) -> Result<(), Error> {
+    fn inst_sub_borrow(&mut self) -> Result<(), Error> {
+        let borrow = self.data_pop()?;
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        self.vm
-            .data_push(if tos < nos { (-1).into() } else { 0.into() });
+        let (difference, borrow) = tos.sub_borrow(nos, borrow.0 != 0);
+        self.vm.data_push(difference)?;
+        self.vm.data_push((borrow as u32).into())?;
         Ok(())
     }
 
-    fn inst_greater_than(&mut self) -> Result<(), Error> {
+    fn inst_min(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
         self.vm
-            .data_push(if tos > nos { (-1).into() } else { 0.into() });
+            .data_push(if i32::from(tos) < i32::from(nos) { tos } else { nos })?;
         Ok(())
     }
 
-    fn inst_add(&mut self) -> Result<(), Error> {
+    fn inst_max(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
-        let value = tos + nos;
-        self.vm.data_push(value);
+        self.vm
+            .data_push(if i32::from(tos) > i32::from(nos) { tos } else { nos })?;
         Ok(())
     }
 
-    fn inst_sub(&mut self) -> Result<(), Error> {
+    fn inst_abs(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
-        let nos = self.data_pop()?;
-        let value = tos - nos;
-        self.vm.data_push(value);
+        self.vm.data_push(if i32::from(tos) < 0 { -tos } else { tos })?;
         Ok(())
     }
 
-    fn inst_mul(&mut self) -> Result<(), Error> {
+    fn inst_negate(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
-        let nos = self.data_pop()?;
-        let value = tos * nos;
-        self.vm.data_push(value);
+        self.vm.data_push(-tos)?;
         Ok(())
     }
 
     fn inst_div(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
+        if nos.0 == 0 {
+            return Err(Error::division_by_zero().with_ip_from_state(self));
+        }
         let q = tos / nos;
-        self.vm.data_push(q);
+        self.vm.data_push(q)?;
         Ok(())
     }
 
     fn inst_rem(&mut self) -> Result<(), Error> {
         let tos = self.data_pop()?;
         let nos = self.data_pop()?;
+        if nos.0 == 0 {
+            return Err(Error::division_by_zero().with_ip_from_state(self));
+        }
         let r = tos.rem(nos);
-        self.vm.data_push(r);
+        self.vm.data_push(r)?;
         Ok(())
     }
 
     fn inst_shift(&mut self) -> Result<(), Error> {
-        let tos: i32 = self.data_pop()?.into();
-        let nos: u32 = self.data_pop()?.into();
-        // TODO: trap if oob?
+        let tos = self.data_pop()?.0 as SignedCellType;
+        let nos = self.data_pop()?.0;
+        // Shifting by the bits in a cell or more shifts every bit out.
+        #[allow(clippy::unnecessary_cast)] // With the `small` feature, it is a `u16`.
+        let amount = tos.unsigned_abs() as u32;
         let value = if tos < 0 {
-            nos >> tos.abs()
+            nos.checked_shr(amount)
+        } else {
+            nos.checked_shl(amount)
+        };
+        let value = value.unwrap_or(0);
+        self.vm.data_push(value.into())?;
+        Ok(())
+    }
+
+    fn inst_rotate(&mut self, left: bool) -> Result<(), Error> {
+        let tos = u32::from(self.data_pop()?);
+        let nos = self.data_pop()?.0;
+        let value = if left {
+            nos.rotate_left(tos)
         } else {
-            nos << tos.abs()
+            nos.rotate_right(tos)
         };
-        self.vm.data_push(value.into());
+        self.vm.data_push(value.into())?;
+        Ok(())
+    }
+
+    fn inst_shift_arithmetic(&mut self) -> Result<(), Error> {
+        let tos = u32::from(self.data_pop()?);
+        let nos = self.data_pop()?.0 as SignedCellType;
+        let value = nos >> tos.min(SignedCellType::BITS - 1);
+        self.vm.data_push(value.into())?;
         Ok(())
     }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
     fn inst_jump(&mut self, ifz: bool) -> Result<(), Error> {
         let ip = self.data_pop()?.0 as usize;
         if ifz && self.data_pop()?.0 != 0 {
             return Ok(());
         }
-        let (w, i) = if ip != 0 && ip % 4 == 0 {
-            ((ip / 4) - 1, 3)
+        let (w, i) = if ip != 0 && ip.is_multiple_of(cell::SIZE) {
+            ((ip / cell::SIZE) - 1, cell::SIZE - 1)
         } else {
-            ((ip / 4), (ip % 4) - 1)
+            ((ip / cell::SIZE), (ip % cell::SIZE) - 1)
         };
         self.ip_set(w, w, i)?;
         Ok(())
@@ -641,13 +1630,12 @@ impl ExecutionState {
         if ifz && self.data_pop()?.0 != 0 {
             return Ok(());
         }
-        let current = self.ip_get_encoded();
-        self.vm
-            .address_push(Cell::try_from(current).map_err(|_| Error::ip_oob(current as usize))?);
-        let (w, i) = if ip != 0 && ip % 4 == 0 {
-            ((ip / 4) - 1, 3)
+        let current = self.ip_get_encoded()?;
+        self.vm.address_push(Cell::from(current))?;
+        let (w, i) = if ip != 0 && ip.is_multiple_of(cell::SIZE) {
+            ((ip / cell::SIZE) - 1, cell::SIZE - 1)
         } else {
-            ((ip / 4), (ip % 4) - 1)
+            ((ip / cell::SIZE), (ip % cell::SIZE) - 1)
         };
         self.ip_set(w, w, i)?;
         Ok(())
@@ -665,45 +1653,153 @@ impl ExecutionState {
         self.ip_set_encoded(ip.0)?;
         Ok(())
     }
+
+    fn inst_interrupt_vector(&mut self) -> Result<(), Error> {
+        let handler = self.data_pop()?.0;
+        let vector = self.data_pop()?.0;
+        if vector >= Interrupts::COUNT as CellType {
+            return Err(Error::invalid_vector(vector).with_ip_from_state(self));
+        }
+        self.handlers[vector as usize] = handler as usize;
+        if handler == 0 {
+            self.handled &= !(1 << vector);
+        } else {
+            self.handled |= 1 << vector;
+        }
+        Ok(())
+    }
+
+    /// Enters the handler of a raised interrupt, if there is one and no handler is running.
+    #[inline]
+    fn poll_interrupts(&mut self) -> Result<(), Error> {
+        if self.handled != 0
+            && !self.in_interrupt
+            && self.vm.interrupts.pending() & self.handled != 0
+        {
+            self.interrupt()
+        } else {
+            Ok(())
+        }
+    }
+
+    /**
+     * Enters the handler of the lowest raised vector which has one, as if the next instruction
+     * were a call to it: the return address pushed resumes at that instruction.  Interrupts are not
+     * taken before the first instruction, since no return address leads back to address `0`.
+     */
+    #[cold]
+    fn interrupt(&mut self) -> Result<(), Error> {
+        let ip = self.ip();
+        if ip == 0 {
+            return Ok(());
+        }
+        let vector = (self.vm.interrupts.pending() & self.handled).trailing_zeros() as u8;
+        self.vm.interrupts.lower(vector);
+        let operands = self.current_word_index - self.loaded_word_index;
+        let resume = CellType::try_from(((ip - 1) << OPERAND_BITS) | operands)
+            .map_err(|_| Error::return_address_oob(ip))?;
+        let handler = self.handlers[vector as usize];
+        if self.vm.image.len() <= handler / cell::SIZE {
+            return Err(Error::ip_oob(handler));
+        }
+        self.vm.address_push(Cell::from(resume))?;
+        let word = handler / cell::SIZE;
+        self.ip_set(word, word, handler % cell::SIZE)?;
+        self.in_interrupt = true;
+        self.vm.emit(Event::Interrupted { vector, ip });
+        Ok(())
+    }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
     fn inst_io(&mut self) -> Result<(), Error> {
         let command = self.data_pop()?;
         let device_id = self.data_pop()?;
-        let device = &mut self.vm.devices[device_id.0 as usize];
+        self.vm.clock.set(self.executed);
+        let Some(device) = self.vm.devices.get_mut(device_id.0 as usize) else {
+            return Err(Error::unknown_device(device_id.0).with_ip_from_state(self));
+        };
+        #[cfg(not(feature = "small"))]
         let result = device.ioctl(command.0);
-        self.vm.data_push(result.into());
+        #[cfg(feature = "small")]
+        let result = device.ioctl(crate::device::widen_command(command.0));
+        self.vm.data_push(result.into())?;
+        if self.vm.sync_policy == SyncPolicy::AfterIo {
+            self.sync();
+        }
         Ok(())
     }
 
+    fn inst_sys(&mut self) -> Result<(), Error> {
+        let number = self.data_pop()?.0;
+        let vm = &mut self.vm;
+        let handler = vm.syscalls.get_mut(&number).ok_or_else(|| Error::unknown_syscall(number))?;
+        handler(&mut vm.data).map_err(|message| Error { message, ip: None })
+    }
+
+    /// Records a race if a device is transferring the `size` bytes at `address`.
+    fn check_dma(&mut self, address: usize, size: usize, store: bool) {
+        if !self.vm.dma_race_detection {
+            return;
+        }
+        for (device, d) in self.vm.devices.iter().enumerate() {
+            let hit = d
+                .dma_pending()
+                .iter()
+                .any(|range| range.start < address + size && address < range.end);
+            if hit {
+                self.vm.dma_races.push(DmaRace {
+                    ip: self.ip(),
+                    address,
+                    device,
+                    store,
+                });
+            }
+        }
+    }
+
+    /**
+     * Traps on a `load` or `store` of the cell at `address` if it is unaligned with
+     * `alignment_checks`, or if any of the bytes it spans are out of memory.
+     */
+    fn check_cell(&self, address: usize) -> Result<(), Error> {
+        if self.vm.alignment_checks && !address.is_multiple_of(cell::SIZE) {
+            return Err(Error::unaligned(address).with_ip_from_state(self));
+        }
+        self.check_bytes(address, cell::SIZE)
+    }
+
     /**
      * [&x] -> [(&x)+4, x]
      */
     fn inst_load(&mut self) -> Result<(), Error> {
         let address: usize = self.data_pop()?.into();
-        let r = address % 4;
-        let value = if r == 0 {
-            self.vm.image[address / 4]
+        self.check_dma(address, cell::SIZE, false);
+        self.check_cell(address)?;
+        let value = if address.is_multiple_of(cell::SIZE) {
+            let shared = self.vm.shared.as_ref().and_then(|shared| shared.load(address));
+            shared.unwrap_or_else(|| self.vm.image[address / cell::SIZE])
         } else {
-            panic!("load must be aligned.")
-            /*
-            let shift = 2 * r;
-            let mask = 0xFFFFFFFF >> shift;
-            let high = (self.vm.image[address / 4] & mask) << shift;
-            let low = (self.vm.image[address / 4 + 1] & !mask) >> (8 - shift);
-            high | low
-            */
+            let mut bytes = [0; cell::SIZE];
+            for (offset, byte) in bytes.iter_mut().enumerate() {
+                *byte = self.read_byte(address + offset);
+            }
+            CellType::from_le_bytes(bytes)
         };
-        self.vm.data_push(Cell::from(value));
+        self.vm.data_push(Cell::from(value))?;
         Ok(())
     }
 
     fn inst_load_8(&mut self) -> Result<(), Error> {
         let address: usize = self.data_pop()?.into();
-        let word = self.vm.image[address / 4];
-        let byte = word.to_le_bytes()[address % 4];
-        self.vm.data_push(Cell::from(byte));
+        self.check_dma(address, 1, false);
+        self.check_bytes(address, 1)?;
+        let shared = self.vm.shared.as_ref().and_then(|shared| shared.load_8(address));
+        let byte = shared.unwrap_or_else(|| {
+            let word = self.vm.image[address / cell::SIZE];
+            word.to_le_bytes()[address % cell::SIZE]
+        });
+        self.vm.data_push(Cell::from(byte))?;
         Ok(())
     }
 
@@ -713,24 +1809,19 @@ impl ExecutionState {
     fn inst_store(&mut self) -> Result<(), Error> {
         let value = self.data_pop()?;
         let address = self.data_pop()?;
-        if let Some(d) = self.vm
-            .callback_debugger
-            .as_ref() { d.store_8(address, value) }
-        let value: u32 = value.into();
+        self.vm.debugger.store(address, value);
+        let value = value.0;
         let address: usize = address.into();
-        let r = address % 4;
-        if r == 0 {
-            self.vm.image[address / 4] = value;
+        self.check_dma(address, cell::SIZE, true);
+        self.check_cell(address)?;
+        if address.is_multiple_of(cell::SIZE) {
+            if !self.vm.shared.as_ref().is_some_and(|shared| shared.store(address, value)) {
+                self.write_word(address / cell::SIZE, value);
+            }
         } else {
-            panic!("store must be aligned.");
-            /*
-            let shift = 2 * r;
-            let mask = 0xFFFFFFFF >> shift;
-            let low = value & !mask;
-            let high = value & mask;
-            self.vm.image[address / 4] = (self.vm.image[address / 4] & mask) | low;
-            self.vm.image[address / 4 + 1] = (self.vm.image[address / 4 + 1] & mask) | high;
-            */
+            for (offset, byte) in value.to_le_bytes().iter().enumerate() {
+                self.write_byte(address + offset, *byte);
+            }
         }
         Ok(())
     }
@@ -738,44 +1829,206 @@ impl ExecutionState {
     fn inst_store_8(&mut self) -> Result<(), Error> {
         let value = self.data_pop()?;
         let address = self.data_pop()?;
-        if let Some(d) = self.vm
-            .callback_debugger
-            .as_ref() { d.store_8(address, value) }
-        // TODO: interupt if too big.
-        let value: u32 = value.into();
+        self.vm.debugger.store_8(address, value);
+        let value = value.0;
         let address: usize = address.into();
-        let word = self.vm.image[address / 4];
-        let mask = 0xFF << ((address % 4) * 8);
-        let value = value << ((address % 4) * 8);
-        self.vm.image[address / 4] = (word & !mask) | value;
+        self.check_dma(address, 1, true);
+        self.check_bytes(address, 1)?;
+        if self.vm.shared.as_ref().is_some_and(|shared| shared.store_8(address, value as u8)) {
+            return Ok(());
+        }
+        let word = self.vm.image[address / cell::SIZE];
+        let mask = 0xFF << ((address % cell::SIZE) * 8);
+        let value = value << ((address % cell::SIZE) * 8);
+        self.write_word(address / cell::SIZE, (word & !mask) | value);
+        Ok(())
+    }
+
+    /// Checks that every byte of `len` from `address` is in the image or the shared memory.
+    fn check_bytes(&self, address: usize, len: usize) -> Result<(), Error> {
+        let image_len = self.vm.image.len() * cell::SIZE;
+        let shared = self.vm.shared.as_ref();
+        let end = address.checked_add(len);
+        let end = end.ok_or_else(|| Error::memory_oob(address).with_ip_from_state(self))?;
+        for address in address..end {
+            if address >= image_len && shared.is_none_or(|shared| shared.load_8(address).is_none()) {
+                return Err(Error::memory_oob(address).with_ip_from_state(self));
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&self, address: usize) -> u8 {
+        let shared = self.vm.shared.as_ref().and_then(|shared| shared.load_8(address));
+        shared.unwrap_or_else(|| self.vm.image[address / cell::SIZE].to_le_bytes()[address % cell::SIZE])
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) {
+        if self.vm.shared.as_ref().is_some_and(|shared| shared.store_8(address, value)) {
+            return;
+        }
+        let word = self.vm.image[address / cell::SIZE];
+        let shift = (address % cell::SIZE) * 8;
+        let value = (value as CellType) << shift;
+        self.write_word(address / cell::SIZE, (word & !(0xFF << shift)) | value);
+    }
+
+    fn copy_byte(&mut self, dst: usize, src: usize) {
+        let byte = self.read_byte(src);
+        self.vm.debugger.store_8(Cell::from(dst as u32), Cell::from(byte));
+        self.write_byte(dst, byte);
+    }
+
+    fn inst_mem_copy(&mut self) -> Result<(), Error> {
+        let len: usize = self.data_pop()?.into();
+        let src: usize = self.data_pop()?.into();
+        let dst: usize = self.data_pop()?.into();
+        self.check_bytes(src, len)?;
+        self.check_bytes(dst, len)?;
+        self.check_dma(src, len, false);
+        self.check_dma(dst, len, true);
+        // Copies backwards when the destination overlaps the end of the source.
+        if src < dst {
+            for offset in (0..len).rev() {
+                self.copy_byte(dst + offset, src + offset);
+            }
+        } else {
+            for offset in 0..len {
+                self.copy_byte(dst + offset, src + offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn inst_mem_fill(&mut self) -> Result<(), Error> {
+        let len: usize = self.data_pop()?.into();
+        let value = self.data_pop()?.0 as u8;
+        let dst: usize = self.data_pop()?.into();
+        self.check_bytes(dst, len)?;
+        self.check_dma(dst, len, true);
+        for address in dst..dst + len {
+            self.vm.debugger.store_8(Cell::from(address as u32), Cell::from(value));
+            self.write_byte(address, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "float")]
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    /// Replaces the top two values, as floats, with `op` of them.
+    fn inst_float(&mut self, op: impl FnOnce(f32, f32) -> u32) -> Result<(), Error> {
+        let tos = f32::from_bits(self.data_pop()?.0);
+        let nos = f32::from_bits(self.data_pop()?.0);
+        self.vm.data_push(op(tos, nos).into())?;
+        Ok(())
+    }
+
+    fn inst_int_to_float(&mut self) -> Result<(), Error> {
+        let tos = i32::from(self.data_pop()?);
+        self.vm.data_push((tos as f32).to_bits().into())?;
+        Ok(())
+    }
+
+    fn inst_float_to_int(&mut self) -> Result<(), Error> {
+        let tos = f32::from_bits(self.data_pop()?.0);
+        self.vm.data_push((tos as i32).into())?;
         Ok(())
     }
 }
 
-impl ExecutionState {
+impl<D: Debugger<T>, T: Threading> ExecutionState<D, T> {
+    /**
+     * Runs the guest from the start until it halts or traps.  Once it has executed `BearVM::fuel`
+     * instructions, or `BearVM::deadline` has passed, it stops with the guest still `running`.
+     */
     pub fn run(&mut self) -> Result<(), Error> {
+        self.run_from_start::<true>()
+    }
+
+    /**
+     * Like `run`, but without checking that each word executed, and each `lit` operand, is in the
+     * image.  The targets of branches are still checked, as are loads and stores.
+     *
+     * # Safety
+     *
+     * Execution must never leave the image by running past its last word, nor a `lit` read its
+     * operand from past it.  `BearVM::verify` finds no defects in images for which that holds of
+     * the code reached through constant targets; code reached through computed targets must be
+     * trusted as well.
+     */
+    pub unsafe fn run_unchecked(&mut self) -> Result<(), Error> {
+        self.run_from_start::<false>()
+    }
+
+    fn run_from_start<const CHECKED: bool>(&mut self) -> Result<(), Error> {
         self.instruction_index = 0;
         self.loaded_word_index = 0;
         self.current_word_index = 0;
-        self.word = self.vm.image[self.loaded_word_index].to_le_bytes();
+        self.load_word();
         self.running = true;
 
         loop {
-            self.step()?;
+            if let Err(e) = self.step_with::<CHECKED>() {
+                self.vm.emit(Event::Halted {
+                    reason: HaltReason::Trap,
+                });
+                return Err(e);
+            }
             if !self.running {
                 break;
             }
-            self.sync();
+            self.sync_if_due();
+            if let Some(reason) = self.limit_reached() {
+                self.vm.emit(Event::Halted { reason });
+                break;
+            }
         }
 
         Ok(())
     }
 
+    /// Why `run` should stop, at its fuel or deadline.  The clock is only read now and then.
+    fn limit_reached(&self) -> Option<HaltReason> {
+        if self.vm.fuel.is_some_and(|fuel| self.executed >= fuel) {
+            return Some(HaltReason::OutOfFuel);
+        }
+        match self.vm.deadline {
+            Some(deadline)
+                if self.executed.is_multiple_of(DEADLINE_INTERVAL) && Instant::now() >= deadline =>
+            {
+                Some(HaltReason::TimedOut)
+            }
+            _ => None,
+        }
+    }
+
     pub fn step(&mut self) -> Result<(), Error> {
+        self.step_with::<true>()
+    }
+
+    #[inline]
+    fn step_with<const CHECKED: bool>(&mut self) -> Result<(), Error> {
+        self.executed += 1;
+        let result = match self.poll_interrupts() {
+            Ok(()) => self.execute::<CHECKED>(),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            let ip = e.ip().unwrap_or_else(|| self.ip());
+            let message = e.message().to_string();
+            self.vm.emit(Event::Trap { ip, message });
+        }
+        result
+    }
+
+    fn execute<const CHECKED: bool>(&mut self) -> Result<(), Error> {
+        // After running off the image, the word is stale; stepping again traps again.
+        if CHECKED && self.vm.image.len() <= self.loaded_word_index {
+            return Err(Error::ip_oob(self.ip()));
+        }
         let instruction = self.instruction()?;
-        if let Some(d) = self.vm
-            .callback_debugger
-            .as_ref() { d.ip(self, instruction) }
+        self.vm.debugger.ip(self, instruction);
         match instruction {
             OpCode::Nop => self.inst_nop(),
 
@@ -797,6 +2050,8 @@ impl ExecutionState {
             OpCode::Dup => self.inst_dup(),
             OpCode::Drop => self.inst_drop(),
             OpCode::Swap => self.inst_swap(),
+            OpCode::Depth => self.inst_depth(),
+            OpCode::AddressDepth => self.inst_address_depth(),
             OpCode::MoveDataToAddr => self.inst_move_data_to_address(),
             OpCode::MoveAddrToData => self.inst_move_address_to_data(),
 
@@ -812,46 +2067,181 @@ impl ExecutionState {
             OpCode::Load8 => self.inst_load_8(),
             OpCode::Store8 => self.inst_store_8(),
 
-            OpCode::Lit => self.inst_lit_next_word(),
+            OpCode::Lit => self.inst_lit_next_word::<CHECKED>(),
             OpCode::Sext8 => self.inst_sext_8(),
             OpCode::Sext16 => self.inst_sext_16(),
+            OpCode::ZeroExtend8 => self.inst_zext(0xFF),
+            OpCode::ZeroExtend16 => self.inst_zext(0xFFFF),
+            OpCode::Sbrk => self.inst_sbrk(),
 
             OpCode::Io => self.inst_io(),
+            OpCode::InterruptVector => self.inst_interrupt_vector(),
+            OpCode::InterruptReturn => {
+                self.in_interrupt = false;
+                self.inst_return(false)
+            }
+            OpCode::Sys => self.inst_sys(),
+            OpCode::AddressPeek => self.inst_address_peek(),
+            OpCode::AddressDrop => self.inst_address_drop(),
+            OpCode::AddressSwap => self.inst_address_swap(),
+            OpCode::Over => self.inst_over(),
+            OpCode::Rot => self.inst_rot(),
+            OpCode::Nip => self.inst_nip(),
+            OpCode::Tuck => self.inst_tuck(),
+            OpCode::LessThanUnsigned => self.inst_less_than_unsigned(),
+            OpCode::GreaterThanUnsigned => self.inst_greater_than_unsigned(),
+            OpCode::MulHigh => self.inst_mul_high(),
+            OpCode::AddCarry => self.inst_add_carry(),
+            OpCode::SubBorrow => self.inst_sub_borrow(),
+            OpCode::Min => self.inst_min(),
+            OpCode::Max => self.inst_max(),
+            OpCode::Abs => self.inst_abs(),
+            OpCode::Negate => self.inst_negate(),
+            OpCode::RotateLeft => self.inst_rotate(true),
+            OpCode::RotateRight => self.inst_rotate(false),
+            OpCode::ShiftArithmetic => self.inst_shift_arithmetic(),
+            OpCode::MemCopy => self.inst_mem_copy(),
+            OpCode::MemFill => self.inst_mem_fill(),
+            #[cfg(feature = "float")]
+            OpCode::FloatAdd => self.inst_float(|tos, nos| (tos + nos).to_bits()),
+            #[cfg(feature = "float")]
+            OpCode::FloatSub => self.inst_float(|tos, nos| (tos - nos).to_bits()),
+            #[cfg(feature = "float")]
+            OpCode::FloatMul => self.inst_float(|tos, nos| (tos * nos).to_bits()),
+            #[cfg(feature = "float")]
+            OpCode::FloatDiv => self.inst_float(|tos, nos| (tos / nos).to_bits()),
+            #[cfg(feature = "float")]
+            OpCode::FloatCompare => self.inst_float(|tos, nos| match tos.partial_cmp(&nos) {
+                Some(std::cmp::Ordering::Less) => -1i32 as u32,
+                Some(std::cmp::Ordering::Equal) => 0,
+                Some(std::cmp::Ordering::Greater) => 1,
+                None => 2,
+            }),
+            #[cfg(feature = "float")]
+            OpCode::IntToFloat => self.inst_int_to_float(),
+            #[cfg(feature = "float")]
+            OpCode::FloatToInt => self.inst_float_to_int(),
+            #[cfg(not(feature = "float"))]
+            OpCode::FloatAdd
+            | OpCode::FloatSub
+            | OpCode::FloatMul
+            | OpCode::FloatDiv
+            | OpCode::FloatCompare
+            | OpCode::IntToFloat
+            | OpCode::FloatToInt => Err(Error::invalid_instruction(instruction.into_u8())),
+            OpCode::Brk => {
+                self.vm.debugger.brk(self);
+                let ip = self.ip();
+                self.vm.emit(Event::BreakpointHit { ip });
+                Ok(())
+            }
 
             OpCode::Halt => {
                 self.inst_halt();
                 self.running = false;
+                self.vm.clock.set(self.executed);
+                for device in self.vm.devices.iter_mut() {
+                    device.halt();
+                }
+                self.sync();
+                self.vm.emit(Event::Halted {
+                    reason: HaltReason::Halt,
+                });
                 return Ok(());
             }
         }?;
 
-        self.ip_inc()?;
+        self.advance::<CHECKED>()?;
         Ok(())
     }
 
+    /// Syncs the devices if the sync policy calls for it after the instruction just executed.
+    /// Steppers call this after each step, like `run`.
+    pub fn sync_if_due(&mut self) {
+        let due = match self.vm.sync_policy {
+            SyncPolicy::Always => true,
+            // Synced by `io` itself.
+            SyncPolicy::AfterIo => false,
+            SyncPolicy::Every(n) => {
+                self.unsynced += 1;
+                self.unsynced >= n
+            }
+            SyncPolicy::OnAttention => self.vm.attention.take(),
+        };
+        if due {
+            self.sync();
+        }
+    }
+
+    /// Answers the DMA requests of every device, whatever the sync policy.
     pub fn sync(&mut self) {
+        self.unsynced = 0;
+        self.vm.clock.set(self.executed);
+        let mut stale = false;
         for i in 0..self.vm.devices.len() {
-            let device = &mut self.vm.devices[i];
-            loop {
-                match device.dma_poll() {
-                    None => break,
-                    Some(DMARequest::Read(address)) => {
-                        assert!(address % 4 == 0);
-                        let word = self.vm.image[address / 4];
-                        device.dma_read_response(address, word);
+            while let Some(request) = self.vm.devices[i].dma_poll() {
+                let index = match self.dma_check(&request) {
+                    Ok(index) => index,
+                    Err(error) => {
+                        self.vm.devices[i].dma_error(request, error);
+                        break;
                     }
-                    Some(DMARequest::Write(address, value)) => {
-                        assert!(address % 4 == 0);
-                        self.vm.image[address / 4] = value;
-                        device.dma_write_response(address);
+                };
+                let loaded = self.loaded_word_index;
+                match request {
+                    DMARequest::Read(address) => {
+                        let word = self.vm.image[index];
+                        self.vm.devices[i].dma_read_response(address, Cell(word).into());
+                    }
+                    DMARequest::Write(address, value) => {
+                        self.vm.write_word(index, Cell::from(value).0);
+                        stale |= index == loaded;
+                        self.vm.devices[i].dma_write_response(address);
+                    }
+                    DMARequest::ReadBurst(address, count) => {
+                        let words: Vec<u32> = self.vm.image[index..index + count]
+                            .iter()
+                            .map(|word| Cell(*word).into())
+                            .collect();
+                        self.vm.devices[i].dma_read_burst_response(address, &words);
+                    }
+                    DMARequest::WriteBurst(address, words) => {
+                        for (offset, value) in words.iter().enumerate() {
+                            self.vm.write_word(index + offset, Cell::from(*value).0);
+                        }
+                        stale |= (index..index + words.len()).contains(&loaded);
+                        self.vm.devices[i].dma_write_burst_response(address, words.len());
                     }
                 }
             }
         }
+        if stale {
+            self.load_word();
+        }
+    }
+
+    /// The index of the first word of a DMA request, if the VM can make it.
+    fn dma_check(&self, request: &DMARequest) -> Result<usize, DmaError> {
+        let address = request.address();
+        if !address.is_multiple_of(cell::SIZE) {
+            return Err(DmaError::Unaligned);
+        }
+        let index = address / cell::SIZE;
+        match index.checked_add(request.count()) {
+            Some(end) if end <= self.vm.image.len() => Ok(index),
+            _ => Err(DmaError::OutOfBounds),
+        }
     }
 }
 
-impl BearVM {
+impl<D: Debugger<T>, T: Threading> BearVM<D, T> {
+    /// Delivers `event` to every observer, in the order they were added.
+    pub fn emit(&mut self, event: Event) {
+        for observer in self.observers.iter_mut() {
+            observer(&event);
+        }
+    }
+
     fn log(&self, _message: &str) {
         #[cfg(debug)]
         {
@@ -864,43 +2254,380 @@ impl BearVM {
 }
 
 impl BearVM {
-    pub fn new(image: Vec<u32>) -> Self {
-        Self{ image, ..Default::default() }
+    pub fn new(image: Vec<CellType>) -> Self {
+        Self {
+            image,
+            ..Default::default()
+        }
+    }
+}
+
+impl<D: Debugger> BearVM<D> {
+    /// Attaches a debugger chosen at run time.
+    pub fn with_callback_debugger(
+        self,
+        debugger: Box<dyn CallbackDebugger>,
+    ) -> BearVM<DynDebugger> {
+        self.with_debugger(Some(debugger))
+    }
+}
+
+impl BearVM<NoopDebugger, Sendable> {
+    /// Like `new`, for a VM which can be sent to another thread.
+    pub fn new_sendable(image: Vec<CellType>) -> Self {
+        Self {
+            image,
+            ..Default::default()
+        }
+    }
+}
+
+impl<D: Debugger<T>, T: Threading> BearVM<D, T> {
+    /** Scans the code which can be reached from address `0` for defects, before it runs.
+     *
+     * Code is followed through `call`s and `jump`s whose target is pushed by the `lit` just before
+     * them.  Words which are only reached through computed targets are not checked.
+     */
+    pub fn verify(&self) -> Vec<Defect> {
+        let mut defects = Vec::new();
+        let mut visited = std::collections::HashSet::new();
+        let mut work = vec![0];
+        while let Some(start) = work.pop() {
+            if !visited.insert(start) {
+                continue;
+            }
+            let (mut word, mut slot) = (start / cell::SIZE, start % cell::SIZE);
+            // The last word read by `lit`, as in `ExecutionState::current_word_index`.
+            let mut current = word;
+            let mut literal = None;
+            'words: loop {
+                let bytes = match self.image.get(word) {
+                    Some(value) => value.to_le_bytes(),
+                    None => {
+                        defects.push(Defect::RunsOffEnd {
+                            address: (word * cell::SIZE).saturating_sub(1),
+                        });
+                        break;
+                    }
+                };
+                while slot < cell::SIZE {
+                    let address = word * cell::SIZE + slot;
+                    let op = match OpCode::decode(bytes[slot]) {
+                        Some(op) => op,
+                        None => {
+                            defects.push(Defect::InvalidOpcode {
+                                address,
+                                byte: bytes[slot],
+                            });
+                            break 'words;
+                        }
+                    };
+                    let target = literal.take();
+                    match op {
+                        _ if op.has_operand() => {
+                            current += 1;
+                            match self.image.get(current) {
+                                Some(value) => literal = Some(*value as usize),
+                                None => {
+                                    defects.push(Defect::MissingOperand { address });
+                                    break 'words;
+                                }
+                            }
+                        }
+                        OpCode::Call | OpCode::CallIfZ | OpCode::Jump | OpCode::JumpIfZ => {
+                            if let Some(target) = target {
+                                if target / cell::SIZE < self.image.len() {
+                                    work.push(target);
+                                } else {
+                                    defects.push(Defect::TargetOutOfBounds { address, target });
+                                }
+                            }
+                            if op == OpCode::Jump {
+                                break 'words;
+                            }
+                        }
+                        OpCode::InterruptVector => {
+                            if let Some(target) = target.filter(|target| *target != 0) {
+                                if target / cell::SIZE < self.image.len() {
+                                    work.push(target);
+                                } else {
+                                    defects.push(Defect::TargetOutOfBounds { address, target });
+                                }
+                            }
+                        }
+                        OpCode::Return | OpCode::InterruptReturn | OpCode::Halt => break 'words,
+                        _ => {}
+                    }
+                    slot += 1;
+                }
+                word = current + 1;
+                current = word;
+                slot = 0;
+            }
+        }
+        defects.sort_by_key(|defect| match defect {
+            Defect::InvalidOpcode { address, .. }
+            | Defect::MissingOperand { address }
+            | Defect::TargetOutOfBounds { address, .. }
+            | Defect::RunsOffEnd { address } => *address,
+        });
+        defects.dedup();
+        defects
     }
 
-    pub fn with_logger(mut self, logger: fn(&str)) -> BearVM {
+    pub fn with_logger(mut self, logger: fn(&str)) -> Self {
         self.debug_logger = Some(logger);
         self
     }
 
-    pub fn with_callback_debugger(mut self, debugger: Box<dyn CallbackDebugger>) -> BearVM {
-        self.callback_debugger = Some(debugger);
+    /// Replaces the debugger, which is compiled into the VM.
+    pub fn with_debugger<E: Debugger<T>>(self, debugger: E) -> BearVM<E, T> {
+        BearVM {
+            image: self.image,
+            data: self.data,
+            address: self.address,
+            devices: self.devices,
+            debug_logger: self.debug_logger,
+            debugger,
+            dma_race_detection: self.dma_race_detection,
+            overflow_traps: self.overflow_traps,
+            alignment_checks: self.alignment_checks,
+            memory_limit: self.memory_limit,
+            dma_races: self.dma_races,
+            observers: self.observers,
+            sync_policy: self.sync_policy,
+            fuel: self.fuel,
+            deadline: self.deadline,
+            core_path: self.core_path,
+            shared: self.shared,
+            syscalls: self.syscalls,
+            attention: self.attention,
+            interrupts: self.interrupts,
+            clock: self.clock,
+            decoded: self.decoded,
+            checkpoints: self.checkpoints,
+            checkpoints_taken: self.checkpoints_taken,
+        }
+    }
+
+    /// Gives both stacks room for `capacity` cells, allocated up front.
+    pub fn with_stack_capacity(mut self, capacity: usize) -> Self {
+        self.data = Stack::fixed(capacity);
+        self.address = Stack::fixed(capacity);
+        self
+    }
+
+    /// Lets both stacks grow as needed, instead of trapping when they are full.
+    pub fn with_growable_stacks(mut self) -> Self {
+        self.data = Stack::growable();
+        self.address = Stack::growable();
+        self
+    }
+
+    /**
+     * Decodes the opcode of every byte of the image once, up front, instead of as it is executed.
+     * Stores and DMA writes decode the word they write again, so self-modifying code still works.
+     */
+    pub fn with_predecode(mut self) -> Self {
+        self.decoded = Some(Vec::new());
+        self.predecode();
+        self
+    }
+
+    pub fn with_dma_race_detection(mut self) -> Self {
+        self.dma_race_detection = true;
         self
     }
 
-    pub fn with_device(mut self, device: Box<dyn Device>) -> BearVM {
+    /// Has `add`, `sub` and `mul` trap on signed overflow, e.g. to catch arithmetic bugs.
+    pub fn with_overflow_traps(mut self) -> Self {
+        self.overflow_traps = true;
+        self
+    }
+
+    /// Has an unaligned `load` or `store` trap, rather than access the bytes it spans.
+    pub fn with_alignment_checks(mut self) -> Self {
+        self.alignment_checks = true;
+        self
+    }
+
+    /// Keeps `sbrk` from growing the image past `bytes`.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    pub fn with_observer(mut self, observer: Box<T::Observer>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// The interrupt lines of the VM, which the host can also raise.
+    pub fn interrupts(&self) -> Interrupts {
+        self.interrupts.clone()
+    }
+
+    pub fn with_sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Stops `ExecutionState::run` once `fuel` instructions have been executed.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Stops `ExecutionState::run` once `timeout` has passed, from now.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    /**
+     * Has `sys` call `handler` when it pops `number`, with the data stack, from which the handler
+     * pops its arguments and onto which it pushes its results.  An error returned by the handler
+     * traps, as does a `sys` with a number which has no handler.
+     */
+    pub fn with_syscall(mut self, number: CellType, handler: Box<T::Syscall>) -> Self {
+        self.syscalls.insert(number, handler);
+        self
+    }
+
+    /// Maps memory shared with other VMs, which `load` and `store` reach instead of the image.
+    pub fn with_shared_memory(mut self, shared: SharedMemory) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    /// Has `halt` write core dumps to `path`.
+    pub fn with_core_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.core_path = path.into();
+        self
+    }
+
+    pub fn with_device(mut self, mut device: Box<T::Device>) -> Self {
+        device.attach(self.attention.clone());
+        device.connect_interrupts(self.interrupts.clone());
+        device.connect_clock(self.clock.clone());
         self.devices.push(device);
+        let id = self.devices.len() - 1;
+        self.emit(Event::DeviceAttached { id });
         self
     }
 
-    pub fn start(self) -> Result<ExecutionState, Error> {
+    pub fn start(mut self) -> Result<ExecutionState<D, T>, Error> {
+        if self.image.is_empty() {
+            return Err(Error::ip_oob(0));
+        }
         self.log("stated.");
+        self.emit(Event::Started);
 
         let state = ExecutionState {
             loaded_word_index: 0,
             current_word_index: 0,
             instruction_index: 0,
             word: self.image[0].to_le_bytes(),
+            unsynced: 0,
+            executed: 0,
+            handlers: [0; Interrupts::COUNT as usize],
+            handled: 0,
+            in_interrupt: false,
             running: true,
             vm: self,
         };
         Ok(state)
     }
 
+    /// Replaces the image, after checking its checksum header, if it has one.
     pub fn load_image(&mut self, image: Vec<u8>) -> Result<(), Error> {
-        self.image = crate::util::convert_slice8_to_vec32(&image);
+        let image = crate::checksum::strip_header(&image).map_err(Error::checksum)?;
+        self.image = crate::util::convert_slice8_to_cells(image);
+        self.checkpoints.clear();
+        self.predecode();
         self.data.clear();
         self.address.clear();
         Ok(())
     }
+
+    /**
+     * Copies the records of a RAM-initialization blob into the image, growing it as needed, though
+     * not past `memory_limit`.
+     */
+    pub fn load_init_blob(&mut self, blob: &[u8]) -> Result<(), Error> {
+        let records = crate::util::decode_init_blob(blob).ok_or_else(Error::malformed_init_blob)?;
+        for (address, bytes) in records {
+            let address = address as usize;
+            let end = address.checked_add(bytes.len()).filter(|&end| end <= self.memory_limit);
+            let end = end.ok_or_else(|| Error::memory_oob(address))?.div_ceil(cell::SIZE);
+            if self.image.len() < end {
+                self.image.resize(end, 0);
+            }
+            for (i, byte) in bytes.into_iter().enumerate() {
+                let index = (address + i) / cell::SIZE;
+                let shift = ((address + i) % cell::SIZE) * 8;
+                self.image[index] = (self.image[index] & !(0xFF << shift)) | ((byte as CellType) << shift);
+            }
+        }
+        self.predecode();
+        Ok(())
+    }
+
+    /**
+     * Grows the image by `bytes`, rounded up to whole words of zeros, as `sbrk` does, and returns
+     * the address of its end before it grew; or returns `None`, leaving it as it is, if it would
+     * grow past `memory_limit` or what a cell can address.
+     */
+    pub fn grow(&mut self, bytes: usize) -> Option<usize> {
+        let end = self.image.len() * cell::SIZE;
+        let words = self.image.len() + bytes.div_ceil(cell::SIZE);
+        let new_end = words.checked_mul(cell::SIZE)?;
+        if new_end > self.memory_limit || new_end - 1 > CellType::MAX as usize {
+            return None;
+        }
+        self.image.resize(words, 0);
+        if let Some(decoded) = self.decoded.as_mut() {
+            decoded.resize(new_end, OpCode::decode(0));
+        }
+        Some(end)
+    }
+
+    /// Writes a word of the image, decoding it again if the image is predecoded.
+    pub fn write_word(&mut self, index: usize, value: CellType) {
+        if let Some(newest) = self.checkpoints.last_mut() {
+            newest.pages.save(index, &self.image);
+        }
+        self.set_word(index, value);
+    }
+
+    /// `write_word`, without saving the page for the newest checkpoint.
+    fn set_word(&mut self, index: usize, value: CellType) {
+        self.image[index] = value;
+        if let Some(decoded) = self.decoded.as_mut() {
+            let slots = &mut decoded[index * cell::SIZE..(index + 1) * cell::SIZE];
+            for (slot, byte) in slots.iter_mut().zip(value.to_le_bytes()) {
+                *slot = OpCode::decode(byte);
+            }
+        }
+    }
+
+    fn check_checkpoint(&self, checkpoint: Checkpoint) -> Result<(), Error> {
+        match self.checkpoints.get(checkpoint.depth) {
+            Some(saved) if saved.serial == checkpoint.serial => Ok(()),
+            _ => Err(Error::unknown_checkpoint()),
+        }
+    }
+
+    /// Writes back the pages saved by a checkpoint.
+    fn restore_pages(&mut self, pages: Pages) {
+        pages.restore(|index, value| self.set_word(index, value));
+    }
+
+    /// Decodes the whole image again, if it is predecoded.
+    fn predecode(&mut self) {
+        if let Some(decoded) = self.decoded.as_mut() {
+            decoded.clear();
+            decoded.extend(self.image.iter().flat_map(|word| word.to_le_bytes()).map(OpCode::decode));
+        }
+    }
 }
@@ -0,0 +1,49 @@
+//! The C ABI, called from Rust.  Run with `cargo test -p bear-vm --features ffi`.
+#![cfg(feature = "ffi")]
+
+use std::ffi::CStr;
+
+use bear_vm::ffi::*;
+use bear_vm::util;
+use bear_vm::vm::OpCode;
+
+/// The image of `words` whose instructions are all `op`.
+fn image(op: OpCode, words: usize) -> Vec<u8> {
+    util::convert_cells_to_vec8(&vec![u32::from_le_bytes([op as u8; 4]); words])
+}
+
+unsafe fn last_error(vm: *const BearVm) -> String {
+    CStr::from_ptr(bear_vm_last_error(vm)).to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_running_off_the_image_traps() {
+    let image = image(OpCode::Nop, 1);
+    unsafe {
+        let vm = bear_vm_new(image.as_ptr(), image.len());
+        assert_eq!(bear_vm_run(vm, 10), BearStatus::BearTrap);
+        assert_eq!(last_error(vm), "IP went out of bounds. (ip: 0x4)");
+        // The VM did not panic, so it reports the trap again rather than being given up on.
+        assert_eq!(bear_vm_step(vm), BearStatus::BearTrap);
+        assert_eq!(last_error(vm), "IP went out of bounds. (ip: 0x4)");
+        bear_vm_free(vm);
+    }
+}
+
+#[test]
+fn test_division_by_zero_traps() {
+    let mut image = util::convert_cells_to_vec8(&[u32::from_le_bytes([
+        OpCode::Lit as u8,
+        OpCode::Lit as u8,
+        OpCode::Div as u8,
+        OpCode::Halt as u8,
+    ])]);
+    image.extend([0u8; 4]);
+    image.extend(7u32.to_le_bytes());
+    unsafe {
+        let vm = bear_vm_new(image.as_ptr(), image.len());
+        assert_eq!(bear_vm_run(vm, 10), BearStatus::BearTrap);
+        assert_eq!(last_error(vm), "Division by zero. (ip: 0x2)");
+        bear_vm_free(vm);
+    }
+}
@@ -0,0 +1,56 @@
+//! The `small` profile: 16-bit cells, two instructions per word.  Run with
+//! `cargo test -p bear-vm --features small`.
+#![cfg(feature = "small")]
+
+use bear_vm::cell::{self, CellType};
+use bear_vm::util;
+use bear_vm::vm::{call_site, BearVM, OpCode};
+
+/// Packs two instructions into a word, the first in the low byte.
+fn word(first: OpCode, second: OpCode) -> CellType {
+    first as CellType | (second as CellType) << 8
+}
+
+#[test]
+fn test_word_packing() {
+    assert_eq!(cell::SIZE, 2);
+    let cells = util::convert_slice8_to_cells(&[1, 2, 3, 4, 5]);
+    assert_eq!(cells, vec![0x0201, 0x0403, 0x0005]);
+    assert_eq!(util::convert_cells_to_vec8(&cells), vec![1, 2, 3, 4, 5, 0]);
+}
+
+#[test]
+fn test_operands_follow_their_word() {
+    let image = vec![word(OpCode::Lit, OpCode::Lit), 5, 7, word(OpCode::Halt, OpCode::Halt)];
+    let mut state = BearVM::new(image).start().unwrap();
+    state.run().unwrap();
+    let data: Vec<CellType> = state.vm.data.iter().map(|cell| cell.0).collect();
+    assert_eq!(data, vec![5, 7]);
+}
+
+#[test]
+fn test_return_address_encoding() {
+    let image = vec![word(OpCode::Lit, OpCode::Lit), 5, 7, word(OpCode::Halt, OpCode::Halt)];
+    let mut state = BearVM::new(image).start().unwrap();
+    state.step().unwrap();
+    // The second `lit`, at address 1, after the 1 operand read by the first.
+    let encoded = state.ip_get_encoded().unwrap();
+    assert_eq!(encoded, 1 << 1 | 1);
+    assert_eq!(call_site(encoded), 1);
+
+    state.ip_set(0, 0, 0).unwrap();
+    state.ip_set_encoded(encoded).unwrap();
+    state.step().unwrap();
+    assert_eq!(state.vm.data.iter().map(|cell| cell.0).collect::<Vec<_>>(), vec![5, 7]);
+    assert_eq!(state.ip(), 6);
+}
+
+#[test]
+fn test_return_addresses_past_32k_are_refused() {
+    let image = vec![word(OpCode::Halt, OpCode::Halt); 0x4001];
+    let mut state = BearVM::new(image).start().unwrap();
+    state.ip_set(0x3FFF, 0x3FFF, 1).unwrap();
+    assert_eq!(state.ip_get_encoded().unwrap(), 0x7FFF << 1);
+    state.ip_set(0x4000, 0x4000, 0).unwrap();
+    assert!(state.ip_get_encoded().is_err());
+}
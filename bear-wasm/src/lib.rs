@@ -0,0 +1,252 @@
+/*!
+ * WebAssembly bindings, for running and debugging guest programs in a browser.  Built with the
+ * `wasm` feature, e.g. by `wasm-pack build bear-wasm --target web -- --features wasm`.
+ *
+ * ```js
+ * import init, { assemble, VM } from "./pkg/bear_wasm.js";
+ *
+ * await init();
+ * const program = assemble(source);
+ * const vm = new VM(program.image, [command => 0]);
+ * vm.run(10000);
+ * console.log(vm.data(), vm.ip, vm.readWord(program.label("result")));
+ * ```
+ */
+#![cfg(feature = "wasm")]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use js_sys::{Array, Function};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use bear_vm::cell::{self, Cell};
+use bear_vm::device::{DMARequest, Device};
+use bear_vm::stack::Stack;
+use bear_vm::vm::{BearVM, ExecutionState};
+
+/// The exception thrown by a device function, which is thrown again by `run` or `step`.
+type Pending = Rc<RefCell<Option<JsValue>>>;
+
+fn error(message: impl std::fmt::Display) -> JsValue {
+    JsError::new(&message.to_string()).into()
+}
+
+/// A device whose `io`s are answered by a JavaScript function taking the command.
+struct JsDevice {
+    function: Function,
+    pending: Pending,
+}
+
+impl Device for JsDevice {
+    fn ioctl(&mut self, command: u32) -> u32 {
+        let result = self
+            .function
+            .call1(&JsValue::NULL, &JsValue::from(command))
+            .and_then(|value| {
+                value
+                    .as_f64()
+                    .map(|value| value as u32)
+                    .ok_or_else(|| error("a device must return a number"))
+            });
+        result.unwrap_or_else(|e| {
+            self.pending.borrow_mut().get_or_insert(e);
+            u32::MAX
+        })
+    }
+
+    fn dma_poll(&mut self) -> Option<DMARequest> {
+        None
+    }
+
+    fn dma_write_response(&mut self, _address: usize) {}
+
+    fn dma_read_response(&mut self, _address: usize, _value: u32) {}
+}
+
+/// An assembled program: the ROM image, and the address of each label.
+#[wasm_bindgen]
+pub struct Program {
+    image: Vec<u8>,
+    labels: HashMap<String, usize>,
+}
+
+#[wasm_bindgen]
+impl Program {
+    #[wasm_bindgen(getter)]
+    pub fn image(&self) -> Vec<u8> {
+        self.image.clone()
+    }
+
+    /// The address of a label.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+
+    /// The names of the labels, sorted.
+    pub fn labels(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.labels.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Assembles a program.
+#[wasm_bindgen]
+pub fn assemble(source: &str) -> Result<Program, JsValue> {
+    let (image, debug) = bear_ass::assemble(source).map_err(error)?;
+    let labels = debug
+        .entries
+        .iter()
+        .flat_map(|entry| entry.names.iter().map(move |name| (name.clone(), entry.address)))
+        .collect();
+    Ok(Program { image, labels })
+}
+
+/// Replaces the cells of `stack`, bottom first.
+fn fill(stack: &mut Stack, cells: Vec<u32>) -> Result<(), JsValue> {
+    stack.clear();
+    for value in cells {
+        stack.push(Cell(value)).map_err(error)?;
+    }
+    Ok(())
+}
+
+/// A VM, ready to run its image from address `0`.  Device `i` of `devices` has id `i`.
+#[wasm_bindgen]
+pub struct VM {
+    state: ExecutionState,
+    pending: Pending,
+}
+
+impl VM {
+    fn step_once(&mut self) -> Result<(), JsValue> {
+        let result = self.state.step();
+        if let Some(e) = self.pending.borrow_mut().take() {
+            return Err(e);
+        }
+        result.map_err(error)?;
+        if self.state.running {
+            self.state.sync_if_due();
+        }
+        Ok(())
+    }
+
+    fn word_index(&self, address: usize) -> Result<usize, JsValue> {
+        let index = address / cell::SIZE;
+        if !address.is_multiple_of(cell::SIZE) || index >= self.state.vm.image.len() {
+            return Err(error(format!("no word at {:#x}", address)));
+        }
+        Ok(index)
+    }
+}
+
+#[wasm_bindgen]
+impl VM {
+    /// `devices` is an array of functions.
+    #[wasm_bindgen(constructor)]
+    pub fn new(image: &[u8], devices: Option<Array>) -> Result<VM, JsValue> {
+        let image = bear_vm::util::convert_slice8_to_vec32(image);
+        if image.is_empty() {
+            return Err(error("the image is empty"));
+        }
+        let pending = Pending::default();
+        let mut vm = BearVM::new(image);
+        for device in devices.iter().flat_map(|devices| devices.iter()) {
+            let function = device
+                .dyn_into::<Function>()
+                .map_err(|_| error("a device must be a function"))?;
+            let pending = pending.clone();
+            vm = vm.with_device(Box::new(JsDevice { function, pending }));
+        }
+        let state = vm.start().map_err(error)?;
+        Ok(VM { state, pending })
+    }
+
+    /// Replaces the image, and restarts from address `0` with empty stacks.
+    #[wasm_bindgen(js_name = loadImage)]
+    pub fn load_image(&mut self, image: &[u8]) -> Result<(), JsValue> {
+        if image.len() < cell::SIZE {
+            return Err(error("the image is empty"));
+        }
+        self.state.vm.load_image(image.to_vec()).map_err(error)?;
+        self.state.ip_set(0, 0, 0).map_err(error)?;
+        self.state.running = true;
+        Ok(())
+    }
+
+    /// Executes one instruction, unless the guest has halted.
+    pub fn step(&mut self) -> Result<(), JsValue> {
+        if self.state.running {
+            self.step_once()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the guest halts, or for at most `fuel` instructions.  Returns whether it halted.
+    pub fn run(&mut self, fuel: Option<u32>) -> Result<bool, JsValue> {
+        let mut remaining = fuel;
+        while self.state.running && remaining != Some(0) {
+            self.step_once()?;
+            remaining = remaining.map(|fuel| fuel - 1);
+        }
+        Ok(!self.state.running)
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn halted(&self) -> bool {
+        !self.state.running
+    }
+
+    /// The address of the next instruction.
+    #[wasm_bindgen(getter)]
+    pub fn ip(&self) -> usize {
+        self.state.ip()
+    }
+
+    /// The mnemonic of the next instruction, if it is valid.
+    pub fn instruction(&self) -> Option<String> {
+        self.state.instruction().ok().map(|op| op.mnemonic().to_string())
+    }
+
+    /// The data stack, bottom first.
+    pub fn data(&self) -> Vec<u32> {
+        self.state.vm.data.iter().map(|cell| cell.0).collect()
+    }
+
+    #[wasm_bindgen(js_name = setData)]
+    pub fn set_data(&mut self, data: Vec<u32>) -> Result<(), JsValue> {
+        fill(&mut self.state.vm.data, data)
+    }
+
+    /// The address stack, bottom first.
+    pub fn address(&self) -> Vec<u32> {
+        self.state.vm.address.iter().map(|cell| cell.0).collect()
+    }
+
+    #[wasm_bindgen(js_name = setAddress)]
+    pub fn set_address(&mut self, address: Vec<u32>) -> Result<(), JsValue> {
+        fill(&mut self.state.vm.address, address)
+    }
+
+    /// Reads the word at a word-aligned byte address.
+    #[wasm_bindgen(js_name = readWord)]
+    pub fn read_word(&self, address: usize) -> Result<u32, JsValue> {
+        Ok(self.state.vm.image[self.word_index(address)?])
+    }
+
+    /// Writes the word at a word-aligned byte address.
+    #[wasm_bindgen(js_name = writeWord)]
+    pub fn write_word(&mut self, address: usize, value: u32) -> Result<(), JsValue> {
+        let index = self.word_index(address)?;
+        self.state.write_word(index, value);
+        Ok(())
+    }
+
+    /// The whole image, as bytes.
+    pub fn memory(&self) -> Vec<u8> {
+        bear_vm::util::convert_cells_to_vec8(&self.state.vm.image)
+    }
+}
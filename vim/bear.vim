@@ -5,9 +5,11 @@
 let b:current_syntax = "bear"
 
 syn match bear_number '@'
-syn match bear_number '\d\+'
-syn match bear_number '[-+]\d\+'
-syn match bear_number '0x[0-9a-fA-F]\+'
+syn match bear_number '\d[0-9_]*'
+syn match bear_number '[-+]\d[0-9_]*'
+syn match bear_number '0x[0-9a-fA-F][0-9a-fA-F_]*'
+syn match bear_number '0b[01][01_]*'
+syn match bear_number '0o[0-7][0-7_]*'
 
 syn match bear_label '\$'
 syn match bear_label '\$>'
@@ -24,6 +26,7 @@ syn region bear_directive start="^#" end=';' contains=bear_list
 syn region bear_string_lit start='"' end='"'
 
 syn match bear_comment "--.*$"
+syn region bear_comment start="/\*" end="\*/" contains=bear_comment
 syn match bear_kw /[a-z]\+[a-z.0-9]*/
 syn match bear_quoted /`[a-z]\+[a-z.0-9]*/
 